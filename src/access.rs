@@ -0,0 +1,137 @@
+// Access to the DB for listeners of other protocols than h2okv's, i.e.
+// RESP (`src/resp.rs`), memcached (`src/memcache.rs`) and HTTP
+// (`src/http.rs`), so that they serve keys the same way as
+// `src/server.rs` does: replicas are read-only, writes in cluster mode
+// are committed through the Raft log, and keys owned by other servers
+// are redirected.
+
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::auth;
+use crate::logging;
+use crate::persistence;
+use crate::raft;
+use crate::replication;
+use crate::slots;
+use crate::store;
+use crate::tools;
+
+/// What the listeners serve
+#[derive(Clone)]
+pub struct Context {
+    pub arc_db: Arc<Mutex<store::DB>>,
+    pub arc_repl: Arc<Mutex<replication::Replication>>,
+    pub cluster: Option<Arc<raft::Node>>,
+    pub arc_slots: Arc<Mutex<slots::SlotMap>>,
+    pub arc_auth: Arc<Mutex<auth::Auth>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// writes to a replica
+    ReadOnly,
+    /// writes in cluster mode to a node other than the leader, with the
+    /// address of the leader, if known
+    NotLeader(Option<String>),
+    /// the key is owned by another server, with its slot and address
+    Moved(u16, String),
+    /// the key's slot is migrating to another server
+    Ask(u16, String),
+    Failed,
+}
+
+/// Check that the keys are served here, `asking` if the query follows
+/// ASKING. The first key which is not gives the error.
+pub fn check_keys(
+    keys: &[String],
+    asking: bool,
+    db: &store::DB,
+    ctx: &Context,
+) -> Result<(), Error> {
+    let map = ctx.arc_slots.lock().unwrap();
+    for key in keys {
+        let exists = db.items.contains_key(key);
+        match slots::route(key, exists, asking, &map) {
+            slots::Route::Local => {}
+            slots::Route::Moved(slot, addr) => return Err(Error::Moved(slot, addr)),
+            slots::Route::Ask(slot, addr) => return Err(Error::Ask(slot, addr)),
+        }
+    }
+    Ok(())
+}
+
+/// Check that writes are served here, before taking the DB
+pub fn check_writable(ctx: &Context) -> Result<(), Error> {
+    if replication::is_replica(&ctx.arc_repl) {
+        return Err(Error::ReadOnly);
+    }
+    Ok(())
+}
+
+/// Apply the writes, `Command::Put` or `Command::Delete`, returns
+/// whether each changed anything.
+///
+/// In cluster mode, the DB is released and each write is committed as
+/// its own log entry, i.e. they are not atomic. Otherwise they are
+/// applied while holding the DB, which is saved afterwards.
+pub fn write(
+    commands: Vec<raft::Command>,
+    mut db: MutexGuard<store::DB>,
+    ctx: &Context,
+) -> Result<Vec<bool>, Error> {
+    let mut result = vec![];
+    if let Some(node) = &ctx.cluster {
+        drop(db);
+        for command in commands {
+            match raft::propose(command, node) {
+                Ok(x) => result.push(x),
+                Err(raft::Error::NotLeader(leader)) => return Err(Error::NotLeader(leader)),
+                Err(raft::Error::Failed) => return Err(Error::Failed),
+            }
+        }
+        return Ok(result);
+    }
+
+    for command in commands {
+        match command {
+            raft::Command::Put(key, value) => {
+                result.push(store::put(&key, value.as_bytes(), &mut db).is_ok());
+            }
+            raft::Command::Delete(key) => {
+                result.push(store::delete(&key, &mut db).is_some());
+            }
+            _ => result.push(false),
+        }
+    }
+    if let Some(db_file) = tools::get_db_file() {
+        if let Err(e) = persistence::save_to_file(&db_file, &db) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+    Ok(result)
+}
+
+/// The address with the port shifted by `offset`, e.g. from the h2okv
+/// port of a server in the slot map to its port of another protocol.
+pub fn shift_port(addr: &str, offset: i32) -> String {
+    let (host, port) = match addr.rfind(':') {
+        Some(i) => (&addr[..i], &addr[i + 1..]),
+        None => return addr.to_string(),
+    };
+    match port.parse::<i32>() {
+        Ok(x) => format!("{}:{}", host, x + offset),
+        Err(_) => addr.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shift_port;
+
+    #[test]
+    fn test_shift_port() {
+        assert_eq!(shift_port("127.0.0.1:30160", 1000), "127.0.0.1:31160");
+        assert_eq!(shift_port("127.0.0.1:30160", -1), "127.0.0.1:30159");
+        assert_eq!(shift_port("foo", 1), "foo");
+    }
+}
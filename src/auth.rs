@@ -0,0 +1,611 @@
+// Authentication of clients, with the password of the default user
+// (`--password`) and of named users (`--users NAME=PASSWORD,...`). When
+// any is configured, connections must send AUTH before other queries,
+// which are replied with Stat `\x08` until then.
+//
+// Servers authenticate to each other, i.e. replicas to their primary,
+// Raft nodes to their peers, and slot migrations to the target, as the
+// default user with their own `--password`, which is expected to be the
+// same on all servers.
+//
+// Users may be restricted by access control lists: the categories of
+// commands they may send (read, write and admin), and the keys they may
+// access, as key patterns, i.e. a key, or a prefix ending with `*`. Users
+// of `--password` and `--users` may do anything. Users are changed with
+// the ACL query, with rules as Redis' ACL SETUSER:
+//
+// - `>PASSWORD`: set the password
+// - `#HASH`: set the password by its hash, as saved, see below
+// - `+read`, `+write`, `+admin`, `+all`: allow the category of commands,
+//   `-read` etc. to disallow them
+// - `~PATTERN`: allow the keys matching the pattern, `allkeys` for `~*`
+// - `resetkeys`: disallow all keys, `reset`: disallow everything
+//
+// The users are then saved into the file `h2okv.acl` under current
+// working directory, which overrides `--password` and `--users` when
+// restarting, except for connections to other servers.
+//
+// Passwords are only kept as hashes, in memory and in the file: PBKDF2
+// with HMAC-SHA256 of the password, with a random salt of `SALT_LEN`
+// bytes and `ITERATIONS` iterations, saved as the iterations (4 bytes,
+// LittleEndian), the salt and the hash, in hex after `#`. Hashes with
+// other iterations are still checked with their own, so that
+// `ITERATIONS` can be raised.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{Read, Write};
+use std::num::NonZeroU32;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::sync::OnceLock;
+
+use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::logging;
+use crate::tools;
+
+pub const DEFAULT_USER: &str = "default";
+
+const SALT_LEN: usize = 16;
+/// iterations of PBKDF2 for new hashes
+const ITERATIONS: u32 = 100_000;
+/// the iterations, the salt, and the hash
+const HASH_LEN: usize = 4 + SALT_LEN + 32;
+
+/// `--password` of this server, for connections to other servers
+static OWN_PASSWORD: OnceLock<String> = OnceLock::new();
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Category {
+    /// reading keys, and subscribing
+    Read,
+    /// writing keys, and publishing
+    Write,
+    /// replication, cluster, slots and users
+    Admin,
+}
+
+const CATEGORIES: [(Category, &str); 3] = [
+    (Category::Read, "read"),
+    (Category::Write, "write"),
+    (Category::Admin, "admin"),
+];
+
+/// What a user may do
+#[derive(Clone, Debug, PartialEq)]
+pub struct Acl {
+    categories: Vec<Category>,
+    /// key patterns, a key or a prefix ending with `*`
+    patterns: Vec<String>,
+}
+
+impl Acl {
+    /// Anything, for users of `--password` and `--users`
+    pub fn all() -> Acl {
+        Acl {
+            categories: CATEGORIES.iter().map(|x| x.0).collect(),
+            patterns: vec!["*".to_string()],
+        }
+    }
+
+    pub fn none() -> Acl {
+        Acl {
+            categories: vec![],
+            patterns: vec![],
+        }
+    }
+}
+
+#[derive(Clone)]
+struct User {
+    /// hash of the password, see `hash()`, none until set by ACL, when
+    /// the user cannot authenticate
+    password: Option<Vec<u8>>,
+    acl: Acl,
+}
+
+pub struct Auth {
+    users: HashMap<String, User>,
+    /// no users may be set, see `freeze()`
+    frozen: bool,
+}
+
+impl Auth {
+    pub fn new() -> Auth {
+        Auth {
+            users: HashMap::new(),
+            frozen: false,
+        }
+    }
+}
+
+/// Parse `NAME=PASSWORD,...`
+pub fn parse_users(value: &str) -> Result<Vec<(String, String)>, String> {
+    let mut users = vec![];
+    for item in value.split(',').filter(|x| !x.is_empty()) {
+        match item.find('=') {
+            Some(i) if i > 0 => users.push((item[..i].to_string(), item[i + 1..].to_string())),
+            _ => return Err(format!("invalid user: {}", item)),
+        }
+    }
+    Ok(users)
+}
+
+/// Hash of the password with a new random salt, see above
+fn new_hash(password: &str) -> Vec<u8> {
+    let mut salt = [0; SALT_LEN];
+    SystemRandom::new().fill(&mut salt).unwrap();
+    let iterations = NonZeroU32::new(ITERATIONS).unwrap();
+    let mut hash = [0; HASH_LEN - 4 - SALT_LEN];
+    pbkdf2::derive(
+        PBKDF2_HMAC_SHA256,
+        iterations,
+        &salt,
+        password.as_bytes(),
+        &mut hash,
+    );
+    let mut result = ITERATIONS.to_le_bytes().to_vec();
+    result.extend(&salt);
+    result.extend(&hash);
+    result
+}
+
+/// Iterations of the hash, in its first 4 bytes
+fn iterations(hash: &[u8]) -> u32 {
+    u32::from_le_bytes([hash[0], hash[1], hash[2], hash[3]])
+}
+
+/// If the password is the one of the hash, in constant time
+fn verify(password: &str, hash: &[u8]) -> bool {
+    let iterations = match NonZeroU32::new(iterations(hash)) {
+        Some(x) => x,
+        None => return false,
+    };
+    let (salt, hash) = hash[4..].split_at(SALT_LEN);
+    pbkdf2::verify(
+        PBKDF2_HMAC_SHA256,
+        iterations,
+        salt,
+        password.as_bytes(),
+        hash,
+    )
+    .is_ok()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+fn parse_hash(text: &str) -> Result<Vec<u8>, String> {
+    let e = format!("invalid password hash: {}", text);
+    if text.len() != HASH_LEN * 2 || !text.is_ascii() {
+        return Err(e);
+    }
+    let mut bytes = vec![];
+    for i in (0..text.len()).step_by(2) {
+        match u8::from_str_radix(&text[i..i + 2], 16) {
+            Ok(x) => bytes.push(x),
+            Err(_) => return Err(e),
+        }
+    }
+    if iterations(&bytes) == 0 {
+        return Err(e);
+    }
+    Ok(bytes)
+}
+
+pub fn from_config(password: &Option<String>, users: &[(String, String)]) -> Auth {
+    let mut auth = Auth::new();
+    if let Some(x) = password {
+        let user = User {
+            password: Some(new_hash(x)),
+            acl: Acl::all(),
+        };
+        auth.users.insert(DEFAULT_USER.to_string(), user);
+        let _ = OWN_PASSWORD.set(x.clone());
+    }
+    for (name, password) in users {
+        let user = User {
+            password: Some(new_hash(password)),
+            acl: Acl::all(),
+        };
+        auth.users.insert(name.clone(), user);
+    }
+    auth
+}
+
+/// Whether connections must authenticate
+pub fn is_required(auth: &Auth) -> bool {
+    !auth.users.is_empty()
+}
+
+/// Check the credentials, an empty user for the default user, returns
+/// the user authenticated.
+pub fn check(user: &str, password: &str, auth: &Auth) -> Option<String> {
+    let user = if user.is_empty() { DEFAULT_USER } else { user };
+    match auth.users.get(user).and_then(|x| x.password.as_ref()) {
+        Some(x) if verify(password, x) => Some(user.to_string()),
+        _ => None,
+    }
+}
+
+/// What the connection authenticated as `user` may do, anything when
+/// authentication is not required, nothing if the user is deleted.
+pub fn acl_of(user: &Option<String>, auth: &Auth) -> Acl {
+    match user {
+        Some(name) => match auth.users.get(name) {
+            Some(x) => x.acl.clone(),
+            None => Acl::none(),
+        },
+        None if is_required(auth) => Acl::none(),
+        None => Acl::all(),
+    }
+}
+
+pub fn allows(category: Category, acl: &Acl) -> bool {
+    acl.categories.contains(&category)
+}
+
+fn matches(pattern: &str, key: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => key.starts_with(prefix),
+        None => key == pattern,
+    }
+}
+
+pub fn allows_key(key: &str, acl: &Acl) -> bool {
+    acl.patterns.iter().any(|x| matches(x, key))
+}
+
+/// Whether all keys with the prefix are allowed, e.g. for PWATCH
+pub fn allows_prefix(prefix: &str, acl: &Acl) -> bool {
+    acl.patterns.iter().any(|x| match x.strip_suffix('*') {
+        Some(x) => prefix.starts_with(x),
+        None => false,
+    })
+}
+
+fn apply_rule(rule: &str, user: &mut User) -> Result<(), String> {
+    if let Some(x) = rule.strip_prefix('>') {
+        user.password = Some(new_hash(x));
+        return Ok(());
+    }
+    if let Some(x) = rule.strip_prefix('#') {
+        user.password = Some(parse_hash(x)?);
+        return Ok(());
+    }
+    if let Some(x) = rule.strip_prefix('~') {
+        if x.is_empty() || x.strip_suffix('*').unwrap_or(x).contains('*') {
+            return Err(format!("invalid key pattern: {}", x));
+        }
+        if !user.acl.patterns.iter().any(|p| p == x) {
+            user.acl.patterns.push(x.to_string());
+        }
+        return Ok(());
+    }
+    match rule {
+        "allkeys" => return apply_rule("~*", user),
+        "resetkeys" => {
+            user.acl.patterns.clear();
+            return Ok(());
+        }
+        "reset" => {
+            user.acl = Acl::none();
+            return Ok(());
+        }
+        _ => {}
+    }
+    let (allow, name) = match rule.chars().next() {
+        Some('+') => (true, &rule[1..]),
+        Some('-') => (false, &rule[1..]),
+        _ => return Err(format!("invalid rule: {}", rule)),
+    };
+    let categories: Vec<Category> = match CATEGORIES.iter().find(|x| x.1 == name) {
+        Some(x) => vec![x.0],
+        None if name == "all" => CATEGORIES.iter().map(|x| x.0).collect(),
+        None => return Err(format!("unknown category: {}", name)),
+    };
+    for x in categories {
+        user.acl.categories.retain(|c| *c != x);
+        if allow {
+            user.acl.categories.push(x);
+        }
+    }
+    Ok(())
+}
+
+/// The user changed with the rules, a new user may do nothing and has no
+/// password.
+fn changed_user(name: &str, rules: &[&str], auth: &Auth) -> Result<User, String> {
+    let mut user = match auth.users.get(name) {
+        Some(x) => x.clone(),
+        None => User {
+            password: None,
+            acl: Acl::none(),
+        },
+    };
+    for rule in rules {
+        apply_rule(rule, &mut user)?;
+    }
+    Ok(user)
+}
+
+/// Create or change the user with the rules. On a server open to anyone,
+/// the first user must have a password and `+admin`, since connections
+/// must authenticate from then on, which would lock everyone out.
+pub fn set_user(name: &str, rules: &[&str], auth: &mut Auth) -> Result<(), String> {
+    if auth.frozen {
+        return Err("cannot set users with --memcache-port".to_string());
+    }
+    let user = changed_user(name, rules, auth)?;
+    let admin = user.acl.categories.contains(&Category::Admin);
+    if auth.users.is_empty() && (user.password.is_none() || !admin) {
+        return Err("the first user needs a password and +admin".to_string());
+    }
+    auth.users.insert(name.to_string(), user);
+    Ok(())
+}
+
+/// Refuse to set users from now on, for the memcached text protocol,
+/// which has no authentication
+pub fn freeze(auth: &mut Auth) {
+    auth.frozen = true;
+}
+
+/// Delete the user, but not the last one, which would leave the server
+/// open to anyone
+pub fn delete_user(name: &str, auth: &mut Auth) -> Result<bool, String> {
+    if !auth.users.contains_key(name) {
+        return Ok(false);
+    }
+    if auth.users.len() == 1 {
+        return Err("cannot delete the last user".to_string());
+    }
+    auth.users.remove(name);
+    Ok(true)
+}
+
+/// Rules of the user, which recreate it, the password hash only if
+/// `with_password`
+fn rules(name: &str, user: &User, with_password: bool) -> String {
+    let mut rules = vec![name.to_string()];
+    if let (Some(x), true) = (&user.password, with_password) {
+        rules.push(format!("#{}", hex(x)));
+    }
+    for (category, name) in CATEGORIES.iter() {
+        if user.acl.categories.contains(category) {
+            rules.push(format!("+{}", name));
+        }
+    }
+    for x in user.acl.patterns.iter() {
+        rules.push(format!("~{}", x));
+    }
+    rules.join(" ")
+}
+
+/// Users and their rules, one per line, without passwords
+pub fn list(auth: &Auth) -> String {
+    let mut names: Vec<&String> = auth.users.keys().collect();
+    names.sort();
+    let lines: Vec<String> = names
+        .into_iter()
+        .map(|x| rules(x, &auth.users[x], false))
+        .collect();
+    lines.join("\n")
+}
+
+/// Save the users into `h2okv.acl`, only readable by us, since the
+/// password hashes are in it
+pub fn save(auth: &Auth) {
+    let path = match tools::get_acl_file() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut names: Vec<&String> = auth.users.keys().collect();
+    names.sort();
+    let mut text = String::new();
+    for x in names {
+        text.push_str(&rules(x, &auth.users[x], true));
+        text.push('\n');
+    }
+    // created with the permissions, rather than changed after writing,
+    // and changed too if the file was there already
+    let result = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| {
+            file.set_permissions(fs::Permissions::from_mode(0o600))?;
+            file.write_all(text.as_bytes())
+        });
+    if let Err(e) = result {
+        logging::error!("cannot save users: {:?}", e);
+    }
+}
+
+/// Load the users saved, replacing the configured ones, returns if any
+pub fn load(auth: &mut Auth) -> bool {
+    let text = match tools::get_acl_file().map(fs::read_to_string) {
+        Some(Ok(x)) => x,
+        _ => return false,
+    };
+    let mut loaded = Auth::new();
+    for line in text.lines().filter(|x| !x.trim().is_empty()) {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        match changed_user(tokens[0], &tokens[1..], &loaded) {
+            Ok(x) => loaded.users.insert(tokens[0].to_string(), x),
+            Err(e) => {
+                logging::warning!("invalid users saved: {}", e);
+                return false;
+            }
+        };
+    }
+    auth.users = loaded.users;
+    true
+}
+
+/// AUTH query: `\x0c\x16\x00<user-len:2><user><pw-len-byte><pw-len-bytes><password>`
+pub fn auth_query(user: &str, password: &str) -> Vec<u8> {
+    let mut query = vec![0x0c, 0x16, 0x00];
+    query.extend(&tools::u16_to_bytes(user.len() as u16));
+    query.extend(user.as_bytes());
+    let (count, bytes) = tools::u64_to_bytes(password.len() as u64);
+    query.push(count);
+    query.extend(&bytes);
+    query.extend(password.as_bytes());
+    query
+}
+
+/// Authenticate a connection to another server with `--password`, if
+/// configured, returns false if refused.
+pub fn login<S: Read + Write>(stream: &mut S) -> bool {
+    let password = match OWN_PASSWORD.get() {
+        Some(x) => x,
+        None => return true,
+    };
+    if stream.write_all(&auth_query("", password)).is_err() {
+        return false;
+    }
+    let mut reply = [0_u8; 2];
+    match stream.read_exact(&mut reply) {
+        Ok(_) => reply == [0x0c, 0x00],
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        acl_of, allows, allows_key, allows_prefix, auth_query, check, delete_user, freeze,
+        from_config, hex, is_required, list, parse_users, rules, set_user, Acl, Auth, Category,
+    };
+    use super::{HASH_LEN, SALT_LEN};
+    use ring::pbkdf2::{self, PBKDF2_HMAC_SHA256};
+    use std::num::NonZeroU32;
+
+    #[test]
+    fn test_check() {
+        assert!(!is_required(&Auth::new()));
+
+        let users = parse_users("alice=secret,bob=a=b").unwrap();
+        assert_eq!(users[1], ("bob".to_string(), "a=b".to_string()));
+        assert!(parse_users("=x").is_err());
+        assert!(parse_users("alice").is_err());
+
+        let auth = from_config(&Some("pw".to_string()), &users);
+        assert!(is_required(&auth));
+        assert_eq!(check("", "pw", &auth), Some("default".to_string()));
+        assert_eq!(check("default", "pw", &auth), Some("default".to_string()));
+        assert_eq!(check("alice", "secret", &auth), Some("alice".to_string()));
+        assert_eq!(check("alice", "secreT", &auth), None);
+        assert_eq!(check("alice", "pw", &auth), None);
+        assert_eq!(check("carol", "", &auth), None);
+
+        let auth = from_config(&None, &users);
+        assert_eq!(check("", "", &auth), None);
+    }
+
+    #[test]
+    fn test_acl() {
+        assert_eq!(acl_of(&None, &Auth::new()), Acl::all());
+
+        let mut auth = from_config(&None, &[("admin".to_string(), "pw".to_string())]);
+        assert_eq!(acl_of(&None, &auth), Acl::none());
+        assert_eq!(acl_of(&Some("admin".to_string()), &auth), Acl::all());
+
+        set_user("team1", &[], &mut auth).unwrap();
+        assert_eq!(check("team1", "", &auth), None);
+        let rules = [">s", "+all", "-admin", "~team1:*", "~shared"];
+        set_user("team1", &rules, &mut auth).unwrap();
+        assert_eq!(check("team1", "s", &auth), Some("team1".to_string()));
+        let acl = acl_of(&Some("team1".to_string()), &auth);
+        assert!(allows(Category::Read, &acl));
+        assert!(allows(Category::Write, &acl));
+        assert!(!allows(Category::Admin, &acl));
+        assert!(allows_key("team1:foo", &acl));
+        assert!(allows_key("shared", &acl));
+        assert!(!allows_key("shared2", &acl));
+        assert!(!allows_key("team2:foo", &acl));
+        assert!(allows_prefix("team1:f", &acl));
+        assert!(!allows_prefix("team", &acl));
+        assert!(!allows_prefix("", &acl));
+        assert_eq!(
+            list(&auth),
+            "admin +read +write +admin ~*\nteam1 +read +write ~team1:* ~shared"
+        );
+
+        assert!(set_user("team1", &["~a*b"], &mut auth).is_err());
+        assert!(set_user("team1", &["+nothing"], &mut auth).is_err());
+        assert!(set_user("team1", &["whatever"], &mut auth).is_err());
+        set_user("team1", &["resetkeys", "allkeys"], &mut auth).unwrap();
+        assert!(allows_prefix(
+            "",
+            &acl_of(&Some("team1".to_string()), &auth)
+        ));
+
+        assert_eq!(delete_user("team2", &mut auth), Ok(false));
+        assert_eq!(delete_user("team1", &mut auth), Ok(true));
+        assert_eq!(acl_of(&Some("team1".to_string()), &auth), Acl::none());
+        assert!(delete_user("admin", &mut auth).is_err());
+    }
+
+    #[test]
+    fn test_hash() {
+        let mut auth = Auth::new();
+        set_user("alice", &[">secret", "+all"], &mut auth).unwrap();
+        let saved = rules("alice", &auth.users["alice"], true);
+        assert!(!saved.contains("secret"));
+
+        let tokens: Vec<&str> = saved.split_whitespace().collect();
+        let mut loaded = Auth::new();
+        set_user(tokens[0], &tokens[1..], &mut loaded).unwrap();
+        assert_eq!(check("alice", "secret", &loaded), Some("alice".to_string()));
+        assert_eq!(check("alice", "secreT", &loaded), None);
+
+        // each hash has its own salt
+        set_user("bob", &[">secret"], &mut auth).unwrap();
+        assert_ne!(auth.users["alice"].password, auth.users["bob"].password);
+        assert!(set_user("carol", &["#00"], &mut auth).is_err());
+
+        // PBKDF2 of its own iterations, saved first
+        assert!(tokens[1].starts_with("#a0860100"));
+        let mut hash = 1000_u32.to_le_bytes().to_vec();
+        hash.extend(&[7; SALT_LEN]);
+        let mut derived = [0; 32];
+        let iterations = NonZeroU32::new(1000).unwrap();
+        let (salt, password) = (&[7; SALT_LEN], b"secret");
+        pbkdf2::derive(PBKDF2_HMAC_SHA256, iterations, salt, password, &mut derived);
+        hash.extend(&derived);
+        set_user("dave", &[&format!("#{}", hex(&hash)), "+all"], &mut auth).unwrap();
+        assert_eq!(check("dave", "secret", &auth), Some("dave".to_string()));
+        assert_eq!(check("dave", "secreT", &auth), None);
+        let zero = format!("#{}", "0".repeat(HASH_LEN * 2));
+        assert!(set_user("erin", &[&zero], &mut auth).is_err());
+    }
+
+    #[test]
+    fn test_first_user() {
+        let mut auth = Auth::new();
+        assert!(set_user("team1", &[">s", "+read"], &mut auth).is_err());
+        assert!(set_user("admin", &["+all"], &mut auth).is_err());
+        assert!(!is_required(&auth));
+        set_user("admin", &[">pw", "+all"], &mut auth).unwrap();
+        set_user("team1", &["+read"], &mut auth).unwrap();
+
+        let mut auth = Auth::new();
+        freeze(&mut auth);
+        assert!(set_user("admin", &[">pw", "+all"], &mut auth).is_err());
+        assert!(!is_required(&auth));
+    }
+
+    #[test]
+    fn test_auth_query() {
+        assert_eq!(
+            auth_query("al", "pw"),
+            b"\x0c\x16\x00\x02\x00al\x01\x02pw".to_vec()
+        );
+    }
+}
@@ -0,0 +1,107 @@
+// Clients blocked popping from lists, with BLPOP and BRPOP.
+//
+// A client popping from lists which are all empty is queued on each of
+// the keys, and its connection thread parks on a channel until an
+// element is handed to it, or its timeout expires. Elements pushed to a
+// list are handed right away by `store::push()`, with the DB held, to
+// the clients queued on the key, the longest waiting first: a client
+// woken up always has its element, and clients are served in the order
+// they blocked, whichever keys they wait on.
+//
+// A client whose timeout expires leaves the queues with the DB held too,
+// so that an element is never handed to a client which gave up: one
+// handed just before is still in its channel.
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::mpsc::{self, Receiver, Sender};
+
+use crate::store::Value;
+
+/// An element handed to a blocked client, with the key of its list
+pub type Popped = (String, Value);
+
+struct Waiter {
+    keys: Vec<String>,
+    /// popping from the left, i.e. BLPOP
+    left: bool,
+    tx: Sender<Popped>,
+}
+
+pub struct Blocked {
+    /// IDs of the connections blocked on each key, in the order they
+    /// blocked
+    queues: HashMap<String, VecDeque<usize>>,
+    waiters: HashMap<usize, Waiter>,
+}
+
+impl Blocked {
+    pub fn new() -> Blocked {
+        Blocked {
+            queues: HashMap::new(),
+            waiters: HashMap::new(),
+        }
+    }
+}
+
+/// Queue the connection `id` on the keys, returns the channel its
+/// element will come through
+pub fn block(id: usize, keys: &[String], left: bool, b: &mut Blocked) -> Receiver<Popped> {
+    unblock(id, b);
+    let (tx, rx) = mpsc::channel();
+    for key in keys {
+        b.queues.entry(key.clone()).or_default().push_back(id);
+    }
+    let keys = keys.to_vec();
+    b.waiters.insert(id, Waiter { keys, left, tx });
+    rx
+}
+
+/// Take the connection `id` out of all queues
+pub fn unblock(id: usize, b: &mut Blocked) {
+    let waiter = match b.waiters.remove(&id) {
+        Some(x) => x,
+        None => return,
+    };
+    for key in waiter.keys {
+        if let Some(queue) = b.queues.get_mut(&key) {
+            queue.retain(|x| *x != id);
+            if queue.is_empty() {
+                b.queues.remove(&key);
+            }
+        }
+    }
+}
+
+/// Take the client blocked on the key for the longest time out of all
+/// queues, returns which side it pops from, and where to hand the element
+pub fn next(key: &str, b: &mut Blocked) -> Option<(bool, Sender<Popped>)> {
+    let id = *b.queues.get(key)?.front()?;
+    let result = b.waiters.get(&id).map(|x| (x.left, x.tx.clone()));
+    unblock(id, b);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{block, next, unblock, Blocked};
+
+    #[test]
+    fn test_blocked() {
+        let mut b = Blocked::new();
+        let keys =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|x| x.to_string()).collect() };
+        let _rx1 = block(1, &keys(&["jobs", "mail"]), true, &mut b);
+        let _rx2 = block(2, &keys(&["mail"]), false, &mut b);
+        let _rx3 = block(3, &keys(&["jobs"]), false, &mut b);
+
+        // the longest waiting first, and out of all its queues
+        assert_eq!(next("mail", &mut b).map(|x| x.0), Some(true));
+        assert_eq!(next("jobs", &mut b).map(|x| x.0), Some(false));
+        assert!(next("jobs", &mut b).is_none());
+        assert!(b.queues.contains_key("mail"));
+
+        unblock(2, &mut b);
+        assert!(b.queues.is_empty() && b.waiters.is_empty());
+        assert!(next("mail", &mut b).is_none());
+    }
+}
@@ -0,0 +1,342 @@
+// Change data capture (CDC).
+//
+// Each write to the DB gets a sequence number, which increases by one
+// for every PUT or DEL, and every element pushed to or popped from a
+// list, and is saved along with the DB file (see `persistence.rs`).
+// The latest `LOG_SIZE` changes are kept in memory,
+// and saved in the DB file too, so a client can follow all changes in
+// order with the CHANGES query, also across restarts of the server,
+// starting from any sequence number still in the log: e.g. after it
+// reconnects, from the sequence number following the last one it got.
+//
+// When the requested changes are no longer (or not yet) in the log, the
+// query is rejected, and the client should resync with a full SCAN
+// instead, then follow from the current sequence number.
+//
+// The changes also have the random ID of their history, saved with the
+// DB as well, which replicas adopt with the snapshot of their primary,
+// and a primary gets a new one when promoted: the same sequence numbers
+// of different histories are different changes, see `replication.rs`.
+
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::large;
+use crate::push;
+use crate::store::Value;
+use crate::tools;
+
+/// How many changes are kept in memory
+pub const LOG_SIZE: usize = 10_000;
+
+pub struct Change {
+    pub seq: u64,
+    /// `watch::EVENT_PUT`, `watch::EVENT_DELETE`, or a push or pop of a
+    /// list
+    pub event: u8,
+    pub key: String,
+    /// shared with the DB, see `store::Value`
+    pub value: Option<Value>,
+}
+
+pub struct ChangeLog {
+    /// ID of the history of the changes
+    id: u64,
+    /// ID of the history before the last `new_history()`, and the
+    /// sequence number it ended with
+    previous: Option<(u64, u64)>,
+    next_seq: u64,
+    entries: VecDeque<Change>,
+    followers: HashMap<usize, push::Queue>,
+}
+
+impl ChangeLog {
+    pub fn new() -> ChangeLog {
+        ChangeLog {
+            id: random_id(),
+            previous: None,
+            next_seq: 1,
+            entries: VecDeque::new(),
+            followers: HashMap::new(),
+        }
+    }
+}
+
+fn random_id() -> u64 {
+    let mut bytes = [0; 8];
+    SystemRandom::new().fill(&mut bytes).unwrap();
+    u64::from_le_bytes(bytes)
+}
+
+/// ID of the history of the changes
+pub fn id(log: &ChangeLog) -> u64 {
+    log.id
+}
+
+/// Take the ID of the history loaded, e.g. with the snapshot of the
+/// primary
+pub fn set_id(id: u64, log: &mut ChangeLog) {
+    log.id = id;
+    log.previous = None;
+}
+
+/// Take the history and the changes of `loaded`, e.g. of a snapshot
+/// loaded, keeping the followers
+pub fn replace(loaded: ChangeLog, log: &mut ChangeLog) {
+    let followers = mem::take(&mut log.followers);
+    *log = loaded;
+    log.followers = followers;
+}
+
+/// Start a new history from now on, e.g. when promoted to primary.
+/// Replicas of the old primary may still follow from before now.
+pub fn new_history(log: &mut ChangeLog) {
+    log.previous = Some((log.id, log.next_seq));
+    log.id = random_id();
+}
+
+/// If the changes from `from` of the history `id` are the ones here
+pub fn same_history(id: u64, from: u64, log: &ChangeLog) -> bool {
+    match log.previous {
+        _ if id == log.id => true,
+        Some((previous, end)) => id == previous && from <= end,
+        None => false,
+    }
+}
+
+/// The sequence number the next change will get
+pub fn next_seq(log: &ChangeLog) -> u64 {
+    log.next_seq
+}
+
+/// Start numbering changes from `seq`, e.g. when loading the DB from
+/// disk. Changes in memory are dropped, since they are from before.
+pub fn set_next_seq(seq: u64, log: &mut ChangeLog) {
+    log.next_seq = seq;
+    log.entries.clear();
+}
+
+/// Append a change into the log and send it to the followers,
+/// returns its sequence number.
+pub fn record(event: u8, key: &str, value: Option<&Value>, log: &mut ChangeLog) -> u64 {
+    let change = Change {
+        seq: log.next_seq,
+        event,
+        key: key.to_string(),
+        value: value.cloned(),
+    };
+    log.next_seq += 1;
+
+    if !log.followers.is_empty() {
+        let frame = change_frame(&change);
+        log.followers.retain(|_, tx| push::send(frame.clone(), tx));
+    }
+
+    log.entries.push_back(change);
+    while log.entries.len() > LOG_SIZE {
+        log.entries.pop_front();
+    }
+    log.next_seq - 1
+}
+
+/// Frames of all changes since `from` (included), or `None` when they
+/// are not all in the log anymore, or `from` is in the future.
+pub fn since(from: u64, log: &ChangeLog) -> Option<Vec<Vec<u8>>> {
+    let first = match log.entries.front() {
+        Some(x) => x.seq,
+        None => log.next_seq,
+    };
+    if from < first || from > log.next_seq {
+        return None;
+    }
+    let skip = (from - first) as usize;
+    Some(log.entries.iter().skip(skip).map(change_frame).collect())
+}
+
+/// Changes in the log, the oldest first, e.g. to save them with the DB
+pub fn entries(log: &ChangeLog) -> impl Iterator<Item = &Change> {
+    log.entries.iter()
+}
+
+/// Put back a change saved with the DB, after `set_next_seq()`. Changes
+/// out of order, or not before the next sequence number, are ignored.
+pub fn restore(change: Change, log: &mut ChangeLog) {
+    let expected = match log.entries.back() {
+        Some(x) => x.seq + 1,
+        None => change.seq,
+    };
+    if change.seq != expected || change.seq >= log.next_seq {
+        return;
+    }
+    log.entries.push_back(change);
+    while log.entries.len() > LOG_SIZE {
+        log.entries.pop_front();
+    }
+}
+
+/// Send all following changes to the connection `id`
+pub fn follow(id: usize, tx: &push::Queue, log: &mut ChangeLog) {
+    log.followers.insert(id, tx.clone());
+}
+
+pub fn unfollow(id: usize, log: &mut ChangeLog) {
+    log.followers.remove(&id);
+}
+
+/// 1 if the connection is following the changes, else 0
+pub fn count(id: usize, log: &ChangeLog) -> usize {
+    if log.followers.contains_key(&id) {
+        1
+    } else {
+        0
+    }
+}
+
+/// Build the frame pushed to followers:
+/// `"\x0C\x12<seq-len-byte><seq-bytes><event><key-len:2><key><value-len-byte><value-len-bytes><value>"`
+/// where the value part is empty (`"\x00"`) for DEL, and the key length
+/// may be long, see `src/large.rs`.
+pub fn change_frame(change: &Change) -> Vec<u8> {
+    let mut frame = vec![0x0c, 0x12];
+    let (count, bytes) = tools::u64_to_bytes(change.seq);
+    frame.push(count);
+    frame.extend(&bytes);
+    frame.push(change.event);
+    frame.extend(&large::len_bytes(change.key.len()));
+    frame.extend(change.key.as_bytes());
+    let len = change.value.as_ref().map_or(0, |x| x.len());
+    let (count, bytes) = tools::u64_to_bytes(len as u64);
+    frame.push(count);
+    frame.extend(&bytes);
+    for chunk in change.value.iter().flat_map(|x| x.chunks()) {
+        frame.extend(chunk);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{change_frame, entries, follow, id, new_history, next_seq, record, restore};
+    use super::{same_history, set_id, set_next_seq, since};
+    use super::{Change, ChangeLog, LOG_SIZE};
+    use crate::push;
+    use crate::store::Value;
+    use crate::watch::{EVENT_DELETE, EVENT_PUT};
+
+    fn put_change(seq: u64) -> Change {
+        Change {
+            seq,
+            event: EVENT_PUT,
+            key: "foo".to_string(),
+            value: Some(Value::from("bar")),
+        }
+    }
+
+    fn put_frame(seq: u64, key: &str, value: &str) -> Vec<u8> {
+        change_frame(&Change {
+            seq,
+            event: EVENT_PUT,
+            key: key.to_string(),
+            value: Some(Value::from(value)),
+        })
+    }
+
+    #[test]
+    fn test_change_log() {
+        let mut log = ChangeLog::new();
+        assert_eq!(next_seq(&log), 1);
+        assert_eq!(since(1, &log), Some(vec![]));
+        assert_eq!(since(2, &log), None);
+
+        assert_eq!(
+            record(EVENT_PUT, "foo", Some(&Value::from("bar")), &mut log),
+            1
+        );
+        assert_eq!(
+            record(EVENT_PUT, "lang", Some(&Value::from("rust")), &mut log),
+            2
+        );
+        assert_eq!(record(EVENT_DELETE, "foo", None, &mut log), 3);
+        assert_eq!(since(0, &log), None);
+        assert_eq!(since(1, &log).unwrap().len(), 3);
+        assert_eq!(since(2, &log).unwrap()[0], put_frame(2, "lang", "rust"));
+        assert_eq!(since(4, &log), Some(vec![]));
+        assert_eq!(since(5, &log), None);
+
+        let (tx, rx) = push::queue();
+        follow(1, &tx, &mut log);
+        record(EVENT_PUT, "foo", Some(&Value::from("baz")), &mut log);
+        assert_eq!(rx.try_next().unwrap(), put_frame(4, "foo", "baz"));
+
+        set_next_seq(100, &mut log);
+        assert_eq!(since(4, &log), None);
+        assert_eq!(since(100, &log), Some(vec![]));
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut log = ChangeLog::new();
+        record(EVENT_PUT, "foo", Some(&Value::from("bar")), &mut log);
+        record(EVENT_DELETE, "foo", None, &mut log);
+        let saved: Vec<Vec<u8>> = entries(&log).map(change_frame).collect();
+
+        let mut restored = ChangeLog::new();
+        set_next_seq(2, &mut restored);
+        // out of order, or from after the DB was saved
+        for change in [put_change(1), put_change(1), put_change(2)] {
+            restore(change, &mut restored);
+        }
+        assert_eq!(since(1, &restored), Some(vec![saved[0].clone()]));
+        assert_eq!(since(2, &restored), Some(vec![]));
+    }
+
+    #[test]
+    fn test_history() {
+        let mut log = ChangeLog::new();
+        let old = id(&log);
+        record(EVENT_PUT, "foo", Some(&Value::from("bar")), &mut log);
+        assert!(same_history(old, 2, &log));
+        assert!(!same_history(old + 1, 2, &log));
+
+        new_history(&mut log);
+        record(EVENT_PUT, "foo", Some(&Value::from("baz")), &mut log);
+        assert_ne!(id(&log), old);
+        assert!(same_history(id(&log), 3, &log));
+        // the old primary may have had its own change 2
+        assert!(same_history(old, 2, &log));
+        assert!(!same_history(old, 3, &log));
+
+        set_id(old, &mut log);
+        assert!(same_history(old, 3, &log));
+    }
+
+    #[test]
+    fn test_change_log_bounded() {
+        let mut log = ChangeLog::new();
+        for _ in 0..LOG_SIZE + 2 {
+            record(EVENT_PUT, "foo", Some(&Value::from("bar")), &mut log);
+        }
+        assert_eq!(since(2, &log), None);
+        assert_eq!(since(3, &log).unwrap().len(), LOG_SIZE);
+    }
+
+    #[test]
+    fn test_change_frame() {
+        assert_eq!(
+            put_frame(1, "k", "v"),
+            b"\x0c\x12\x01\x01\x01\x01\x00k\x01\x01v".to_vec()
+        );
+        let change = Change {
+            seq: 256,
+            event: EVENT_DELETE,
+            key: "k".to_string(),
+            value: None,
+        };
+        assert_eq!(
+            change_frame(&change),
+            b"\x0c\x12\x02\x00\x01\x02\x01\x00k\x00".to_vec()
+        );
+    }
+}
@@ -0,0 +1,139 @@
+// Ciphered values with Flag `\x02` in queries: with `--cipher-key-file`,
+// the values of PUT-like queries may be encrypted by the client with
+// ChaCha20-Poly1305 and a pre-shared key, and GET replies are encrypted
+// by the server, with Flag `\x02` in the reply, when the query has it.
+//
+// A ciphered value is `<nonce:12><ciphertext><tag:16>`, with the key of
+// the query (or the user for AUTH) as the additional authenticated data,
+// so that a value cannot be moved to another key. Keys and other contents
+// are never encrypted, so that servers and proxies can still route them.
+//
+// The key file holds the 32-byte key as 64 hex digits, e.g. from
+// `openssl rand -hex 32`. Values are stored decrypted.
+
+use std::fs;
+use std::sync::OnceLock;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+/// `--cipher-key-file` of this server
+static KEY: OnceLock<LessSafeKey> = OnceLock::new();
+
+/// Parse the 64 hex digits of a 32-byte key
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    let text = text.trim();
+    if text.len() != 64 || !text.is_ascii() {
+        return Err("key must be 64 hex digits".to_string());
+    }
+    let mut bytes = vec![];
+    for i in (0..64).step_by(2) {
+        match u8::from_str_radix(&text[i..i + 2], 16) {
+            Ok(x) => bytes.push(x),
+            Err(_) => return Err("key must be 64 hex digits".to_string()),
+        }
+    }
+    Ok(bytes)
+}
+
+/// Parse the 64 hex digits of a key file
+pub fn parse_key(text: &str) -> Result<LessSafeKey, String> {
+    let bytes = parse_hex(text)?;
+    // only fails with a key of a wrong length
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes).unwrap();
+    Ok(LessSafeKey::new(key))
+}
+
+/// Load the key of the server from the file
+pub fn load(path: &str) -> Result<(), String> {
+    let text = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("cannot read cipher key file {}: {}", path, e)),
+    };
+    let _ = KEY.set(parse_key(&text)?);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    KEY.get().is_some()
+}
+
+pub fn seal(data: &[u8], aad: &[u8], key: &LessSafeKey) -> Vec<u8> {
+    let mut nonce = [0; NONCE_LEN];
+    // the system random generator does not fail on supported platforms
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    let mut result = data.to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    // only fails with values larger than 256GB
+    key.seal_in_place_append_tag(n, Aad::from(aad), &mut result)
+        .unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(result);
+    sealed
+}
+
+pub fn open(data: &[u8], aad: &[u8], key: &LessSafeKey) -> Result<Vec<u8>, String> {
+    if data.len() < NONCE_LEN {
+        return Err("ciphered value too short".to_string());
+    }
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&data[..NONCE_LEN]);
+    let mut result = data[NONCE_LEN..].to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    match key.open_in_place(n, Aad::from(aad), &mut result) {
+        Ok(x) => {
+            let len = x.len();
+            result.truncate(len);
+            Ok(result)
+        }
+        Err(_) => Err("ciphered value cannot be authenticated".to_string()),
+    }
+}
+
+/// Encrypt a value with the key of the server, which must be loaded
+pub fn encrypt(data: &[u8], aad: &[u8]) -> Vec<u8> {
+    seal(data, aad, KEY.get().expect("no cipher key"))
+}
+
+/// Decrypt a value with the key of the server
+pub fn decrypt(data: &[u8], aad: &[u8]) -> Result<Vec<u8>, String> {
+    match KEY.get() {
+        Some(key) => open(data, aad, key),
+        None => Err("no cipher key".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open, parse_key, seal};
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key(HEX).is_ok());
+        assert!(parse_key(&format!("{}\n", HEX)).is_ok());
+        assert!(parse_key(&HEX[2..]).is_err());
+        assert!(parse_key(&HEX.replace("1f", "zz")).is_err());
+        assert!(parse_key(&HEX.replace("1f", "é")).is_err());
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let key = parse_key(HEX).unwrap();
+        let sealed = seal(b"bar", b"foo", &key);
+        assert_eq!(sealed.len(), 12 + 3 + 16);
+        assert_ne!(seal(b"bar", b"foo", &key), sealed);
+        assert_eq!(open(&sealed, b"foo", &key), Ok(b"bar".to_vec()));
+
+        // tampered values, another key, or another AAD
+        let mut tampered = sealed.clone();
+        tampered[12] ^= 0x01;
+        assert!(open(&tampered, b"foo", &key).is_err());
+        assert!(open(&sealed[..sealed.len() - 1], b"foo", &key).is_err());
+        assert!(open(&sealed[..5], b"foo", &key).is_err());
+        assert!(open(&sealed, b"fop", &key).is_err());
+        let other = parse_key(&HEX.replace("1f", "20")).unwrap();
+        assert!(open(&sealed, b"foo", &other).is_err());
+    }
+}
@@ -1,20 +1,60 @@
-use std::net::TcpStream;
-
+use crate::do_acl;
+use crate::do_changes;
+use crate::do_cluster;
 use crate::do_delete;
 use crate::do_get;
+use crate::do_list;
+use crate::do_log;
+use crate::do_mget;
+use crate::do_migrate;
+use crate::do_publish;
 use crate::do_put;
+use crate::do_rotate_key;
 use crate::do_scan;
+use crate::do_subscribe;
+use crate::router::{self, Redirect, Router};
+use crate::stream::Stream;
+
+/// Send a query of the key to the server owning it, following MOVED
+/// and ASK redirects.
+fn routed<F>(key: &str, router: &mut Router, mut f: F)
+where
+    F: FnMut(&mut Stream) -> Option<Redirect>,
+{
+    let mut ask: Option<String> = None;
+    for _ in 0..router::MAX_REDIRECTS {
+        let stream = match &ask {
+            Some(addr) => router::asking(addr, router),
+            None => router::stream_for(key, router),
+        };
+        let redirect = match stream {
+            Some(stream) => f(stream),
+            None => return,
+        };
+        match redirect {
+            Some(Redirect::Moved(slot, addr)) => {
+                router::moved(slot, &addr, router);
+                ask = None;
+            }
+            Some(Redirect::Ask(addr)) => ask = Some(addr),
+            None => return,
+        }
+    }
+    println!("too many redirects");
+}
 
-pub fn query(line: &str, stream: &mut TcpStream) {
+pub fn query(line: &str, router: &mut Router) {
     if line.starts_with("del ") {
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        do_delete::delete(tokens[1], stream);
+        routed(tokens[1], router, |stream| {
+            do_delete::delete(tokens[1], stream)
+        });
         return;
     }
 
     if line.starts_with("get ") {
         let tokens: Vec<&str> = line.split_whitespace().collect();
-        do_get::get(tokens[1], stream);
+        routed(tokens[1], router, |stream| do_get::get(tokens[1], stream));
         return;
     }
 
@@ -24,10 +64,56 @@ pub fn query(line: &str, stream: &mut TcpStream) {
             println!("invalid command");
             return;
         }
-        do_put::put(tokens[1], tokens[2], stream);
+        routed(tokens[1], router, |stream| {
+            do_put::put(tokens[1], tokens[2], stream)
+        });
         return;
     }
 
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    match tokens.first() {
+        Some(&"lpush") | Some(&"rpush") => {
+            if tokens.len() != 3 {
+                println!("invalid command");
+                return;
+            }
+            let left = tokens[0] == "lpush";
+            routed(tokens[1], router, |stream| {
+                do_list::push(tokens[1], tokens[2], left, stream)
+            });
+            return;
+        }
+        Some(&"lpop") | Some(&"rpop") => {
+            if tokens.len() != 2 {
+                println!("invalid command");
+                return;
+            }
+            let left = tokens[0] == "lpop";
+            routed(tokens[1], router, |stream| {
+                do_list::pop(tokens[1], left, stream)
+            });
+            return;
+        }
+        Some(&"blpop") | Some(&"brpop") => {
+            // BLPOP KEY [KEY ...] TIMEOUT, with the timeout in seconds
+            let timeout = match tokens.last().map(|x| x.parse::<u32>()) {
+                Some(Ok(x)) if tokens.len() > 2 => x,
+                _ => {
+                    println!("invalid command");
+                    return;
+                }
+            };
+            let (left, keys) = (tokens[0] == "blpop", &tokens[1..tokens.len() - 1]);
+            routed(keys[0], router, |stream| {
+                do_list::blocking_pop(keys, timeout, left, stream)
+            });
+            return;
+        }
+        _ => {}
+    }
+
+    let stream = router::default_stream(router);
+
     if line.starts_with("scan") {
         let tokens: Vec<&str> = line.split_whitespace().collect();
         if tokens.len() > 1 {
@@ -38,6 +124,88 @@ pub fn query(line: &str, stream: &mut TcpStream) {
         return;
     }
 
+    if line.starts_with("mget ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        do_mget::mget(&tokens[1..], stream);
+        return;
+    }
+
+    if line.starts_with("publish ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() != 3 {
+            println!("invalid command");
+            return;
+        }
+        do_publish::publish(tokens[1], tokens[2], stream);
+        return;
+    }
+
+    if line.starts_with("changes") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        let from = match tokens.get(1) {
+            Some(x) => match x.parse::<u64>() {
+                Ok(n) => n,
+                Err(_) => {
+                    println!("invalid sequence number");
+                    return;
+                }
+            },
+            None => 1,
+        };
+        do_changes::changes(from, stream);
+        return;
+    }
+
+    if line.starts_with("migrate ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.len() != 3 {
+            println!("invalid command");
+            return;
+        }
+        do_migrate::migrate(&tokens[1..].join(" "), stream);
+        return;
+    }
+
+    if line.trim() == "cluster" || line.starts_with("cluster ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        do_cluster::cluster(&tokens[1..].join(" "), stream);
+        return;
+    }
+
+    if line.starts_with("acl ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        do_acl::acl(&tokens[1..].join(" "), stream);
+        return;
+    }
+
+    if line.trim() == "log" || line.starts_with("log ") {
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        do_log::log(&tokens[1..].join(" "), stream);
+        return;
+    }
+
+    if line.trim() == "rotatekey" {
+        do_rotate_key::rotate_key(stream);
+        return;
+    }
+
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    let cmd = match tokens.first() {
+        Some(&"subscribe") => Some(0x05),
+        Some(&"psubscribe") => Some(0x07),
+        Some(&"watch") => Some(0x09),
+        Some(&"pwatch") => Some(0x0A),
+        _ => None,
+    };
+    if let Some(cmd) = cmd {
+        if tokens.len() < 2 {
+            println!("invalid command");
+            return;
+        }
+        do_subscribe::subscribe(&tokens[1..], cmd, stream);
+        return;
+    }
+
     if !line.is_empty() {
         println!("unknown command: {:?}", line);
     }
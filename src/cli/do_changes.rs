@@ -0,0 +1,85 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+/// Print all changes since the sequence number `from`, then the new ones
+/// as they happen, until the connection is closed.
+pub fn changes(from: u64, stream: &mut Stream) {
+    // send query
+    let (count, buf_seq) = tools::u64_to_bytes(from);
+    stream.write_all(b"\x0c\x0c\x00").unwrap();
+    stream
+        .write_all(&tools::u16_to_bytes(count as u16))
+        .unwrap();
+    stream.write_all(&buf_seq).unwrap();
+
+    // handle response
+    let data = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return;
+    }
+    if tools::print_error(data[1]) {
+        return;
+    }
+    let buf_llen = match tools::read_exact(stream, 1) {
+        Some(x) => x,
+        None => return,
+    };
+    let buf_seq = match tools::read_exact(stream, buf_llen[0] as usize) {
+        Some(x) => x,
+        None => return,
+    };
+    let next_seq = tools::bytes_to_u64(&buf_seq);
+    if data[1] == 0x03 {
+        println!(
+            "changes since {} are not available, current: {}",
+            from, next_seq
+        );
+        return;
+    } else if data[1] != 0x00 {
+        println!("unknown error code");
+        return;
+    }
+
+    println!("Reading changes... (press Ctrl-C to quit)");
+    loop {
+        let data = match tools::read_exact(stream, 3) {
+            Some(x) => x,
+            None => return,
+        };
+        if data[0] != 0x0c || data[1] != 0x12 {
+            println!("bad header from server");
+            return;
+        }
+        let seq = match tools::read_exact(stream, data[2] as usize) {
+            Some(x) => tools::bytes_to_u64(&x),
+            None => return,
+        };
+        let event = match tools::read_exact(stream, 1) {
+            Some(x) => x[0],
+            None => return,
+        };
+        let key = match tools::read_key(stream) {
+            Some(x) => x,
+            None => return,
+        };
+        let value = match tools::read_value(stream) {
+            Some(x) => x,
+            None => return,
+        };
+        match event {
+            0x01 => println!("{}) put {:?}: {}", seq, key, value),
+            0x02 => println!("{}) del {:?}", seq, key),
+            0x03 => println!("{}) lpush {:?}: {}", seq, key, value),
+            0x04 => println!("{}) rpush {:?}: {}", seq, key, value),
+            0x05 => println!("{}) lpop {:?}: {}", seq, key, value),
+            0x06 => println!("{}) rpop {:?}: {}", seq, key, value),
+            _ => println!("{}) event {} {:?}", seq, event, key),
+        }
+    }
+}
@@ -1,15 +1,18 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::str;
 
+use crate::router::Redirect;
+use crate::stream::Stream;
 use crate::tools;
 
-pub fn delete(key: &str, stream: &mut TcpStream) {
+/// Returns the redirect to another server, if any
+pub fn delete(key: &str, stream: &mut Stream) -> Option<Redirect> {
+    if !tools::check_key(key) {
+        return None;
+    }
     // send query
     stream.write(b"\x0c\x03\x00").unwrap();
-    let klen = key.len();
-    assert!(klen <= 0xFFFF);
-    stream.write(&tools::u16_to_bytes(klen as u16)).unwrap();
+    stream.write(&tools::key_len_bytes(key.len())).unwrap();
     stream.write(key.as_bytes()).unwrap();
 
     // handle response
@@ -22,12 +25,24 @@ pub fn delete(key: &str, stream: &mut TcpStream) {
     }
     if data[0] != 0x0c {
         println!("bad header from server");
-        return;
+        return None;
+    }
+    if data[1] == 0x06 || data[1] == 0x07 {
+        return tools::read_redirect(data[1], stream);
     }
 
     if data[1] == 0x00 {
         println!("1");
+    } else if data[1] == 0x05 {
+        tools::print_not_leader(stream);
+    } else if data[1] == 0x08 {
+        println!("(error) authentication required");
+    } else if data[1] == 0x09 {
+        println!("(error) permission denied");
+    } else if data[1] == 0x0B {
+        println!("(error) request too large");
     } else {
         println!("0");
     }
+    None
 }
@@ -1,15 +1,20 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::str;
 
+use crate::router::Redirect;
+use crate::stream::Stream;
 use crate::tools;
 
-pub fn get(key: &str, stream: &mut TcpStream) {
-    // send query
-    stream.write(b"\x0c\x01\x00").unwrap();
-    let len = key.len();
-    assert!(len <= 0xFFFF);
-    stream.write(&tools::u16_to_bytes(len as u16)).unwrap();
+/// Returns the redirect to another server, if any
+pub fn get(key: &str, stream: &mut Stream) -> Option<Redirect> {
+    if !tools::check_key(key) {
+        return None;
+    }
+    // send query, asking for ciphered replies with a key; replies are
+    // compressed anyway if the server has `gzip`, see HELLO in `router.rs`
+    let flag = if tools::has_cipher_key() { 0x02 } else { 0x00 };
+    stream.write(&[0x0c, 0x01, flag]).unwrap();
+    stream.write(&tools::key_len_bytes(key.len())).unwrap();
     stream.write(key.as_bytes()).unwrap();
 
     // handle response
@@ -22,23 +27,38 @@ pub fn get(key: &str, stream: &mut TcpStream) {
     }
     if data[0] != 0x0c {
         println!("bad header from server");
-        return;
+        return None;
+    }
+    if data[1] == 0x06 || data[1] == 0x07 {
+        return tools::read_redirect(data[1], stream);
     }
 
     if data[1] == 0x01 {
         println!("query failed");
-        return;
+        return None;
     } else if data[1] == 0x02 {
         println!("(None)");
-        return;
+        return None;
+    } else if data[1] == 0x08 {
+        println!("(error) authentication required");
+        return None;
+    } else if data[1] == 0x09 {
+        println!("(error) permission denied");
+        return None;
+    } else if data[1] == 0x0B {
+        println!("(error) request too large");
+        return None;
+    } else if data[1] == 0x0C {
+        println!("(error) wrong type of value");
+        return None;
     } else if data[1] == 0xFF {
         println!("unknown command");
-        return;
+        return None;
     }
 
     if data[1] != 0x00 {
         println!("unknown error code");
-        return;
+        return None;
     }
 
     let mut buf_flag = [0; 1];
@@ -46,12 +66,12 @@ pub fn get(key: &str, stream: &mut TcpStream) {
         Ok(_) => {}
         Err(e) => {
             println!("cannot read full llen bytes: {:?}", e);
-            return;
+            return None;
         }
     }
-    if buf_flag[0] != 0x00 {
-        println!("currently, only plain text is supported.");
-        return;
+    if buf_flag[0] != flag && !(buf_flag[0] == 0x01 && tools::server_has("gzip")) {
+        println!("unsupported flag: {}", buf_flag[0]);
+        return None;
     }
 
     let mut buf_llen = [0; 1];
@@ -59,19 +79,44 @@ pub fn get(key: &str, stream: &mut TcpStream) {
         Ok(_) => {}
         Err(e) => {
             println!("cannot read full llen bytes: {:?}", e);
-            return;
+            return None;
         }
     }
 
-    let mut buffer_len = Vec::with_capacity(buf_llen[0] as usize);
-    for _ in 0..buf_llen[0] {
+    // large values in chunks, see HELLO in `router.rs`
+    let mut buffer_content = if buf_llen[0] == tools::CHUNKED {
+        tools::read_chunks(stream)?
+    } else {
+        read_content(buf_llen[0], stream)?
+    };
+    if buf_flag[0] == 0x01 {
+        buffer_content = tools::gunzip(&buffer_content)?;
+    } else if buf_flag[0] == 0x02 {
+        buffer_content = tools::decrypt(&buffer_content, key.as_bytes())?;
+    }
+    match str::from_utf8(&buffer_content) {
+        Ok(x) => {
+            println!("{:?}", x);
+        }
+        Err(e) => {
+            println!("ERROR: from_utf8 failed: {:?}", e);
+            println!("content: {:?}", &buffer_content);
+        }
+    }
+    None
+}
+
+/// Read `<vlen><value>` of a reply, with VLLen `llen`
+fn read_content(llen: u8, stream: &mut Stream) -> Option<Vec<u8>> {
+    let mut buffer_len = Vec::with_capacity(llen as usize);
+    for _ in 0..llen {
         buffer_len.push(0_u8);
     }
     match stream.read_exact(&mut buffer_len) {
         Ok(_) => {}
         Err(e) => {
             println!("cannot read full len bytes: {:?}", e);
-            return;
+            return None;
         }
     }
 
@@ -84,16 +129,8 @@ pub fn get(key: &str, stream: &mut TcpStream) {
         Ok(_) => {}
         Err(e) => {
             println!("cannot read full content bytes: {:?}", e);
-            return;
-        }
-    }
-    match str::from_utf8(&buffer_content) {
-        Ok(x) => {
-            println!("{:?}", x);
-        }
-        Err(e) => {
-            println!("ERROR: from_utf8 failed: {:?}", e);
-            println!("content: {:?}", &buffer_content);
+            return None;
         }
     }
+    Some(buffer_content)
 }
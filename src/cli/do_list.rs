@@ -0,0 +1,152 @@
+use std::io::Write;
+use std::str;
+
+use crate::router::Redirect;
+use crate::stream::Stream;
+use crate::tools;
+
+/// Push the value to the left or the right of the list, print the length
+/// of the list then. Returns the redirect to another server, if any.
+pub fn push(key: &str, value: &str, left: bool, stream: &mut Stream) -> Option<Redirect> {
+    if !tools::check_key(key) {
+        return None;
+    }
+    let cmd = if left { 0x1B } else { 0x1C };
+    let mut query = vec![0x0c, cmd, 0x00];
+    query.extend(&tools::key_len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    let (count, buf_len) = tools::u64_to_bytes(value.len() as u64);
+    query.push(count);
+    query.extend(&buf_len);
+    query.extend(value.as_bytes());
+    if let Err(e) = stream.write_all(&query) {
+        println!("Failed to send query: {}", e);
+        return None;
+    }
+
+    let status = read_status(stream)?;
+    if status == 0x06 || status == 0x07 {
+        return tools::read_redirect(status, stream);
+    }
+    if status == 0x00 {
+        let buf_len = tools::read_exact(stream, 4)?;
+        println!("(integer) {}", tools::bytes_to_u32(&buf_len));
+    } else {
+        print_failed(status, "push failed");
+    }
+    None
+}
+
+/// Pop a value from the left or the right of the list, and print it.
+/// Returns the redirect to another server, if any.
+pub fn pop(key: &str, left: bool, stream: &mut Stream) -> Option<Redirect> {
+    if !tools::check_key(key) {
+        return None;
+    }
+    let cmd = if left { 0x1D } else { 0x1E };
+    let mut query = vec![0x0c, cmd, 0x00];
+    query.extend(&tools::key_len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    if let Err(e) = stream.write_all(&query) {
+        println!("Failed to send query: {}", e);
+        return None;
+    }
+
+    let status = read_status(stream)?;
+    if status == 0x06 || status == 0x07 {
+        return tools::read_redirect(status, stream);
+    }
+    match status {
+        0x00 => {
+            tools::read_exact(stream, 1)?;
+            let value = read_element(stream)?;
+            println!("{}", value);
+        }
+        0x02 => println!("(None)"),
+        x => print_failed(x, "pop failed"),
+    }
+    None
+}
+
+/// Pop a value from the first of the lists having one, or wait for one
+/// up to `timeout` seconds, forever if 0, and print it with its key.
+/// Returns the redirect to another server, if any.
+pub fn blocking_pop(
+    keys: &[&str],
+    timeout: u32,
+    left: bool,
+    stream: &mut Stream,
+) -> Option<Redirect> {
+    let mut content = tools::u32_to_bytes(timeout.saturating_mul(1000)).to_vec();
+    for key in keys {
+        content.extend(&tools::u16_to_bytes(key.len() as u16));
+        content.extend(key.as_bytes());
+    }
+    if content.len() >= 0xFFFF {
+        println!("keys too long");
+        return None;
+    }
+    let cmd = if left { 0x1F } else { 0x20 };
+    let mut query = vec![0x0c, cmd, 0x00];
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content);
+    if let Err(e) = stream.write_all(&query) {
+        println!("Failed to send query: {}", e);
+        return None;
+    }
+
+    let status = read_status(stream)?;
+    if status == 0x06 || status == 0x07 {
+        return tools::read_redirect(status, stream);
+    }
+    match status {
+        0x00 => {
+            tools::read_exact(stream, 1)?;
+            let key = tools::read_key(stream)?;
+            let value = read_element(stream)?;
+            println!("1) {:?}", key);
+            println!("2) {}", value);
+        }
+        0x02 => println!("(None)"),
+        x => print_failed(x, "pop failed"),
+    }
+    None
+}
+
+fn read_status(stream: &mut Stream) -> Option<u8> {
+    let data = tools::read_exact(stream, 2)?;
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return None;
+    }
+    Some(data[1])
+}
+
+/// Read `<vllen><vlen><value>`, or the value in chunks, formatted for
+/// printing
+fn read_element(stream: &mut Stream) -> Option<String> {
+    let buf_llen = tools::read_exact(stream, 1)?;
+    if buf_llen[0] != tools::CHUNKED {
+        let buf_len = tools::read_exact(stream, buf_llen[0] as usize)?;
+        let value = tools::read_exact(stream, tools::bytes_to_u64(&buf_len) as usize)?;
+        return Some(format_value(&value));
+    }
+    tools::read_chunks(stream).map(|x| format_value(&x))
+}
+
+fn format_value(value: &[u8]) -> String {
+    match str::from_utf8(value) {
+        Ok(x) => format!("{:?}", x),
+        Err(_) => format!("{:?}", value),
+    }
+}
+
+fn print_failed(status: u8, text: &str) {
+    match status {
+        0x01 => println!("{}", text),
+        0x04 => println!("(error) read-only replica"),
+        0xFF => println!("unknown command"),
+        x if tools::print_error(x) => (),
+        _ => println!("unknown error code"),
+    }
+}
@@ -0,0 +1,81 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+pub fn mget(keys: &[&str], stream: &mut Stream) {
+    let mut content: Vec<u8> = vec![];
+    for key in keys {
+        content.extend(&tools::u16_to_bytes(key.len() as u16));
+        content.extend(key.as_bytes());
+    }
+    if content.len() >= 0xFFFF {
+        println!("keys too long");
+        return;
+    }
+    let mut query = b"\x0c\x15\x00".to_vec();
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content);
+    if let Err(e) = stream.write_all(&query) {
+        println!("Failed to send query: {}", e);
+        return;
+    }
+
+    let data = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return;
+    }
+    match data[1] {
+        0x00 => {}
+        0x01 => {
+            println!("query failed");
+            return;
+        }
+        0x06 | 0x07 => {
+            // keys are on different servers, the proxy could get them all
+            if tools::read_redirect(data[1], stream).is_some() {
+                println!("(moved) keys are not all on this server");
+            }
+            return;
+        }
+        0x08 => {
+            println!("(error) authentication required");
+            return;
+        }
+        0x09 => {
+            println!("(error) permission denied");
+            return;
+        }
+        0xFF => {
+            println!("unknown command");
+            return;
+        }
+        _ => {
+            println!("unknown error code");
+            return;
+        }
+    }
+
+    let buf_count = match tools::read_exact(stream, 4) {
+        Some(x) => x,
+        None => return,
+    };
+    for i in 0..tools::bytes_to_u32(&buf_count) {
+        let found = match tools::read_exact(stream, 1) {
+            Some(x) => x,
+            None => return,
+        };
+        if found[0] == 0x00 {
+            println!("{}) (None)", i + 1);
+            continue;
+        }
+        match tools::read_value(stream) {
+            Some(x) => println!("{}) {}", i + 1, x),
+            None => return,
+        }
+    }
+}
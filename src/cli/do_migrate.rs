@@ -0,0 +1,38 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+/// Send MIGRATE with `args`, e.g. "0-99 127.0.0.1:30162", to the server
+/// owning the slots.
+pub fn migrate(args: &str, stream: &mut Stream) {
+    // send query
+    stream.write_all(b"\x0c\x12\x00").unwrap();
+    assert!(args.len() < 0xFFFF);
+    stream
+        .write_all(&tools::u16_to_bytes(args.len() as u16))
+        .unwrap();
+    stream.write_all(args.as_bytes()).unwrap();
+
+    // handle response
+    let data = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return;
+    }
+    if tools::print_error(data[1]) {
+        return;
+    }
+    let text = match tools::read_string(stream) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[1] == 0x00 {
+        println!("{}", text);
+    } else {
+        println!("(error) {}", text);
+    }
+}
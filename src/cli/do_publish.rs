@@ -0,0 +1,41 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+pub fn publish(channel: &str, message: &str, stream: &mut Stream) {
+    // send query
+    stream.write_all(b"\x0c\x08\x00").unwrap();
+    let klen = channel.len();
+    assert!(klen < 0xFFFF);
+    stream.write_all(&tools::u16_to_bytes(klen as u16)).unwrap();
+    stream.write_all(channel.as_bytes()).unwrap();
+    let mlen = message.len();
+    let (count, buf_len) = tools::u64_to_bytes(mlen as u64);
+    stream.write_all(&[count]).unwrap();
+    stream.write_all(&buf_len).unwrap();
+    stream.write_all(message.as_bytes()).unwrap();
+
+    // handle response
+    let data = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return;
+    }
+    if tools::print_error(data[1]) {
+        return;
+    }
+
+    if data[1] == 0x00 {
+        let buf_count = match tools::read_exact(stream, 4) {
+            Some(x) => x,
+            None => return,
+        };
+        println!("(integer) {}", tools::bytes_to_u32(&buf_count));
+    } else {
+        println!("publish failed");
+    }
+}
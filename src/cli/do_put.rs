@@ -1,21 +1,37 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::str;
 
+use crate::router::Redirect;
+use crate::stream::Stream;
 use crate::tools;
 
-pub fn put(key: &str, value: &str, stream: &mut TcpStream) {
-    // send query
-    stream.write(b"\x0c\x02\x00").unwrap();
-    let klen = key.len();
-    assert!(klen <= 0xFFFF);
-    stream.write(&tools::u16_to_bytes(klen as u16)).unwrap();
+/// Returns the redirect to another server, if any
+pub fn put(key: &str, value: &str, stream: &mut Stream) -> Option<Redirect> {
+    if !tools::check_key(key) {
+        return None;
+    }
+    // send query, values encrypted with a cipher key, or large values
+    // compressed if the server supports it
+    let (flag, value) = if tools::has_cipher_key() {
+        (0x02, tools::encrypt(value.as_bytes(), key.as_bytes()))
+    } else if value.len() >= tools::GZIP_MIN_SIZE && tools::server_has("gzip") {
+        (0x01, tools::gzip(value.as_bytes()))
+    } else {
+        (0x00, value.as_bytes().to_vec())
+    };
+    stream.write(&[0x0c, 0x02, flag]).unwrap();
+    stream.write(&tools::key_len_bytes(key.len())).unwrap();
     stream.write(key.as_bytes()).unwrap();
     let vlen = value.len();
-    let (count, buf_len) = tools::u64_to_bytes(vlen as u64);
-    stream.write(&[count]).unwrap();
-    stream.write(&buf_len).unwrap();
-    stream.write(value.as_bytes()).unwrap();
+    if vlen > tools::CHUNK_SIZE && tools::server_has("chunked") {
+        stream.write_all(&[tools::CHUNKED]).unwrap();
+        stream.write_all(&tools::chunks(&value)).unwrap();
+    } else {
+        let (count, buf_len) = tools::u64_to_bytes(vlen as u64);
+        stream.write(&[count]).unwrap();
+        stream.write(&buf_len).unwrap();
+        stream.write(&value).unwrap();
+    }
 
     // handle response
     let mut data = [0_u8; 2];
@@ -27,14 +43,26 @@ pub fn put(key: &str, value: &str, stream: &mut TcpStream) {
     }
     if data[0] != 0x0c {
         println!("bad header from server");
-        return;
+        return None;
+    }
+    if data[1] == 0x06 || data[1] == 0x07 {
+        return tools::read_redirect(data[1], stream);
     }
 
     if data[1] == 0x00 {
         println!("OK");
     } else if data[1] == 0x01 {
         println!("put failed");
+    } else if data[1] == 0x05 {
+        tools::print_not_leader(stream);
+    } else if data[1] == 0x08 {
+        println!("(error) authentication required");
+    } else if data[1] == 0x09 {
+        println!("(error) permission denied");
+    } else if data[1] == 0x0B {
+        println!("(error) request too large");
     } else {
         println!("unknown server error");
     }
+    None
 }
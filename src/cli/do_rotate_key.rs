@@ -0,0 +1,36 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+/// Send ROTATEKEY, for the server to encrypt its files with the last key
+/// of its data key file
+pub fn rotate_key(stream: &mut Stream) {
+    // send query
+    stream.write_all(b"\x0c\x18\x00\x00\x00").unwrap();
+
+    // handle response
+    let data = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return,
+    };
+    if data[0] != 0x0c {
+        println!("bad header from server");
+        return;
+    }
+    if tools::print_error(data[1]) {
+        return;
+    }
+    if data[1] == 0xFF {
+        println!("unknown command");
+        return;
+    }
+    let text = match tools::read_string(stream) {
+        Some(x) => x,
+        None => return,
+    };
+    match data[1] {
+        0x00 => println!("data key: {}", text),
+        _ => println!("(error) {}", text),
+    }
+}
@@ -1,14 +1,14 @@
 use std::io::{Read, Write};
-use std::net::TcpStream;
 use std::str;
 
+use crate::stream::Stream;
 use crate::tools;
 
-pub fn scan(key: &str, stream: &mut TcpStream) {
+pub fn scan(key: &str, stream: &mut Stream) {
     // send query
     stream.write(b"\x0c\x04\x00").unwrap();
     let len = key.len();
-    assert!(len <= 0xFFFF);
+    assert!(len < 0xFFFF);
     stream.write(&tools::u16_to_bytes(len as u16)).unwrap();
     if !key.is_empty() {
         stream.write(key.as_bytes()).unwrap();
@@ -33,6 +33,12 @@ pub fn scan(key: &str, stream: &mut TcpStream) {
     } else if data[1] == 0x02 {
         println!("no such key");
         return;
+    } else if data[1] == 0x08 {
+        println!("(error) authentication required");
+        return;
+    } else if data[1] == 0x09 {
+        println!("(error) permission denied");
+        return;
     } else if data[1] == 0xFF {
         println!("unknown command");
         return;
@@ -67,6 +73,7 @@ pub fn scan(key: &str, stream: &mut TcpStream) {
 
     let count = tools::bytes_to_u32(&buf_count);
     for i in 0..count {
+        // keys of 64 KiB or more have a long length
         let mut buf_len = [0; 2];
         match stream.read_exact(&mut buf_len) {
             Ok(_) => {}
@@ -76,11 +83,14 @@ pub fn scan(key: &str, stream: &mut TcpStream) {
             }
         }
 
-        let size = tools::bytes_to_u16(&buf_len);
-        let mut buf_key = Vec::with_capacity(size as usize);
-        for _ in 0..size {
-            buf_key.push(0_u8);
+        let mut size = tools::bytes_to_u16(&buf_len) as usize;
+        if size == tools::LONG_LEN && tools::server_has("bigkeys") {
+            size = match tools::read_exact(stream, 4) {
+                Some(x) => tools::bytes_to_u32(&x) as usize,
+                None => return,
+            };
         }
+        let mut buf_key = vec![0_u8; size];
         match stream.read_exact(&mut buf_key) {
             Ok(_) => {}
             Err(e) => {
@@ -0,0 +1,94 @@
+use std::io::Write;
+
+use crate::stream::Stream;
+use crate::tools;
+
+/// Subscribe to the channels, patterns, keys or prefixes, depending on
+/// `cmd`, then print the pushed messages and key events as they arrive,
+/// until the connection is closed.
+pub fn subscribe(names: &[&str], cmd: u8, stream: &mut Stream) {
+    for name in names {
+        let len = name.len();
+        assert!(len < 0xFFFF);
+        stream.write_all(&[0x0c, cmd, 0x00]).unwrap();
+        stream.write_all(&tools::u16_to_bytes(len as u16)).unwrap();
+        stream.write_all(name.as_bytes()).unwrap();
+    }
+
+    println!("Reading messages... (press Ctrl-C to quit)");
+    let mut acked = 0;
+    loop {
+        let data = match tools::read_exact(stream, 2) {
+            Some(x) => x,
+            None => return,
+        };
+        if data[0] != 0x0c {
+            println!("bad header from server");
+            return;
+        }
+
+        match data[1] {
+            0x00 => {
+                let buf_count = match tools::read_exact(stream, 4) {
+                    Some(x) => x,
+                    None => return,
+                };
+                if acked < names.len() {
+                    println!(
+                        "subscribed {:?} ({})",
+                        names[acked],
+                        tools::bytes_to_u32(&buf_count)
+                    );
+                    acked += 1;
+                }
+            }
+            0x10 => {
+                let pattern = match tools::read_string(stream) {
+                    Some(x) => x,
+                    None => return,
+                };
+                let channel = match tools::read_string(stream) {
+                    Some(x) => x,
+                    None => return,
+                };
+                let message = match tools::read_value(stream) {
+                    Some(x) => x,
+                    None => return,
+                };
+                if pattern.is_empty() {
+                    println!("{:?}: {}", channel, message);
+                } else {
+                    println!("{:?} ({:?}): {}", channel, pattern, message);
+                }
+            }
+            0x11 => {
+                let event = match tools::read_exact(stream, 1) {
+                    Some(x) => x[0],
+                    None => return,
+                };
+                let key = match tools::read_key(stream) {
+                    Some(x) => x,
+                    None => return,
+                };
+                let value = match tools::read_value(stream) {
+                    Some(x) => x,
+                    None => return,
+                };
+                match event {
+                    0x01 => println!("put {:?}: {}", key, value),
+                    0x02 => println!("del {:?}", key),
+                    0x03 => println!("lpush {:?}: {}", key, value),
+                    0x04 => println!("rpush {:?}: {}", key, value),
+                    0x05 => println!("lpop {:?}: {}", key, value),
+                    0x06 => println!("rpop {:?}: {}", key, value),
+                    _ => println!("event {} {:?}", event, key),
+                }
+            }
+            x if tools::print_error(x) => return,
+            _ => {
+                println!("subscribe failed");
+                return;
+            }
+        }
+    }
+}
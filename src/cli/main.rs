@@ -1,34 +1,78 @@
+use std::env;
 use std::io::{self, Write};
-use std::net::TcpStream;
+use std::process;
 
 mod cli;
+mod config;
+mod do_acl;
+mod do_changes;
+mod do_cluster;
 mod do_delete;
 mod do_get;
+mod do_list;
+mod do_log;
+mod do_mget;
+mod do_migrate;
+mod do_publish;
 mod do_put;
+mod do_rotate_key;
 mod do_scan;
+mod do_subscribe;
+mod router;
+mod stream;
+mod tls;
 mod tools;
 
 fn main() {
-    let host = "127.0.0.1";
-    let port = 30160;
-    let addr = format!("{}:{}", host, port);
-
-    match TcpStream::connect(&addr) {
-        Ok(mut stream) => {
-            println!("Connected to h2okv server {}, Ctrl-C to exit", &addr);
-
-            let stdin = io::stdin();
-            let input = &mut String::new();
-            loop {
-                input.clear();
-                print!("h2okv> ");
-                io::stdout().flush().unwrap();
-                stdin.read_line(input).unwrap();
-                cli::query(&input, &mut stream);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", config::USAGE);
+            process::exit(1);
+        }
+    };
+    let tls = match &config.tls_ca_cert {
+        Some(ca) => {
+            let (cert, key) = (config.tls_cert.as_deref(), config.tls_key.as_deref());
+            match tls::client_config(ca, cert, key) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    println!("{}", e);
+                    process::exit(1);
+                }
             }
         }
-        Err(e) => {
-            println!("Failed to connect: {}", e);
+        None => None,
+    };
+    if let Some(path) = &config.cipher_key_file {
+        if let Err(e) = tools::load_cipher_key(path) {
+            println!("{}", e);
+            process::exit(1);
+        }
+    }
+    let credentials = match (config.user, config.password) {
+        (user, Some(password)) => Some((user.unwrap_or_default(), password)),
+        (Some(_), None) => {
+            println!("--user needs a password");
+            process::exit(1);
+        }
+        (None, None) => None,
+    };
+    let addr = config.addr;
+
+    if let Some(mut router) = router::connect(&addr, tls, credentials) {
+        println!("Connected to h2okv server {}, Ctrl-C to exit", &addr);
+
+        let stdin = io::stdin();
+        let input = &mut String::new();
+        loop {
+            input.clear();
+            print!("h2okv> ");
+            io::stdout().flush().unwrap();
+            stdin.read_line(input).unwrap();
+            cli::query(&input, &mut router);
         }
     }
 }
@@ -0,0 +1,260 @@
+// Client-side routing of queries to sharded servers.
+//
+// The slot map (see `src/slots.rs` of the server) is fetched with the
+// SLOTS query when connecting, and queries of a key are sent to the
+// owner of its slot, with one connection per server. When a server
+// replies MOVED, the map is out of date: it's fetched again from that
+// server, and the query retried there. When it replies ASK, the slot is
+// being migrated, and the query is retried on the server asked, after
+// ASKING, without changing the map.
+//
+// Each connection starts with HELLO, to agree on the protocol version,
+// and learn about the features of the server. With credentials, it's
+// then authenticated with AUTH.
+
+use std::collections::HashMap;
+use std::io::{Read, Write};
+use std::sync::Arc;
+
+use rustls::ClientConfig;
+
+use crate::stream::{self, Stream};
+use crate::tools;
+
+const SLOTS: usize = 16384;
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Oldest and latest protocol versions the CLI speaks
+const MIN_VERSION: u8 = 1;
+const VERSION: u8 = 1;
+
+pub enum Redirect {
+    Moved(u16, String),
+    Ask(String),
+}
+
+pub struct Router {
+    /// the server we connected to first, for queries without a key
+    default: String,
+    streams: HashMap<String, Stream>,
+    /// owner of each slot, empty when the servers are not sharded
+    slots: Vec<String>,
+    /// set when connecting with TLS
+    tls: Option<Arc<ClientConfig>>,
+    /// user and password, for servers requiring authentication
+    credentials: Option<(String, String)>,
+}
+
+/// CRC16/XMODEM
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for x in bytes {
+        crc ^= (*x as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// The slot the key belongs to, same as the server
+pub fn key_slot(key: &str) -> u16 {
+    let mut part = key;
+    if let Some(start) = key.find('{') {
+        if let Some(len) = key[start + 1..].find('}') {
+            if len > 0 {
+                part = &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+    (crc16(part.as_bytes()) as usize % SLOTS) as u16
+}
+
+/// Send AUTH: `<user-len:2><user><pw-len-byte><pw-len-bytes><password>`
+fn login(user: &str, password: &str, stream: &mut Stream) -> Result<(), String> {
+    let mut query = vec![0x0c, 0x16, 0x00];
+    query.extend(&tools::u16_to_bytes(user.len() as u16));
+    query.extend(user.as_bytes());
+    let (count, bytes) = tools::u64_to_bytes(password.len() as u64);
+    query.push(count);
+    query.extend(&bytes);
+    query.extend(password.as_bytes());
+    let mut reply = [0_u8; 2];
+    match stream
+        .write_all(&query)
+        .and_then(|_| stream.read_exact(&mut reply))
+    {
+        Ok(_) if reply == [0x0c, 0x00] => Ok(()),
+        Ok(_) => Err("authentication failed".to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Send HELLO, returns the features of the server, none for servers
+/// older than HELLO
+fn hello(stream: &mut Stream) -> Result<Vec<String>, String> {
+    let mut content = format!("{} gzip bigkeys chunked", VERSION);
+    if tools::has_cipher_key() {
+        content.push_str(" cipher");
+    }
+    let mut query = vec![0x0c, 0x19, 0x00];
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content.as_bytes());
+    let mut reply = [0_u8; 2];
+    stream
+        .write_all(&query)
+        .and_then(|_| stream.read_exact(&mut reply))
+        .map_err(|e| e.to_string())?;
+    if reply == [0x0c, 0xff] {
+        return Ok(vec![]);
+    }
+    let text = match tools::read_string(stream) {
+        Some(x) => x,
+        None => return Err("connection lost".to_string()),
+    };
+    if reply != [0x0c, 0x00] {
+        return Err(text);
+    }
+    let mut tokens = text.split_whitespace();
+    match tokens.next().map(|x| x.parse::<u8>()) {
+        Some(Ok(x)) if x >= MIN_VERSION => {}
+        _ => return Err(format!("unsupported protocol version: {}", text)),
+    }
+    Ok(tokens.map(|x| x.to_string()).collect())
+}
+
+fn open(
+    addr: &str,
+    tls: &Option<Arc<ClientConfig>>,
+    credentials: &Option<(String, String)>,
+) -> Result<Stream, String> {
+    let mut stream = stream::connect(addr, tls).map_err(|e| e.to_string())?;
+    let features = hello(&mut stream)?;
+    if tools::has_cipher_key() && !features.iter().any(|x| x == "cipher") {
+        return Err("server has no cipher key".to_string());
+    }
+    tools::set_server_features(features);
+    if let Some((user, password)) = credentials {
+        login(user, password, &mut stream)?;
+    }
+    Ok(stream)
+}
+
+pub fn connect(
+    addr: &str,
+    tls: Option<Arc<ClientConfig>>,
+    credentials: Option<(String, String)>,
+) -> Option<Router> {
+    let stream = match open(addr, &tls, &credentials) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("Failed to connect: {}", e);
+            return None;
+        }
+    };
+    let mut router = Router {
+        default: addr.to_string(),
+        streams: HashMap::new(),
+        slots: vec![],
+        tls,
+        credentials,
+    };
+    router.streams.insert(addr.to_string(), stream);
+    fetch_slots(addr, &mut router);
+    Some(router)
+}
+
+pub fn default_stream(router: &mut Router) -> &mut Stream {
+    let addr = router.default.clone();
+    router.streams.get_mut(&addr).unwrap()
+}
+
+fn stream_of<'a>(addr: &str, router: &'a mut Router) -> Option<&'a mut Stream> {
+    if !router.streams.contains_key(addr) {
+        match open(addr, &router.tls, &router.credentials) {
+            Ok(x) => {
+                router.streams.insert(addr.to_string(), x);
+            }
+            Err(e) => {
+                println!("Failed to connect {}: {}", addr, e);
+                return None;
+            }
+        }
+    }
+    router.streams.get_mut(addr)
+}
+
+/// Connection to the server owning the key
+pub fn stream_for<'a>(key: &str, router: &'a mut Router) -> Option<&'a mut Stream> {
+    let addr = match router.slots.get(key_slot(key) as usize) {
+        Some(x) => x.clone(),
+        None => router.default.clone(),
+    };
+    stream_of(&addr, router)
+}
+
+/// Connection to the server which replied ASK, ready for the query
+pub fn asking<'a>(addr: &str, router: &'a mut Router) -> Option<&'a mut Stream> {
+    let stream = stream_of(addr, router)?;
+    stream.write_all(b"\x0c\x14\x00\x00\x00").ok()?;
+    let reply = tools::read_exact(stream, 2)?;
+    if reply != b"\x0c\x00" {
+        return None;
+    }
+    Some(stream)
+}
+
+/// The slot was MOVED to the server at `addr`
+pub fn moved(slot: u16, addr: &str, router: &mut Router) {
+    if !fetch_slots(addr, router) {
+        if router.slots.is_empty() {
+            router.slots = vec![router.default.clone(); SLOTS];
+        }
+        router.slots[slot as usize] = addr.to_string();
+    }
+}
+
+/// Update the slot map from the server at `addr`, returns if done
+fn fetch_slots(addr: &str, router: &mut Router) -> bool {
+    let stream = match stream_of(addr, router) {
+        Some(x) => x,
+        None => return false,
+    };
+    if stream.write_all(b"\x0c\x11\x00\x00\x00").is_err() {
+        return false;
+    }
+    let header = match tools::read_exact(stream, 2) {
+        Some(x) => x,
+        None => return false,
+    };
+    if header[0] != 0x0c || header[1] != 0x00 {
+        if header[1] == 0x08 {
+            println!("authentication required, see --user and --password");
+        }
+        return false;
+    }
+    let count = match tools::read_exact(stream, 2) {
+        Some(x) => tools::bytes_to_u16(&x),
+        None => return false,
+    };
+
+    let mut slots = vec![];
+    for _ in 0..count {
+        let range = match tools::read_exact(stream, 4) {
+            Some(x) => x,
+            None => return false,
+        };
+        let owner = match tools::read_string(stream) {
+            Some(x) => x,
+            None => return false,
+        };
+        let end = tools::bytes_to_u16(&range[2..]) as usize;
+        slots.resize(end + 1, owner);
+    }
+    router.slots = slots;
+    true
+}
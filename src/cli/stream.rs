@@ -0,0 +1,55 @@
+// Connections to servers, over TCP, a unix domain socket for addresses
+// like `unix:/tmp/h2okv.sock`, or TLS when configured.
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::sync::Arc;
+
+use rustls::ClientConfig;
+
+use crate::tls::{self, TlsStream};
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(Box<TlsStream>),
+}
+
+pub fn connect(addr: &str, tls: &Option<Arc<ClientConfig>>) -> io::Result<Stream> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        return UnixStream::connect(path).map(Stream::Unix);
+    }
+    match tls {
+        Some(config) => tls::connect(addr, config).map(|x| Stream::Tls(Box::new(x))),
+        None => TcpStream::connect(addr).map(Stream::Tcp),
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.read(buf),
+            Stream::Unix(x) => x.read(buf),
+            Stream::Tls(x) => x.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.write(buf),
+            Stream::Unix(x) => x.write(buf),
+            Stream::Tls(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.flush(),
+            Stream::Unix(x) => x.flush(),
+            Stream::Tls(x) => x.flush(),
+        }
+    }
+}
@@ -0,0 +1,69 @@
+// TLS connections to the `--tls-port` of servers, verified with the CAs
+// in `--tls-ca-cert`, and with the client certificate in `--tls-cert`
+// and `--tls-key` for servers requiring one.
+
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+pub type TlsStream = StreamOwned<ClientConnection, TcpStream>;
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let certs: Result<Vec<_>, _> = rustls_pemfile::certs(&mut BufReader::new(file)).collect();
+    match certs {
+        Ok(x) if !x.is_empty() => Ok(x),
+        Ok(_) => Err(format!("no certificates in {}", path)),
+        Err(e) => Err(format!("invalid certificates in {}: {}", path, e)),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(x)) => Ok(x),
+        Ok(None) => Err(format!("no private key in {}", path)),
+        Err(e) => Err(format!("invalid private key in {}: {}", path, e)),
+    }
+}
+
+pub fn client_config(
+    ca: &str,
+    cert: Option<&str>,
+    key: Option<&str>,
+) -> Result<Arc<ClientConfig>, String> {
+    let mut roots = RootCertStore::empty();
+    for x in load_certs(ca)? {
+        roots
+            .add(x)
+            .map_err(|e| format!("invalid CA in {}: {}", ca, e))?;
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    match (cert, key) {
+        (Some(cert), Some(key)) => {
+            let config = builder
+                .with_client_auth_cert(load_certs(cert)?, load_key(key)?)
+                .map_err(|e| format!("invalid certificate or key: {}", e))?;
+            Ok(Arc::new(config))
+        }
+        _ => Ok(Arc::new(builder.with_no_client_auth())),
+    }
+}
+
+/// Connect to `HOST:PORT`, whose certificate must be valid for `HOST`
+pub fn connect(addr: &str, config: &Arc<ClientConfig>) -> io::Result<TlsStream> {
+    let host = match addr.rfind(':') {
+        Some(i) => addr[..i].trim_start_matches('[').trim_end_matches(']'),
+        None => addr,
+    };
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let conn = ClientConnection::new(config.clone(), name).map_err(io::Error::other)?;
+    let tcp = TcpStream::connect(addr)?;
+    Ok(StreamOwned::new(conn, tcp))
+}
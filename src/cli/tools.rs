@@ -1,6 +1,17 @@
-use std::io::Cursor;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::str;
+use std::sync::OnceLock;
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::router::Redirect;
+use crate::stream::Stream;
 
 pub fn bytes_to_u64(bytes: &[u8]) -> u64 {
     let mut buf = [0; 8];
@@ -51,3 +62,243 @@ pub fn u16_to_bytes(n: u16) -> [u8; 2] {
     array.copy_from_slice(&buffer);
     array
 }
+
+pub fn u32_to_bytes(n: u32) -> [u8; 4] {
+    let mut buffer = vec![];
+    buffer.write_u32::<LittleEndian>(n).unwrap();
+    let mut array = [0; 4];
+    array.copy_from_slice(&buffer);
+    array
+}
+
+/// Key lengths from this one up are followed by the actual length as 4
+/// bytes, with servers having `bigkeys`
+pub const LONG_LEN: usize = 0xFFFF;
+
+/// VLLen of values in chunks `<len:4><chunk>`, ended by an empty chunk,
+/// with servers having `chunked`
+pub const CHUNKED: u8 = 0xFF;
+
+/// Values larger than this are sent in chunks of this size
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// `<len:2>`, or `\xFF\xFF<len:4>` for keys of 64 KiB or more with
+/// servers having `bigkeys`
+pub fn key_len_bytes(len: usize) -> Vec<u8> {
+    if len < LONG_LEN || !server_has("bigkeys") {
+        return u16_to_bytes(len as u16).to_vec();
+    }
+    let mut bytes = u16_to_bytes(LONG_LEN as u16).to_vec();
+    bytes.extend(&u32_to_bytes(len as u32));
+    bytes
+}
+
+/// Check the key can be sent to the server, printing the error if not
+pub fn check_key(key: &str) -> bool {
+    if key.len() > LONG_LEN && !server_has("bigkeys") {
+        println!("(error) key too long for the server");
+        return false;
+    }
+    true
+}
+
+/// The value in chunks, ended by the empty chunk
+pub fn chunks(value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for chunk in value.chunks(CHUNK_SIZE) {
+        buffer.extend(&u32_to_bytes(chunk.len() as u32));
+        buffer.extend(chunk);
+    }
+    buffer.extend(&[0; 4]);
+    buffer
+}
+
+/// Values smaller than this are sent as is, larger ones with GZIP
+pub const GZIP_MIN_SIZE: usize = 1024;
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+pub fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    match GzDecoder::new(data).read_to_end(&mut result) {
+        Ok(_) => Some(result),
+        Err(e) => {
+            println!("invalid GZIP data: {}", e);
+            None
+        }
+    }
+}
+
+/// `--cipher-key-file`, values are sent and received encrypted with it
+static CIPHER_KEY: OnceLock<LessSafeKey> = OnceLock::new();
+
+/// Load the key of 64 hex digits from the file
+pub fn load_cipher_key(path: &str) -> Result<(), String> {
+    let text = match fs::read_to_string(path) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("cannot read cipher key file {}: {}", path, e)),
+    };
+    let text = text.trim();
+    let bytes: Vec<u8> = (0..text.len())
+        .step_by(2)
+        .filter_map(|i| text.get(i..i + 2))
+        .filter_map(|x| u8::from_str_radix(x, 16).ok())
+        .collect();
+    if text.len() != 64 || bytes.len() != 32 {
+        return Err("cipher key must be 64 hex digits".to_string());
+    }
+    let key = UnboundKey::new(&CHACHA20_POLY1305, &bytes).unwrap();
+    let _ = CIPHER_KEY.set(LessSafeKey::new(key));
+    Ok(())
+}
+
+pub fn has_cipher_key() -> bool {
+    CIPHER_KEY.get().is_some()
+}
+
+/// Encrypt a value as `<nonce:12><ciphertext><tag:16>`, with the key of
+/// the query as the additional authenticated data
+pub fn encrypt(data: &[u8], aad: &[u8]) -> Vec<u8> {
+    let key = CIPHER_KEY.get().expect("no cipher key");
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new().fill(&mut nonce).unwrap();
+    let mut result = data.to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    key.seal_in_place_append_tag(n, Aad::from(aad), &mut result)
+        .unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(result);
+    sealed
+}
+
+pub fn decrypt(data: &[u8], aad: &[u8]) -> Option<Vec<u8>> {
+    let key = CIPHER_KEY.get()?;
+    if data.len() < NONCE_LEN {
+        println!("ciphered value too short");
+        return None;
+    }
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&data[..NONCE_LEN]);
+    let mut result = data[NONCE_LEN..].to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    match key.open_in_place(n, Aad::from(aad), &mut result) {
+        Ok(x) => {
+            let len = x.len();
+            result.truncate(len);
+            Some(result)
+        }
+        Err(_) => {
+            println!("ciphered value cannot be authenticated");
+            None
+        }
+    }
+}
+
+/// Features of the server we connected to first, from HELLO
+static SERVER_FEATURES: OnceLock<Vec<String>> = OnceLock::new();
+
+pub fn set_server_features(features: Vec<String>) {
+    let _ = SERVER_FEATURES.set(features);
+}
+
+pub fn server_has(feature: &str) -> bool {
+    match SERVER_FEATURES.get() {
+        Some(x) => x.iter().any(|x| x == feature),
+        None => false,
+    }
+}
+
+pub fn read_exact(stream: &mut Stream, size: usize) -> Option<Vec<u8>> {
+    let mut buffer = vec![0_u8; size];
+    match stream.read_exact(&mut buffer) {
+        Ok(_) => Some(buffer),
+        Err(e) => {
+            println!("connection lost: {}", e);
+            None
+        }
+    }
+}
+
+/// Read `<len:2><bytes>` from stream
+pub fn read_string(stream: &mut Stream) -> Option<String> {
+    let buf_len = read_exact(stream, 2)?;
+    let buffer = read_exact(stream, bytes_to_u16(&buf_len) as usize)?;
+    Some(String::from_utf8_lossy(&buffer).to_string())
+}
+
+/// Read a key `<len:2><key>`, or `\xFF\xFF<len:4><key>` from servers
+/// having `bigkeys`, from stream
+pub fn read_key(stream: &mut Stream) -> Option<String> {
+    let buf_len = read_exact(stream, 2)?;
+    let mut size = bytes_to_u16(&buf_len) as usize;
+    if size == LONG_LEN && server_has("bigkeys") {
+        size = bytes_to_u32(&read_exact(stream, 4)?) as usize;
+    }
+    let buffer = read_exact(stream, size)?;
+    Some(String::from_utf8_lossy(&buffer).to_string())
+}
+
+/// Read a value in chunks from stream, until the empty chunk
+pub fn read_chunks(stream: &mut Stream) -> Option<Vec<u8>> {
+    let mut value = vec![];
+    loop {
+        let buf_len = read_exact(stream, 4)?;
+        let size = bytes_to_u32(&buf_len) as usize;
+        if size == 0 {
+            return Some(value);
+        }
+        value.extend(read_exact(stream, size)?);
+    }
+}
+
+/// Read `<len-byte><len-bytes><bytes>` from stream, formatted for printing
+pub fn read_value(stream: &mut Stream) -> Option<String> {
+    let buf_llen = read_exact(stream, 1)?;
+    let buf_len = read_exact(stream, buf_llen[0] as usize)?;
+    let size = bytes_to_u64(&buf_len) as usize;
+    let value = read_exact(stream, size)?;
+    match str::from_utf8(&value) {
+        Ok(x) => Some(format!("{:?}", x)),
+        Err(_) => Some(format!("{:?}", &value)),
+    }
+}
+
+/// Print the error of a reply without content, i.e. not authenticated,
+/// no permission, too large or wrong type, returns if it is one
+pub fn print_error(status: u8) -> bool {
+    match status {
+        0x08 => println!("(error) authentication required"),
+        0x09 => println!("(error) permission denied"),
+        0x0B => println!("(error) request too large"),
+        0x0C => println!("(error) wrong type of value"),
+        _ => return false,
+    }
+    true
+}
+
+/// Print the rest of a "not leader" reply: `<len:2><leader-address>`
+pub fn print_not_leader(stream: &mut Stream) {
+    if let Some(leader) = read_string(stream) {
+        if leader.is_empty() {
+            println!("(not leader) leader unknown");
+        } else {
+            println!("(not leader) leader: {}", leader);
+        }
+    }
+}
+
+/// Read the rest of a MOVED or ASK reply: `<slot:2><len:2><address>`
+pub fn read_redirect(status: u8, stream: &mut Stream) -> Option<Redirect> {
+    let buf_slot = read_exact(stream, 2)?;
+    let addr = read_string(stream)?;
+    let slot = bytes_to_u16(&buf_slot);
+    if status == 0x07 {
+        Some(Redirect::Ask(addr))
+    } else {
+        Some(Redirect::Moved(slot, addr))
+    }
+}
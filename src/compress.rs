@@ -0,0 +1,76 @@
+// GZIP compression of values, with Flag `\x01` in queries: the values of
+// PUT-like queries are compressed by the client, and GET replies of
+// values of at least `MIN_SIZE` bytes are compressed by the server, with
+// Flag `\x01` in the reply, when the query has it. Keys and other
+// contents are never compressed.
+
+use std::io::{Read, Write};
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+/// Values smaller than this are not worth compressing
+pub const MIN_SIZE: usize = 1024;
+
+pub fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    // writing into a Vec never fails
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+/// Error of `gunzip` for data over its `max`
+pub const TOO_LARGE: &str = "GZIP data too large";
+
+/// Decompress up to `max` bytes, so that a small query cannot take all
+/// the memory, see `src/limits.rs`
+pub fn gunzip(data: &[u8], max: u64) -> Result<Vec<u8>, String> {
+    let mut result = vec![];
+    let mut decoder = GzDecoder::new(data).take(max + 1);
+    if let Err(e) = decoder.read_to_end(&mut result) {
+        return Err(format!("invalid GZIP data: {}", e));
+    }
+    if result.len() as u64 > max {
+        return Err(TOO_LARGE.to_string());
+    }
+    Ok(result)
+}
+
+/// The value for a GET reply, and its Flag: compressed if the client
+/// `accepts` it, the value is large enough, and it gets smaller.
+pub fn reply_value(value: &[u8], accepts: bool) -> (u8, Vec<u8>) {
+    if accepts && value.len() >= MIN_SIZE {
+        let compressed = gzip(value);
+        if compressed.len() < value.len() {
+            return (0x01, compressed);
+        }
+    }
+    (0x00, value.to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{gunzip, gzip, reply_value, MIN_SIZE};
+
+    #[test]
+    fn test_gzip() {
+        let data = b"hello hello hello hello".to_vec();
+        let compressed = gzip(&data);
+        assert_eq!(&compressed[..2], b"\x1f\x8b");
+        assert_eq!(gunzip(&compressed, 100), Ok(data));
+        assert!(gunzip(&compressed, 22).is_err());
+        assert!(gunzip(b"hello", 100).is_err());
+        assert!(gunzip(&compressed[..10], 100).is_err());
+    }
+
+    #[test]
+    fn test_reply_value() {
+        assert_eq!(reply_value(b"bar", true), (0x00, b"bar".to_vec()));
+        let value = vec![b'x'; MIN_SIZE];
+        assert_eq!(reply_value(&value, false), (0x00, value.clone()));
+        let (flag, compressed) = reply_value(&value, true);
+        assert_eq!(flag, 0x01);
+        assert_eq!(gunzip(&compressed, MIN_SIZE as u64), Ok(value));
+    }
+}
@@ -0,0 +1,370 @@
+// Server options, from command line arguments:
+//
+//     h2okv [--host HOST] [--port PORT] [--replicaof HOST:PORT]
+//           [--raft-id ID [--raft-peers ID=HOST:PORT,...] [--raft-snapshot N]]
+//           [--slots START-END=HOST:PORT,...] [--resp-port PORT]
+//           [--memcache-port PORT] [--http-port PORT]
+//           [--unix-socket PATH [--unix-socket-perm MODE]]
+//           [--tls-port PORT --tls-cert PATH --tls-key PATH
+//            [--tls-ca-cert PATH]]
+//           [--password PASSWORD] [--users NAME=PASSWORD,...]
+//           [--cipher-key-file PATH] [--data-key-file PATH]
+//           [--snapshot-compression none|lz4]
+//           [--max-key-size SIZE] [--max-value-size SIZE]
+//           [--max-request-size SIZE]
+//           [--log-level error|warn|info|debug] [--log-format text|json]
+//           [--log-file PATH [--log-max-size SIZE] [--log-files N]]
+//
+// With `--raft-id`, the server runs in cluster mode (see `src/raft.rs`),
+// where `--raft-peers` lists all voting members including itself. A node
+// to be added into a running cluster is started without `--raft-peers`.
+//
+// With `--slots`, keys are sharded between servers (see `src/slots.rs`),
+// each server is known in the map by its `--host` and `--port`.
+//
+// With `--resp-port`, the server also speaks the Redis protocol on the
+// port (see `src/resp.rs`), and with `--memcache-port` the memcached
+// text protocol (see `src/memcache.rs`). With `--http-port`, keys can be
+// accessed over HTTP (see `src/http.rs`).
+//
+// With `--unix-socket`, the server also listens on a unix domain socket,
+// with permissions `--unix-socket-perm` in octal, 700 by default. With
+// `--port 0` as well, it listens on the unix socket only.
+//
+// With `--tls-port`, the server also listens for TLS clients, with the
+// certificate and key in PEM, and with `--tls-ca-cert` only accepts
+// clients with certificates signed by the CAs (see `src/tls.rs`). With
+// `--port 0` as well, TCP clients must use TLS.
+//
+// With `--password` or `--users`, clients must authenticate (see
+// `src/auth.rs`).
+//
+// With `--cipher-key-file`, values can be sent encrypted with the
+// pre-shared key in the file (see `src/cipher.rs`). With `--data-key-file`,
+// or the environment variable `H2OKV_DATA_KEY`, the data file and the
+// Raft state are encrypted (see `src/encryption.rs`). With
+// `--snapshot-compression lz4`, the data file is compressed (see
+// `src/persistence.rs`).
+//
+// With `--max-key-size`, `--max-value-size` and `--max-request-size`,
+// queries over the sizes are rejected (see `src/limits.rs`).
+//
+// With `--log-level` and `--log-format`, what is logged and how, into
+// `--log-file` instead of stdout, rotated when over `--log-max-size`,
+// keeping `--log-files` old files (see `src/logging.rs`).
+
+use crate::auth;
+use crate::limits;
+use crate::logging;
+use crate::persistence;
+use crate::raft;
+use crate::slots;
+
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// address of the primary server, when started as a replica
+    pub replica_of: Option<String>,
+    /// ID of this node, when running in cluster mode
+    pub raft_id: Option<u64>,
+    pub raft_peers: raft::Peers,
+    /// how many applied log entries before taking a snapshot
+    pub raft_snapshot: usize,
+    /// slot map, when keys are sharded between servers
+    pub slots: Option<String>,
+    /// port for Redis clients
+    pub resp_port: Option<u16>,
+    /// port for memcached clients
+    pub memcache_port: Option<u16>,
+    /// port for HTTP clients
+    pub http_port: Option<u16>,
+    /// path of the unix domain socket to listen on
+    pub unix_socket: Option<String>,
+    pub unix_socket_perm: u32,
+    /// port for TLS clients
+    pub tls_port: Option<u16>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// CAs of client certificates, for mutual TLS
+    pub tls_ca_cert: Option<String>,
+    /// password of the default user
+    pub password: Option<String>,
+    /// named users with their passwords
+    pub users: Vec<(String, String)>,
+    /// file of the pre-shared key for ciphered values
+    pub cipher_key_file: Option<String>,
+    /// file of the keys for encrypting files
+    pub data_key_file: Option<String>,
+    pub snapshot_compression: persistence::Compression,
+    /// largest keys, values and queries accepted
+    pub limits: limits::Limits,
+    pub log: logging::Options,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 30160,
+            replica_of: None,
+            raft_id: None,
+            raft_peers: raft::Peers::new(),
+            raft_snapshot: 1000,
+            slots: None,
+            resp_port: None,
+            memcache_port: None,
+            http_port: None,
+            unix_socket: None,
+            unix_socket_perm: 0o700,
+            tls_port: None,
+            tls_cert: None,
+            tls_key: None,
+            tls_ca_cert: None,
+            password: None,
+            users: vec![],
+            cipher_key_file: None,
+            data_key_file: None,
+            snapshot_compression: persistence::Compression::None,
+            limits: limits::Limits::new(),
+            log: logging::Options::new(),
+        }
+    }
+}
+
+pub const USAGE: &str = "usage: h2okv [--host HOST] [--port PORT] [--replicaof HOST:PORT]
+             [--raft-id ID [--raft-peers ID=HOST:PORT,...] [--raft-snapshot N]]
+             [--slots START-END=HOST:PORT,...] [--resp-port PORT]
+             [--memcache-port PORT] [--http-port PORT]
+             [--unix-socket PATH [--unix-socket-perm MODE]]
+             [--tls-port PORT --tls-cert PATH --tls-key PATH
+              [--tls-ca-cert PATH]]
+             [--password PASSWORD] [--users NAME=PASSWORD,...]
+             [--cipher-key-file PATH] [--data-key-file PATH]
+             [--snapshot-compression none|lz4]
+             [--max-key-size SIZE] [--max-value-size SIZE]
+             [--max-request-size SIZE]
+             [--log-level error|warn|info|debug] [--log-format text|json]
+             [--log-file PATH [--log-max-size SIZE] [--log-files N]]";
+
+/// Parse `ID=HOST:PORT,...`
+fn parse_peers(value: &str) -> Result<raft::Peers, String> {
+    let mut peers = raft::Peers::new();
+    for item in value.split(',').filter(|x| !x.is_empty()) {
+        let mut parts = item.splitn(2, '=');
+        let id = parts.next().and_then(|x| x.parse::<u64>().ok());
+        match (id, parts.next()) {
+            (Some(id), Some(addr)) if id > 0 && !addr.is_empty() => {
+                peers.insert(id, addr.to_string());
+            }
+            _ => return Err(format!("invalid raft peer: {}", item)),
+        }
+    }
+    Ok(peers)
+}
+
+/// Parse options from the arguments, not including the program name
+pub fn from_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = match iter.next() {
+            Some(x) => x,
+            None => return Err(format!("missing value of option {}", arg)),
+        };
+        match arg.as_str() {
+            "--host" => config.host = value.to_string(),
+            "--port" => match value.parse::<u16>() {
+                Ok(x) => config.port = x,
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--replicaof" => config.replica_of = Some(value.to_string()),
+            "--raft-id" => match value.parse::<u64>() {
+                Ok(x) if x > 0 => config.raft_id = Some(x),
+                _ => return Err(format!("invalid raft id: {}", value)),
+            },
+            "--raft-peers" => config.raft_peers = parse_peers(value)?,
+            "--raft-snapshot" => match value.parse::<usize>() {
+                Ok(x) if x > 0 => config.raft_snapshot = x,
+                _ => return Err(format!("invalid raft snapshot: {}", value)),
+            },
+            "--slots" => {
+                slots::parse(value, &mut slots::SlotMap::new(""))?;
+                config.slots = Some(value.to_string());
+            }
+            "--resp-port" => match value.parse::<u16>() {
+                Ok(x) => config.resp_port = Some(x),
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--memcache-port" => match value.parse::<u16>() {
+                Ok(x) => config.memcache_port = Some(x),
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--http-port" => match value.parse::<u16>() {
+                Ok(x) => config.http_port = Some(x),
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--unix-socket" => config.unix_socket = Some(value.to_string()),
+            "--unix-socket-perm" => match u32::from_str_radix(value, 8) {
+                Ok(x) if x <= 0o777 => config.unix_socket_perm = x,
+                _ => return Err(format!("invalid permissions: {}", value)),
+            },
+            "--tls-port" => match value.parse::<u16>() {
+                Ok(x) => config.tls_port = Some(x),
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--tls-cert" => config.tls_cert = Some(value.to_string()),
+            "--tls-key" => config.tls_key = Some(value.to_string()),
+            "--tls-ca-cert" => config.tls_ca_cert = Some(value.to_string()),
+            "--password" => config.password = Some(value.to_string()),
+            "--users" => config.users = auth::parse_users(value)?,
+            "--cipher-key-file" => config.cipher_key_file = Some(value.to_string()),
+            "--data-key-file" => config.data_key_file = Some(value.to_string()),
+            "--snapshot-compression" => {
+                config.snapshot_compression = persistence::parse_compression(value)?
+            }
+            "--max-key-size" => config.limits.max_key_size = limits::parse_size(value)?,
+            "--max-value-size" => config.limits.max_value_size = limits::parse_size(value)?,
+            "--max-request-size" => config.limits.max_request_size = limits::parse_size(value)?,
+            "--log-level" => config.log.level = logging::parse_level(value)?,
+            "--log-format" => config.log.format = logging::parse_format(value)?,
+            "--log-file" => config.log.file = Some(value.to_string()),
+            "--log-max-size" => config.log.max_size = limits::parse_size(value)?,
+            "--log-files" => match value.parse::<usize>() {
+                Ok(x) => config.log.files = x,
+                Err(_) => return Err(format!("invalid log files: {}", value)),
+            },
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    if config.tls_port.is_some() && (config.tls_cert.is_none() || config.tls_key.is_none()) {
+        return Err("--tls-port needs --tls-cert and --tls-key".to_string());
+    }
+    if config.port == 0 {
+        if config.unix_socket.is_none() && config.tls_port.is_none() {
+            return Err("--port 0 needs --unix-socket or --tls-port".to_string());
+        }
+        if config.raft_id.is_some() || config.slots.is_some() {
+            return Err("cannot use --port 0 with --raft-id or --slots".to_string());
+        }
+    }
+    if let Some(id) = config.raft_id {
+        if config.replica_of.is_some() {
+            return Err("cannot use --replicaof in cluster mode".to_string());
+        }
+        if !config.raft_peers.is_empty() && !config.raft_peers.contains_key(&id) {
+            return Err(format!("raft id {} is not in --raft-peers", id));
+        }
+    } else if !config.raft_peers.is_empty() {
+        return Err("--raft-peers needs --raft-id".to_string());
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_args;
+    use crate::logging;
+    use crate::persistence;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = from_args(&args("")).unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.port, 30160);
+        assert_eq!(config.replica_of, None);
+        assert_eq!(config.resp_port, None);
+
+        let config = from_args(&args("--port 30161 --replicaof 127.0.0.1:30160")).unwrap();
+        assert_eq!(config.port, 30161);
+        assert_eq!(config.replica_of, Some("127.0.0.1:30160".to_string()));
+
+        let config = from_args(&args("--resp-port 6379")).unwrap();
+        assert_eq!(config.resp_port, Some(6379));
+        assert!(from_args(&args("--resp-port 70000")).is_err());
+
+        let config = from_args(&args("--memcache-port 11211")).unwrap();
+        assert_eq!(config.memcache_port, Some(11211));
+        let config = from_args(&args("--http-port 8080")).unwrap();
+        assert_eq!(config.http_port, Some(8080));
+        let config = from_args(&args("--unix-socket /tmp/h.sock --unix-socket-perm 770")).unwrap();
+        assert_eq!(config.unix_socket, Some("/tmp/h.sock".to_string()));
+        assert_eq!(config.unix_socket_perm, 0o770);
+        assert!(from_args(&args("--unix-socket /tmp/h.sock --port 0")).is_ok());
+        assert!(from_args(&args("--port 0")).is_err());
+        assert!(from_args(&args("--unix-socket-perm 800")).is_err());
+
+        let config = from_args(&args("--tls-port 30161 --tls-cert c.pem --tls-key k.pem")).unwrap();
+        assert_eq!(config.tls_port, Some(30161));
+        assert_eq!(config.tls_cert, Some("c.pem".to_string()));
+        assert_eq!(config.tls_key, Some("k.pem".to_string()));
+        assert_eq!(config.tls_ca_cert, None);
+        assert!(from_args(&args("--tls-port 30161 --tls-cert c.pem")).is_err());
+        let line = "--port 0 --tls-port 30161 --tls-cert c.pem --tls-key k.pem";
+        assert!(from_args(&args(line)).is_ok());
+
+        let config = from_args(&args("--password pw --users alice=a,bob=b")).unwrap();
+        assert_eq!(config.password, Some("pw".to_string()));
+        assert_eq!(config.users.len(), 2);
+        assert!(from_args(&args("--users alice")).is_err());
+
+        let config = from_args(&args("--cipher-key-file h2okv.key")).unwrap();
+        assert_eq!(config.cipher_key_file, Some("h2okv.key".to_string()));
+        let config = from_args(&args("--data-key-file data.key")).unwrap();
+        assert_eq!(config.data_key_file, Some("data.key".to_string()));
+        let config = from_args(&args("--snapshot-compression lz4")).unwrap();
+        assert_eq!(config.snapshot_compression, persistence::Compression::Lz4);
+        assert!(from_args(&args("--snapshot-compression zip")).is_err());
+
+        let config = from_args(&args("--max-key-size 1K --max-value-size 2M")).unwrap();
+        assert_eq!(config.limits.max_key_size, 1024);
+        assert_eq!(config.limits.max_value_size, 2 * 1024 * 1024);
+        let config = from_args(&args("--max-request-size 100")).unwrap();
+        assert_eq!(config.limits.max_request_size, 100);
+        assert!(from_args(&args("--max-value-size 0")).is_err());
+        let config = from_args(&args("--log-level debug --log-format json")).unwrap();
+        assert_eq!(config.log.level, logging::Level::Debug);
+        assert_eq!(config.log.format, logging::Format::Json);
+        assert_eq!(config.log.file, None);
+        let config = from_args(&args(
+            "--log-file h2okv.log --log-max-size 1M --log-files 2",
+        ))
+        .unwrap();
+        assert_eq!(config.log.file, Some("h2okv.log".to_string()));
+        assert_eq!((config.log.max_size, config.log.files), (1 << 20, 2));
+        assert!(from_args(&args("--log-level trace")).is_err());
+
+        assert!(from_args(&args("--port")).is_err());
+        assert!(from_args(&args("--port abc")).is_err());
+        assert!(from_args(&args("--foo bar")).is_err());
+    }
+
+    #[test]
+    fn test_from_args_raft() {
+        let config = from_args(&args("--raft-id 2 --raft-peers 1=a:1,2=b:2,3=c:3")).unwrap();
+        assert_eq!(config.raft_id, Some(2));
+        assert_eq!(config.raft_peers.len(), 3);
+        assert_eq!(config.raft_peers[&3], "c:3");
+        assert_eq!(config.raft_snapshot, 1000);
+
+        let config = from_args(&args("--raft-id 4 --raft-snapshot 10")).unwrap();
+        assert!(config.raft_peers.is_empty());
+        assert_eq!(config.raft_snapshot, 10);
+
+        assert!(from_args(&args("--raft-id 0")).is_err());
+        assert!(from_args(&args("--raft-id 4 --raft-peers 1=a:1,2=b:2")).is_err());
+        assert!(from_args(&args("--raft-id 1 --raft-peers 1=a:1,b:2")).is_err());
+        assert!(from_args(&args("--raft-peers 1=a:1")).is_err());
+        assert!(from_args(&args("--raft-id 1 --replicaof a:1")).is_err());
+    }
+
+    #[test]
+    fn test_from_args_slots() {
+        let config = from_args(&args("--slots 0-9999=a:1,10000-16383=b:2")).unwrap();
+        assert_eq!(config.slots, Some("0-9999=a:1,10000-16383=b:2".to_string()));
+        assert!(from_args(&args("--slots 0-9999=a:1")).is_err());
+    }
+}
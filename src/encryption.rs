@@ -0,0 +1,222 @@
+// Encryption at rest of the data file `h2okv.data` and the Raft state
+// `h2okv.raft`, with a key from `--data-key-file`, or the environment
+// variable `H2OKV_DATA_KEY` without it.
+//
+// An encrypted file is:
+//
+//     "\x0eH2OKV" <cipher:1> <key-id:8> <nonce:12> <ciphertext> <tag:16>
+//
+// where the cipher is `\x01` for ChaCha20-Poly1305, and the key ID is the
+// first 8 bytes of the SHA-256 of the key. The header before the nonce is
+// the additional authenticated data. Files without the header are loaded
+// as is, so that existing files get encrypted the next time they are
+// saved.
+//
+// Keys are 64 hex digits, as for `--cipher-key-file` (see `cipher.rs`).
+// The key file may have several keys, one per line: files are encrypted
+// with the last one, and may be decrypted with any of them. To rotate
+// keys, append a new one to the file, and send ROTATEKEY (`\x18`): the
+// file is read again, and the data file and the Raft state are saved
+// with the new key. Older keys can be removed from the file after that.
+
+use std::fs;
+use std::sync::Mutex;
+
+use ring::aead::{LessSafeKey, UnboundKey, CHACHA20_POLY1305};
+use ring::digest::{digest, SHA256};
+
+use crate::cipher;
+use crate::logging;
+
+const MAGIC: &[u8] = b"\x0eH2OKV";
+
+/// Environment variable of the key, without `--data-key-file`
+const KEY_ENV: &str = "H2OKV_DATA_KEY";
+
+const CHACHA20_POLY1305_ID: u8 = 0x01;
+
+const KEY_ID_LEN: usize = 8;
+
+const HEADER_LEN: usize = 6 + 1 + KEY_ID_LEN;
+
+struct DataKey {
+    id: [u8; KEY_ID_LEN],
+    key: LessSafeKey,
+}
+
+struct Keys {
+    /// `--data-key-file`, none for a key from the environment
+    file: Option<String>,
+    /// the last one encrypts
+    keys: Vec<DataKey>,
+}
+
+static KEYS: Mutex<Option<Keys>> = Mutex::new(None);
+
+fn data_key(text: &str) -> Result<DataKey, String> {
+    let bytes = cipher::parse_hex(text)?;
+    let mut id = [0; KEY_ID_LEN];
+    id.copy_from_slice(&digest(&SHA256, &bytes).as_ref()[..KEY_ID_LEN]);
+    // only fails with a key of a wrong length
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &bytes).unwrap());
+    Ok(DataKey { id, key })
+}
+
+/// Parse the keys of a key file, one per line, ignoring empty lines and
+/// comments starting with `#`
+fn parse_keys(text: &str) -> Result<Vec<DataKey>, String> {
+    let mut keys = vec![];
+    for line in text.lines().map(|x| x.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        keys.push(data_key(line)?);
+    }
+    if keys.is_empty() {
+        return Err("no keys in data key file".to_string());
+    }
+    Ok(keys)
+}
+
+fn read_key_file(path: &str) -> Result<Vec<DataKey>, String> {
+    match fs::read_to_string(path) {
+        Ok(x) => parse_keys(&x).map_err(|e| format!("{}: {}", path, e)),
+        Err(e) => Err(format!("cannot read data key file {}: {}", path, e)),
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|x| format!("{:02x}", x)).collect()
+}
+
+/// Load the keys from the file, or the environment variable without it.
+/// Files are not encrypted without any key.
+pub fn init(file: &Option<String>) -> Result<(), String> {
+    let keys = match file {
+        Some(path) => read_key_file(path)?,
+        None => match std::env::var(KEY_ENV) {
+            Ok(x) => vec![data_key(&x).map_err(|e| format!("{}: {}", KEY_ENV, e))?],
+            Err(_) => return Ok(()),
+        },
+    };
+    let keys = Keys {
+        file: file.clone(),
+        keys,
+    };
+    logging::info!("data key: {}", hex(&keys.keys.last().unwrap().id));
+    *KEYS.lock().unwrap() = Some(keys);
+    Ok(())
+}
+
+/// Read the key file again, returns the ID of the key files are
+/// encrypted with from now on
+pub fn rotate() -> Result<String, String> {
+    let mut guard = KEYS.lock().unwrap();
+    let keys = match guard.as_mut() {
+        Some(x) => x,
+        None => return Err("no data key".to_string()),
+    };
+    let path = match &keys.file {
+        Some(x) => x.clone(),
+        None => return Err(format!("cannot rotate the key of {}", KEY_ENV)),
+    };
+    keys.keys = read_key_file(&path)?;
+    let id = hex(&keys.keys.last().unwrap().id);
+    logging::info!("data key rotated: {}", id);
+    Ok(id)
+}
+
+fn seal_with(data: &[u8], keys: &[DataKey]) -> Vec<u8> {
+    let current = match keys.last() {
+        Some(x) => x,
+        None => return data.to_vec(),
+    };
+    let mut header = MAGIC.to_vec();
+    header.push(CHACHA20_POLY1305_ID);
+    header.extend(&current.id);
+    let mut result = header.clone();
+    result.extend(cipher::seal(data, &header, &current.key));
+    result
+}
+
+fn open_with(data: Vec<u8>, keys: &[DataKey]) -> Result<Vec<u8>, String> {
+    if !data.starts_with(MAGIC) {
+        return Ok(data);
+    }
+    if data.len() < HEADER_LEN {
+        return Err("encrypted file too short".to_string());
+    }
+    let (header, sealed) = data.split_at(HEADER_LEN);
+    if header[MAGIC.len()] != CHACHA20_POLY1305_ID {
+        return Err(format!("unknown cipher: {}", header[MAGIC.len()]));
+    }
+    let id = &header[MAGIC.len() + 1..];
+    match keys.iter().find(|x| x.id == id) {
+        Some(x) => cipher::open(sealed, header, &x.key),
+        None => Err(format!("encrypted with unknown data key {}", hex(id))),
+    }
+}
+
+/// Encrypt the content of a file, if there is a key
+pub fn seal(data: &[u8]) -> Vec<u8> {
+    match KEYS.lock().unwrap().as_ref() {
+        Some(keys) => seal_with(data, &keys.keys),
+        None => data.to_vec(),
+    }
+}
+
+/// Decrypt the content of a file, if it's encrypted
+pub fn open(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    match KEYS.lock().unwrap().as_ref() {
+        Some(keys) => open_with(data, &keys.keys),
+        None => open_with(data, &[]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_with, parse_keys, seal_with, MAGIC};
+
+    const KEY1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+    const KEY2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+    #[test]
+    fn test_parse_keys() {
+        let keys = parse_keys(&format!("# old\n{}\n\n{}\n", KEY1, KEY2)).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_ne!(keys[0].id, keys[1].id);
+        assert!(parse_keys("# none\n").is_err());
+        assert!(parse_keys("00ff\n").is_err());
+    }
+
+    #[test]
+    fn test_seal_open() {
+        let keys = parse_keys(KEY1).unwrap();
+        let data = b"\x0d\x01\x01".to_vec();
+        assert_eq!(seal_with(&data, &[]), data);
+        assert_eq!(open_with(data.clone(), &keys), Ok(data.clone()));
+
+        let sealed = seal_with(&data, &keys);
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(sealed[6], 0x01);
+        assert_eq!(&sealed[7..15], &keys[0].id);
+        assert_eq!(open_with(sealed.clone(), &keys), Ok(data.clone()));
+        assert!(open_with(sealed.clone(), &[]).is_err());
+
+        // rotated, with the old key still there
+        let rotated = parse_keys(&format!("{}\n{}", KEY1, KEY2)).unwrap();
+        assert_eq!(open_with(sealed.clone(), &rotated), Ok(data.clone()));
+        let resealed = seal_with(&data, &rotated);
+        assert_eq!(&resealed[7..15], &rotated[1].id);
+        assert!(open_with(resealed, &keys).is_err());
+
+        // tampered content or header
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 0x01;
+        assert!(open_with(tampered, &keys).is_err());
+        let mut tampered = sealed.clone();
+        tampered[6] = 0x02;
+        assert!(open_with(tampered, &keys).is_err());
+        assert!(open_with(sealed[..10].to_vec(), &keys).is_err());
+    }
+}
@@ -0,0 +1,81 @@
+// Errors of the store, the data file and the queries of clients, so that
+// what goes wrong is passed up with `Result` instead of panicking: a
+// broken query or a client gone away ends its connection at worst, never
+// the server.
+//
+// Errors of queries are replied with the Stat of `status()`, if the
+// client can still be told, before the connection is closed.
+
+use std::fmt;
+use std::io;
+
+use crate::limits;
+
+#[derive(Debug)]
+pub enum Error {
+    /// reading or writing failed, e.g. the client disconnected
+    Io(io::Error),
+    /// keys and values are stored as UTF-8 text
+    InvalidUtf8,
+    /// the query is malformed, e.g. with an invalid length
+    InvalidQuery(String),
+    /// over a limit, see `src/limits.rs`
+    TooLarge(String),
+    /// the value cannot be decompressed or decrypted
+    InvalidValue(String),
+    /// the data file, or a snapshot, is broken
+    Corrupted(String),
+    /// the key holds a value of another type, e.g. a list for GET
+    WrongType,
+}
+
+impl Error {
+    /// Stat to reply, `None` when the stream is broken
+    pub fn status(&self) -> Option<u8> {
+        match self {
+            Error::Io(_) => None,
+            Error::TooLarge(_) => Some(limits::TOO_LARGE[1]),
+            Error::WrongType => Some(0x0C),
+            _ => Some(0x01),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::InvalidUtf8 => write!(f, "invalid UTF-8"),
+            Error::InvalidQuery(x) => write!(f, "invalid query: {}", x),
+            Error::TooLarge(x) => write!(f, "too large: {}", x),
+            Error::InvalidValue(x) => write!(f, "invalid value: {}", x),
+            Error::Corrupted(x) => write!(f, "corrupted data: {}", x),
+            Error::WrongType => write!(f, "wrong type of value"),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+    use std::io;
+
+    #[test]
+    fn test_status() {
+        let e = Error::from(io::Error::from(io::ErrorKind::UnexpectedEof));
+        assert_eq!(e.status(), None);
+        assert_eq!(Error::TooLarge("key".to_string()).status(), Some(0x0B));
+        assert_eq!(Error::InvalidUtf8.status(), Some(0x01));
+        assert_eq!(Error::WrongType.status(), Some(0x0C));
+        assert_eq!(
+            Error::InvalidQuery("x".to_string()).to_string(),
+            "invalid query: x"
+        );
+    }
+}
@@ -0,0 +1,124 @@
+// HELLO (`\x19`), for clients and servers of different versions to talk
+// to each other. The client sends the highest protocol version it speaks
+// and the features it supports, as text, e.g. `1 gzip cipher`, and the
+// server replies with the version used on the connection and its own
+// features, e.g. `1 gzip acl pubsub type:string`. A client whose version
+// the server no longer speaks is replied with Incompatible (`\x0A`), and
+// disconnected. The client may also give up on a server replying with a
+// version too old for it, or with Unknown command (servers before HELLO).
+//
+// HELLO may be sent before authenticating, so that clients learn about
+// the `auth` feature. Clients never sending it are served with version 1
+// and no features, as before HELLO.
+//
+// Version 1 is the protocol described in the README. Features are:
+//
+// - `gzip`: values with Flag `\x01`, see `src/compress.rs`. When the
+//   client has it, GET replies are compressed when worth it, even if the
+//   query does not have the flag.
+// - `cipher`: values with Flag `\x02`, only when the server has a key,
+//   see `src/cipher.rs`
+// - `auth`: the server requires authentication, see `src/auth.rs`
+// - `acl`, `pubsub`, `watch`, `changes`, `mget`: the commands
+// - `bigkeys`: keys of 64 KiB or more, see `src/large.rs`. Lengths are
+//   only read or written in the long form for clients with it, and SCAN
+//   replies have such keys only for them.
+// - `chunked`: values in chunks, see `src/large.rs`. GET replies of
+//   large values are chunked for clients with it.
+// - `cluster`: the server is a node in cluster mode, see `src/raft.rs`
+// - `slots`: keys are sharded, and may be redirected, see `src/slots.rs`
+// - `type:string`, `type:list`: the data types of values, lists only
+//   when not in cluster mode, see `src/blocking.rs`
+
+/// Oldest version this server speaks
+pub const MIN_VERSION: u8 = 1;
+
+/// Latest version this server speaks
+pub const VERSION: u8 = 1;
+
+#[derive(Debug, PartialEq)]
+pub struct Hello {
+    pub version: u8,
+    pub features: Vec<String>,
+}
+
+/// Parse the content of a HELLO query, or the text of its reply
+pub fn parse(content: &str) -> Result<Hello, String> {
+    let mut tokens = content.split_whitespace();
+    let version = match tokens.next().map(|x| x.parse::<u8>()) {
+        Some(Ok(x)) => x,
+        _ => return Err(format!("invalid hello: {:?}", content)),
+    };
+    let features = tokens.map(|x| x.to_string()).collect();
+    Ok(Hello { version, features })
+}
+
+/// The version to speak with a client speaking up to `version`
+pub fn negotiate(version: u8) -> Result<u8, String> {
+    if version < MIN_VERSION {
+        return Err(format!(
+            "unsupported protocol version {}, supported {}-{}",
+            version, MIN_VERSION, VERSION
+        ));
+    }
+    Ok(version.min(VERSION))
+}
+
+/// Features of this server, see above
+pub fn features(cipher: bool, auth: bool, cluster: bool, sharded: bool) -> Vec<&'static str> {
+    let mut result = vec!["gzip"];
+    if cipher {
+        result.push("cipher");
+    }
+    if auth {
+        result.push("auth");
+    }
+    result.extend(&["acl", "pubsub", "watch", "changes", "mget"]);
+    result.extend(&["bigkeys", "chunked"]);
+    if cluster {
+        result.push("cluster");
+    }
+    if sharded {
+        result.push("slots");
+    }
+    result.push("type:string");
+    if !cluster {
+        result.push("type:list");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{features, negotiate, parse, Hello, VERSION};
+
+    #[test]
+    fn test_parse() {
+        let hello = Hello {
+            version: 1,
+            features: vec!["gzip".to_string(), "cipher".to_string()],
+        };
+        assert_eq!(parse("1 gzip cipher"), Ok(hello));
+        assert_eq!(parse("2").unwrap().features, Vec::<String>::new());
+        assert!(parse("").is_err());
+        assert!(parse("v1 gzip").is_err());
+        assert!(parse("256").is_err());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(negotiate(1), Ok(1));
+        assert_eq!(negotiate(VERSION + 1), Ok(VERSION));
+        assert!(negotiate(0).is_err());
+
+        let all = features(true, true, true, true).join(" ");
+        assert_eq!(
+            all,
+            "gzip cipher auth acl pubsub watch changes mget bigkeys chunked cluster slots \
+             type:string"
+        );
+        let plain = features(false, false, false, false);
+        assert!(!plain.contains(&"auth"));
+        assert_eq!(plain.last(), Some(&"type:list"));
+    }
+}
@@ -0,0 +1,650 @@
+// HTTP/JSON gateway, so that keys can be accessed with curl:
+//
+//     GET    /keys/{key}         the value
+//     PUT    /keys/{key}         set the value to the request body
+//     DELETE /keys/{key}         delete the key
+//     GET    /keys?prefix={p}    keys starting with the prefix, in JSON
+//
+// Values are sent as `application/octet-stream`, or as JSON objects
+// `{"key": ..., "value": ...}` when the client accepts
+// `application/json`. Values must be UTF-8, as in the DB.
+//
+// Errors are JSON objects with a message and the status of the h2okv
+// protocol, e.g. `{"error": "no such key", "status": 2}`, with the HTTP
+// status mapped from it (see `http_status()`). Keys owned by other
+// servers, and writes to a node other than the leader in cluster mode,
+// are redirected with 307 to the same path on the other server, whose
+// address is shifted by the offset between `--http-port` and `--port`.
+// For ASK redirects, `?asking=1` is added, which stands for ASKING.
+//
+// When users are configured (see `src/auth.rs`), requests need their
+// credentials with Basic authentication, e.g. `curl -u alice:secret`,
+// or are replied with 401. An empty user name is the default user.
+// Keys the user may not access are replied with 403, and only the keys
+// the user may access are listed.
+//
+// Connections are kept alive as in HTTP/1.1, request bodies need a
+// Content-Length, which is replied with 413 if it's over
+// `--max-value-size` or `--max-request-size` (see `src/limits.rs`).
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use crate::access;
+use crate::auth;
+use crate::limits;
+use crate::logging;
+use crate::raft;
+use crate::store;
+
+const MAX_LINE: usize = 8 * 1024;
+const MAX_HEADERS: usize = 100;
+
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    /// with lowercase names
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    /// other headers, e.g. Location
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        204 => "No Content",
+        307 => "Temporary Redirect",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        411 => "Length Required",
+        413 => "Payload Too Large",
+        415 => "Unsupported Media Type",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    }
+}
+
+/// HTTP status of a status of the h2okv protocol
+fn http_status(status: u8) -> u16 {
+    match status {
+        0x00 => 200,
+        0x02 => 404,
+        0x04 | 0x09 => 403,
+        0x05..=0x07 => 307,
+        0x08 => 401,
+        0xFF => 404,
+        _ => 500,
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn json(status: u16, body: String) -> Response {
+    Response {
+        status,
+        content_type: "application/json",
+        headers: vec![],
+        body: body.into_bytes(),
+    }
+}
+
+/// Error response, with the status of the h2okv protocol
+fn error(status: u8, message: &str) -> Response {
+    let body = format!(
+        "{{\"error\": {}, \"status\": {}}}",
+        json_string(message),
+        status
+    );
+    json(http_status(status), body)
+}
+
+/// Error response not of the h2okv protocol, e.g. a bad request
+fn http_error(http_status: u16, message: &str) -> Response {
+    let body = format!("{{\"error\": {}, \"status\": {}}}", json_string(message), 1);
+    json(http_status, body)
+}
+
+fn no_content() -> Response {
+    Response {
+        status: 204,
+        content_type: "",
+        headers: vec![],
+        body: vec![],
+    }
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut result = vec![];
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let hex = text.get(i + 1..i + 3)?;
+                result.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' => {
+                result.push(b' ');
+                i += 1;
+            }
+            x => {
+                result.push(x);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8(result).ok()
+}
+
+fn percent_encode(text: &str) -> String {
+    let mut result = String::new();
+    for x in text.as_bytes() {
+        match *x {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                result.push(*x as char)
+            }
+            x => result.push_str(&format!("%{:02X}", x)),
+        }
+    }
+    result
+}
+
+/// Value of the parameter in the query string, decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    for pair in query.split('&') {
+        let mut parts = pair.splitn(2, '=');
+        if parts.next() == Some(name) {
+            return percent_decode(parts.next().unwrap_or(""));
+        }
+    }
+    None
+}
+
+fn header<'a>(request: &'a Request, name: &str) -> Option<&'a str> {
+    request
+        .headers
+        .iter()
+        .find(|x| x.0 == name)
+        .map(|x| x.1.as_str())
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, Response> {
+    let mut line = vec![];
+    match reader.take(MAX_LINE as u64).read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(_) => return Ok(None),
+    }
+    if !line.ends_with(b"\n") {
+        return Err(http_error(431, "line too long"));
+    }
+    match String::from_utf8(line) {
+        Ok(x) => Ok(Some(x.trim_end().to_string())),
+        Err(_) => Err(http_error(400, "invalid request")),
+    }
+}
+
+/// Read a request, returns `None` when the client disconnected, or an
+/// error response for bad requests, after which the connection is
+/// closed. The body, i.e. the value of PUT, is bounded by the `limits`.
+fn read_request<R: BufRead, W: Write>(
+    reader: &mut R,
+    writer: &mut W,
+    limits: &limits::Limits,
+) -> Result<Option<Request>, Response> {
+    let line = match read_line(reader)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let tokens: Vec<&str> = line.split(' ').collect();
+    let (method, target) = match tokens.as_slice() {
+        [method, target, version] if version.starts_with("HTTP/1.") => (method, target),
+        _ => return Err(http_error(400, "invalid request line")),
+    };
+    let (path, query) = match target.find('?') {
+        Some(i) => (&target[..i], &target[i + 1..]),
+        None => (*target, ""),
+    };
+
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        headers: vec![],
+        body: vec![],
+    };
+    if tokens[2] == "HTTP/1.0" {
+        request
+            .headers
+            .push(("connection".to_string(), "close".to_string()));
+    }
+    loop {
+        let line = match read_line(reader)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        if line.is_empty() {
+            break;
+        }
+        if request.headers.len() >= MAX_HEADERS {
+            return Err(http_error(431, "too many headers"));
+        }
+        match line.find(':') {
+            Some(i) => {
+                let name = line[..i].trim().to_lowercase();
+                let value = line[i + 1..].trim().to_string();
+                request.headers.push((name, value));
+            }
+            None => return Err(http_error(400, "invalid header")),
+        }
+    }
+
+    if header(&request, "transfer-encoding").is_some() {
+        return Err(http_error(411, "Content-Length is required"));
+    }
+    let size = match header(&request, "content-length").map(|x| x.parse::<usize>()) {
+        Some(Ok(x)) => x,
+        Some(Err(_)) => return Err(http_error(400, "invalid Content-Length")),
+        None => 0,
+    };
+    if limits::exceeds(0, size as u64, limits) {
+        return Err(http_error(413, "request body too large"));
+    }
+    if size > 0 && header(&request, "expect") == Some("100-continue") {
+        let _ = writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+    // allocated as it comes in, rather than as the client claims
+    let body = &mut request.body;
+    if reader.take(size as u64).read_to_end(body).is_err() || body.len() != size {
+        return Ok(None);
+    }
+    Ok(Some(request))
+}
+
+fn write_response<W: Write>(response: &Response, writer: &mut W) -> bool {
+    let mut buffer = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: {}\r\n",
+        response.status,
+        reason(response.status),
+        response.body.len()
+    );
+    if !response.content_type.is_empty() {
+        buffer.push_str(&format!("Content-Type: {}\r\n", response.content_type));
+    }
+    for (name, value) in response.headers.iter() {
+        buffer.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    buffer.push_str("\r\n");
+    let mut buffer = buffer.into_bytes();
+    buffer.extend(&response.body);
+    writer.write_all(&buffer).is_ok()
+}
+
+/// Response of an access error of the key
+fn access_error(e: access::Error, key: &str, offset: i32) -> Response {
+    let (status, message, location) = match e {
+        access::Error::ReadOnly => (0x04, "read-only replica".to_string(), None),
+        access::Error::NotLeader(Some(leader)) => {
+            let message = format!("not leader, leader is {}", leader);
+            (0x05, message, Some((leader, "")))
+        }
+        access::Error::NotLeader(None) => (0x05, "not leader, leader unknown".to_string(), None),
+        access::Error::Moved(slot, addr) => {
+            let message = format!("slot {} is moved to {}", slot, addr);
+            (0x06, message, Some((addr, "")))
+        }
+        access::Error::Ask(slot, addr) => {
+            let message = format!("slot {} is migrating to {}", slot, addr);
+            (0x07, message, Some((addr, "?asking=1")))
+        }
+        access::Error::Failed => (0x01, "write failed".to_string(), None),
+    };
+    let mut response = error(status, &message);
+    if let Some((addr, query)) = location {
+        let addr = access::shift_port(&addr, offset);
+        let url = format!("http://{}/keys/{}{}", addr, percent_encode(key), query);
+        response.headers.push(("Location", url));
+    }
+    response
+}
+
+fn get_key(
+    key: &str,
+    request: &Request,
+    asking: bool,
+    ctx: &access::Context,
+) -> Result<Response, access::Error> {
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&[key.to_string()], asking, &db, ctx)?;
+    let value = match store::get(key, &db) {
+        Some(x) => x,
+        None => return Ok(error(0x02, "no such key")),
+    };
+    let accept = header(request, "accept").unwrap_or("");
+    if accept.contains("application/json") {
+        let body = format!(
+            "{{\"key\": {}, \"value\": {}}}",
+            json_string(key),
+            json_string(&value)
+        );
+        return Ok(json(200, body));
+    }
+    Ok(Response {
+        status: 200,
+        content_type: "application/octet-stream",
+        headers: vec![],
+        body: value.into_bytes(),
+    })
+}
+
+fn put_key(
+    key: &str,
+    request: Request,
+    asking: bool,
+    ctx: &access::Context,
+) -> Result<Response, access::Error> {
+    let value = match String::from_utf8(request.body) {
+        Ok(x) => x,
+        Err(_) => return Ok(http_error(415, "only UTF-8 values are supported")),
+    };
+    access::check_writable(ctx)?;
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&[key.to_string()], asking, &db, ctx)?;
+    access::write(vec![raft::Command::Put(key.to_string(), value)], db, ctx)?;
+    Ok(no_content())
+}
+
+fn delete_key(key: &str, asking: bool, ctx: &access::Context) -> Result<Response, access::Error> {
+    access::check_writable(ctx)?;
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&[key.to_string()], asking, &db, ctx)?;
+    let result = access::write(vec![raft::Command::Delete(key.to_string())], db, ctx)?;
+    if result.first() == Some(&true) {
+        Ok(no_content())
+    } else {
+        Ok(error(0x02, "no such key"))
+    }
+}
+
+fn list_keys(prefix: &str, acl: &auth::Acl, ctx: &access::Context) -> Response {
+    let db = ctx.arc_db.lock().unwrap();
+    let mut keys: Vec<&String> = db
+        .items
+        .keys()
+        .filter(|x| x.starts_with(prefix) && auth::allows_key(x, acl))
+        .collect();
+    keys.sort();
+    let keys: Vec<String> = keys.into_iter().map(|x| json_string(x)).collect();
+    json(200, format!("{{\"keys\": [{}]}}", keys.join(", ")))
+}
+
+/// Decode base64, as in `Authorization: Basic`
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let (mut bits, mut count) = (0_u32, 0);
+    for c in text.trim_end_matches('=').bytes() {
+        let x = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | x as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            result.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    Some(result)
+}
+
+/// What the user of the credentials in the request may do, none if
+/// they are missing or wrong when users are configured
+fn authenticate(request: &Request, ctx: &access::Context) -> Option<auth::Acl> {
+    let auth = ctx.arc_auth.lock().unwrap();
+    if !auth::is_required(&auth) {
+        return Some(auth::acl_of(&None, &auth));
+    }
+    let credentials = header(request, "authorization")
+        .and_then(|x| x.strip_prefix("Basic "))
+        .and_then(|x| base64_decode(x.trim()))
+        .and_then(|x| String::from_utf8(x).ok());
+    let credentials = credentials?;
+    let i = credentials.find(':')?;
+    let user = auth::check(&credentials[..i], &credentials[i + 1..], &auth)?;
+    Some(auth::acl_of(&Some(user), &auth))
+}
+
+fn handle_request(request: Request, offset: i32, ctx: &access::Context) -> Response {
+    let acl = match authenticate(&request, ctx) {
+        Some(x) => x,
+        None => {
+            let mut response = error(0x08, "authentication required");
+            let value = "Basic realm=\"h2okv\"".to_string();
+            response.headers.push(("WWW-Authenticate", value));
+            return response;
+        }
+    };
+    if request.path == "/keys" {
+        if request.method != "GET" {
+            let mut response = http_error(405, "method not allowed");
+            response.headers.push(("Allow", "GET".to_string()));
+            return response;
+        }
+        if !auth::allows(auth::Category::Read, &acl) {
+            return error(0x09, "permission denied");
+        }
+        let prefix = query_param(&request.query, "prefix").unwrap_or_default();
+        return list_keys(&prefix, &acl, ctx);
+    }
+
+    let key = match request.path.strip_prefix("/keys/").map(percent_decode) {
+        Some(Some(x)) if !x.is_empty() => x,
+        Some(_) => return http_error(400, "invalid key"),
+        None => return error(0xFF, "not found"),
+    };
+    if limits::exceeds(key.len(), request.body.len() as u64, limits::get()) {
+        return http_error(413, "key too large");
+    }
+    let category = match request.method.as_str() {
+        "GET" => Some(auth::Category::Read),
+        "PUT" | "DELETE" => Some(auth::Category::Write),
+        _ => None,
+    };
+    if let Some(x) = category {
+        if !auth::allows(x, &acl) || !auth::allows_key(&key, &acl) {
+            return error(0x09, "permission denied");
+        }
+    }
+    let asking = query_param(&request.query, "asking").as_deref() == Some("1");
+    let result = match request.method.as_str() {
+        "GET" => get_key(&key, &request, asking, ctx),
+        "PUT" => put_key(&key, request, asking, ctx),
+        "DELETE" => delete_key(&key, asking, ctx),
+        _ => {
+            let mut response = http_error(405, "method not allowed");
+            response
+                .headers
+                .push(("Allow", "GET, PUT, DELETE".to_string()));
+            return response;
+        }
+    };
+    match result {
+        Ok(x) => x,
+        Err(e) => access_error(e, &key, offset),
+    }
+}
+
+fn handle_client(stream: TcpStream, offset: i32, ctx: access::Context) {
+    let peer = match stream.peer_addr() {
+        Ok(x) => x.to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+    logging::new_connection(&peer);
+    let mut writer = match stream.try_clone() {
+        Ok(x) => x,
+        Err(e) => {
+            logging::warning!("cannot clone client stream: {:?}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        let request = match read_request(&mut reader, &mut writer, limits::get()) {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(response) => {
+                write_response(&response, &mut writer);
+                break;
+            }
+        };
+        let close =
+            header(&request, "connection").map(|x| x.to_lowercase()) == Some("close".to_string());
+        let response = handle_request(request, offset, &ctx);
+        if !write_response(&response, &mut writer) || close {
+            break;
+        }
+    }
+}
+
+/// Listen on `addr` for HTTP clients, in a thread, `offset` is
+/// `--http-port` minus `--port`.
+pub fn start(addr: &str, offset: i32, ctx: access::Context) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(x) => x,
+        Err(e) => {
+            logging::error!("cannot listen on {} for HTTP: {:?}", addr, e);
+            return;
+        }
+    };
+    logging::info!("HTTP listener started at {}", addr);
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    let ctx = ctx.clone();
+                    thread::spawn(move || handle_client(stream, offset, ctx));
+                }
+                Err(e) => {
+                    logging::warning!("HTTP accept failed: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        base64_decode, json_string, percent_decode, percent_encode, query_param, read_request,
+    };
+    use crate::limits::Limits;
+
+    #[test]
+    fn test_percent() {
+        assert_eq!(percent_decode("a%20b+c%2Fd"), Some("a b c/d".to_string()));
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%e4%bd%a0"), Some("你".to_string()));
+        assert_eq!(percent_encode("a b/你"), "a%20b%2F%E4%BD%A0");
+        assert_eq!(
+            query_param("x=1&prefix=us%3A", "prefix"),
+            Some("us:".to_string())
+        );
+        assert_eq!(query_param("x=1", "prefix"), None);
+    }
+
+    #[test]
+    fn test_base64_decode() {
+        assert_eq!(
+            base64_decode("YWxpY2U6c2VjcmV0"),
+            Some(b"alice:secret".to_vec())
+        );
+        assert_eq!(base64_decode("OnB3"), Some(b":pw".to_vec()));
+        assert_eq!(base64_decode("YQ=="), Some(b"a".to_vec()));
+        assert_eq!(base64_decode("YQ!="), None);
+    }
+
+    #[test]
+    fn test_json_string() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}"), "\"a\\\"b\\\\c\\n\\u0001\"");
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut input: &[u8] =
+            b"PUT /keys/foo?x=1 HTTP/1.1\r\nHost: a\r\nContent-Length: 3\r\n\r\nbarGET / HTTP/1.0\r\n\r\n";
+        let mut output = vec![];
+        let limits = Limits::new();
+        let request = read_request(&mut input, &mut output, &limits)
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/keys/foo");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.headers[0], ("host".to_string(), "a".to_string()));
+        assert_eq!(request.body, b"bar");
+        let request = read_request(&mut input, &mut output, &limits)
+            .ok()
+            .unwrap()
+            .unwrap();
+        assert_eq!(request.headers[0].1, "close");
+        assert!(read_request(&mut input, &mut output, &limits)
+            .ok()
+            .unwrap()
+            .is_none());
+
+        let mut input: &[u8] = b"GET /\r\n\r\n";
+        assert!(read_request(&mut input, &mut output, &limits).is_err());
+
+        // bodies over the limits, or cut short
+        let limits = Limits {
+            max_key_size: 10,
+            max_value_size: 100,
+            max_request_size: 105,
+        };
+        let mut input: &[u8] = b"PUT /keys/foo HTTP/1.1\r\nContent-Length: 101\r\n\r\n";
+        let response = read_request(&mut input, &mut output, &limits)
+            .err()
+            .unwrap();
+        assert_eq!(response.status, 413);
+        let mut input: &[u8] = b"PUT /keys/foo HTTP/1.1\r\nContent-Length: 100\r\n\r\nbar";
+        assert!(read_request(&mut input, &mut output, &limits)
+            .ok()
+            .unwrap()
+            .is_none());
+    }
+}
@@ -0,0 +1,188 @@
+// Keys of 64 KiB or more, and values sent in chunks.
+//
+// The Length of queries, and the KLen of PUT-like queries, are 2 bytes:
+// `\xFF\xFF` is followed by the actual length as 4 bytes, LittleEndian,
+// so that keys can be up to 4 GiB. The same goes for the key lengths of
+// SCAN replies, key events and changes. The long form is only for
+// connections with the `bigkeys` feature of HELLO, both ways: for other
+// clients `\xFF\xFF` is a length of 65535 as before, and keys of more
+// are left out of their replies.
+//
+// A VLLen of `\xFF`, in PUT-like queries or GET replies, is followed by
+// the value in chunks, `<len:4><chunk>`, until an empty chunk, so that
+// clients can send values without knowing their length first. The server
+// allocates such values as they come, rather than the length the client
+// claims. GET replies of values larger than `CHUNK_SIZE` are chunked for
+// clients with the `chunked` feature of HELLO. Flags apply to the whole
+// value, e.g. it's compressed before being chunked.
+//
+// Values are stored in pieces of up to `CHUNK_SIZE`, see `store::Value`:
+// PUT reads values into pieces as they come, chunked or not, whatever
+// the size of the chunks sent, and GET replies values larger than
+// `CHUNK_SIZE` from their pieces, without Flag, so that the server never
+// has them in one buffer. Values with Flags, of the other protocols, in
+// MGET replies, Raft commands, and events pushed to watchers and
+// followers are still made whole. Values are bounded by
+// `--max-value-size`, see `src/limits.rs`.
+//
+// Servers have the features `bigkeys` and `chunked` in HELLO, clients
+// should not use them with servers without them.
+
+use std::io::{self, Read, Write};
+
+use crate::tools;
+
+/// Length telling the actual length follows as 4 bytes
+pub const LONG_LEN: u16 = 0xFFFF;
+
+/// VLLen telling the value follows in chunks
+pub const CHUNKED: u8 = 0xFF;
+
+/// Size of the pieces values are stored in, and of the chunks of GET
+/// replies
+pub const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// `<len:2>`, or `\xFF\xFF<len:4>` from `LONG_LEN` up
+pub fn len_bytes(len: usize) -> Vec<u8> {
+    if len < LONG_LEN as usize {
+        return tools::u16_to_bytes(len as u16).to_vec();
+    }
+    let mut bytes = tools::u16_to_bytes(LONG_LEN).to_vec();
+    bytes.extend(&tools::u32_to_bytes(len as u32));
+    bytes
+}
+
+/// The length of its first 2 bytes, reading the actual length after
+/// `\xFF\xFF` with `bigkeys`
+pub fn read_len<R: Read>(buf_len: &[u8], reader: &mut R, bigkeys: bool) -> io::Result<usize> {
+    let len = tools::bytes_to_u16(buf_len);
+    if len != LONG_LEN || !bigkeys {
+        return Ok(len as usize);
+    }
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(tools::bytes_to_u64(&buffer) as usize)
+}
+
+/// Read a chunked value, until the empty chunk, into pieces of up to
+/// `CHUNK_SIZE`, whatever the size of the chunks sent. Values larger
+/// than `max` are an `InvalidData` error, before reading the chunk over
+/// it.
+pub fn read_chunks<R: Read>(reader: &mut R, max: u64) -> io::Result<Vec<Vec<u8>>> {
+    let mut pieces = vec![];
+    let mut size = 0;
+    loop {
+        let mut buf_len = [0; 4];
+        reader.read_exact(&mut buf_len)?;
+        let len = tools::bytes_to_u64(&buf_len);
+        if len == 0 {
+            return Ok(pieces);
+        }
+        if size + len > max {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "value too large",
+            ));
+        }
+        read_into(reader, len, &mut pieces)?;
+        size += len;
+    }
+}
+
+/// Read a value of `size` bytes into pieces of up to `CHUNK_SIZE`
+pub fn read_pieces<R: Read>(reader: &mut R, size: u64) -> io::Result<Vec<Vec<u8>>> {
+    let mut pieces = vec![];
+    read_into(reader, size, &mut pieces)?;
+    Ok(pieces)
+}
+
+/// Read `len` bytes, filling the last piece up to `CHUNK_SIZE` before
+/// starting another one
+fn read_into<R: Read>(reader: &mut R, len: u64, pieces: &mut Vec<Vec<u8>>) -> io::Result<()> {
+    let mut left = len;
+    while left > 0 {
+        if pieces.last().is_none_or(|x| x.len() >= CHUNK_SIZE) {
+            pieces.push(vec![]);
+        }
+        let piece = pieces.last_mut().unwrap();
+        let count = ((CHUNK_SIZE - piece.len()) as u64).min(left);
+        // grows as the bytes come, whatever the length claims
+        if reader.by_ref().take(count).read_to_end(piece)? as u64 != count {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        left -= count;
+    }
+    Ok(())
+}
+
+/// Write the chunks, skipping empty ones, and the empty chunk
+pub fn write_chunks<'a, W, I>(chunks: I, writer: &mut W) -> io::Result<()>
+where
+    W: Write,
+    I: Iterator<Item = &'a [u8]>,
+{
+    for chunk in chunks.filter(|x| !x.is_empty()) {
+        writer.write_all(&tools::u32_to_bytes(chunk.len() as u32))?;
+        writer.write_all(chunk)?;
+    }
+    writer.write_all(&[0; 4])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{len_bytes, read_chunks, read_len, read_pieces, write_chunks, CHUNK_SIZE};
+    use std::io::ErrorKind;
+
+    #[test]
+    fn test_len() {
+        assert_eq!(len_bytes(3), vec![3, 0]);
+        assert_eq!(len_bytes(0xFFFE), vec![0xFE, 0xFF]);
+        assert_eq!(len_bytes(0xFFFF), vec![0xFF, 0xFF, 0xFF, 0xFF, 0, 0]);
+        assert_eq!(len_bytes(0x10000), vec![0xFF, 0xFF, 0, 0, 1, 0]);
+
+        let mut reader: &[u8] = b"\x00\x00\x01\x00rest";
+        assert_eq!(read_len(&[3, 0], &mut reader, true).unwrap(), 3);
+        assert_eq!(read_len(&[0xFF, 0xFF], &mut reader, false).unwrap(), 0xFFFF);
+        assert_eq!(read_len(&[0xFF, 0xFF], &mut reader, true).unwrap(), 0x10000);
+        assert_eq!(reader, b"rest");
+        assert!(read_len(&[0xFF, 0xFF], &mut &b"\x00"[..], true).is_err());
+    }
+
+    #[test]
+    fn test_chunks() {
+        let value = vec![7; CHUNK_SIZE * 2 + 3];
+        let mut buffer = vec![];
+        write_chunks(value.chunks(CHUNK_SIZE), &mut buffer).unwrap();
+        assert_eq!(buffer.len(), value.len() + 4 * 4);
+        assert_eq!(&buffer[buffer.len() - 7..], b"\x07\x07\x07\x00\x00\x00\x00");
+        let pieces = read_chunks(&mut &buffer[..], u64::MAX).unwrap();
+        assert_eq!(pieces.len(), 3);
+        assert_eq!(pieces.concat(), value);
+        let max = value.len() as u64;
+        assert_eq!(read_chunks(&mut &buffer[..], max).unwrap().concat(), value);
+        let e = read_chunks(&mut &buffer[..], max - 1).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::InvalidData);
+
+        // small chunks are put together into pieces
+        let mut buffer = vec![];
+        write_chunks(value.chunks(1000), &mut buffer).unwrap();
+        let pieces = read_chunks(&mut &buffer[..], u64::MAX).unwrap();
+        assert_eq!(
+            pieces.iter().map(|x| x.len()).collect::<Vec<_>>(),
+            [CHUNK_SIZE, CHUNK_SIZE, 3]
+        );
+        let pieces = read_pieces(&mut &value[..], value.len() as u64).unwrap();
+        assert_eq!(pieces.len(), 3);
+        assert!(read_pieces(&mut &value[..], value.len() as u64 + 1).is_err());
+
+        let mut buffer = vec![];
+        write_chunks([&b""[..]].iter().copied(), &mut buffer).unwrap();
+        assert_eq!(buffer, vec![0; 4]);
+        assert!(read_chunks(&mut &buffer[..], 0).unwrap().is_empty());
+
+        // chunks longer than what is sent, or no empty chunk
+        let e = read_chunks(&mut &b"\xff\xff\xff\xff\x01"[..], u64::MAX).unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::UnexpectedEof);
+        assert!(read_chunks(&mut &b"\x01\x00\x00\x00\x01"[..], u64::MAX).is_err());
+    }
+}
@@ -0,0 +1,114 @@
+// Limits of the sizes of queries, so that a client cannot take all the
+// memory of the server with the lengths it claims, e.g. the 8-byte VLen
+// of PUT. Lengths are checked before anything is allocated for them, and
+// queries over a limit are replied with Too large (`\x0B`). The rest of
+// such a query is not read, so the connection is closed after the reply.
+//
+// - `--max-key-size`: keys of GET, PUT, DEL, and the KEY part of other
+//   PUT-like queries, 1 MiB by default
+// - `--max-value-size`: values of PUT-like queries, also after GZIP
+//   decompression, 512 MiB by default
+// - `--max-request-size`: whole queries, 513 MiB by default
+//
+// RAFT messages between nodes of a cluster are bounded by the key and
+// value limits instead, as a node sends the DB in chunks, and entries in
+// batches of a few of them, see `src/raft.rs`.
+//
+// The other listeners apply them as well, see `src/resp.rs`,
+// `src/memcache.rs` and `src/http.rs`, and so does the proxy, see
+// `src/proxy/proxy.rs`.
+//
+// Sizes are in bytes, or with a suffix `K`, `M` or `G` for KiB, MiB and
+// GiB, e.g. `--max-value-size 64M`.
+
+use std::sync::OnceLock;
+
+/// Reply of queries over a limit
+pub const TOO_LARGE: &[u8] = b"\x0c\x0b";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    pub max_request_size: usize,
+}
+
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            max_key_size: 1024 * 1024,
+            max_value_size: 512 * 1024 * 1024,
+            max_request_size: 513 * 1024 * 1024,
+        }
+    }
+}
+
+static LIMITS: OnceLock<Limits> = OnceLock::new();
+
+/// Set the limits from the options, before serving any client
+pub fn set(limits: Limits) {
+    let _ = LIMITS.set(limits);
+}
+
+pub fn get() -> &'static Limits {
+    LIMITS.get_or_init(Limits::new)
+}
+
+/// Parse a size in bytes, with an optional suffix `K`, `M` or `G`
+pub fn parse_size(text: &str) -> Result<usize, String> {
+    let (digits, unit) = match text.chars().last() {
+        Some('K') | Some('k') => (&text[..text.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&text[..text.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&text[..text.len() - 1], 1 << 30),
+        _ => (text, 1),
+    };
+    match digits
+        .parse::<usize>()
+        .ok()
+        .and_then(|x| x.checked_mul(unit))
+    {
+        Some(x) if x > 0 => Ok(x),
+        _ => Err(format!("invalid size: {}", text)),
+    }
+}
+
+/// If a PUT-like query with the key and value lengths is over a limit
+pub fn exceeds(klen: usize, vlen: u64, limits: &Limits) -> bool {
+    klen > limits.max_key_size
+        || vlen > limits.max_value_size as u64
+        || klen as u64 + vlen > limits.max_request_size as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{exceeds, parse_size, Limits};
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4K"), Ok(4096));
+        assert_eq!(parse_size("64m"), Ok(64 << 20));
+        assert_eq!(parse_size("2G"), Ok(2 << 30));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("1T").is_err());
+        assert!(parse_size("99999999999999999999G").is_err());
+    }
+
+    #[test]
+    fn test_exceeds() {
+        let limits = Limits {
+            max_key_size: 10,
+            max_value_size: 100,
+            max_request_size: 105,
+        };
+        assert!(!exceeds(10, 0, &limits));
+        assert!(!exceeds(5, 100, &limits));
+        assert!(exceeds(11, 0, &limits));
+        assert!(exceeds(0, 101, &limits));
+        assert!(exceeds(10, 100, &limits));
+        assert!(exceeds(0, u64::MAX, &limits));
+    }
+}
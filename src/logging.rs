@@ -0,0 +1,434 @@
+// Logging of the server, one line per event, with a timestamp in UTC, a
+// level, and the connection it's about, if any:
+//
+//     2026-10-19T08:00:00.123Z INFO [#3 127.0.0.1:51234] client accepted
+//
+// or, with `--log-format json`, one JSON object per line:
+//
+//     {"time":"2026-10-19T08:00:00.123Z","level":"info","conn":3,
+//      "peer":"127.0.0.1:51234","msg":"client accepted"}
+//
+// - `--log-level error|warn|info|debug`: lines below the level are
+//   dropped, `info` by default. `debug` also logs every query.
+// - `--log-file PATH`: log into the file instead of stdout. When it gets
+//   over `--log-max-size` (64 MiB by default), it's renamed `PATH.1`, the
+//   older `PATH.1` to `PATH.2` and so on, keeping `--log-files` (5 by
+//   default) old files.
+//
+// The level and the format can be changed at runtime with LOG (`\x1A`),
+// e.g. `level debug` or `format json`, and an empty LOG replies the
+// current settings.
+//
+// Threads serving a connection call `new_connection()`, so that their
+// lines have its ID and peer address.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+macro_rules! error {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Error, format_args!($($arg)*))
+    };
+}
+
+macro_rules! warning {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Warn, format_args!($($arg)*))
+    };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Info, format_args!($($arg)*))
+    };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        $crate::logging::log($crate::logging::Level::Debug, format_args!($($arg)*))
+    };
+}
+
+pub(crate) use {debug, error, info, warning};
+
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Level {
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Options {
+    pub level: Level,
+    pub format: Format,
+    /// log into the file instead of stdout
+    pub file: Option<String>,
+    /// size of the file before rotating it
+    pub max_size: usize,
+    /// how many rotated files are kept
+    pub files: usize,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            level: Level::Info,
+            format: Format::Text,
+            file: None,
+            max_size: 64 * 1024 * 1024,
+            files: 5,
+        }
+    }
+}
+
+struct LogFile {
+    path: String,
+    file: File,
+    size: u64,
+    max_size: u64,
+    files: usize,
+}
+
+struct Logger {
+    format: Format,
+    /// stdout if none
+    file: Option<LogFile>,
+}
+
+/// checked before formatting anything, without taking the logger
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+static LOGGER: Mutex<Logger> = Mutex::new(Logger {
+    format: Format::Text,
+    file: None,
+});
+
+static NEXT_CONNECTION_ID: AtomicUsize = AtomicUsize::new(1);
+
+thread_local! {
+    /// ID and peer address of the connection served by this thread
+    static CONNECTION: RefCell<Option<(usize, String)>> = const { RefCell::new(None) };
+}
+
+impl Level {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+impl Format {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Text => "text",
+            Format::Json => "json",
+        }
+    }
+}
+
+pub fn parse_level(name: &str) -> Result<Level, String> {
+    match name {
+        "error" => Ok(Level::Error),
+        "warn" => Ok(Level::Warn),
+        "info" => Ok(Level::Info),
+        "debug" => Ok(Level::Debug),
+        _ => Err(format!("unknown log level: {}", name)),
+    }
+}
+
+pub fn parse_format(name: &str) -> Result<Format, String> {
+    match name {
+        "text" => Ok(Format::Text),
+        "json" => Ok(Format::Json),
+        _ => Err(format!("unknown log format: {}", name)),
+    }
+}
+
+/// Set up logging from the options, before logging anything
+pub fn init(options: &Options) -> Result<(), String> {
+    set_level(options.level);
+    let mut logger = LOGGER.lock().unwrap();
+    logger.format = options.format;
+    if let Some(path) = &options.file {
+        let file = open(path).map_err(|e| format!("cannot open log file {}: {}", path, e))?;
+        let size = file.metadata().map(|x| x.len()).unwrap_or(0);
+        logger.file = Some(LogFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_size: options.max_size as u64,
+            files: options.files,
+        });
+    }
+    Ok(())
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn set_format(format: Format) {
+    LOGGER.lock().unwrap().format = format;
+}
+
+/// Current settings, e.g. `level info format text file h2okv.log`
+pub fn settings() -> String {
+    let logger = LOGGER.lock().unwrap();
+    let level = match LEVEL.load(Ordering::Relaxed) {
+        1 => Level::Error,
+        2 => Level::Warn,
+        3 => Level::Info,
+        _ => Level::Debug,
+    };
+    let mut text = format!("level {} format {}", level.name(), logger.format.name());
+    if let Some(x) = &logger.file {
+        text.push_str(&format!(" file {}", x.path));
+    }
+    text
+}
+
+pub fn enabled(level: Level) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// A new connection served by this thread, returns its ID
+pub fn new_connection(peer: &str) -> usize {
+    let id = NEXT_CONNECTION_ID.fetch_add(1, Ordering::SeqCst);
+    set_connection(Some((id, peer.to_string())));
+    id
+}
+
+/// The connection served by this thread, to be passed to the threads
+/// working for it
+pub fn connection() -> Option<(usize, String)> {
+    CONNECTION.with(|x| x.borrow().clone())
+}
+
+pub fn set_connection(connection: Option<(usize, String)>) {
+    CONNECTION.with(|x| *x.borrow_mut() = connection);
+}
+
+/// Log the message, use the macros `error!`, `warning!`, `info!` and
+/// `debug!` instead
+pub fn log(level: Level, args: fmt::Arguments) {
+    if !enabled(level) {
+        return;
+    }
+    let time = timestamp(SystemTime::now());
+    let mut logger = LOGGER.lock().unwrap();
+    let line = format_line(
+        level,
+        &time,
+        &connection(),
+        &args.to_string(),
+        logger.format,
+    );
+    let result = match &mut logger.file {
+        Some(x) => write_file(&line, x),
+        None => io::stdout().write_all(line.as_bytes()),
+    };
+    if let Err(e) = result {
+        // nowhere else to log it
+        print!("cannot write log: {:?}: {}", e, line);
+    }
+}
+
+fn format_line(
+    level: Level,
+    time: &str,
+    connection: &Option<(usize, String)>,
+    msg: &str,
+    format: Format,
+) -> String {
+    match (format, connection) {
+        (Format::Text, Some((id, peer))) => {
+            let name = level.name().to_uppercase();
+            format!("{} {} [#{} {}] {}\n", time, name, id, peer, msg)
+        }
+        (Format::Text, None) => format!("{} {} {}\n", time, level.name().to_uppercase(), msg),
+        (Format::Json, _) => {
+            let mut line = format!("{{\"time\":\"{}\",\"level\":\"{}\"", time, level.name());
+            if let Some((id, peer)) = connection {
+                line.push_str(&format!(",\"conn\":{},\"peer\":{}", id, json_string(peer)));
+            }
+            line.push_str(&format!(",\"msg\":{}}}\n", json_string(msg)));
+            line
+        }
+    }
+}
+
+fn json_string(text: &str) -> String {
+    let mut result = String::from("\"");
+    for c in text.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+/// `2026-10-19T08:00:00.123Z`
+fn timestamp(time: SystemTime) -> String {
+    let elapsed = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = elapsed.as_secs();
+    // days to year, month and day, from Howard Hinnant's `civil_from_days`
+    let z = (secs / 86400) as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        elapsed.subsec_millis()
+    )
+}
+
+fn open(path: &str) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Write the line, after rotating the file if it would get too large
+fn write_file(line: &str, log: &mut LogFile) -> io::Result<()> {
+    if log.size > 0 && log.size + line.len() as u64 > log.max_size {
+        rotate(log)?;
+    }
+    log.file.write_all(line.as_bytes())?;
+    log.size += line.len() as u64;
+    Ok(())
+}
+
+/// `PATH` to `PATH.1`, `PATH.1` to `PATH.2` and so on, dropping the
+/// oldest file, then start a new `PATH`
+fn rotate(log: &mut LogFile) -> io::Result<()> {
+    for i in (1..log.files).rev() {
+        let from = format!("{}.{}", log.path, i);
+        if fs::metadata(&from).is_ok() {
+            fs::rename(&from, format!("{}.{}", log.path, i + 1))?;
+        }
+    }
+    if log.files > 0 {
+        fs::rename(&log.path, format!("{}.1", log.path))?;
+    } else {
+        fs::remove_file(&log.path)?;
+    }
+    log.file = open(&log.path)?;
+    log.size = 0;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_line, open, parse_level, timestamp, write_file, Format, Level, LogFile};
+    use std::env;
+    use std::fs;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        let time = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        assert_eq!(timestamp(time), "2023-11-14T22:13:20.500Z");
+        let time = UNIX_EPOCH + Duration::from_secs(951_782_400);
+        assert_eq!(timestamp(time), "2000-02-29T00:00:00.000Z");
+    }
+
+    #[test]
+    fn test_format_line() {
+        let time = "2026-10-19T08:00:00.123Z";
+        let connection = Some((3, "127.0.0.1:51234".to_string()));
+        assert_eq!(
+            format_line(
+                Level::Info,
+                time,
+                &connection,
+                "client accepted",
+                Format::Text
+            ),
+            "2026-10-19T08:00:00.123Z INFO [#3 127.0.0.1:51234] client accepted\n"
+        );
+        assert_eq!(
+            format_line(Level::Warn, time, &None, "no db file", Format::Text),
+            "2026-10-19T08:00:00.123Z WARN no db file\n"
+        );
+        assert_eq!(
+            format_line(
+                Level::Error,
+                time,
+                &connection,
+                "bad \"key\"\n",
+                Format::Json
+            ),
+            "{\"time\":\"2026-10-19T08:00:00.123Z\",\"level\":\"error\",\"conn\":3,\
+             \"peer\":\"127.0.0.1:51234\",\"msg\":\"bad \\\"key\\\"\\n\"}\n"
+        );
+        assert_eq!(
+            format_line(Level::Debug, time, &None, "\x01", Format::Json),
+            "{\"time\":\"2026-10-19T08:00:00.123Z\",\"level\":\"debug\",\"msg\":\"\\u0001\"}\n"
+        );
+        assert!(Level::Error < Level::Debug);
+        assert_eq!(parse_level("warn"), Ok(Level::Warn));
+        assert!(parse_level("trace").is_err());
+    }
+
+    #[test]
+    fn test_rotate() {
+        let mut dir = env::temp_dir();
+        dir.push(format!("h2okv-test-log-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/h2okv.log", dir.to_str().unwrap());
+        let mut log = LogFile {
+            path: path.clone(),
+            file: open(&path).unwrap(),
+            size: 0,
+            max_size: 10,
+            files: 2,
+        };
+        for line in &["one\n", "two\n", "three\n", "four\n", "five\n", "six\n"] {
+            write_file(line, &mut log).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "six\n");
+        let rotated = fs::read_to_string(format!("{}.1", path)).unwrap();
+        assert_eq!(rotated, "four\nfive\n");
+        assert_eq!(
+            fs::read_to_string(format!("{}.2", path)).unwrap(),
+            "three\n"
+        );
+        assert!(fs::metadata(format!("{}.3", path)).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,22 +1,77 @@
 extern crate byteorder;
 
+use std::env;
+use std::process;
 use std::sync::{Arc, Mutex};
 
+mod access;
+mod auth;
+mod blocking;
+mod changes;
+mod cipher;
+mod compress;
+mod config;
+mod encryption;
+mod error;
+mod hello;
+mod http;
+mod large;
+mod limits;
+mod logging;
+mod memcache;
+mod migration;
 mod persistence;
+mod pubsub;
+mod push;
+mod raft;
+mod replication;
+mod resp;
 mod server;
+mod slots;
 mod store;
+mod stream;
+mod tls;
 mod tools;
+mod watch;
 
 fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", config::USAGE);
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = logging::init(&config.log) {
+        println!("{}", e);
+        process::exit(1);
+    }
+    persistence::set_compression(config.snapshot_compression);
+    limits::set(config.limits);
+    if let Err(e) = encryption::init(&config.data_key_file) {
+        logging::error!("{}", e);
+        process::exit(1);
+    }
+
     let arc_db = Arc::new(Mutex::new(store::DB::new()));
-    load_from_file_arc(arc_db.clone());
-    server::run(arc_db.clone());
+    if config.raft_id.is_none() {
+        // in cluster mode, the DB is restored from the Raft log instead
+        if let Err(e) = load_from_file_arc(arc_db.clone()) {
+            logging::error!("cannot load the data file: {}", e);
+            process::exit(1);
+        }
+    }
+    server::run(arc_db.clone(), &config);
 }
 
-fn load_from_file_arc(arc_db: Arc<Mutex<store::DB>>) {
+fn load_from_file_arc(arc_db: Arc<Mutex<store::DB>>) -> Result<(), error::Error> {
     let clone_arc = arc_db.clone();
     let mut db = clone_arc.lock().unwrap();
     if let Some(db_file) = tools::get_db_file() {
-        persistence::load_from_file(&db_file, &mut db);
+        persistence::load_from_file(&db_file, &mut db)?;
     }
+    Ok(())
 }
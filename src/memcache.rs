@@ -0,0 +1,628 @@
+// Listener speaking the memcached text protocol, so that h2okv can be
+// used by memcached clients, as a persistent memcached.
+//
+// Supported: get, gets, set, add, replace, append, prepend, cas, delete,
+// incr, decr, touch, version and quit, on the same keys as the h2okv
+// protocol (see `src/access.rs`).
+//
+// The DB only has the values, so the flags, expiration time and CAS
+// unique of each key are kept here, in the file `h2okv.memcache` under
+// current working directory, along with a digest of the value they were
+// set with. When the value has been changed since by other protocols,
+// the digest does not match, and the key gets flags 0, no expiration
+// and a new CAS unique. Expired keys are not served, and are deleted
+// from the DB every second.
+//
+// Values over `--max-value-size` are replied with `SERVER_ERROR object
+// too large for cache`, and dropped without being held, see
+// `src/limits.rs`.
+//
+// The protocol has no authentication, so the server does not start with
+// users, nor are users created by ACL while this listener runs.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::access;
+use crate::limits;
+use crate::logging;
+use crate::raft;
+use crate::store;
+use crate::tools;
+
+const MAX_KEY: usize = 250;
+/// Longest command line, enough for get of hundreds of keys
+const MAX_LINE: usize = 64 * 1024;
+/// Expiration times up to 30 days are relative to now, as in memcached
+const MAX_RELATIVE: i64 = 60 * 60 * 24 * 30;
+
+#[derive(Clone, Debug, PartialEq)]
+struct Item {
+    flags: u32,
+    /// Unix time in seconds, 0 for never
+    expires: u64,
+    cas: u64,
+    /// digest of the value, see `digest()`
+    digest: u64,
+}
+
+struct Items {
+    next_cas: u64,
+    items: HashMap<String, Item>,
+}
+
+impl Items {
+    fn new() -> Items {
+        Items {
+            next_cas: 1,
+            items: HashMap::new(),
+        }
+    }
+}
+
+/// FNV-1a
+fn digest(value: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for x in value.as_bytes() {
+        hash ^= *x as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(x) => x.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+/// Unix time of an expiration time given by clients
+fn expires_at(exptime: i64, now: u64) -> u64 {
+    if exptime == 0 {
+        0
+    } else if exptime < 0 {
+        // already expired
+        1
+    } else if exptime <= MAX_RELATIVE {
+        now + exptime as u64
+    } else {
+        exptime as u64
+    }
+}
+
+fn is_expired(item: &Item, now: u64) -> bool {
+    item.expires != 0 && item.expires <= now
+}
+
+/// Item of the key with the value, a new one when the value has been
+/// changed by other protocols
+fn item_of(key: &str, value: &str, items: &mut Items) -> Item {
+    let digest = digest(value);
+    if let Some(x) = items.items.get(key) {
+        if x.digest == digest {
+            return x.clone();
+        }
+    }
+    let item = Item {
+        flags: 0,
+        expires: 0,
+        cas: items.next_cas,
+        digest,
+    };
+    items.next_cas += 1;
+    items.items.insert(key.to_string(), item.clone());
+    item
+}
+
+/// Value and item of the key, if it's there and not expired
+fn lookup(key: &str, db: &store::DB, items: &mut Items) -> Option<(String, Item)> {
+    let value = store::get(key, db)?;
+    let item = item_of(key, &value, items);
+    if is_expired(&item, now()) {
+        return None;
+    }
+    Some((value, item))
+}
+
+fn set_item(key: &str, value: &str, flags: u32, expires: u64, items: &mut Items) {
+    let item = Item {
+        flags,
+        expires,
+        cas: items.next_cas,
+        digest: digest(value),
+    };
+    items.next_cas += 1;
+    items.items.insert(key.to_string(), item);
+}
+
+/// Save the items: the next CAS unique in the first line, then a line
+/// of `<key> <flags> <expires> <cas> <digest>` for each.
+fn save(items: &Items) {
+    let path = match tools::get_memcache_file() {
+        Some(x) => x,
+        None => return,
+    };
+    let mut text = format!("{}\n", items.next_cas);
+    for (key, x) in items.items.iter() {
+        let line = format!("{} {} {} {} {}\n", key, x.flags, x.expires, x.cas, x.digest);
+        text.push_str(&line);
+    }
+    if let Err(e) = fs::write(&path, text) {
+        logging::error!("cannot save memcache items: {:?}", e);
+    }
+}
+
+fn parse_items(text: &str, items: &mut Items) -> Option<()> {
+    let mut lines = text.lines();
+    items.next_cas = lines.next()?.parse().ok()?;
+    for line in lines {
+        let tokens: Vec<&str> = line.split(' ').collect();
+        if tokens.len() != 5 {
+            return None;
+        }
+        let item = Item {
+            flags: tokens[1].parse().ok()?,
+            expires: tokens[2].parse().ok()?,
+            cas: tokens[3].parse().ok()?,
+            digest: tokens[4].parse().ok()?,
+        };
+        items.items.insert(tokens[0].to_string(), item);
+    }
+    Some(())
+}
+
+/// Load the items saved, if any
+fn load(items: &mut Items) {
+    let text = match tools::get_memcache_file().map(fs::read_to_string) {
+        Some(Ok(x)) => x,
+        _ => return,
+    };
+    if parse_items(&text, items).is_none() {
+        logging::warning!("invalid memcache items file, ignored");
+        *items = Items::new();
+    }
+}
+
+fn error_reply(e: access::Error) -> String {
+    match e {
+        access::Error::ReadOnly => "SERVER_ERROR read only replica".to_string(),
+        access::Error::NotLeader(Some(leader)) => {
+            format!("SERVER_ERROR not leader, leader is {}", leader)
+        }
+        access::Error::NotLeader(None) => "SERVER_ERROR not leader".to_string(),
+        access::Error::Moved(_, addr) | access::Error::Ask(_, addr) => {
+            format!("SERVER_ERROR key is served by {}", addr)
+        }
+        access::Error::Failed => "SERVER_ERROR write failed".to_string(),
+    }
+}
+
+fn get(
+    keys: &[&str],
+    with_cas: bool,
+    ctx: &access::Context,
+    arc_items: &Arc<Mutex<Items>>,
+) -> String {
+    let db = ctx.arc_db.lock().unwrap();
+    let mut items = arc_items.lock().unwrap();
+    let mut reply = String::new();
+    for key in keys {
+        // keys of other servers are just missing, as in memcached
+        if access::check_keys(&[key.to_string()], false, &db, ctx).is_err() {
+            continue;
+        }
+        let (value, item) = match lookup(key, &db, &mut items) {
+            Some(x) => x,
+            None => continue,
+        };
+        if with_cas {
+            let line = format!(
+                "VALUE {} {} {} {}\r\n",
+                key,
+                item.flags,
+                value.len(),
+                item.cas
+            );
+            reply.push_str(&line);
+        } else {
+            reply.push_str(&format!("VALUE {} {} {}\r\n", key, item.flags, value.len()));
+        }
+        reply.push_str(&value);
+        reply.push_str("\r\n");
+    }
+    reply.push_str("END");
+    reply
+}
+
+/// A storage command: set, add, replace, append, prepend or cas
+struct Storage<'a> {
+    name: &'a str,
+    key: &'a str,
+    flags: u32,
+    exptime: i64,
+    cas: u64,
+}
+
+fn store_value(
+    cmd: &Storage,
+    data: String,
+    ctx: &access::Context,
+    arc_items: &Arc<Mutex<Items>>,
+) -> Result<String, access::Error> {
+    access::check_writable(ctx)?;
+    let key = cmd.key.to_string();
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(std::slice::from_ref(&key), false, &db, ctx)?;
+    let mut items = arc_items.lock().unwrap();
+
+    let current = lookup(&key, &db, &mut items);
+    let (value, flags, expires) = match (cmd.name, current) {
+        ("add", Some(_)) | ("replace", None) | ("append", None) | ("prepend", None) => {
+            return Ok("NOT_STORED".to_string());
+        }
+        ("cas", None) => return Ok("NOT_FOUND".to_string()),
+        ("cas", Some((_, item))) if item.cas != cmd.cas => return Ok("EXISTS".to_string()),
+        ("append", Some((value, item))) => (value + &data, item.flags, item.expires),
+        ("prepend", Some((value, item))) => (data + &value, item.flags, item.expires),
+        _ => (data, cmd.flags, expires_at(cmd.exptime, now())),
+    };
+
+    access::write(
+        vec![raft::Command::Put(key.clone(), value.clone())],
+        db,
+        ctx,
+    )?;
+    set_item(&key, &value, flags, expires, &mut items);
+    save(&items);
+    Ok("STORED".to_string())
+}
+
+fn delete(
+    key: &str,
+    ctx: &access::Context,
+    arc_items: &Arc<Mutex<Items>>,
+) -> Result<String, access::Error> {
+    access::check_writable(ctx)?;
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&[key.to_string()], false, &db, ctx)?;
+    let mut items = arc_items.lock().unwrap();
+    if lookup(key, &db, &mut items).is_none() {
+        return Ok("NOT_FOUND".to_string());
+    }
+    access::write(vec![raft::Command::Delete(key.to_string())], db, ctx)?;
+    items.items.remove(key);
+    save(&items);
+    Ok("DELETED".to_string())
+}
+
+fn incr(
+    key: &str,
+    delta: u64,
+    up: bool,
+    ctx: &access::Context,
+    arc_items: &Arc<Mutex<Items>>,
+) -> Result<String, access::Error> {
+    access::check_writable(ctx)?;
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&[key.to_string()], false, &db, ctx)?;
+    let mut items = arc_items.lock().unwrap();
+    let (value, item) = match lookup(key, &db, &mut items) {
+        Some(x) => x,
+        None => return Ok("NOT_FOUND".to_string()),
+    };
+    let number = match value.parse::<u64>() {
+        Ok(x) => x,
+        Err(_) => {
+            let text = "CLIENT_ERROR cannot increment or decrement non-numeric value";
+            return Ok(text.to_string());
+        }
+    };
+    // incr wraps around, decr stops at 0, as in memcached
+    let number = if up {
+        number.wrapping_add(delta)
+    } else {
+        number.saturating_sub(delta)
+    };
+
+    let value = number.to_string();
+    access::write(
+        vec![raft::Command::Put(key.to_string(), value.clone())],
+        db,
+        ctx,
+    )?;
+    set_item(key, &value, item.flags, item.expires, &mut items);
+    save(&items);
+    Ok(value)
+}
+
+fn touch(key: &str, exptime: i64, ctx: &access::Context, arc_items: &Arc<Mutex<Items>>) -> String {
+    let db = ctx.arc_db.lock().unwrap();
+    if access::check_keys(&[key.to_string()], false, &db, ctx).is_err() {
+        return "NOT_FOUND".to_string();
+    }
+    let mut items = arc_items.lock().unwrap();
+    if lookup(key, &db, &mut items).is_none() {
+        return "NOT_FOUND".to_string();
+    }
+    if let Some(x) = items.items.get_mut(key) {
+        x.expires = expires_at(exptime, now());
+    }
+    save(&items);
+    "TOUCHED".to_string()
+}
+
+/// Read the data block of a storage command: `<bytes>` and `\r\n`
+fn read_data<R: BufRead>(reader: &mut R, size: usize) -> Option<Vec<u8>> {
+    let total = size.checked_add(2)?;
+    let mut buffer = vec![];
+    reader.take(total as u64).read_to_end(&mut buffer).ok()?;
+    if buffer.len() != total {
+        return None;
+    }
+    Some(buffer)
+}
+
+/// Drop the data block of a storage command, without holding it
+fn skip_data<R: BufRead>(reader: &mut R, size: usize) -> Option<()> {
+    let total = size.checked_add(2)? as u64;
+    let n = io::copy(&mut reader.take(total), &mut io::sink()).ok()?;
+    if n != total {
+        return None;
+    }
+    Some(())
+}
+
+/// Read a command line, returns `None` when the client disconnected, or
+/// an error reply for lines over `MAX_LINE`
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, String> {
+    let mut line = vec![];
+    match reader.take(MAX_LINE as u64).read_until(b'\n', &mut line) {
+        Ok(0) | Err(_) => return Ok(None),
+        Ok(_) => {}
+    }
+    if line.len() == MAX_LINE && !line.ends_with(b"\n") {
+        return Err("CLIENT_ERROR line too long".to_string());
+    }
+    match String::from_utf8(line) {
+        Ok(x) => Ok(Some(x)),
+        Err(_) => Err("CLIENT_ERROR bad command line format".to_string()),
+    }
+}
+
+/// Handle a command line, returns the reply without `\r\n`, and whether
+/// to send it (not with `noreply`). `None` to close the connection.
+fn handle_command<R: BufRead>(
+    tokens: &[&str],
+    reader: &mut R,
+    ctx: &access::Context,
+    arc_items: &Arc<Mutex<Items>>,
+) -> Option<(String, bool)> {
+    let noreply = tokens.last() == Some(&"noreply");
+    let reply = match tokens {
+        ["get", keys @ ..] if !keys.is_empty() => get(keys, false, ctx, arc_items),
+        ["gets", keys @ ..] if !keys.is_empty() => get(keys, true, ctx, arc_items),
+        [name, key, flags, exptime, bytes, rest @ ..]
+            if ["set", "add", "replace", "append", "prepend", "cas"].contains(name) =>
+        {
+            let size = match bytes.parse::<usize>() {
+                Ok(x) => x,
+                Err(_) => return Some(("CLIENT_ERROR bad data chunk".to_string(), true)),
+            };
+            if size > limits::get().max_value_size {
+                // drop the data, keeping the stream in sync
+                skip_data(reader, size)?;
+                return Some(("SERVER_ERROR object too large for cache".to_string(), true));
+            }
+            let data = read_data(reader, size)?;
+            if &data[size..] != b"\r\n" {
+                return Some(("CLIENT_ERROR bad data chunk".to_string(), true));
+            }
+            let cas = match (*name, rest.first()) {
+                ("cas", Some(x)) => x.parse::<u64>().ok(),
+                ("cas", None) => None,
+                _ => Some(0),
+            };
+            let cmd = match (flags.parse::<u32>(), exptime.parse::<i64>(), cas) {
+                (Ok(flags), Ok(exptime), Some(cas)) if key.len() <= MAX_KEY => Storage {
+                    name,
+                    key,
+                    flags,
+                    exptime,
+                    cas,
+                },
+                _ => return Some(("CLIENT_ERROR bad command line format".to_string(), true)),
+            };
+            match String::from_utf8(data[..size].to_vec()) {
+                Ok(data) => match store_value(&cmd, data, ctx, arc_items) {
+                    Ok(x) => x,
+                    Err(e) => error_reply(e),
+                },
+                Err(_) => "SERVER_ERROR only UTF-8 values are supported".to_string(),
+            }
+        }
+        ["delete", key] | ["delete", key, "noreply"] => match delete(key, ctx, arc_items) {
+            Ok(x) => x,
+            Err(e) => error_reply(e),
+        },
+        [name, key, delta] | [name, key, delta, "noreply"]
+            if *name == "incr" || *name == "decr" =>
+        {
+            match delta.parse::<u64>() {
+                Ok(delta) => match incr(key, delta, *name == "incr", ctx, arc_items) {
+                    Ok(x) => x,
+                    Err(e) => error_reply(e),
+                },
+                Err(_) => "CLIENT_ERROR invalid numeric delta argument".to_string(),
+            }
+        }
+        ["touch", key, exptime] | ["touch", key, exptime, "noreply"] => {
+            match exptime.parse::<i64>() {
+                Ok(x) => touch(key, x, ctx, arc_items),
+                Err(_) => "CLIENT_ERROR bad command line format".to_string(),
+            }
+        }
+        ["version"] => format!("VERSION {}", env!("CARGO_PKG_VERSION")),
+        ["quit"] => return None,
+        _ => "ERROR".to_string(),
+    };
+    Some((reply, !noreply))
+}
+
+fn handle_client(stream: TcpStream, ctx: access::Context, arc_items: Arc<Mutex<Items>>) {
+    let peer = match stream.peer_addr() {
+        Ok(x) => x.to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+    logging::new_connection(&peer);
+    logging::info!("memcache client accepted");
+    let mut writer = match stream.try_clone() {
+        Ok(x) => x,
+        Err(e) => {
+            logging::warning!("cannot clone client stream: {:?}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    loop {
+        let line = match read_line(&mut reader) {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(e) => {
+                // the rest of the line is not read, so disconnect
+                let _ = writer.write_all(format!("{}\r\n", e).as_bytes());
+                break;
+            }
+        };
+        let tokens: Vec<&str> = line.split_whitespace().collect();
+        if tokens.is_empty() {
+            continue;
+        }
+        let (reply, send) = match handle_command(&tokens, &mut reader, &ctx, &arc_items) {
+            Some(x) => x,
+            None => break,
+        };
+        if send
+            && writer
+                .write_all(format!("{}\r\n", reply).as_bytes())
+                .is_err()
+        {
+            break;
+        }
+    }
+    logging::info!("memcache client disconnected");
+}
+
+/// Delete expired keys from the DB
+fn expire(ctx: &access::Context, arc_items: &Arc<Mutex<Items>>) {
+    if access::check_writable(ctx).is_err() {
+        return;
+    }
+    let db = ctx.arc_db.lock().unwrap();
+    let mut items = arc_items.lock().unwrap();
+    let now = now();
+    let mut commands = vec![];
+    for (key, item) in items.items.iter() {
+        if !is_expired(item, now) {
+            continue;
+        }
+        if let Some(value) = store::get(key, &db) {
+            if digest(&value) == item.digest {
+                commands.push(raft::Command::Delete(key.clone()));
+            }
+        }
+    }
+    if commands.is_empty() {
+        return;
+    }
+    let keys: Vec<String> = commands
+        .iter()
+        .map(|x| match x {
+            raft::Command::Delete(key) => key.clone(),
+            _ => String::new(),
+        })
+        .collect();
+    if access::write(commands, db, ctx).is_ok() {
+        for key in keys {
+            items.items.remove(&key);
+        }
+        save(&items);
+    }
+}
+
+/// Listen on `addr` for memcached clients, in a thread
+pub fn start(addr: &str, ctx: access::Context) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(x) => x,
+        Err(e) => {
+            logging::error!("cannot listen on {} for memcache: {:?}", addr, e);
+            return;
+        }
+    };
+    logging::info!("memcache listener started at {}", addr);
+    let mut items = Items::new();
+    load(&mut items);
+    let arc_items = Arc::new(Mutex::new(items));
+
+    let (clone_ctx, clone_items) = (ctx.clone(), arc_items.clone());
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(1));
+        expire(&clone_ctx, &clone_items);
+    });
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    let (ctx, arc_items) = (ctx.clone(), arc_items.clone());
+                    thread::spawn(move || handle_client(stream, ctx, arc_items));
+                }
+                Err(e) => {
+                    logging::warning!("memcache accept failed: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{digest, expires_at, item_of, parse_items, set_item, Items};
+
+    #[test]
+    fn test_expires_at() {
+        assert_eq!(expires_at(0, 1000), 0);
+        assert_eq!(expires_at(-1, 1000), 1);
+        assert_eq!(expires_at(60, 1000), 1060);
+        assert_eq!(expires_at(2000000000, 1000), 2000000000);
+    }
+
+    #[test]
+    fn test_item_of() {
+        let mut items = Items::new();
+        set_item("foo", "bar", 42, 0, &mut items);
+        let item = item_of("foo", "bar", &mut items);
+        assert_eq!((item.flags, item.cas), (42, 1));
+
+        // changed by other protocols
+        let item = item_of("foo", "baz", &mut items);
+        assert_eq!((item.flags, item.cas), (0, 2));
+        assert_eq!(item.digest, digest("baz"));
+        assert_ne!(digest("bar"), digest("baz"));
+    }
+
+    #[test]
+    fn test_parse_items() {
+        let mut items = Items::new();
+        assert!(parse_items("5\nfoo 42 0 3 123\nbar 0 1700000000 4 456\n", &mut items).is_some());
+        assert_eq!(items.next_cas, 5);
+        assert_eq!(items.items["foo"].flags, 42);
+        assert_eq!(items.items["bar"].expires, 1700000000);
+        assert!(parse_items("5\nfoo 42 0\n", &mut Items::new()).is_none());
+    }
+}
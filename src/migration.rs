@@ -0,0 +1,288 @@
+// Online migration of hash slots between servers (see `src/slots.rs`).
+//
+// MIGRATE START-END HOST:PORT, sent to the server owning the slots, moves
+// them to the server at the address while both keep serving queries:
+//
+// 1. the target is told it's importing the slots, with SETSLOT
+// 2. the slots are marked migrating here, so new keys go to the target
+// 3. each key is sent to the target with ASKING and PUT, or for a list
+//    with ASKING and DEL, then RPUSH of each element, then deleted here
+//    if it's still the same: the DB is not held while waiting for
+//    the target, so a key written meanwhile is sent again, and one
+//    deleted meanwhile is deleted on the target too, and no writes to it
+//    are lost
+// 4. with no keys of the slots left here, the target, this server and
+//    then the other servers in the map are told the new owner
+//
+// When it fails in the middle, the slots are left migrating, and keys
+// are on either server, where they are still served. Sending the same
+// MIGRATE again goes on from there.
+
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::auth;
+use crate::hello;
+use crate::large;
+use crate::logging;
+use crate::persistence;
+use crate::slots;
+use crate::store;
+use crate::tools;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// times a key written while being sent is sent again
+const MAX_TRIES: usize = 3;
+
+/// What a key holds, to tell if it's written while being sent
+#[derive(PartialEq)]
+enum Held {
+    Value(String),
+    List(VecDeque<store::Value>),
+}
+
+fn held(key: &str, db: &store::DB) -> Option<Held> {
+    if let Some(x) = db.lists.get(key) {
+        return Some(Held::List(x.clone()));
+    }
+    store::get(key, db).map(Held::Value)
+}
+
+fn connect(addr: &str) -> Result<TcpStream, String> {
+    let mut stream = match TcpStream::connect(addr) {
+        Ok(x) => x,
+        Err(e) => return Err(format!("cannot connect to {}: {}", addr, e)),
+    };
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(TIMEOUT));
+    let _ = stream.set_write_timeout(Some(TIMEOUT));
+    if !auth::login(&mut stream) {
+        return Err(format!("authentication to {} failed", addr));
+    }
+    hello(&mut stream)?;
+    Ok(stream)
+}
+
+/// Send HELLO with `bigkeys`, so that keys are sent with long lengths
+fn hello(stream: &mut TcpStream) -> Result<(), String> {
+    let content = format!("{} bigkeys", hello::VERSION);
+    let mut query = vec![0x0c, 0x19, 0x00];
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content.as_bytes());
+    send(stream, &query)?;
+
+    let reply = read_exact(stream, 4)?;
+    let text = read_exact(stream, tools::bytes_to_u16(&reply[2..]) as usize)?;
+    if reply[0] != 0x0c || reply[1] != 0x00 {
+        let text = String::from_utf8_lossy(&text);
+        return Err(format!("HELLO failed: {}", text));
+    }
+    Ok(())
+}
+
+fn read_exact(stream: &mut TcpStream, size: usize) -> Result<Vec<u8>, String> {
+    let mut buffer = vec![0_u8; size];
+    match stream.read_exact(&mut buffer) {
+        Ok(_) => Ok(buffer),
+        Err(e) => Err(format!("connection to target lost: {}", e)),
+    }
+}
+
+fn send(stream: &mut TcpStream, query: &[u8]) -> Result<(), String> {
+    match stream.write_all(query) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("connection to target lost: {}", e)),
+    }
+}
+
+/// Send SETSLOT with the content to the server
+fn setslot(stream: &mut TcpStream, content: &str) -> Result<(), String> {
+    let mut query = vec![0x0c, 0x13, 0x00];
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content.as_bytes());
+    send(stream, &query)?;
+
+    let reply = read_exact(stream, 4)?;
+    let text = read_exact(stream, tools::bytes_to_u16(&reply[2..]) as usize)?;
+    if reply[0] != 0x0c || reply[1] != 0x00 {
+        let text = String::from_utf8_lossy(&text);
+        return Err(format!("SETSLOT {} failed: {}", content, text));
+    }
+    Ok(())
+}
+
+/// Send the key to the importing server, with ASKING and PUT
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> Result<(), String> {
+    let mut query = b"\x0c\x14\x00\x00\x00\x0c\x02\x00".to_vec();
+    query.extend(&large::len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    let (count, bytes) = tools::u64_to_bytes(value.len() as u64);
+    query.push(count);
+    query.extend(&bytes);
+    query.extend(value.as_bytes());
+    send(stream, &query)?;
+
+    let reply = read_exact(stream, 4)?;
+    if reply != b"\x0c\x00\x0c\x00" {
+        return Err(format!("target failed to store key {:?}", key));
+    }
+    Ok(())
+}
+
+/// Send an element of the list of the key to the importing server, with
+/// ASKING and RPUSH, the list being `len` long then
+fn push(stream: &mut TcpStream, key: &str, value: &str, len: usize) -> Result<(), String> {
+    let mut query = b"\x0c\x14\x00\x00\x00\x0c\x1c\x00".to_vec();
+    query.extend(&large::len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    let (count, bytes) = tools::u64_to_bytes(value.len() as u64);
+    query.push(count);
+    query.extend(&bytes);
+    query.extend(value.as_bytes());
+    send(stream, &query)?;
+
+    let reply = read_exact(stream, 8)?;
+    if reply[..4] != b"\x0c\x00\x0c\x00"[..] || reply[4..] != tools::u32_to_bytes(len as u32) {
+        return Err(format!("target failed to push to list {:?}", key));
+    }
+    Ok(())
+}
+
+/// Send the list of the key to the importing server, replacing what it
+/// has of it
+fn put_list(
+    stream: &mut TcpStream,
+    key: &str,
+    list: &VecDeque<store::Value>,
+) -> Result<(), String> {
+    del(stream, key)?;
+    for (i, x) in list.iter().enumerate() {
+        push(stream, key, &x.to_string(), i + 1)?;
+    }
+    Ok(())
+}
+
+/// Delete the key sent to the importing server, with ASKING and DEL
+fn del(stream: &mut TcpStream, key: &str) -> Result<(), String> {
+    let mut query = b"\x0c\x14\x00\x00\x00\x0c\x03\x00".to_vec();
+    query.extend(&large::len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    send(stream, &query)?;
+
+    let reply = read_exact(stream, 4)?;
+    if reply != b"\x0c\x00\x0c\x00" && reply != b"\x0c\x00\x0c\x02" {
+        return Err(format!("target failed to delete key {:?}", key));
+    }
+    Ok(())
+}
+
+fn in_range(key: &str, start: u16, end: u16) -> bool {
+    let slot = slots::key_slot(key);
+    slot >= start && slot <= end
+}
+
+/// Move the slots `start` to `end` (included) and their keys to the server
+/// at `target`, returns how many keys are moved.
+pub fn migrate(
+    start: u16,
+    end: u16,
+    target: &str,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+) -> Result<usize, String> {
+    let myself = {
+        let map = arc_slots.lock().unwrap();
+        if !slots::is_sharded(&map) {
+            return Err("not sharded".to_string());
+        }
+        for slot in start..=end {
+            if slots::owner(slot, &map) != Some(slots::myself(&map)) {
+                return Err(format!("slot {} is not owned by this server", slot));
+            }
+        }
+        if target == slots::myself(&map) {
+            return Err("cannot migrate to this server itself".to_string());
+        }
+        slots::myself(&map).to_string()
+    };
+    let range = format!("{}-{}", start, end);
+
+    let mut stream = connect(target)?;
+    setslot(&mut stream, &format!("importing {} {}", range, myself))?;
+    slots::set_migrating(start, end, target, &mut arc_slots.lock().unwrap());
+
+    // no new keys of the slots are added here from now on
+    let keys: Vec<String> = {
+        let db = arc_db.lock().unwrap();
+        db.items
+            .keys()
+            .chain(db.lists.keys())
+            .filter(|x| in_range(x, start, end))
+            .cloned()
+            .collect()
+    };
+    let mut count = 0;
+    for key in keys {
+        for _ in 0..MAX_TRIES {
+            let value = match held(&key, &arc_db.lock().unwrap()) {
+                Some(x) => x,
+                None => break,
+            };
+            match &value {
+                Held::Value(x) => put(&mut stream, &key, x)?,
+                Held::List(x) => put_list(&mut stream, &key, x)?,
+            }
+            let mut db = arc_db.lock().unwrap();
+            match held(&key, &db) {
+                Some(x) if x == value => {
+                    store::delete(&key, &mut db);
+                    count += 1;
+                    break;
+                }
+                Some(_) => continue,
+                None => {
+                    del(&mut stream, &key)?;
+                    break;
+                }
+            }
+        }
+    }
+    if let Some(db_file) = tools::get_db_file() {
+        if let Err(e) = persistence::save_to_file(&db_file, &arc_db.lock().unwrap()) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+
+    {
+        let db = arc_db.lock().unwrap();
+        let keys = db.items.keys().chain(db.lists.keys());
+        let left = keys.filter(|x| in_range(x, start, end)).count();
+        if left > 0 {
+            return Err(format!("{} keys of the slots are left, try again", left));
+        }
+    }
+    setslot(&mut stream, &format!("node {} {}", range, target))?;
+    let servers = {
+        let mut map = arc_slots.lock().unwrap();
+        slots::set_owner(start, end, target, &mut map);
+        slots::save(&map);
+        slots::servers(&map)
+    };
+    logging::info!("slots {} moved to {}, {} keys", range, target, count);
+
+    // others learn it from MOVED anyway, if they cannot be told now
+    for addr in servers {
+        if addr == myself || addr == target {
+            continue;
+        }
+        let result =
+            connect(&addr).and_then(|mut x| setslot(&mut x, &format!("node {} {}", range, target)));
+        if let Err(e) = result {
+            logging::warning!("cannot tell {} the new owner: {}", addr, e);
+        }
+    }
+    Ok(count)
+}
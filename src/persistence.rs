@@ -12,20 +12,95 @@
 //   that bad data save into the current single file.
 //
 
-use std::fs::File;
-use std::io::BufReader;
+use std::fs::{self, File};
 use std::io::ErrorKind;
 use std::io::{Read, Write};
+use std::path::Path;
+use std::sync::OnceLock;
 
+use crate::changes;
+use crate::encryption;
+use crate::error::Error;
+use crate::large;
+use crate::logging;
 use crate::store;
 use crate::tools;
+use crate::watch;
+
+/// Header of compressed data files, followed by the compression, the
+/// length of the content before compression, and the compressed content
+const COMPRESSED_MAGIC: &[u8] = b"\x0fH2OKV";
+
+const COMPRESSED_HEADER_LEN: usize = 6 + 1 + 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None,
+    /// the LZ4 block format
+    Lz4,
+}
+
+/// `--snapshot-compression` of this server
+static COMPRESSION: OnceLock<Compression> = OnceLock::new();
+
+pub fn parse_compression(name: &str) -> Result<Compression, String> {
+    match name {
+        "none" => Ok(Compression::None),
+        "lz4" => Ok(Compression::Lz4),
+        _ => Err(format!("unknown compression: {}", name)),
+    }
+}
+
+pub fn set_compression(compression: Compression) {
+    let _ = COMPRESSION.set(compression);
+}
+
+/// Compress the content of the data file, with the header
+pub fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+    match compression {
+        Compression::None => data.to_vec(),
+        Compression::Lz4 => {
+            let mut result = COMPRESSED_MAGIC.to_vec();
+            result.push(0x01);
+            result.extend(&(data.len() as u64).to_le_bytes());
+            result.extend(lz4_flex::block::compress(data));
+            result
+        }
+    }
+}
+
+/// Decompress the content of the data file, files without the header
+/// are returned as is
+pub fn decompress(data: Vec<u8>) -> Result<Vec<u8>, String> {
+    if !data.starts_with(COMPRESSED_MAGIC) {
+        return Ok(data);
+    }
+    if data.len() < COMPRESSED_HEADER_LEN {
+        return Err("compressed file too short".to_string());
+    }
+    let size = tools::bytes_to_u64(&data[7..COMPRESSED_HEADER_LEN]);
+    let content = &data[COMPRESSED_HEADER_LEN..];
+    match data[6] {
+        0x01 => {
+            // LZ4 cannot compress more than 255 times, do not trust a
+            // broken header to allocate more than that
+            if size > content.len() as u64 * 255 + 16 {
+                return Err("invalid LZ4 content length".to_string());
+            }
+            lz4_flex::block::decompress(content, size as usize)
+                .map_err(|e| format!("invalid LZ4 data: {}", e))
+        }
+        x => Err(format!("unknown compression: {}", x)),
+    }
+}
 
 /// Save current DB content into disk file for persistence.
 ///
 /// The target disk file is the current working directory, with file
-/// name: h2okv.data. Currently, each time saving, we destroy & recreate it,
-/// which is a temporary solution. For details, please see comments at
-/// the top of this file.
+/// name: h2okv.data. Currently, each time saving, we write the whole DB
+/// into a temporary file, and rename it over the data file once synced,
+/// so that a crash leaves either the old or the new file. For details,
+/// please see comments at the top of this file.
 ///
 /// Basically, we loop on all keys in the HashMap (our data store in memory),
 /// for each key, we save it into buffer with content
@@ -34,130 +109,241 @@ use crate::tools;
 /// expansing usage; `<key-len-byte>` is one-byte that how many bytes
 /// the next `<key-len-bytes>` used. which store the real key bytes in total
 /// the following `<key-bytes>` stored. The value bytes are the same logic.
-pub fn save_to_file(db_file: &str, db: &store::DB) {
-    let mut file = match File::create(db_file) {
-        Ok(file) => file,
-        Err(why) => {
-            println!("couldn't create db file: {:?}", why);
-            return;
-        }
+///
+/// Before the items, we save `"\x0D<seq-len-byte><seq-bytes>"`, the
+/// sequence number of the next change (see `src/changes.rs`), so that
+/// numbering goes on after restarting, and `"\x0A<id-len-byte><id-bytes>"`,
+/// the ID of the history of the changes, then the changes in the log, each
+/// as `"\x0B<seq-len-byte><seq-bytes><event><key-item><value-item>"`
+/// with the items as above, and an empty value for DEL.
+///
+/// After the items, the elements of lists are saved from left to right,
+/// each as `"\x0E<key-item><value-item>"`.
+///
+/// With `--snapshot-compression`, the whole content is compressed, with
+/// the header `"\x0FH2OKV<compression:1><length:8>"`. Then with a data
+/// key, it is encrypted, see `src/encryption.rs`.
+pub fn save_to_file(db_file: &str, db: &store::DB) -> Result<(), Error> {
+    let compression = *COMPRESSION.get().unwrap_or(&Compression::None);
+    let data = compress(&dump(db), compression);
+
+    let tmp_file = format!("{}.tmp", db_file);
+    let mut file = File::create(&tmp_file)?;
+    file.write_all(&encryption::seal(&data))?;
+    file.sync_all()?;
+    fs::rename(&tmp_file, db_file)?;
+    // the rename is only durable once the directory is synced
+    let dir = match Path::new(db_file).parent() {
+        Some(x) if !x.as_os_str().is_empty() => x,
+        _ => Path::new("."),
     };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
 
+/// Dump DB content into bytes, in the format of the disk file.
+///
+/// Also used to send snapshots to replicas, see `src/replication.rs`.
+pub fn dump(db: &store::DB) -> Vec<u8> {
     let mut buffer: Vec<u8> = Vec::new();
-    for (key, value) in db {
+    let (count, bytes) = tools::u64_to_bytes(changes::next_seq(&db.changes));
+    buffer.push(0x0d_u8); // header of sequence number
+    buffer.push(count);
+    buffer.extend(&bytes);
+    let (count, bytes) = tools::u64_to_bytes(changes::id(&db.changes));
+    buffer.push(0x0a_u8); // header of the ID of the changes
+    buffer.push(count);
+    buffer.extend(&bytes);
+    for change in changes::entries(&db.changes) {
+        let (count, bytes) = tools::u64_to_bytes(change.seq);
+        buffer.push(0x0b_u8); // header of change
+        buffer.push(count);
+        buffer.extend(&bytes);
+        buffer.push(change.event);
+        let (count, bytes) = tools::u64_to_bytes(change.key.len() as u64);
+        buffer.push(count);
+        buffer.extend(&bytes);
+        buffer.extend(change.key.as_bytes());
+        let empty = store::Value::from("");
+        dump_value(change.value.as_ref().unwrap_or(&empty), &mut buffer);
+    }
+    for (key, value) in &db.items {
         let (count, bytes) = tools::u64_to_bytes(key.len() as u64);
         buffer.push(0x0c_u8); // header
         buffer.push(count.into()); // key-length-bytes count
         buffer.extend(&bytes); // key-length bytes
         buffer.extend(key.as_bytes());
-        let (count, bytes) = tools::u64_to_bytes(value.len() as u64);
-        buffer.push(count.into()); // value-length-bytes count
-        buffer.extend(&bytes); // value-length bytes
-        buffer.extend(value.as_bytes());
+        dump_value(value, &mut buffer);
     }
+    for (key, list) in &db.lists {
+        let (count, bytes) = tools::u64_to_bytes(key.len() as u64);
+        for value in list {
+            buffer.push(0x0e_u8); // header of list element
+            buffer.push(count);
+            buffer.extend(&bytes);
+            buffer.extend(key.as_bytes());
+            dump_value(value, &mut buffer);
+        }
+    }
+    buffer
+}
 
-    if let Err(e) = file.write_all(&buffer) {
-        println!("Error when save db: {:?}", e);
+/// Dump `<len-byte><len-bytes><bytes>` of the value, chunk by chunk
+fn dump_value(value: &store::Value, buffer: &mut Vec<u8>) {
+    let (count, bytes) = tools::u64_to_bytes(value.len() as u64);
+    buffer.push(count); // value-length-bytes count
+    buffer.extend(&bytes); // value-length bytes
+    for chunk in value.chunks() {
+        buffer.extend(chunk);
     }
 }
 
-fn read_buffer(reader: &mut BufReader<File>, buffer: &mut [u8], can_be_empty: bool) -> bool {
-    match reader.read(buffer) {
-        Ok(n) => {
-            if n == 0 {
-                if !can_be_empty {
-                    panic!("cannot read any data");
-                }
-                return false;
-            }
-            true
-        }
-        Err(e) => {
-            panic!("buffer read error: {:?}", e);
-        }
+/// Read the whole buffer, returns false at EOF if it `can_be_empty`
+fn read_buffer<R: Read>(
+    reader: &mut R,
+    buffer: &mut [u8],
+    can_be_empty: bool,
+) -> Result<bool, Error> {
+    match reader.read_exact(buffer) {
+        Ok(_) => Ok(true),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof && can_be_empty => Ok(false),
+        Err(e) => Err(Error::Corrupted(format!("buffer read error: {}", e))),
+    }
+}
+
+/// Read `<count-byte><bytes>`, a number of up to 8 bytes
+fn read_number<R: Read>(reader: &mut R) -> Result<u64, Error> {
+    let mut buf_count = [0_u8; 1];
+    read_buffer(reader, &mut buf_count, false)?;
+    if buf_count[0] > 8 {
+        let e = format!("invalid length: {} bytes", buf_count[0]);
+        return Err(Error::Corrupted(e));
     }
+    let mut buffer = vec![0_u8; buf_count[0] as usize];
+    read_buffer(reader, &mut buffer, false)?;
+    Ok(tools::bytes_to_u64(&buffer))
+}
+
+/// Read `<len-byte><len-bytes><bytes>`, e.g. a key or a value
+fn read_item<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let count = read_number(reader)?;
+    // the bytes as they come, rather than what a broken length claims
+    let mut buffer = vec![];
+    reader.by_ref().take(count).read_to_end(&mut buffer)?;
+    if buffer.len() as u64 != count {
+        return Err(Error::Corrupted("truncated item".to_string()));
+    }
+    Ok(buffer)
+}
+
+/// Read `<len-byte><len-bytes><bytes>` of a value, in pieces, see
+/// `store::Value`
+fn read_value<R: Read>(reader: &mut R) -> Result<store::Value, Error> {
+    let count = read_number(reader)?;
+    let pieces = match large::read_pieces(reader, count) {
+        Ok(x) => x,
+        Err(_) => return Err(Error::Corrupted("truncated item".to_string())),
+    };
+    store::Value::from_pieces(pieces)
+}
+
+/// Read a change of the log, after its header
+fn read_change<R: Read>(reader: &mut R) -> Result<changes::Change, Error> {
+    let seq = read_number(reader)?;
+    let mut buf_event = [0_u8; 1];
+    read_buffer(reader, &mut buf_event, false)?;
+    let key = String::from_utf8(read_item(reader)?).map_err(|_| Error::InvalidUtf8)?;
+    let value = read_value(reader)?;
+    let value = match buf_event[0] {
+        watch::EVENT_DELETE => None,
+        watch::EVENT_PUT | watch::EVENT_LPUSH..=watch::EVENT_RPOP => Some(value),
+        x => return Err(Error::Corrupted(format!("invalid change event: {}", x))),
+    };
+    Ok(changes::Change {
+        seq,
+        event: buf_event[0],
+        key,
+        value,
+    })
 }
 
 /// Load existing DB disk file into DB memory.
 ///
 /// The reverse action with `save_to_file()`. For file format, please see
 /// comments of `save_to_file()`.
-pub fn load_from_file(db_file: &str, db: &mut store::DB) {
-    let file = match File::open(db_file) {
-        Ok(file) => file,
-        Err(why) => {
-            if why.kind() == ErrorKind::NotFound {
-                println!("No existing db file found.");
-                return;
-            }
-            println!("open db file failed: {:?}", why);
-            return;
+///
+/// Only a missing file is an empty DB. Returns an error if the file
+/// cannot be read, is encrypted and cannot be decrypted, or is broken,
+/// when the server should not go on with an empty DB.
+pub fn load_from_file(db_file: &str, db: &mut store::DB) -> Result<(), Error> {
+    let data = match fs::read(db_file) {
+        Ok(x) => x,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            logging::info!("No existing db file found.");
+            return Ok(());
         }
+        Err(e) => return Err(Error::Io(e)),
     };
 
-    let mut reader = BufReader::new(file);
+    let data = encryption::open(data).map_err(Error::Corrupted)?;
+    // compressed or not, whatever `--snapshot-compression` is now
+    let data = decompress(data).map_err(Error::Corrupted)?;
+    load(&mut &data[..], db)
+}
+
+/// Load DB content from bytes in the format of the disk file, keys
+/// already in DB are kept unless overwritten.
+///
+/// On errors, the items before the broken one are loaded.
+pub fn load<R: Read>(reader: &mut R, db: &mut store::DB) -> Result<(), Error> {
     loop {
         // read and confirm the header
         let mut buf_header = [0_u8; 1];
-        if !read_buffer(&mut reader, &mut buf_header, true) {
-            break; // EOF
+        if !read_buffer(reader, &mut buf_header, true)? {
+            return Ok(()); // EOF
         }
-        assert_eq!(&buf_header, &[0x0c_u8]);
-
-        // BEGIN of read key
-        // 1. read key length byte
-        let mut buf_key_len_byte = [0_u8; 1];
-        read_buffer(&mut reader, &mut buf_key_len_byte, false);
-
-        // 2. read key length bytes
-        let count = buf_key_len_byte[0] as usize;
-        let mut buf_key_len = Vec::with_capacity(count);
-        for _ in 0..count {
-            buf_key_len.push(0_u8);
+        if buf_header[0] == 0x0d {
+            // the sequence number, missing in files of older versions
+            let seq = read_number(reader)?;
+            changes::set_next_seq(seq, &mut db.changes);
+            continue;
         }
-        read_buffer(&mut reader, &mut buf_key_len, false);
-
-        // 3. read key bytes
-        let key_bytes_count = tools::bytes_to_u64(&buf_key_len);
-        let mut buf_key = Vec::with_capacity(key_bytes_count as usize);
-        for _ in 0..key_bytes_count {
-            buf_key.push(0_u8);
+        if buf_header[0] == 0x0a {
+            let id = read_number(reader)?;
+            changes::set_id(id, &mut db.changes);
+            continue;
         }
-        read_buffer(&mut reader, &mut buf_key, false);
-
-        // BEGIN of read value
-        // 1. read value length byte
-        let mut buf_value_len_byte = [0_u8; 1];
-        read_buffer(&mut reader, &mut buf_value_len_byte, false);
-
-        // 2. read value length bytes
-        let count = buf_value_len_byte[0] as usize;
-        let mut buf_value_len = Vec::with_capacity(count);
-        for _ in 0..count {
-            buf_value_len.push(0_u8);
+        if buf_header[0] == 0x0b {
+            let change = read_change(reader)?;
+            changes::restore(change, &mut db.changes);
+            continue;
         }
-        read_buffer(&mut reader, &mut buf_value_len, false);
-
-        // 3. read value bytes
-        let value_bytes_count = tools::bytes_to_u64(&buf_value_len);
-        let mut buf_value = Vec::with_capacity(value_bytes_count as usize);
-        for _ in 0..value_bytes_count {
-            buf_value.push(0_u8);
+        if buf_header[0] == 0x0e {
+            let buf_key = read_item(reader)?;
+            let key = String::from_utf8(buf_key).map_err(|_| Error::InvalidUtf8)?;
+            let value = read_value(reader)?;
+            db.lists.entry(key).or_default().push_back(value);
+            continue;
+        }
+        if buf_header[0] != 0x0c {
+            let e = format!("invalid header: {}", buf_header[0]);
+            return Err(Error::Corrupted(e));
         }
-        read_buffer(&mut reader, &mut buf_value, false);
 
-        db.insert(
-            String::from_utf8(buf_key).unwrap(),
-            String::from_utf8(buf_value).unwrap(),
-        );
+        let buf_key = read_item(reader)?;
+        let key = String::from_utf8(buf_key).map_err(|_| Error::InvalidUtf8)?;
+        let value = read_value(reader)?;
+        db.items.insert(key, value);
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::changes;
     use super::load_from_file;
     use super::save_to_file;
     use super::store;
+    use super::{compress, decompress, dump, load, Compression};
     use std::path::PathBuf;
 
     #[test]
@@ -166,7 +352,7 @@ mod tests {
         d.push("tests/data/dataset-001.db");
         let mut db = store::DB::new();
         assert_eq!(store::scan("", &db), Vec::<String>::new());
-        load_from_file(d.to_str().unwrap(), &mut db);
+        load_from_file(d.to_str().unwrap(), &mut db).unwrap();
         assert_eq!(store::scan("", &db), Vec::<String>::new());
     }
 
@@ -176,7 +362,7 @@ mod tests {
         d.push("tests/data/dataset-002.db");
         let mut db = store::DB::new();
         assert_eq!(store::scan("", &db), Vec::<String>::new());
-        load_from_file(d.to_str().unwrap(), &mut db);
+        load_from_file(d.to_str().unwrap(), &mut db).unwrap();
         assert_eq!(store::scan("", &db), vec!["foo".to_string()]);
     }
 
@@ -186,7 +372,7 @@ mod tests {
         db_file.push("tests/data/dataset-003.db");
         let mut db = store::DB::new();
         assert_eq!(store::scan("", &db), Vec::<String>::new());
-        load_from_file(db_file.to_str().unwrap(), &mut db);
+        load_from_file(db_file.to_str().unwrap(), &mut db).unwrap();
         let v = store::scan("", &db);
         assert_eq!(v.len(), 4);
         assert_eq!(store::get("foo", &db), Some("barbaz".to_string()));
@@ -203,16 +389,18 @@ mod tests {
         let mut db_tmp = store::DB::new();
 
         // test empty
-        save_to_file(db_file, &db_tmp);
+        save_to_file(db_file, &db_tmp).unwrap();
+        // written into a temporary file, renamed over the data file
+        assert!(!PathBuf::from(format!("{}.tmp", db_file)).exists());
         let mut db = store::DB::new();
-        load_from_file(db_file, &mut db);
+        load_from_file(db_file, &mut db).unwrap();
         assert_eq!(store::scan("", &db), Vec::<String>::new());
 
         // test one item
         store::put("foo", "bar".as_bytes(), &mut db_tmp).unwrap();
-        save_to_file(db_file, &db_tmp);
+        save_to_file(db_file, &db_tmp).unwrap();
         let mut db = store::DB::new();
-        load_from_file(db_file, &mut db);
+        load_from_file(db_file, &mut db).unwrap();
         let v = store::scan("", &db);
         assert_eq!(v.len(), 1);
         assert_eq!(store::get("foo", &db), Some("bar".to_string()));
@@ -220,9 +408,9 @@ mod tests {
         // test more items
         store::put("location", "地铁西小口128号".as_bytes(), &mut db_tmp).unwrap();
         store::put("age", "18".as_bytes(), &mut db_tmp).unwrap();
-        save_to_file(db_file, &db_tmp);
+        save_to_file(db_file, &db_tmp).unwrap();
         let mut db = store::DB::new();
-        load_from_file(db_file, &mut db);
+        load_from_file(db_file, &mut db).unwrap();
         let v = store::scan("", &db);
         assert_eq!(v.len(), 3);
         assert_eq!(store::get("foo", &db), Some("bar".to_string()));
@@ -231,12 +419,14 @@ mod tests {
             Some("地铁西小口128号".to_string())
         );
         assert_eq!(store::get("age", &db), Some("18".to_string()));
+        assert_eq!(changes::next_seq(&db.changes), 4);
+        assert_eq!(changes::id(&db.changes), changes::id(&db_tmp.changes));
 
         // test delete item
         store::delete("age", &mut db_tmp).unwrap();
-        save_to_file(db_file, &db_tmp);
+        save_to_file(db_file, &db_tmp).unwrap();
         let mut db = store::DB::new();
-        load_from_file(db_file, &mut db);
+        load_from_file(db_file, &mut db).unwrap();
         let v = store::scan("", &db);
         assert_eq!(v.len(), 2);
         assert_eq!(store::get("foo", &db), Some("bar".to_string()));
@@ -245,4 +435,105 @@ mod tests {
             Some("地铁西小口128号".to_string())
         );
     }
+
+    #[test]
+    fn test_load_from_file_errors() {
+        let mut db = store::DB::new();
+        let dir = env!("CARGO_MANIFEST_DIR");
+        load_from_file(&format!("{}/tests/data/nothing.db", dir), &mut db).unwrap();
+        // only a missing file is an empty DB
+        assert!(load_from_file(dir, &mut db).is_err());
+        let db_file = format!("{}/tests/data/nothing/dataset.db", dir);
+        assert!(save_to_file(&db_file, &db).is_err());
+    }
+
+    #[test]
+    fn test_compress() {
+        let mut db_tmp = store::DB::new();
+        store::put("foo", "bar".repeat(100).as_bytes(), &mut db_tmp).unwrap();
+        store::put("name-cn", "宏钢".as_bytes(), &mut db_tmp).unwrap();
+        let data = dump(&db_tmp);
+        assert_eq!(compress(&data, Compression::None), data);
+
+        let compressed = compress(&data, Compression::Lz4);
+        assert!(compressed.starts_with(b"\x0fH2OKV\x01"));
+        assert!(compressed.len() < data.len());
+        let mut db = store::DB::new();
+        load(&mut &decompress(compressed.clone()).unwrap()[..], &mut db).unwrap();
+        assert_eq!(store::get("foo", &db), Some("bar".repeat(100)));
+        assert_eq!(store::get("name-cn", &db), Some("宏钢".to_string()));
+
+        // files of older versions are not compressed
+        assert_eq!(decompress(data.clone()), Ok(data));
+        assert!(decompress(compressed[..10].to_vec()).is_err());
+        let mut broken = compressed.clone();
+        broken[6] = 0x02;
+        assert!(decompress(broken).is_err());
+        let mut broken = compressed.clone();
+        broken[14] = 0xff;
+        assert!(decompress(broken).is_err());
+    }
+
+    #[test]
+    fn test_dump_changes() {
+        let mut db_tmp = store::DB::new();
+        store::put("foo", b"bar", &mut db_tmp).unwrap();
+        store::put("empty", b"", &mut db_tmp).unwrap();
+        store::delete("foo", &mut db_tmp);
+        let data = dump(&db_tmp);
+        let mut db = store::DB::new();
+        load(&mut &data[..], &mut db).unwrap();
+        assert_eq!(changes::next_seq(&db.changes), 4);
+        let frames = changes::since(1, &db.changes).unwrap();
+        assert_eq!(frames, changes::since(1, &db_tmp.changes).unwrap());
+        assert_eq!(frames.len(), 3);
+
+        let broken = [&data[..], b"\x0b\x01\x09\x7f\x01\x01k\x00"].concat();
+        assert!(load(&mut &broken[..], &mut store::DB::new()).is_err());
+    }
+
+    #[test]
+    fn test_dump_lists() {
+        let mut db_tmp = store::DB::new();
+        for x in ["a", "b", "c"] {
+            store::push("jobs", store::Value::from(x), false, &mut db_tmp).unwrap();
+        }
+        store::pop("jobs", true, &mut db_tmp).unwrap();
+        store::put("foo", b"bar", &mut db_tmp).unwrap();
+        let data = dump(&db_tmp);
+        let mut db = store::DB::new();
+        load(&mut &data[..], &mut db).unwrap();
+        assert_eq!(db.lists, db_tmp.lists);
+        assert_eq!(store::get("foo", &db), Some("bar".to_string()));
+        let frames = changes::since(1, &db.changes).unwrap();
+        assert_eq!(frames, changes::since(1, &db_tmp.changes).unwrap());
+        assert_eq!(frames.len(), 5);
+    }
+
+    #[test]
+    fn test_load_broken() {
+        let mut db_tmp = store::DB::new();
+        store::put("foo", b"bar", &mut db_tmp).unwrap();
+        store::put("empty", b"", &mut db_tmp).unwrap();
+        let data = dump(&db_tmp);
+        let mut db = store::DB::new();
+        load(&mut &data[..], &mut db).unwrap();
+        assert_eq!(store::get("empty", &db), Some("".to_string()));
+
+        // truncated anywhere, or with a broken header, length or text
+        for i in 1..data.len() {
+            if [0x0a, 0x0b, 0x0c].contains(&data[i]) {
+                continue;
+            }
+            assert!(load(&mut &data[..i], &mut store::DB::new()).is_err());
+        }
+        let broken = [&data[..], b"\x0e"].concat();
+        assert!(load(&mut &broken[..], &mut store::DB::new()).is_err());
+        let broken = [&data[..], b"\x0c\x09"].concat();
+        assert!(load(&mut &broken[..], &mut store::DB::new()).is_err());
+        let broken = [&data[..], b"\x0c\x01\xff"].concat();
+        assert!(load(&mut &broken[..], &mut store::DB::new()).is_err());
+        let broken = [&data[..], b"\x0c\x01\x01k\x01\x01\xff"].concat();
+        assert!(load(&mut &broken[..], &mut store::DB::new()).is_err());
+    }
 }
@@ -0,0 +1,271 @@
+// Backend servers of the proxy, and which of them owns each key.
+//
+// When the backends are sharded (see `src/slots.rs` of the server), the
+// slot map is fetched from them with the SLOTS query, and kept up to
+// date from MOVED replies and health checks. When they are plain
+// servers, the proxy shards the keys itself, splitting the slots evenly
+// between the backends, in the order given on the command line.
+//
+// Every backend is checked with a SLOTS query each `CHECK_INTERVAL`,
+// after authenticating with `--password` if given. Queries for a backend
+// failing the check are replied Failed right away, until it passes again.
+
+use std::collections::{BTreeSet, HashSet};
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::logging;
+use crate::tools;
+
+const SLOTS: usize = 16384;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const CHECK_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub struct Backends {
+    /// backends given on the command line
+    seeds: Vec<String>,
+    /// owner of each slot
+    slots: Vec<String>,
+    /// backends which failed the last health check
+    down: HashSet<String>,
+    /// password of the default user, for the health checks
+    password: Option<String>,
+}
+
+impl Backends {
+    pub fn new(seeds: &[String], password: Option<String>) -> Backends {
+        Backends {
+            seeds: seeds.to_vec(),
+            slots: split(seeds),
+            down: HashSet::new(),
+            password,
+        }
+    }
+}
+
+/// CRC16/XMODEM
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for x in bytes {
+        crc ^= (*x as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// The slot the key belongs to, same as the server
+pub fn key_slot(key: &str) -> u16 {
+    let mut part = key;
+    if let Some(start) = key.find('{') {
+        if let Some(len) = key[start + 1..].find('}') {
+            if len > 0 {
+                part = &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+    (crc16(part.as_bytes()) as usize % SLOTS) as u16
+}
+
+/// Split the slots evenly between the servers
+fn split(servers: &[String]) -> Vec<String> {
+    let mut slots = Vec::with_capacity(SLOTS);
+    for i in 0..SLOTS {
+        slots.push(servers[i * servers.len() / SLOTS].clone());
+    }
+    slots
+}
+
+/// Address of the backend owning the key
+pub fn owner(key: &str, backends: &Backends) -> String {
+    backends.slots[key_slot(key) as usize].clone()
+}
+
+/// Addresses of all backends owning any slots
+pub fn owners(backends: &Backends) -> Vec<String> {
+    let set: BTreeSet<&String> = backends.slots.iter().collect();
+    set.into_iter().cloned().collect()
+}
+
+pub fn is_up(addr: &str, backends: &Backends) -> bool {
+    !backends.down.contains(addr)
+}
+
+/// The backends require authentication, as told by `--password`
+pub fn has_password(backends: &Backends) -> bool {
+    backends.password.is_some()
+}
+
+/// The slot was MOVED to the backend at `addr`
+pub fn moved(slot: u16, addr: &str, backends: &mut Backends) {
+    backends.slots[slot as usize] = addr.to_string();
+}
+
+pub fn connect(addr: &str, timeout: Duration) -> Option<TcpStream> {
+    let sock_addr = addr.to_socket_addrs().ok()?.next()?;
+    let stream = TcpStream::connect_timeout(&sock_addr, timeout).ok()?;
+    let _ = stream.set_nodelay(true);
+    let _ = stream.set_read_timeout(Some(Duration::from_secs(5)));
+    let _ = stream.set_write_timeout(Some(Duration::from_secs(5)));
+    Some(stream)
+}
+
+/// AUTH query of the user with the password
+pub fn auth_query(user: &str, password: &str) -> Vec<u8> {
+    let mut query = b"\x0c\x16\x00".to_vec();
+    query.extend(&tools::u16_to_bytes(user.len() as u16));
+    query.extend(user.as_bytes());
+    query.push(8);
+    query.extend(&(password.len() as u64).to_le_bytes());
+    query.extend(password.as_bytes());
+    query
+}
+
+/// Send HELLO with `bigkeys`, so that keys of any length are sent in the
+/// long form, returns the features of the backend
+pub fn hello(stream: &mut TcpStream) -> Option<Vec<String>> {
+    let content = b"1 bigkeys";
+    let mut query = b"\x0c\x19\x00".to_vec();
+    query.extend(&tools::u16_to_bytes(content.len() as u16));
+    query.extend(content);
+    stream.write_all(&query).ok()?;
+    let header = tools::read_exact(stream, 4)?;
+    let text = tools::read_exact(stream, tools::bytes_to_u16(&header[2..]) as usize)?;
+    if header[0] != 0x0c || header[1] != 0x00 {
+        return None;
+    }
+    let text = String::from_utf8_lossy(&text);
+    Some(
+        text.split_whitespace()
+            .skip(1)
+            .map(|x| x.to_string())
+            .collect(),
+    )
+}
+
+/// Send the AUTH query, returns false if refused
+pub fn login(query: &[u8], stream: &mut TcpStream) -> bool {
+    let mut reply = [0_u8; 2];
+    stream.write_all(query).is_ok()
+        && stream.read_exact(&mut reply).is_ok()
+        && reply == [0x0c, 0x00]
+}
+
+/// Fetch the slot map from the backend, empty when it's not sharded
+fn fetch_slots(addr: &str, password: &Option<String>) -> Option<Vec<String>> {
+    let mut stream = connect(addr, CHECK_TIMEOUT)?;
+    let _ = stream.set_read_timeout(Some(CHECK_TIMEOUT));
+    if let Some(x) = password {
+        if !login(&auth_query("", x), &mut stream) {
+            logging::warning!("authentication to backend {} failed", addr);
+            return None;
+        }
+    }
+    stream.write_all(b"\x0c\x11\x00\x00\x00").ok()?;
+    let header = tools::read_exact(&mut stream, 4)?;
+    if header[0] != 0x0c || header[1] != 0x00 {
+        return None;
+    }
+
+    let mut slots = vec![];
+    for _ in 0..tools::bytes_to_u16(&header[2..]) {
+        let range = tools::read_exact(&mut stream, 4)?;
+        let buf_len = tools::read_exact(&mut stream, 2)?;
+        let buf_addr = tools::read_exact(&mut stream, tools::bytes_to_u16(&buf_len) as usize)?;
+        let owner = String::from_utf8_lossy(&buf_addr).to_string();
+        let end = tools::bytes_to_u16(&range[2..]) as usize;
+        slots.resize(end + 1, owner);
+    }
+    if !slots.is_empty() && slots.len() != SLOTS {
+        return None;
+    }
+    Some(slots)
+}
+
+/// Check all backends once, updating the slot map from the first one
+/// which is sharded.
+pub fn check(arc_backends: &Arc<Mutex<Backends>>) {
+    let (addrs, password) = {
+        let backends = arc_backends.lock().unwrap();
+        let mut addrs: BTreeSet<String> = backends.seeds.iter().cloned().collect();
+        addrs.extend(owners(&backends));
+        (addrs, backends.password.clone())
+    };
+
+    let mut down = HashSet::new();
+    let mut slots: Option<Vec<String>> = None;
+    for addr in addrs {
+        match fetch_slots(&addr, &password) {
+            Some(x) => {
+                if slots.is_none() && !x.is_empty() {
+                    slots = Some(x);
+                }
+            }
+            None => {
+                down.insert(addr);
+            }
+        }
+    }
+
+    let mut backends = arc_backends.lock().unwrap();
+    for addr in down.iter() {
+        if !backends.down.contains(addr) {
+            logging::warning!("backend {} is down", addr);
+        }
+    }
+    for addr in backends.down.iter() {
+        if !down.contains(addr) {
+            logging::info!("backend {} is up", addr);
+        }
+    }
+    backends.down = down;
+    if let Some(x) = slots {
+        backends.slots = x;
+    }
+}
+
+/// Check the backends in a thread, every `CHECK_INTERVAL`
+pub fn start_checks(arc_backends: Arc<Mutex<Backends>>) {
+    thread::spawn(move || loop {
+        thread::sleep(CHECK_INTERVAL);
+        check(&arc_backends);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_slot, moved, owner, owners, split, Backends, SLOTS};
+
+    #[test]
+    fn test_split() {
+        let servers = vec!["a:1".to_string(), "b:2".to_string(), "c:3".to_string()];
+        let slots = split(&servers);
+        assert_eq!(slots.len(), SLOTS);
+        assert_eq!(slots[0], "a:1");
+        assert_eq!(slots[5461], "a:1");
+        assert_eq!(slots[5462], "b:2");
+        assert_eq!(slots[SLOTS - 1], "c:3");
+    }
+
+    #[test]
+    fn test_owner() {
+        let servers = vec!["a:1".to_string(), "b:2".to_string()];
+        let mut backends = Backends::new(&servers, None);
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(owner("foo", &backends), "b:2");
+        assert_eq!(owner("{foo}:bar", &backends), "b:2");
+        assert_eq!(owners(&backends), servers);
+
+        moved(12182, "c:3", &mut backends);
+        assert_eq!(owner("foo", &backends), "c:3");
+        assert_eq!(owners(&backends).len(), 3);
+    }
+}
@@ -0,0 +1,119 @@
+// Proxy options, from command line arguments:
+//
+//     h2okv-proxy [--host HOST] [--port PORT] --backends HOST:PORT,...
+//                 [--password PASSWORD]
+//                 [--max-key-size SIZE] [--max-value-size SIZE]
+//                 [--max-request-size SIZE]
+//                 [--log-level error|warn|info|debug] [--log-format text|json]
+//                 [--log-file PATH]
+//
+// With `--password`, the proxy authenticates to the backends with it for
+// its health checks. Clients authenticate with AUTH themselves, which is
+// passed on to the backends (see `proxy.rs`).
+//
+// The limits and the logging options are the same as the server's.
+
+use crate::limits;
+use crate::logging;
+
+pub struct Config {
+    pub host: String,
+    pub port: u16,
+    /// addresses of the backend servers
+    pub backends: Vec<String>,
+    /// password of the default user of the backends
+    pub password: Option<String>,
+    pub limits: limits::Limits,
+    pub log: logging::Options,
+}
+
+impl Config {
+    pub fn new() -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: 30170,
+            backends: vec![],
+            password: None,
+            limits: limits::Limits::new(),
+            log: logging::Options::new(),
+        }
+    }
+}
+
+pub const USAGE: &str = "usage: h2okv-proxy [--host HOST] [--port PORT] --backends HOST:PORT,...
+                   [--password PASSWORD]
+                   [--max-key-size SIZE] [--max-value-size SIZE]
+                   [--max-request-size SIZE]
+                   [--log-level error|warn|info|debug] [--log-format text|json]
+                   [--log-file PATH]";
+
+/// Parse options from the arguments, not including the program name
+pub fn from_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        let value = match iter.next() {
+            Some(x) => x,
+            None => return Err(format!("missing value of option {}", arg)),
+        };
+        match arg.as_str() {
+            "--host" => config.host = value.to_string(),
+            "--port" => match value.parse::<u16>() {
+                Ok(x) => config.port = x,
+                Err(_) => return Err(format!("invalid port: {}", value)),
+            },
+            "--backends" => {
+                config.backends = value
+                    .split(',')
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_string())
+                    .collect();
+            }
+            "--password" => config.password = Some(value.to_string()),
+            "--max-key-size" => config.limits.max_key_size = limits::parse_size(value)?,
+            "--max-value-size" => config.limits.max_value_size = limits::parse_size(value)?,
+            "--max-request-size" => config.limits.max_request_size = limits::parse_size(value)?,
+            "--log-level" => config.log.level = logging::parse_level(value)?,
+            "--log-format" => config.log.format = logging::parse_format(value)?,
+            "--log-file" => config.log.file = Some(value.to_string()),
+            _ => return Err(format!("unknown option: {}", arg)),
+        }
+    }
+    if config.backends.is_empty() {
+        return Err("--backends is required".to_string());
+    }
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_args;
+    use crate::logging;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(|x| x.to_string()).collect()
+    }
+
+    #[test]
+    fn test_from_args() {
+        let config = from_args(&args("--backends a:1,b:2")).unwrap();
+        assert_eq!(config.host, "127.0.0.1");
+        assert_eq!(config.port, 30170);
+        assert_eq!(config.backends, vec!["a:1", "b:2"]);
+
+        let config = from_args(&args("--port 30171 --backends a:1")).unwrap();
+        assert_eq!(config.port, 30171);
+
+        assert!(from_args(&args("")).is_err());
+        assert!(from_args(&args("--backends")).is_err());
+        assert!(from_args(&args("--port abc --backends a:1")).is_err());
+        assert!(from_args(&args("--foo bar")).is_err());
+
+        let line = "--backends a:1 --password pw --max-key-size 1K --log-level debug";
+        let config = from_args(&args(line)).unwrap();
+        assert_eq!(config.password, Some("pw".to_string()));
+        assert_eq!(config.limits.max_key_size, 1024);
+        assert_eq!(config.log.level, logging::Level::Debug);
+        assert!(from_args(&args("--backends a:1 --max-value-size 1T")).is_err());
+    }
+}
@@ -0,0 +1,69 @@
+extern crate byteorder;
+
+use std::env;
+use std::net::TcpListener;
+use std::process;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+mod backends;
+mod config;
+// shared with the server
+#[allow(dead_code)]
+#[path = "../error.rs"]
+mod error;
+#[allow(dead_code)]
+#[path = "../limits.rs"]
+mod limits;
+#[allow(unused)]
+#[path = "../logging.rs"]
+mod logging;
+mod proxy;
+mod tools;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let config = match config::from_args(&args) {
+        Ok(x) => x,
+        Err(e) => {
+            println!("{}", e);
+            println!("{}", config::USAGE);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = logging::init(&config.log) {
+        println!("{}", e);
+        process::exit(1);
+    }
+    limits::set(config.limits);
+
+    let arc_backends = Arc::new(Mutex::new(backends::Backends::new(
+        &config.backends,
+        config.password.clone(),
+    )));
+    backends::check(&arc_backends);
+    backends::start_checks(arc_backends.clone());
+
+    let addr = format!("{}:{}", config.host, config.port);
+    let listener = TcpListener::bind(&addr).expect("socket bind failed");
+    logging::info!("H2o KV proxy started at {}", &addr);
+    for connection in listener.incoming() {
+        match connection {
+            Ok(mut stream) => {
+                let _ = stream.set_nodelay(true);
+                let clone_backends = arc_backends.clone();
+                let peer = match stream.peer_addr() {
+                    Ok(x) => x.to_string(),
+                    Err(_) => "?".to_string(),
+                };
+                thread::spawn(move || {
+                    logging::new_connection(&peer);
+                    proxy::handle_client(&mut stream, clone_backends);
+                });
+            }
+            Err(e) => {
+                logging::warning!("accept failed: {:?}", e);
+            }
+        }
+    }
+}
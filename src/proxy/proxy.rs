@@ -0,0 +1,638 @@
+// Serving the clients of the proxy.
+//
+// Each client gets its own connections to the backends. GET, PUT and
+// DEL are sent to the backend owning the key, following MOVED and ASK
+// redirects, and the reply is sent back as is. SCAN is sent to all
+// backends, and the keys merged. MGET is split by backends, and the
+// values put back in the order of the keys. SLOTS is replied with an
+// empty map, so that clients send everything to the proxy. HELLO is
+// replied by the proxy, with its own features, and those of the backends
+// it passes on when all backends replying to HELLO have them.
+//
+// AUTH is sent to all backends, and once they all accept it, the client's
+// connections to the backends authenticate with it, so that the users and
+// their ACL are the backends' own. Other replies of the backends, e.g. Not
+// authenticated, are passed on as is.
+//
+// Queries over the limits of `--max-key-size` and the like are replied
+// with Too large, and replies of the backends with keys or values over
+// them are taken as failures, see `src/limits.rs` of the server. Keys of
+// 64 KiB or more are passed on, with their length in the long form: the
+// proxy has `bigkeys` with the backends, and with the clients sending it
+// in HELLO, see `src/large.rs` of the server.
+//
+// Other queries are replied with unknown command.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::backends::{self, Backends};
+use crate::error::Error;
+use crate::limits;
+use crate::logging;
+use crate::tools;
+
+const MAX_REDIRECTS: usize = 5;
+const FAILED: &[u8] = b"\x0c\x01";
+const INCOMPATIBLE: &[u8] = b"\x0c\x0a";
+/// Features of the backends the proxy passes on, in the order replied
+const PASSED_ON: [&str; 3] = ["gzip", "cipher", "type:string"];
+
+/// Connections of a client to the backends
+struct Conns {
+    streams: HashMap<String, TcpStream>,
+    /// AUTH query of the client, sent first on new connections
+    login: Option<Vec<u8>>,
+    /// the client has `bigkeys`, from HELLO
+    bigkeys: bool,
+    /// features of each backend, from HELLO on connecting
+    features: HashMap<String, Vec<String>>,
+}
+
+fn stream_of<'a>(addr: &str, conns: &'a mut Conns) -> Option<&'a mut TcpStream> {
+    if !conns.streams.contains_key(addr) {
+        let mut stream = backends::connect(addr, Duration::from_secs(1))?;
+        match backends::hello(&mut stream) {
+            Some(x) => conns.features.insert(addr.to_string(), x),
+            None => {
+                logging::warning!("HELLO to backend {} failed", addr);
+                return None;
+            }
+        };
+        if let Some(query) = &conns.login {
+            if !backends::login(query, &mut stream) {
+                logging::warning!("authentication to backend {} failed", addr);
+                return None;
+            }
+        }
+        conns.streams.insert(addr.to_string(), stream);
+    }
+    conns.streams.get_mut(addr)
+}
+
+/// Read `<vllen><vlen><value>` of a reply, returns None if the value is
+/// over the limit
+fn read_value(stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let (size, mut value) = tools::read_vlen(stream)?;
+    if size > limits::get().max_value_size as u64 {
+        logging::warning!("value of {} bytes from backend", size);
+        return None;
+    }
+    value.extend(tools::read_exact(stream, size as usize)?);
+    Some(value)
+}
+
+/// Read a reply of the command from a backend, returns the whole reply
+fn read_reply(cmd: u8, stream: &mut TcpStream) -> Option<Vec<u8>> {
+    let mut reply = tools::read_exact(stream, 2)?;
+    match reply[1] {
+        0x00 if cmd == 0x01 => {
+            reply.extend(tools::read_exact(stream, 1)?);
+            reply.extend(read_value(stream)?);
+        }
+        0x00 if cmd == 0x04 => {
+            let buf_count = tools::read_exact(stream, 5)?;
+            let count = tools::bytes_to_u32(&buf_count[1..]);
+            reply.extend(buf_count);
+            for _ in 0..count {
+                let buf_len = tools::read_exact(stream, 2)?;
+                let size = tools::read_len(stream, &buf_len, true)?;
+                if size > limits::get().max_key_size {
+                    logging::warning!("key of {} bytes from backend", size);
+                    return None;
+                }
+                reply.extend(tools::len_bytes(size));
+                reply.extend(tools::read_exact(stream, size)?);
+            }
+        }
+        0x00 if cmd == 0x15 => {
+            let buf_count = tools::read_exact(stream, 4)?;
+            let count = tools::bytes_to_u32(&buf_count);
+            reply.extend(buf_count);
+            for _ in 0..count {
+                let found = tools::read_exact(stream, 1)?;
+                reply.extend(&found);
+                if found[0] == 0x00 {
+                    continue;
+                }
+                reply.extend(read_value(stream)?);
+            }
+        }
+        0x05 => {
+            let buf_len = tools::read_exact(stream, 2)?;
+            let size = tools::bytes_to_u16(&buf_len) as usize;
+            reply.extend(buf_len);
+            reply.extend(tools::read_exact(stream, size)?);
+        }
+        0x06 | 0x07 => {
+            reply.extend(tools::read_exact(stream, 2)?);
+            let buf_len = tools::read_exact(stream, 2)?;
+            let size = tools::bytes_to_u16(&buf_len) as usize;
+            reply.extend(buf_len);
+            reply.extend(tools::read_exact(stream, size)?);
+        }
+        _ => {}
+    }
+    Some(reply)
+}
+
+/// Send the query to the backend and read its reply
+fn request(query: &[u8], addr: &str, asking: bool, conns: &mut Conns) -> Option<Vec<u8>> {
+    let stream = stream_of(addr, conns)?;
+    let mut buffer = vec![];
+    if asking {
+        buffer.extend(b"\x0c\x14\x00\x00\x00");
+    }
+    buffer.extend(query);
+    let result = stream.write_all(&buffer).ok().and_then(|_| {
+        if asking && tools::read_exact(stream, 2)? != b"\x0c\x00" {
+            return None;
+        }
+        read_reply(query[1], stream)
+    });
+    if result.is_none() {
+        // the stream may be out of sync, connect again next time
+        conns.streams.remove(addr);
+    }
+    result
+}
+
+/// Send a query of the key to the backend owning it, following MOVED
+/// and ASK redirects, returns the reply.
+fn forward(
+    key: &str,
+    query: &[u8],
+    conns: &mut Conns,
+    arc_backends: &Arc<Mutex<Backends>>,
+) -> Vec<u8> {
+    let mut addr = backends::owner(key, &arc_backends.lock().unwrap());
+    let mut asking = false;
+    for _ in 0..MAX_REDIRECTS {
+        if !backends::is_up(&addr, &arc_backends.lock().unwrap()) {
+            return FAILED.to_vec();
+        }
+        let reply = match request(query, &addr, asking, conns) {
+            Some(x) => x,
+            None => {
+                logging::warning!("query to backend {} failed", addr);
+                return FAILED.to_vec();
+            }
+        };
+        if reply[1] != 0x06 && reply[1] != 0x07 {
+            return reply;
+        }
+
+        let slot = tools::bytes_to_u16(&reply[2..4]);
+        let to = String::from_utf8_lossy(&reply[6..]).to_string();
+        if reply[1] == 0x06 {
+            backends::moved(slot, &to, &mut arc_backends.lock().unwrap());
+        }
+        asking = reply[1] == 0x07;
+        addr = to;
+    }
+    logging::warning!("too many redirects for key {:?}", key);
+    FAILED.to_vec()
+}
+
+/// Send SCAN to all backends and merge the keys, with keys of 64 KiB or
+/// more only for clients with `bigkeys`
+fn scan(query: &[u8], conns: &mut Conns, arc_backends: &Arc<Mutex<Backends>>) -> Vec<u8> {
+    let addrs = {
+        let backends = arc_backends.lock().unwrap();
+        let addrs = backends::owners(&backends);
+        if addrs.iter().any(|x| !backends::is_up(x, &backends)) {
+            return FAILED.to_vec();
+        }
+        addrs
+    };
+
+    // keys of migrating slots could be on both backends for a moment
+    let mut keys = BTreeSet::new();
+    for addr in addrs {
+        let reply = match request(query, &addr, false, conns) {
+            Some(x) => x,
+            None => return FAILED.to_vec(),
+        };
+        match reply[1] {
+            0x00 => {}
+            0x02 => continue,
+            _ => return reply,
+        }
+        let mut i = 7;
+        while i < reply.len() {
+            let mut klen = tools::bytes_to_u16(&reply[i..i + 2]) as usize;
+            i += 2;
+            if klen == tools::LONG_LEN as usize {
+                klen = tools::bytes_to_u32(&reply[i..i + 4]) as usize;
+                i += 4;
+            }
+            if conns.bigkeys || klen < tools::LONG_LEN as usize {
+                keys.insert(reply[i..i + klen].to_vec());
+            }
+            i += klen;
+        }
+    }
+
+    if keys.is_empty() {
+        return b"\x0c\x02".to_vec();
+    }
+    let mut reply = b"\x0c\x00\x00".to_vec();
+    reply.extend(&tools::u32_to_bytes(keys.len() as u32));
+    for key in keys {
+        reply.extend(&tools::len_bytes(key.len()));
+        reply.extend(key);
+    }
+    reply
+}
+
+/// Send AUTH to all backends, and authenticate new connections to them
+/// with it from now on, if they all accept it
+fn auth(query: &[u8], conns: &mut Conns, arc_backends: &Arc<Mutex<Backends>>) -> Vec<u8> {
+    let addrs = backends::owners(&arc_backends.lock().unwrap());
+    let previous = conns.login.take();
+    // connections authenticated as before are not kept
+    conns.streams.clear();
+    for addr in addrs {
+        let reply = request(query, &addr, false, conns).unwrap_or_else(|| FAILED.to_vec());
+        if reply != b"\x0c\x00" {
+            conns.streams.clear();
+            conns.login = previous;
+            return reply;
+        }
+    }
+    conns.login = Some(query.to_vec());
+    b"\x0c\x00".to_vec()
+}
+
+/// Split MGET content `<klen:2><key><klen:2><key>...` into keys
+fn parse_keys(content: &[u8]) -> Option<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < content.len() {
+        if i + 2 > content.len() {
+            return None;
+        }
+        let klen = tools::bytes_to_u16(&content[i..i + 2]) as usize;
+        i += 2;
+        if i + klen > content.len() {
+            return None;
+        }
+        match str::from_utf8(&content[i..i + klen]) {
+            Ok(x) => keys.push(x.to_string()),
+            Err(_) => return None,
+        }
+        i += klen;
+    }
+    Some(keys)
+}
+
+fn mget_query(keys: &[&str]) -> Vec<u8> {
+    let mut content: Vec<u8> = vec![];
+    for key in keys {
+        content.extend(&tools::u16_to_bytes(key.len() as u16));
+        content.extend(key.as_bytes());
+    }
+    let mut query = b"\x0c\x15\x00".to_vec();
+    query.extend(&tools::len_bytes(content.len()));
+    query.extend(content);
+    query
+}
+
+/// Split an MGET reply into its values, `\x00` or `\x01<vllen><vlen><value>`,
+/// None if it is truncated
+fn split_values(reply: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let mut values = vec![];
+    let mut i = 6;
+    while i < reply.len() {
+        if reply[i] == 0x00 {
+            values.push(vec![0x00]);
+            i += 1;
+            continue;
+        }
+        let llen = *reply.get(i + 1)? as usize;
+        if llen > 8 {
+            return None;
+        }
+        let size = tools::bytes_to_u64(reply.get(i + 2..i + 2 + llen)?) as usize;
+        let end = (i + 2 + llen).checked_add(size)?;
+        values.push(reply.get(i..end)?.to_vec());
+        i = end;
+    }
+    Some(values)
+}
+
+/// Get the key with GET, as a value of an MGET reply
+fn get_value(key: &str, conns: &mut Conns, arc_backends: &Arc<Mutex<Backends>>) -> Option<Vec<u8>> {
+    let mut query = b"\x0c\x01\x00".to_vec();
+    query.extend(&tools::len_bytes(key.len()));
+    query.extend(key.as_bytes());
+    let reply = forward(key, &query, conns, arc_backends);
+    match reply[1] {
+        0x00 => {
+            let mut value = vec![0x01];
+            value.extend(&reply[3..]);
+            Some(value)
+        }
+        0x02 => Some(vec![0x00]),
+        _ => None,
+    }
+}
+
+/// Send MGET to the backends owning the keys, and put the values back
+/// in order. Keys of a backend replying a redirect are got one by one.
+fn mget(content: &[u8], conns: &mut Conns, arc_backends: &Arc<Mutex<Backends>>) -> Vec<u8> {
+    let keys = match parse_keys(content) {
+        Some(x) => x,
+        None => return FAILED.to_vec(),
+    };
+    let mut groups: BTreeMap<String, Vec<usize>> = BTreeMap::new();
+    {
+        let backends = arc_backends.lock().unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            let addr = backends::owner(key, &backends);
+            if !backends::is_up(&addr, &backends) {
+                return FAILED.to_vec();
+            }
+            groups.entry(addr).or_default().push(i);
+        }
+    }
+
+    let mut values: Vec<Vec<u8>> = vec![vec![]; keys.len()];
+    for (addr, indexes) in groups {
+        let group: Vec<&str> = indexes.iter().map(|i| keys[*i].as_str()).collect();
+        let reply = match request(&mget_query(&group), &addr, false, conns) {
+            Some(x) => x,
+            None => return FAILED.to_vec(),
+        };
+        match reply[1] {
+            0x00 => {
+                let split = match split_values(&reply) {
+                    Some(x) if x.len() == indexes.len() => x,
+                    _ => return FAILED.to_vec(),
+                };
+                for (i, value) in indexes.iter().zip(split) {
+                    values[*i] = value;
+                }
+            }
+            0x06 | 0x07 => {
+                for i in indexes {
+                    match get_value(&keys[i], conns, arc_backends) {
+                        Some(x) => values[i] = x,
+                        None => return FAILED.to_vec(),
+                    }
+                }
+            }
+            _ => return reply,
+        }
+    }
+
+    let mut reply = b"\x0c\x00".to_vec();
+    reply.extend(&tools::u32_to_bytes(keys.len() as u32));
+    for value in values {
+        reply.extend(value);
+    }
+    reply
+}
+
+fn closed() -> Error {
+    Error::Io(io::Error::from(io::ErrorKind::UnexpectedEof))
+}
+
+/// Read the Length of the query, or the KLen of a PUT-like query, and
+/// the content of that length up to `max`. Returns the length bytes for
+/// the backends, in the long form from `LONG_LEN`, and the content.
+fn read_sized(
+    data: &[u8],
+    stream: &mut TcpStream,
+    max: usize,
+    bigkeys: bool,
+) -> Result<(Vec<u8>, Vec<u8>), Error> {
+    let size = tools::read_len(stream, &data[3..], bigkeys).ok_or_else(closed)?;
+    if size > max {
+        return Err(Error::TooLarge(format!("query of {} bytes", size)));
+    }
+    let content = tools::read_exact(stream, size).ok_or_else(closed)?;
+    Ok((tools::len_bytes(size), content))
+}
+
+/// Read the rest of a PUT-like query: `<key-len:2><key><vllen><vlen><value>`
+///
+/// Returns the key and the rest of the query as is, from the key length.
+fn read_key_value(
+    data: &[u8],
+    stream: &mut TcpStream,
+    bigkeys: bool,
+) -> Result<(String, Vec<u8>), Error> {
+    let limits = limits::get();
+    let (mut buffer, buf_key) = read_sized(data, stream, limits.max_key_size, bigkeys)?;
+    let key = String::from_utf8(buf_key).map_err(|_| Error::InvalidUtf8)?;
+    buffer.extend(key.as_bytes());
+
+    let buf_vllen = tools::read_exact(stream, 1).ok_or_else(closed)?;
+    if buf_vllen[0] > 8 {
+        let e = format!("value length of {} bytes", buf_vllen[0]);
+        return Err(Error::InvalidQuery(e));
+    }
+    let buf_vlen = tools::read_exact(stream, buf_vllen[0] as usize).ok_or_else(closed)?;
+    let vlen = tools::bytes_to_u64(&buf_vlen);
+    if limits::exceeds(key.len(), vlen, limits) {
+        return Err(Error::TooLarge(format!("value of {} bytes", vlen)));
+    }
+    buffer.extend(buf_vllen);
+    buffer.extend(buf_vlen);
+    buffer.extend(tools::read_exact(stream, vlen as usize).ok_or_else(closed)?);
+    Ok((key, buffer))
+}
+
+pub fn handle_client(stream: &mut TcpStream, arc_backends: Arc<Mutex<Backends>>) {
+    let mut conns = Conns {
+        streams: HashMap::new(),
+        login: None,
+        bigkeys: false,
+        features: HashMap::new(),
+    };
+    while let Some(data) = tools::read_exact(stream, 5) {
+        if data[0] != 0x0c {
+            logging::warning!("invalid query header");
+            break;
+        }
+        // GZIP and ciphered values of GET and PUT are left to the backends
+        if data[2] > 0x02 {
+            logging::warning!("unsupported flag: {}", data[2]);
+            break;
+        }
+
+        let reply = match serve(&data, stream, &mut conns, &arc_backends) {
+            Ok(x) => x,
+            Err(e) => {
+                // the rest of the query is not read, so disconnect
+                logging::warning!("{}", e);
+                if let Some(status) = e.status() {
+                    let _ = stream.write_all(&[0x0c, status]);
+                }
+                break;
+            }
+        };
+        if stream.write_all(&reply).is_err() || reply.starts_with(INCOMPATIBLE) {
+            break;
+        }
+    }
+    logging::info!("client disconnected");
+}
+
+/// Read the rest of the query and serve it, returns the reply
+fn serve(
+    data: &[u8],
+    stream: &mut TcpStream,
+    conns: &mut Conns,
+    arc_backends: &Arc<Mutex<Backends>>,
+) -> Result<Vec<u8>, Error> {
+    let mut query = data[..3].to_vec();
+    let reply = match data[1] {
+        0x02 | 0x08 | 0x16 => {
+            let (key, rest) = read_key_value(data, stream, conns.bigkeys)?;
+            query.extend(rest);
+            match data[1] {
+                0x02 => forward(&key, &query, conns, arc_backends),
+                0x16 => auth(&query, conns, arc_backends),
+                _ => b"\x0c\xff".to_vec(),
+            }
+        }
+        _ => {
+            let max = match data[1] {
+                0x01 | 0x03 => limits::get().max_key_size,
+                _ => limits::get().max_request_size,
+            };
+            let (len_bytes, content) = read_sized(data, stream, max, conns.bigkeys)?;
+            query.extend(len_bytes);
+            query.extend(&content);
+            match data[1] {
+                0x01 | 0x03 => match str::from_utf8(&content) {
+                    Ok(key) => forward(key, &query, conns, arc_backends),
+                    Err(_) => FAILED.to_vec(),
+                },
+                0x04 => scan(&query, conns, arc_backends),
+                0x11 => b"\x0c\x00\x00\x00".to_vec(),
+                0x15 => mget(&content, conns, arc_backends),
+                0x19 => hello(&content, conns, arc_backends),
+                _ => b"\x0c\xff".to_vec(),
+            }
+        }
+    };
+    Ok(reply)
+}
+
+/// Features of the proxy: those of `PASSED_ON` all the backends have,
+/// `auth` when it authenticates to them, and its own `mget` and `bigkeys`.
+/// Nothing is passed on when no backend replied to HELLO.
+fn features(backends: &[&Vec<String>], auth: bool) -> Vec<&'static str> {
+    let has = |feature: &str| {
+        !backends.is_empty() && backends.iter().all(|x| x.iter().any(|y| y == feature))
+    };
+    let mut result: Vec<&str> = vec![];
+    for feature in &PASSED_ON[..2] {
+        if has(feature) {
+            result.push(feature);
+        }
+    }
+    if auth {
+        result.push("auth");
+    }
+    result.extend(&["mget", "bigkeys"]);
+    if has(PASSED_ON[2]) {
+        result.push("type:string");
+    }
+    result
+}
+
+/// Reply HELLO with protocol version 1, the only one for now, and the
+/// features of the proxy, connecting to the backends to learn theirs
+fn hello(content: &[u8], conns: &mut Conns, arc_backends: &Arc<Mutex<Backends>>) -> Vec<u8> {
+    let content = str::from_utf8(content).unwrap_or_default();
+    let version = content.split_whitespace().next().map(|x| x.parse::<u8>());
+    conns.bigkeys = content.split_whitespace().any(|x| x == "bigkeys");
+    let (status, text) = match version {
+        Some(Ok(0)) => (
+            INCOMPATIBLE,
+            "unsupported protocol version 0, supported 1-1".to_string(),
+        ),
+        Some(Ok(_)) => {
+            let (addrs, auth) = {
+                let backends = arc_backends.lock().unwrap();
+                (
+                    backends::owners(&backends),
+                    backends::has_password(&backends),
+                )
+            };
+            let mut replied = vec![];
+            for addr in addrs {
+                if stream_of(&addr, conns).is_some() {
+                    replied.push(addr);
+                }
+            }
+            let of_backends: Vec<&Vec<String>> = replied
+                .iter()
+                .filter_map(|x| conns.features.get(x))
+                .collect();
+            let text = format!("1 {}", features(&of_backends, auth).join(" "));
+            (&b"\x0c\x00"[..], text)
+        }
+        _ => (FAILED, "invalid hello".to_string()),
+    };
+    let mut reply = status.to_vec();
+    reply.extend(&tools::u16_to_bytes(text.len() as u16));
+    reply.extend(text.as_bytes());
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{features, mget_query, parse_keys, split_values};
+
+    #[test]
+    fn test_mget_query() {
+        let query = mget_query(&["foo", "lang"]);
+        assert_eq!(query, b"\x0c\x15\x00\x0b\x00\x03\x00foo\x04\x00lang");
+        let keys = parse_keys(&query[5..]).unwrap();
+        assert_eq!(keys, vec!["foo", "lang"]);
+        assert_eq!(parse_keys(b"\x03\x00fo"), None);
+    }
+
+    #[test]
+    fn test_features() {
+        let full: Vec<String> = "gzip cipher auth mget bigkeys type:string"
+            .split(' ')
+            .map(|x| x.to_string())
+            .collect();
+        let plain = vec!["gzip".to_string(), "type:string".to_string()];
+        assert_eq!(
+            features(&[&full], true),
+            ["gzip", "cipher", "auth", "mget", "bigkeys", "type:string"]
+        );
+        assert_eq!(
+            features(&[&full, &plain], false),
+            ["gzip", "mget", "bigkeys", "type:string"]
+        );
+        assert_eq!(features(&[], true), ["auth", "mget", "bigkeys"]);
+    }
+
+    #[test]
+    fn test_split_values() {
+        let reply = b"\x0c\x00\x03\x00\x00\x00\x01\x01\x03bar\x00\x01\x01\x04Rust";
+        let values = split_values(reply).unwrap();
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[0], b"\x01\x01\x03bar");
+        assert_eq!(values[1], b"\x00");
+        assert_eq!(values[2], b"\x01\x01\x04Rust");
+        assert_eq!(split_values(b"\x0c\x00\x01\x00\x00\x00\x01"), None);
+        assert_eq!(split_values(b"\x0c\x00\x01\x00\x00\x00\x01\x09"), None);
+        assert_eq!(
+            split_values(b"\x0c\x00\x01\x00\x00\x00\x01\x01\x04bar"),
+            None
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::io::{Cursor, Read};
+use std::net::TcpStream;
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+/// Length telling the actual length follows as 4 bytes
+pub const LONG_LEN: u16 = 0xFFFF;
+
+pub fn bytes_to_u64(bytes: &[u8]) -> u64 {
+    let mut buf = [0; 8];
+    for (i, x) in bytes.iter().enumerate() {
+        buf[i] = *x;
+    }
+    let mut rdr = Cursor::new(&buf);
+    rdr.read_u64::<LittleEndian>().expect("read_u64 error")
+}
+
+pub fn bytes_to_u32(bytes: &[u8]) -> u32 {
+    let mut buf = [0; 4];
+    for (i, x) in bytes.iter().enumerate() {
+        buf[i] = *x;
+    }
+    let mut rdr = Cursor::new(&buf);
+    rdr.read_u32::<LittleEndian>().expect("read_u32 error")
+}
+
+pub fn bytes_to_u16(bytes: &[u8]) -> u16 {
+    let mut buf = [0; 2];
+    for (i, x) in bytes.iter().enumerate() {
+        buf[i] = *x;
+    }
+    let mut rdr = Cursor::new(&buf);
+    rdr.read_u16::<LittleEndian>().expect("read_u16 error")
+}
+
+pub fn u32_to_bytes(n: u32) -> [u8; 4] {
+    let mut buffer = vec![];
+    buffer.write_u32::<LittleEndian>(n).unwrap();
+    let mut array = [0; 4];
+    array.copy_from_slice(&buffer);
+    array
+}
+
+pub fn u16_to_bytes(n: u16) -> [u8; 2] {
+    let mut buffer = vec![];
+    buffer.write_u16::<LittleEndian>(n).unwrap();
+    let mut array = [0; 2];
+    array.copy_from_slice(&buffer);
+    array
+}
+
+/// Bytes of a length of 2 bytes, or of `\xFF\xFF` and 4 bytes from
+/// `LONG_LEN` on
+pub fn len_bytes(len: usize) -> Vec<u8> {
+    if len < LONG_LEN as usize {
+        return u16_to_bytes(len as u16).to_vec();
+    }
+    let mut bytes = u16_to_bytes(LONG_LEN).to_vec();
+    bytes.extend(&u32_to_bytes(len as u32));
+    bytes
+}
+
+/// Read `size` bytes, which are only allocated as they come in
+pub fn read_exact(stream: &mut TcpStream, size: usize) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match Read::by_ref(stream)
+        .take(size as u64)
+        .read_to_end(&mut buffer)
+    {
+        Ok(n) if n == size => Some(buffer),
+        _ => None,
+    }
+}
+
+/// Read a length of 2 bytes, or of 4 bytes after `\xFF\xFF` with
+/// `bigkeys`, see `src/large.rs` of the server
+pub fn read_len(stream: &mut TcpStream, buf_len: &[u8], bigkeys: bool) -> Option<usize> {
+    let len = bytes_to_u16(buf_len);
+    if len != LONG_LEN || !bigkeys {
+        return Some(len as usize);
+    }
+    let buf_long = read_exact(stream, 4)?;
+    Some(bytes_to_u32(&buf_long) as usize)
+}
+
+/// Read a value length `<vllen><vlen>` of at most 8 bytes, returns the
+/// length and its bytes
+pub fn read_vlen(stream: &mut TcpStream) -> Option<(u64, Vec<u8>)> {
+    let mut bytes = read_exact(stream, 1)?;
+    if bytes[0] > 8 {
+        return None;
+    }
+    bytes.extend(read_exact(stream, bytes[0] as usize)?);
+    Some((bytes_to_u64(&bytes[1..]), bytes))
+}
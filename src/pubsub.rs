@@ -0,0 +1,157 @@
+// Publish/Subscribe messaging, the way Redis does it[0]: a connection
+// subscribes to channels (exact names) or patterns (glob-style, `*` and
+// `?`), then every message published to a matching channel is pushed
+// to it.
+//
+// Each subscribed connection owns a writer thread which drains a
+// `push::Queue`, so a slow subscriber never blocks the publisher.
+// Here we only keep those senders, indexed by channel/pattern and by
+// the connection id given by the server.
+//
+// [0] https://redis.io/topics/pubsub
+
+use std::collections::HashMap;
+
+use crate::push;
+use crate::tools;
+
+pub struct PubSub {
+    channels: HashMap<String, HashMap<usize, push::Queue>>,
+    patterns: HashMap<String, HashMap<usize, push::Queue>>,
+}
+
+impl PubSub {
+    pub fn new() -> PubSub {
+        PubSub {
+            channels: HashMap::new(),
+            patterns: HashMap::new(),
+        }
+    }
+}
+
+/// Subscribe connection `id` to the channel, returns how many channels
+/// and patterns the connection is subscribed to now.
+pub fn subscribe(channel: &str, id: usize, tx: &push::Queue, ps: &mut PubSub) -> usize {
+    ps.channels
+        .entry(channel.to_string())
+        .or_default()
+        .insert(id, tx.clone());
+    count(id, ps)
+}
+
+/// Subscribe connection `id` to all channels matching the pattern.
+pub fn psubscribe(pattern: &str, id: usize, tx: &push::Queue, ps: &mut PubSub) -> usize {
+    ps.patterns
+        .entry(pattern.to_string())
+        .or_default()
+        .insert(id, tx.clone());
+    count(id, ps)
+}
+
+/// Remove the channel or pattern subscription named `name` of the
+/// connection. An empty name removes all its subscriptions.
+pub fn unsubscribe(name: &str, id: usize, ps: &mut PubSub) -> usize {
+    for map in [&mut ps.channels, &mut ps.patterns].iter_mut() {
+        for (key, subscribers) in map.iter_mut() {
+            if name.is_empty() || key == name {
+                subscribers.remove(&id);
+            }
+        }
+        map.retain(|_, subscribers| !subscribers.is_empty());
+    }
+    count(id, ps)
+}
+
+/// How many channels and patterns the connection subscribed to
+pub fn count(id: usize, ps: &PubSub) -> usize {
+    let channels = ps.channels.values().filter(|x| x.contains_key(&id));
+    let patterns = ps.patterns.values().filter(|x| x.contains_key(&id));
+    channels.count() + patterns.count()
+}
+
+/// Push the message to all subscribers of the channel, returns the
+/// number of connections that received it.
+///
+/// Subscribers whose writer thread has gone away are dropped here.
+pub fn publish(channel: &str, message: &[u8], ps: &mut PubSub) -> usize {
+    let mut received = 0;
+    if let Some(subscribers) = ps.channels.get_mut(channel) {
+        let frame = message_frame("", channel, message);
+        subscribers.retain(|_, tx| push::send(frame.clone(), tx));
+        received += subscribers.len();
+    }
+    for (pattern, subscribers) in ps.patterns.iter_mut() {
+        if !tools::glob_match(pattern, channel) {
+            continue;
+        }
+        let frame = message_frame(pattern, channel, message);
+        subscribers.retain(|_, tx| push::send(frame.clone(), tx));
+        received += subscribers.len();
+    }
+    ps.channels.retain(|_, subscribers| !subscribers.is_empty());
+    ps.patterns.retain(|_, subscribers| !subscribers.is_empty());
+    received
+}
+
+/// Build the frame pushed to subscribers:
+/// `"\x0C\x10<pattern-len:2><pattern><channel-len:2><channel><msg-len-byte><msg-len-bytes><msg>"`
+/// where pattern is empty when the subscription is on the exact channel.
+pub fn message_frame(pattern: &str, channel: &str, message: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0x0c, 0x10];
+    frame.extend(&tools::u16_to_bytes(pattern.len() as u16));
+    frame.extend(pattern.as_bytes());
+    frame.extend(&tools::u16_to_bytes(channel.len() as u16));
+    frame.extend(channel.as_bytes());
+    let (count, bytes) = tools::u64_to_bytes(message.len() as u64);
+    frame.push(count);
+    frame.extend(&bytes);
+    frame.extend(message);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, message_frame, psubscribe, publish, subscribe, unsubscribe, PubSub};
+    use crate::push;
+
+    #[test]
+    fn test_pubsub() {
+        let mut ps = PubSub::new();
+        let (tx1, rx1) = push::queue();
+        let (tx2, rx2) = push::queue();
+        assert_eq!(publish("news", b"hi", &mut ps), 0);
+
+        assert_eq!(subscribe("news", 1, &tx1, &mut ps), 1);
+        assert_eq!(subscribe("sport", 1, &tx1, &mut ps), 2);
+        assert_eq!(psubscribe("n*", 2, &tx2, &mut ps), 1);
+
+        assert_eq!(publish("news", b"hi", &mut ps), 2);
+        assert_eq!(rx1.try_next().unwrap(), message_frame("", "news", b"hi"));
+        assert_eq!(rx2.try_next().unwrap(), message_frame("n*", "news", b"hi"));
+        assert_eq!(publish("sport", b"go", &mut ps), 1);
+        assert_eq!(rx1.try_next().unwrap(), message_frame("", "sport", b"go"));
+        assert!(rx2.try_next().is_none());
+
+        assert_eq!(unsubscribe("news", 1, &mut ps), 1);
+        assert_eq!(publish("news", b"hi", &mut ps), 1);
+        assert_eq!(unsubscribe("", 1, &mut ps), 0);
+        assert_eq!(count(2, &ps), 1);
+
+        // subscribers gone away are dropped
+        drop(rx2);
+        assert_eq!(publish("news", b"hi", &mut ps), 0);
+        assert_eq!(count(2, &ps), 0);
+    }
+
+    #[test]
+    fn test_message_frame() {
+        assert_eq!(
+            message_frame("", "ch", b"abc"),
+            b"\x0c\x10\x00\x00\x02\x00ch\x01\x03abc".to_vec()
+        );
+        assert_eq!(
+            message_frame("c?", "ch", b""),
+            b"\x0c\x10\x02\x00c?\x02\x00ch\x00".to_vec()
+        );
+    }
+}
@@ -0,0 +1,162 @@
+// Queues of the connections in push mode, i.e. subscribed to channels,
+// watching keys or following changes, see `server.rs`.
+//
+// Publishers put frames into the queue of the connection without waiting,
+// and its writer thread sends them out. A queue holds at most `QUEUE_SIZE`
+// frames, and no more frames are queued when it's over `QUEUE_BYTES`: a
+// connection which doesn't read what is pushed to it fast enough gets
+// lagging, is dropped by the publishers, and is disconnected by its
+// writer thread, instead of making the memory of the server grow without
+// limit.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::changes;
+
+/// Frames queued per connection, the changes of CHANGES & SYNC included
+pub const QUEUE_SIZE: usize = changes::LOG_SIZE + 1024;
+
+/// Bytes queued per connection before it's lagging
+pub const QUEUE_BYTES: usize = 64 * 1024 * 1024;
+
+/// How long the writer thread waits for the client to read
+pub const WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Clone)]
+pub struct Queue {
+    tx: SyncSender<Vec<u8>>,
+    state: Arc<State>,
+}
+
+/// The end of the queue drained by the writer thread, which ends when the
+/// queue is dropped by all, or gets lagging
+pub struct Frames {
+    rx: Receiver<Vec<u8>>,
+    state: Arc<State>,
+}
+
+struct State {
+    bytes: AtomicUsize,
+    lagging: AtomicBool,
+}
+
+impl Iterator for Frames {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Vec<u8>> {
+        if self.state.lagging.load(Ordering::SeqCst) {
+            return None;
+        }
+        let frame = self.rx.recv().ok()?;
+        self.state.bytes.fetch_sub(frame.len(), Ordering::SeqCst);
+        Some(frame)
+    }
+}
+
+impl Frames {
+    /// The next frame if any, without waiting
+    #[cfg(test)]
+    pub fn try_next(&self) -> Option<Vec<u8>> {
+        let frame = self.rx.try_recv().ok()?;
+        self.state.bytes.fetch_sub(frame.len(), Ordering::SeqCst);
+        Some(frame)
+    }
+}
+
+/// A new queue, and its end for the writer thread
+pub fn queue() -> (Queue, Frames) {
+    let (tx, rx) = sync_channel(QUEUE_SIZE);
+    let state = Arc::new(State {
+        bytes: AtomicUsize::new(0),
+        lagging: AtomicBool::new(false),
+    });
+    let frames = Frames {
+        rx,
+        state: state.clone(),
+    };
+    (Queue { tx, state }, frames)
+}
+
+/// Queue the frame, returns false if the connection has gone away or is
+/// lagging, and should be dropped.
+pub fn send(frame: Vec<u8>, queue: &Queue) -> bool {
+    if queue.state.bytes.load(Ordering::SeqCst) > QUEUE_BYTES {
+        queue.state.lagging.store(true, Ordering::SeqCst);
+    }
+    enqueue(frame, queue)
+}
+
+/// Queue the changes to push before the new ones to a follower. They are
+/// already in memory in the change log, so only `QUEUE_SIZE` applies.
+pub fn send_backlog(frames: Vec<Vec<u8>>, queue: &Queue) -> bool {
+    frames.into_iter().all(|x| enqueue(x, queue))
+}
+
+fn enqueue(frame: Vec<u8>, queue: &Queue) -> bool {
+    if queue.state.lagging.load(Ordering::SeqCst) {
+        return false;
+    }
+    // counted first, since the writer thread may take it right away
+    let size = frame.len();
+    queue.state.bytes.fetch_add(size, Ordering::SeqCst);
+    let result = queue.tx.try_send(frame);
+    if result.is_err() {
+        queue.state.bytes.fetch_sub(size, Ordering::SeqCst);
+    }
+    match result {
+        Ok(_) => true,
+        Err(TrySendError::Full(_)) => {
+            queue.state.lagging.store(true, Ordering::SeqCst);
+            false
+        }
+        Err(TrySendError::Disconnected(_)) => false,
+    }
+}
+
+/// If the queue got full, and the client is to be disconnected
+pub fn is_lagging(frames: &Frames) -> bool {
+    frames.state.lagging.load(Ordering::SeqCst)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{is_lagging, queue, send, send_backlog, QUEUE_BYTES, QUEUE_SIZE};
+
+    #[test]
+    fn test_queue() {
+        let (q, mut frames) = queue();
+        assert!(send(b"a".to_vec(), &q));
+        assert_eq!(frames.next(), Some(b"a".to_vec()));
+        assert!(send_backlog(vec![b"x".to_vec(); QUEUE_SIZE], &q));
+        assert!(!is_lagging(&frames));
+        assert!(!send(b"x".to_vec(), &q));
+        assert!(is_lagging(&frames));
+
+        // once lagging, nothing is queued or sent out anymore
+        assert!(!send(b"x".to_vec(), &q));
+        assert_eq!(frames.next(), None);
+
+        let (q, frames) = queue();
+        drop(frames);
+        assert!(!send(b"x".to_vec(), &q));
+    }
+
+    #[test]
+    fn test_queue_bytes() {
+        let (q, frames) = queue();
+        // a frame larger than the limit is still queued alone
+        assert!(send(vec![0; QUEUE_BYTES + 1], &q));
+        assert!(!send(b"x".to_vec(), &q));
+        assert!(is_lagging(&frames));
+
+        let (q, frames) = queue();
+        assert!(send(vec![0; QUEUE_BYTES], &q));
+        assert!(send(b"x".to_vec(), &q));
+        assert_eq!(frames.try_next().unwrap().len(), QUEUE_BYTES);
+        assert!(send(b"y".to_vec(), &q));
+        assert!(!is_lagging(&frames));
+    }
+}
@@ -0,0 +1,1921 @@
+// Raft consensus[0], for the cluster mode.
+//
+// In cluster mode, every node is started with the same list of voting
+// members `ID=HOST:PORT` (the addresses the nodes serve clients on), and
+// PUT/DEL queries are only accepted by the leader: they are appended to
+// the Raft log, replicated to the other nodes, and acknowledged only
+// after being committed (stored by a majority) and applied to the DB.
+// Followers reply with the address of the leader instead.
+//
+// Nodes talk to each other with the RAFT query (`\x0F`) on the same port
+// as clients, each message is one of `Message` below. The Raft state,
+// i.e. the current term, the vote, the log and the latest snapshot, is
+// saved into the file `h2okv.raft` under current working directory
+// before replying to any message, and loaded when restarting. With a
+// data key, the file is encrypted (see `src/encryption.rs`).
+//
+// The file is the whole state, followed by records of what changed since
+// then, i.e. the term and vote, or entries appended to the log, which are
+// appended and synced to disk one write each. It is written anew, into a
+// temporary file renamed over it, only when a snapshot is made or got,
+// or after `MAX_RECORDS` records. A record cut short at the end, by a
+// crash while appending it, was never replied to and is ignored, but a
+// file which cannot be read otherwise is an error, instead of starting
+// over and voting again in terms already voted in.
+//
+// Log compaction: when more than `snapshot_entries` entries are applied
+// since the latest snapshot, the DB is dumped (see `src/persistence.rs`)
+// as the new snapshot, and the entries before are dropped. Followers too
+// far behind get the snapshot with InstallSnapshot, in chunks of
+// `SNAPSHOT_CHUNK` bytes, and AppendEntries has up to `MAX_BATCH` bytes
+// of entries, so that messages stay within `max_message_size()` however
+// large the DB is.
+//
+// Membership changes are done one server at a time (section 4.1 of the
+// Raft dissertation[1]): a configuration entry with the new members is
+// appended to the log, and takes effect as soon as it's in the log. Only
+// one change can be in progress. A new node is started with an empty
+// member list, so it never starts elections before being added.
+//
+// [0] https://raft.github.io/raft.pdf
+// [1] https://github.com/ongardie/dissertation
+
+use std::cmp;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::auth;
+use crate::encryption;
+use crate::error;
+use crate::limits;
+use crate::logging;
+use crate::persistence;
+use crate::store;
+use crate::tools;
+
+const TICK: Duration = Duration::from_millis(20);
+const HEARTBEAT: Duration = Duration::from_millis(100);
+/// election timeout is random between this and twice of it
+const ELECTION_TIMEOUT_MS: u64 = 500;
+const RPC_TIMEOUT: Duration = Duration::from_millis(1000);
+const PROPOSE_TIMEOUT: Duration = Duration::from_secs(5);
+/// max bytes of entries sent in one AppendEntries, which has at least
+/// one entry though
+const MAX_BATCH: usize = 1024 * 1024;
+/// bytes of the snapshot sent in one InstallSnapshot
+const SNAPSHOT_CHUNK: usize = 1024 * 1024;
+/// bytes of a message other than entries or the snapshot, e.g. peers
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+/// max number of records appended to the state file before writing it anew
+const MAX_RECORDS: usize = 4096;
+
+/// Voting members of the cluster, node ID to address
+pub type Peers = BTreeMap<u64, String>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    Noop,
+    Put(String, String),
+    Delete(String),
+    Config(Peers),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub term: u64,
+    pub command: Command,
+}
+
+#[derive(Debug, PartialEq)]
+enum Message {
+    RequestVote {
+        term: u64,
+        candidate: u64,
+        last_index: u64,
+        last_term: u64,
+    },
+    VoteReply {
+        term: u64,
+        granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        leader: u64,
+        prev_index: u64,
+        prev_term: u64,
+        commit: u64,
+        entries: Vec<Entry>,
+    },
+    AppendReply {
+        term: u64,
+        success: bool,
+        /// last index matching the leader on success, or a hint of where
+        /// the logs may match on failure
+        last_index: u64,
+    },
+    /// a chunk of the snapshot from `offset`, the last one if `done`
+    InstallSnapshot {
+        term: u64,
+        leader: u64,
+        last_index: u64,
+        last_term: u64,
+        peers: Peers,
+        offset: u64,
+        data: Vec<u8>,
+        done: bool,
+    },
+    SnapshotReply {
+        term: u64,
+        /// offset of the next chunk, i.e. the bytes got so far
+        next: u64,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// with the address of the leader, if known
+    NotLeader(Option<String>),
+    Failed,
+}
+
+struct State {
+    id: u64,
+    role: Role,
+    term: u64,
+    voted_for: Option<u64>,
+    leader: Option<u64>,
+    /// the log: snapshot, then entries from index `snapshot_index + 1`
+    snapshot_index: u64,
+    snapshot_term: u64,
+    snapshot_peers: Peers,
+    snapshot: Vec<u8>,
+    entries: Vec<Entry>,
+    commit_index: u64,
+    last_applied: u64,
+    /// snapshot got from leader, for the applier to load into DB
+    pending_snapshot: Option<Vec<u8>>,
+    /// chunks of a snapshot being got from leader: its last index and
+    /// term, and the bytes so far
+    incoming_snapshot: Option<(u64, u64, Vec<u8>)>,
+    snapshot_entries: usize,
+    election_deadline: Instant,
+    last_contact: Option<Instant>,
+    votes: HashSet<u64>,
+    // for leader only
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    inflight: HashSet<u64>,
+    failed: HashSet<u64>,
+    last_sent: HashMap<u64, Instant>,
+    /// snapshot being sent to the peer: its last index, and the bytes
+    /// the peer got so far
+    snapshot_sent: HashMap<u64, (u64, u64)>,
+    /// entries proposed on this node: index -> (term, result) once applied
+    waiting: HashMap<u64, Option<(u64, bool)>>,
+    state_file: Option<String>,
+    /// what the state file has, `None` to write it anew
+    saved: Option<Saved>,
+}
+
+/// The state in the file, to only append what changed since
+struct Saved {
+    term: u64,
+    voted_for: Option<u64>,
+    snapshot_index: u64,
+    /// entries in the file, the same as in memory
+    entries: usize,
+    records: usize,
+}
+
+pub struct Node {
+    state: Mutex<State>,
+    /// notified when commit index, applied index or role changed
+    changed: Condvar,
+    db: Arc<Mutex<store::DB>>,
+}
+
+fn election_timeout() -> Duration {
+    let random = RandomState::new().build_hasher().finish();
+    Duration::from_millis(ELECTION_TIMEOUT_MS + random % ELECTION_TIMEOUT_MS)
+}
+
+fn is_majority(votes: &HashSet<u64>, peers: &Peers) -> bool {
+    let count = peers.keys().filter(|x| votes.contains(x)).count();
+    count * 2 > peers.len()
+}
+
+impl State {
+    fn new(id: u64, peers: Peers, snapshot_entries: usize) -> State {
+        State {
+            id,
+            role: Role::Follower,
+            term: 0,
+            voted_for: None,
+            leader: None,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot_peers: peers,
+            snapshot: vec![],
+            entries: vec![],
+            commit_index: 0,
+            last_applied: 0,
+            pending_snapshot: None,
+            incoming_snapshot: None,
+            snapshot_entries,
+            election_deadline: Instant::now() + election_timeout(),
+            last_contact: None,
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            inflight: HashSet::new(),
+            failed: HashSet::new(),
+            last_sent: HashMap::new(),
+            snapshot_sent: HashMap::new(),
+            waiting: HashMap::new(),
+            state_file: None,
+            saved: None,
+        }
+    }
+
+    fn last_index(&self) -> u64 {
+        self.snapshot_index + self.entries.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        match self.entries.last() {
+            Some(x) => x.term,
+            None => self.snapshot_term,
+        }
+    }
+
+    fn term_at(&self, index: u64) -> Option<u64> {
+        if index == self.snapshot_index {
+            return Some(self.snapshot_term);
+        }
+        if index < self.snapshot_index || index > self.last_index() {
+            return None;
+        }
+        Some(self.entry(index).term)
+    }
+
+    fn entry(&self, index: u64) -> &Entry {
+        &self.entries[(index - self.snapshot_index - 1) as usize]
+    }
+
+    /// Members in effect at the index, i.e. of the latest configuration
+    /// entry up to it
+    fn peers_at(&self, index: u64) -> Peers {
+        let count = index.saturating_sub(self.snapshot_index) as usize;
+        for entry in self.entries.iter().take(count).rev() {
+            if let Command::Config(peers) = &entry.command {
+                return peers.clone();
+            }
+        }
+        self.snapshot_peers.clone()
+    }
+
+    fn peers(&self) -> Peers {
+        self.peers_at(self.last_index())
+    }
+
+    /// Whether the latest configuration entry is not committed yet
+    fn config_pending(&self) -> bool {
+        let mut index = self.last_index();
+        for entry in self.entries.iter().rev() {
+            if index <= self.commit_index {
+                return false;
+            }
+            if let Command::Config(_) = entry.command {
+                return true;
+            }
+            index -= 1;
+        }
+        false
+    }
+
+    fn leader_addr(&self) -> Option<String> {
+        let leader = self.leader?;
+        self.peers().get(&leader).cloned()
+    }
+
+    fn reset_deadline(&mut self) {
+        self.election_deadline = Instant::now() + election_timeout();
+    }
+
+    fn become_follower(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        if self.role != Role::Follower {
+            self.role = Role::Follower;
+            self.reset_deadline();
+        }
+    }
+
+    fn become_leader(&mut self) {
+        logging::info!("raft: became leader of term {}", self.term);
+        self.role = Role::Leader;
+        self.leader = Some(self.id);
+        self.next_index.clear();
+        self.match_index.clear();
+        self.failed.clear();
+        self.last_sent.clear();
+        self.snapshot_sent.clear();
+        // commit entries of previous terms with an entry of our term
+        self.entries.push(Entry {
+            term: self.term,
+            command: Command::Noop,
+        });
+        if let Err(e) = self.persist() {
+            // not leading with an entry which is not stored
+            logging::error!("raft: cannot save state: {:?}", e);
+            self.entries.pop();
+            self.role = Role::Follower;
+            self.leader = None;
+            return;
+        }
+        self.advance_commit();
+    }
+
+    /// Commit the entries stored by a majority, returns if anything was
+    /// committed.
+    fn advance_commit(&mut self) -> bool {
+        let peers = self.peers();
+        let mut index = self.last_index();
+        while index > self.commit_index && self.term_at(index) == Some(self.term) {
+            // only entries of current term are committed by counting
+            let mut stored: HashSet<u64> = self
+                .match_index
+                .iter()
+                .filter(|(_, x)| **x >= index)
+                .map(|(id, _)| *id)
+                .collect();
+            stored.insert(self.id);
+            if is_majority(&stored, &peers) {
+                break;
+            }
+            index -= 1;
+        }
+        if index <= self.commit_index || self.term_at(index) != Some(self.term) {
+            return false;
+        }
+        self.commit_index = index;
+
+        // a leader removed from the cluster steps down once it's done
+        if !peers.contains_key(&self.id) && !self.config_pending() {
+            logging::info!("raft: removed from cluster, stepping down");
+            self.role = Role::Follower;
+            self.leader = None;
+        }
+        true
+    }
+
+    /// Make a snapshot with the DB dumped at `index`, dropping the entries
+    /// up to it.
+    fn compact(&mut self, index: u64, data: Vec<u8>) {
+        if index <= self.snapshot_index || index > self.last_index() {
+            return;
+        }
+        self.snapshot_term = self.term_at(index).unwrap_or(0);
+        self.snapshot_peers = self.peers_at(index);
+        self.entries.drain(..(index - self.snapshot_index) as usize);
+        self.snapshot_index = index;
+        self.snapshot = data;
+        // the state is written anew the next time, if not now
+        if let Err(e) = self.persist() {
+            logging::error!("raft: cannot save state: {:?}", e);
+        }
+    }
+
+    /// The AppendEntries or InstallSnapshot to send to the peer
+    fn request_for(&mut self, peer: u64) -> Message {
+        let last = self.last_index();
+        let next = *self.next_index.entry(peer).or_insert(last + 1);
+        if next <= self.snapshot_index {
+            // from where the peer is, unless it's another snapshot
+            let offset = match self.snapshot_sent.get(&peer) {
+                Some((index, x)) if *index == self.snapshot_index => *x as usize,
+                _ => 0,
+            };
+            let offset = cmp::min(offset, self.snapshot.len());
+            let end = cmp::min(self.snapshot.len(), offset + SNAPSHOT_CHUNK);
+            return Message::InstallSnapshot {
+                term: self.term,
+                leader: self.id,
+                last_index: self.snapshot_index,
+                last_term: self.snapshot_term,
+                peers: self.snapshot_peers.clone(),
+                offset: offset as u64,
+                data: self.snapshot[offset..end].to_vec(),
+                done: end == self.snapshot.len(),
+            };
+        }
+        let prev_index = next - 1;
+        let start = (next - self.snapshot_index - 1) as usize;
+        let mut end = start;
+        let mut size = 0;
+        while end < self.entries.len() {
+            size += entry_size(&self.entries[end]);
+            if end > start && size > MAX_BATCH {
+                break;
+            }
+            end += 1;
+        }
+        Message::AppendEntries {
+            term: self.term,
+            leader: self.id,
+            prev_index,
+            prev_term: self.term_at(prev_index).unwrap_or(0),
+            commit: self.commit_index,
+            entries: self.entries[start..end].to_vec(),
+        }
+    }
+
+    /// Drop the entries from `index` on
+    fn truncate(&mut self, index: u64) {
+        let count = (index - self.snapshot_index - 1) as usize;
+        self.entries.truncate(count);
+        if let Some(saved) = &mut self.saved {
+            saved.entries = cmp::min(saved.entries, count);
+        }
+    }
+
+    /// Save the state into the state file, see the top of this file.
+    /// Nothing may be replied or acknowledged on an error.
+    fn persist(&mut self) -> io::Result<()> {
+        let path = match &self.state_file {
+            Some(x) => x.clone(),
+            None => return Ok(()),
+        };
+        let result = match &self.saved {
+            Some(x) if x.snapshot_index == self.snapshot_index && x.records < MAX_RECORDS => {
+                self.append_records(&path)
+            }
+            _ => self.write_state(&path),
+        };
+        if result.is_err() {
+            // not knowing what is in the file, write it anew next time
+            self.saved = None;
+        }
+        result
+    }
+
+    /// Write the whole state into a new file, then rename it, never
+    /// leaving a half-written one
+    fn write_state(&mut self, path: &str) -> io::Result<()> {
+        let mut buffer = vec![];
+        put_u64(&mut buffer, self.term);
+        put_u64(&mut buffer, self.voted_for.unwrap_or(0));
+        put_u64(&mut buffer, self.snapshot_index);
+        put_u64(&mut buffer, self.snapshot_term);
+        put_peers(&mut buffer, &self.snapshot_peers);
+        put_bytes(&mut buffer, &self.snapshot);
+        put_u64(&mut buffer, self.entries.len() as u64);
+        for entry in &self.entries {
+            put_entry(&mut buffer, entry);
+        }
+        let mut data = vec![];
+        put_bytes(&mut data, &encryption::seal(&buffer));
+
+        let tmp_file = format!("{}.tmp", path);
+        let mut file = File::create(&tmp_file)?;
+        file.write_all(&data)?;
+        file.sync_all()?;
+        fs::rename(&tmp_file, path)?;
+        // the rename is only durable once the directory is synced
+        let dir = match Path::new(path).parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+
+        self.saved = Some(Saved {
+            term: self.term,
+            voted_for: self.voted_for,
+            snapshot_index: self.snapshot_index,
+            entries: self.entries.len(),
+            records: 0,
+        });
+        Ok(())
+    }
+
+    /// Append what changed since the state file was written to it
+    fn append_records(&mut self, path: &str) -> io::Result<()> {
+        let saved = match &mut self.saved {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let mut records = vec![];
+        if (saved.term, saved.voted_for) != (self.term, self.voted_for) {
+            records.push(vec![0x01]);
+            put_u64(&mut records[0], self.term);
+            put_u64(&mut records[0], self.voted_for.unwrap_or(0));
+        }
+        if saved.entries != self.entries.len() {
+            let mut record = vec![0x02];
+            put_u64(&mut record, self.snapshot_index + saved.entries as u64 + 1);
+            put_u64(&mut record, (self.entries.len() - saved.entries) as u64);
+            for entry in &self.entries[saved.entries..] {
+                put_entry(&mut record, entry);
+            }
+            records.push(record);
+        }
+        if records.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = vec![];
+        for record in &records {
+            put_bytes(&mut data, &encryption::seal(record));
+        }
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        file.write_all(&data)?;
+        file.sync_data()?;
+
+        saved.term = self.term;
+        saved.voted_for = self.voted_for;
+        saved.entries = self.entries.len();
+        saved.records += records.len();
+        Ok(())
+    }
+
+    /// Load the state saved by `persist()`, returns false if there is
+    /// no state file yet.
+    fn load(&mut self, path: &str) -> Result<bool, error::Error> {
+        let data = match fs::read(path) {
+            Ok(x) => x,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => return Err(error::Error::Io(e)),
+        };
+        let broken = |x: &str| error::Error::Corrupted(format!("{}: {}", path, x));
+        let mut decoder = Decoder::new(&data);
+        let state = decoder.bytes().ok_or_else(|| broken("cut short"))?;
+        let state = encryption::open(state).map_err(|e| broken(&e))?;
+        if self.load_state(&state).is_none() {
+            return Err(broken("invalid state"));
+        }
+        while !decoder.is_empty() {
+            let record = match decoder.bytes() {
+                Some(x) => x,
+                None => {
+                    logging::warning!("raft: {}: last record cut short", path);
+                    break;
+                }
+            };
+            let record = encryption::open(record).map_err(|e| broken(&e))?;
+            if self.load_record(&record).is_none() {
+                return Err(broken("invalid record"));
+            }
+        }
+
+        self.commit_index = self.snapshot_index;
+        self.last_applied = self.snapshot_index;
+        if self.snapshot_index > 0 {
+            self.pending_snapshot = Some(self.snapshot.clone());
+        }
+        Ok(true)
+    }
+
+    fn load_state(&mut self, data: &[u8]) -> Option<()> {
+        let mut decoder = Decoder::new(data);
+        self.term = decoder.u64()?;
+        self.voted_for = match decoder.u64()? {
+            0 => None,
+            x => Some(x),
+        };
+        self.snapshot_index = decoder.u64()?;
+        self.snapshot_term = decoder.u64()?;
+        self.snapshot_peers = decoder.peers()?;
+        self.snapshot = decoder.bytes()?;
+        let count = decoder.u64()?;
+        self.entries.clear();
+        for _ in 0..count {
+            self.entries.push(decoder.entry()?);
+        }
+        Some(())
+    }
+
+    /// Apply a record appended by `append_records()`
+    fn load_record(&mut self, data: &[u8]) -> Option<()> {
+        let mut decoder = Decoder::new(data);
+        match decoder.u8()? {
+            0x01 => {
+                self.term = decoder.u64()?;
+                self.voted_for = match decoder.u64()? {
+                    0 => None,
+                    x => Some(x),
+                };
+            }
+            0x02 => {
+                let index = decoder.u64()?;
+                if index <= self.snapshot_index || index > self.last_index() + 1 {
+                    return None;
+                }
+                self.truncate(index);
+                for _ in 0..decoder.u64()? {
+                    self.entries.push(decoder.entry()?);
+                }
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+fn on_request_vote(
+    st: &mut State,
+    term: u64,
+    candidate: u64,
+    last_index: u64,
+    last_term: u64,
+) -> io::Result<Message> {
+    // ignore nodes removed from the cluster, which do not know
+    // there is a leader, and keep starting elections
+    let min_timeout = Duration::from_millis(ELECTION_TIMEOUT_MS);
+    if let Some(x) = st.last_contact {
+        if st.role == Role::Follower && st.leader.is_some() && x.elapsed() < min_timeout {
+            return Ok(Message::VoteReply {
+                term: st.term,
+                granted: false,
+            });
+        }
+    }
+
+    if term > st.term {
+        st.become_follower(term);
+    }
+    let up_to_date = (last_term, last_index) >= (st.last_term(), st.last_index());
+    let granted = term == st.term
+        && up_to_date
+        && (st.voted_for.is_none() || st.voted_for == Some(candidate));
+    if granted {
+        st.voted_for = Some(candidate);
+        st.reset_deadline();
+    }
+    st.persist()?;
+    Ok(Message::VoteReply {
+        term: st.term,
+        granted,
+    })
+}
+
+fn on_append_entries(
+    st: &mut State,
+    term: u64,
+    leader: u64,
+    prev_index: u64,
+    prev_term: u64,
+    commit: u64,
+    entries: Vec<Entry>,
+) -> io::Result<Message> {
+    if term < st.term {
+        return Ok(Message::AppendReply {
+            term: st.term,
+            success: false,
+            last_index: 0,
+        });
+    }
+    st.become_follower(term);
+    st.leader = Some(leader);
+    st.last_contact = Some(Instant::now());
+    st.reset_deadline();
+
+    if prev_index > st.last_index() {
+        st.persist()?;
+        return Ok(Message::AppendReply {
+            term: st.term,
+            success: false,
+            last_index: st.last_index(),
+        });
+    }
+    if prev_index >= st.snapshot_index && st.term_at(prev_index) != Some(prev_term) {
+        st.persist()?;
+        return Ok(Message::AppendReply {
+            term: st.term,
+            success: false,
+            last_index: prev_index - 1,
+        });
+    }
+
+    let count = entries.len() as u64;
+    for (i, entry) in entries.into_iter().enumerate() {
+        let index = prev_index + 1 + i as u64;
+        if index <= st.snapshot_index {
+            continue; // committed, so it must be the same
+        }
+        if index <= st.last_index() {
+            if st.term_at(index) == Some(entry.term) {
+                continue;
+            }
+            // conflicting, drop it and all that follow it
+            st.truncate(index);
+        }
+        st.entries.push(entry);
+    }
+    st.persist()?;
+
+    let matched = prev_index + count;
+    if commit > st.commit_index {
+        st.commit_index = cmp::max(st.commit_index, cmp::min(commit, matched));
+    }
+    Ok(Message::AppendReply {
+        term: st.term,
+        success: true,
+        last_index: matched,
+    })
+}
+
+/// Handle a chunk of the snapshot, which is installed with the last one
+#[allow(clippy::too_many_arguments)]
+fn on_install_snapshot(
+    st: &mut State,
+    term: u64,
+    leader: u64,
+    last_index: u64,
+    last_term: u64,
+    peers: Peers,
+    offset: u64,
+    data: Vec<u8>,
+    done: bool,
+) -> io::Result<Message> {
+    let next = offset + data.len() as u64;
+    if term < st.term {
+        return Ok(Message::SnapshotReply {
+            term: st.term,
+            next: 0,
+        });
+    }
+    st.become_follower(term);
+    st.leader = Some(leader);
+    st.last_contact = Some(Instant::now());
+    st.reset_deadline();
+    if last_index <= st.snapshot_index {
+        // already have it, or a more recent one
+        st.persist()?;
+        return Ok(Message::SnapshotReply {
+            term: st.term,
+            next,
+        });
+    }
+
+    if offset == 0 {
+        st.incoming_snapshot = Some((last_index, last_term, vec![]));
+    }
+    let got = match &mut st.incoming_snapshot {
+        Some((index, t, x)) if (*index, *t) == (last_index, last_term) => x,
+        _ => {
+            // from the start, e.g. the first chunk was lost
+            st.persist()?;
+            return Ok(Message::SnapshotReply {
+                term: st.term,
+                next: 0,
+            });
+        }
+    };
+    if got.len() as u64 != offset {
+        // from where we are, e.g. a reply was lost
+        let next = got.len() as u64;
+        st.persist()?;
+        return Ok(Message::SnapshotReply {
+            term: st.term,
+            next,
+        });
+    }
+    got.extend(&data);
+    if !done {
+        st.persist()?;
+        return Ok(Message::SnapshotReply {
+            term: st.term,
+            next,
+        });
+    }
+    let data = st.incoming_snapshot.take().map(|x| x.2).unwrap_or_default();
+
+    if st.term_at(last_index) == Some(last_term) {
+        // keep the entries following the snapshot
+        st.entries
+            .drain(..(last_index - st.snapshot_index) as usize);
+    } else {
+        st.entries.clear();
+    }
+    st.snapshot_index = last_index;
+    st.snapshot_term = last_term;
+    st.snapshot_peers = peers;
+    st.snapshot = data;
+    st.commit_index = cmp::max(st.commit_index, last_index);
+    if st.last_applied < last_index {
+        st.last_applied = last_index;
+        st.pending_snapshot = Some(st.snapshot.clone());
+    }
+    st.persist()?;
+    Ok(Message::SnapshotReply {
+        term: st.term,
+        next,
+    })
+}
+
+/// Start the Raft node `id`, with its saved state if any, else with the
+/// members `peers`, which may be empty for a node to be added later.
+pub fn start(
+    id: u64,
+    peers: Peers,
+    snapshot_entries: usize,
+    arc_db: Arc<Mutex<store::DB>>,
+) -> Result<Arc<Node>, error::Error> {
+    let mut st = State::new(id, peers, snapshot_entries);
+    if let Some(path) = tools::get_raft_file() {
+        if st.load(&path)? {
+            logging::info!("raft: state loaded, term {}", st.term);
+        }
+        st.state_file = Some(path);
+    }
+
+    let node = Arc::new(Node {
+        state: Mutex::new(st),
+        changed: Condvar::new(),
+        db: arc_db,
+    });
+    let clone_node = node.clone();
+    thread::spawn(move || tick(clone_node));
+    let clone_node = node.clone();
+    thread::spawn(move || apply(clone_node));
+    Ok(node)
+}
+
+/// Start elections when the leader is gone, and send heartbeats when
+/// we are the leader.
+fn tick(node: Arc<Node>) {
+    loop {
+        thread::sleep(TICK);
+        let mut st = node.state.lock().unwrap();
+        if st.role == Role::Leader {
+            drop(st);
+            replicate(&node);
+            continue;
+        }
+        if Instant::now() < st.election_deadline || !st.peers().contains_key(&st.id) {
+            continue;
+        }
+
+        st.term += 1;
+        st.role = Role::Candidate;
+        st.voted_for = Some(st.id);
+        st.leader = None;
+        st.votes.clear();
+        let id = st.id;
+        st.votes.insert(id);
+        st.reset_deadline();
+        if let Err(e) = st.persist() {
+            // asking for votes only after voting for ourselves is saved
+            logging::error!("raft: cannot save state: {:?}", e);
+            st.role = Role::Follower;
+            continue;
+        }
+        logging::info!("raft: starting election of term {}", st.term);
+        if is_majority(&st.votes, &st.peers()) {
+            st.become_leader();
+            node.changed.notify_all();
+            continue;
+        }
+
+        let term = st.term;
+        let request = Message::RequestVote {
+            term,
+            candidate: st.id,
+            last_index: st.last_index(),
+            last_term: st.last_term(),
+        };
+        let request = Arc::new(request);
+        for (peer, addr) in st.peers() {
+            if peer == st.id {
+                continue;
+            }
+            let node = node.clone();
+            let request = request.clone();
+            thread::spawn(move || {
+                let reply = call(&addr, &request);
+                let mut st = node.state.lock().unwrap();
+                if let Some(Message::VoteReply { term: t, granted }) = reply {
+                    if t > st.term {
+                        st.become_follower(t);
+                        if let Err(e) = st.persist() {
+                            logging::error!("raft: cannot save state: {:?}", e);
+                        }
+                    } else if granted && st.role == Role::Candidate && st.term == term {
+                        st.votes.insert(peer);
+                        if is_majority(&st.votes, &st.peers()) {
+                            st.become_leader();
+                            drop(st);
+                            node.changed.notify_all();
+                            replicate(&node);
+                        }
+                    }
+                }
+            });
+        }
+    }
+}
+
+/// As leader, send new entries or heartbeats to the peers that need them
+fn replicate(node: &Arc<Node>) {
+    let mut st = node.state.lock().unwrap();
+    if st.role != Role::Leader {
+        return;
+    }
+    let now = Instant::now();
+    let term = st.term;
+    for (peer, addr) in st.peers() {
+        if peer == st.id || st.inflight.contains(&peer) {
+            continue;
+        }
+        let behind = match st.next_index.get(&peer) {
+            Some(x) => *x <= st.last_index(),
+            None => false,
+        };
+        let due = match st.last_sent.get(&peer) {
+            Some(x) => now.duration_since(*x) >= HEARTBEAT,
+            None => true,
+        };
+        // new entries are sent at once, unless the peer seems down
+        let urgent = behind && !st.failed.contains(&peer);
+        if !due && !urgent {
+            continue;
+        }
+
+        let request = st.request_for(peer);
+        st.inflight.insert(peer);
+        st.last_sent.insert(peer, now);
+        let node = node.clone();
+        thread::spawn(move || {
+            let reply = call(&addr, &request);
+            let mut st = node.state.lock().unwrap();
+            st.inflight.remove(&peer);
+            let reply = match reply {
+                Some(x) => x,
+                None => {
+                    st.failed.insert(peer);
+                    return;
+                }
+            };
+            st.failed.remove(&peer);
+            if on_reply(&mut st, peer, term, &request, reply) {
+                node.changed.notify_all();
+            }
+            let behind = st.next_index.get(&peer).cloned().unwrap_or(0) <= st.last_index();
+            drop(st);
+            if behind {
+                replicate(&node);
+            }
+        });
+    }
+}
+
+/// Handle reply of AppendEntries or InstallSnapshot from the peer,
+/// returns if anything was committed.
+fn on_reply(st: &mut State, peer: u64, term: u64, request: &Message, reply: Message) -> bool {
+    let reply_term = match reply {
+        Message::AppendReply { term, .. } | Message::SnapshotReply { term, .. } => term,
+        _ => return false,
+    };
+    if reply_term > st.term {
+        st.become_follower(reply_term);
+        if let Err(e) = st.persist() {
+            logging::error!("raft: cannot save state: {:?}", e);
+        }
+        return true;
+    }
+    if st.role != Role::Leader || st.term != term {
+        return false;
+    }
+
+    // Ok with the last index matching ours, or Err with the peer's hint
+    let matched = match (request, reply) {
+        (
+            _,
+            Message::AppendReply {
+                success,
+                last_index,
+                ..
+            },
+        ) => {
+            if success {
+                Ok(last_index)
+            } else {
+                Err(last_index)
+            }
+        }
+        (
+            Message::InstallSnapshot {
+                last_index,
+                offset,
+                data,
+                done,
+                ..
+            },
+            Message::SnapshotReply { next, .. },
+        ) => {
+            if !*done || next != offset + data.len() as u64 {
+                // more chunks to send, from where the peer is
+                st.snapshot_sent.insert(peer, (*last_index, next));
+                return false;
+            }
+            st.snapshot_sent.remove(&peer);
+            Ok(*last_index)
+        }
+        _ => return false,
+    };
+    match matched {
+        Ok(index) => {
+            let index = cmp::max(index, st.match_index.get(&peer).cloned().unwrap_or(0));
+            st.match_index.insert(peer, index);
+            st.next_index.insert(peer, index + 1);
+            st.advance_commit()
+        }
+        Err(hint) => {
+            // step back, skipping to right after the peer's hint
+            let next = st.next_index.get(&peer).cloned().unwrap_or(1);
+            let next = cmp::min(next.saturating_sub(1), hint + 1);
+            st.next_index.insert(peer, cmp::max(1, next));
+            false
+        }
+    }
+}
+
+/// Apply committed entries to the DB, in order
+fn apply(node: Arc<Node>) {
+    loop {
+        let (first, batch, snapshot_due) = {
+            let mut st = node.state.lock().unwrap();
+            while st.pending_snapshot.is_none() && st.commit_index <= st.last_applied {
+                st = node.changed.wait(st).unwrap();
+            }
+            if let Some(data) = st.pending_snapshot.take() {
+                drop(st);
+                let mut loaded = store::DB::new();
+                if let Err(e) = persistence::load(&mut &data[..], &mut loaded) {
+                    // entries are applied after the snapshot, which the
+                    // DB would not have
+                    logging::error!("raft: cannot load snapshot: {}", e);
+                    process::exit(1);
+                }
+                store::replace(loaded, &mut node.db.lock().unwrap());
+                continue;
+            }
+            let first = st.last_applied + 1;
+            let batch: Vec<Entry> = (first..=st.commit_index)
+                .map(|i| st.entry(i).clone())
+                .collect();
+            let applied = (st.commit_index - st.snapshot_index) as usize;
+            (first, batch, applied >= st.snapshot_entries)
+        };
+
+        let mut results = vec![];
+        let dump = {
+            let mut db = node.db.lock().unwrap();
+            for entry in &batch {
+                let result = match &entry.command {
+                    Command::Put(key, value) => store::put(key, value.as_bytes(), &mut db).is_ok(),
+                    Command::Delete(key) => store::delete(key, &mut db).is_some(),
+                    _ => true,
+                };
+                results.push((entry.term, result));
+            }
+            if snapshot_due {
+                Some(persistence::dump(&db))
+            } else {
+                None
+            }
+        };
+
+        let last = first + batch.len() as u64 - 1;
+        let mut st = node.state.lock().unwrap();
+        for (i, result) in results.into_iter().enumerate() {
+            if let Some(slot) = st.waiting.get_mut(&(first + i as u64)) {
+                *slot = Some(result);
+            }
+        }
+        st.last_applied = cmp::max(st.last_applied, last);
+        if let Some(data) = dump {
+            st.compact(last, data);
+        }
+        node.changed.notify_all();
+    }
+}
+
+/// Max size of messages between nodes, rather than `--max-request-size`
+/// of client queries: an AppendEntries has up to `MAX_BATCH` bytes of
+/// entries, or one entry of a PUT larger than it, and an InstallSnapshot
+/// a chunk of `SNAPSHOT_CHUNK` bytes. Nodes of a cluster should have the
+/// same limits.
+pub fn max_message_size() -> u64 {
+    let limits = limits::get();
+    let entry = limits.max_key_size + limits.max_value_size;
+    (cmp::max(entry, MAX_BATCH) + MESSAGE_OVERHEAD) as u64
+}
+
+/// Send a message to the node at `addr`, returns its reply
+fn call(addr: &str, message: &Message) -> Option<Message> {
+    let addr = addr.to_socket_addrs().ok()?.next()?;
+    let mut stream = TcpStream::connect_timeout(&addr, RPC_TIMEOUT).ok()?;
+    stream.set_read_timeout(Some(RPC_TIMEOUT)).ok()?;
+    stream.set_write_timeout(Some(RPC_TIMEOUT)).ok()?;
+    if !auth::login(&mut stream) {
+        return None;
+    }
+
+    let body = encode_message(message);
+    let mut buffer = b"\x0c\x0f\x00\x00\x00".to_vec();
+    let (count, bytes) = tools::u64_to_bytes(body.len() as u64);
+    buffer.push(count);
+    buffer.extend(&bytes);
+    buffer.extend(&body);
+    stream.write_all(&buffer).ok()?;
+
+    let mut buf_llen = [0_u8; 1];
+    stream.read_exact(&mut buf_llen).ok()?;
+    if buf_llen[0] > 8 {
+        return None;
+    }
+    let mut buf_len = vec![0_u8; buf_llen[0] as usize];
+    stream.read_exact(&mut buf_len).ok()?;
+    let size = tools::bytes_to_u64(&buf_len);
+    if size > max_message_size() {
+        return None;
+    }
+    // allocated as it comes in, rather than as the peer claims
+    let mut reply = vec![];
+    Read::by_ref(&mut stream)
+        .take(size)
+        .read_to_end(&mut reply)
+        .ok()?;
+    if reply.len() as u64 != size {
+        return None;
+    }
+    decode_message(&reply)
+}
+
+/// Handle a message from another node, returns the encoded reply, or
+/// none if the state cannot be saved, as nothing is replied before
+pub fn handle_rpc(node: &Node, data: &[u8]) -> Option<Vec<u8>> {
+    let request = decode_message(data)?;
+    let mut st = node.state.lock().unwrap();
+    let reply = match request {
+        Message::RequestVote {
+            term,
+            candidate,
+            last_index,
+            last_term,
+        } => on_request_vote(&mut st, term, candidate, last_index, last_term),
+        Message::AppendEntries {
+            term,
+            leader,
+            prev_index,
+            prev_term,
+            commit,
+            entries,
+        } => on_append_entries(
+            &mut st, term, leader, prev_index, prev_term, commit, entries,
+        ),
+        Message::InstallSnapshot {
+            term,
+            leader,
+            last_index,
+            last_term,
+            peers,
+            offset,
+            data,
+            done,
+        } => on_install_snapshot(
+            &mut st, term, leader, last_index, last_term, peers, offset, data, done,
+        ),
+        _ => return None,
+    };
+    node.changed.notify_all();
+    match reply {
+        Ok(x) => Some(encode_message(&x)),
+        Err(e) => {
+            logging::error!("raft: cannot save state: {:?}", e);
+            None
+        }
+    }
+}
+
+/// Append the command to the log as leader, and wait until it's applied,
+/// returns the result of applying it: for `Command::Delete`, whether the
+/// key existed.
+pub fn propose(command: Command, node: &Arc<Node>) -> Result<bool, Error> {
+    let (index, term) = {
+        let mut st = node.state.lock().unwrap();
+        if st.role != Role::Leader {
+            return Err(Error::NotLeader(st.leader_addr()));
+        }
+        if let Command::Config(_) = command {
+            if st.config_pending() {
+                return Err(Error::Failed);
+            }
+        }
+        let term = st.term;
+        st.entries.push(Entry { term, command });
+        let index = st.last_index();
+        st.waiting.insert(index, None);
+        if let Err(e) = st.persist() {
+            logging::error!("raft: cannot save state: {:?}", e);
+            st.truncate(index);
+            st.waiting.remove(&index);
+            return Err(Error::Failed);
+        }
+        if st.advance_commit() {
+            node.changed.notify_all();
+        }
+        (index, term)
+    };
+    replicate(node);
+
+    let deadline = Instant::now() + PROPOSE_TIMEOUT;
+    let mut st = node.state.lock().unwrap();
+    loop {
+        if let Some(Some((t, result))) = st.waiting.get(&index) {
+            let (t, result) = (*t, *result);
+            st.waiting.remove(&index);
+            // another leader may have replaced our entry
+            return if t == term {
+                Ok(result)
+            } else {
+                Err(Error::Failed)
+            };
+        }
+        let now = Instant::now();
+        if now >= deadline || st.last_applied >= index {
+            st.waiting.remove(&index);
+            return Err(Error::Failed);
+        }
+        st = node.changed.wait_timeout(st, deadline - now).unwrap().0;
+    }
+}
+
+/// Add the node `id` serving at `addr` into the cluster
+pub fn add_peer(id: u64, addr: &str, node: &Arc<Node>) -> Result<bool, Error> {
+    let mut peers = node.state.lock().unwrap().peers();
+    peers.insert(id, addr.to_string());
+    propose(Command::Config(peers), node)
+}
+
+/// Remove the node `id` from the cluster
+pub fn remove_peer(id: u64, node: &Arc<Node>) -> Result<bool, Error> {
+    let mut peers = node.state.lock().unwrap().peers();
+    if peers.remove(&id).is_none() {
+        return Ok(false);
+    }
+    propose(Command::Config(peers), node)
+}
+
+/// Save the whole state again, e.g. encrypted with a new data key,
+/// rather than only appending what changed to the file
+pub fn persist(node: &Node) -> io::Result<()> {
+    let mut st = node.state.lock().unwrap();
+    st.saved = None;
+    st.persist()
+}
+
+/// Human readable status of the node
+pub fn info(node: &Node) -> String {
+    let st = node.state.lock().unwrap();
+    let peers: Vec<String> = st
+        .peers()
+        .iter()
+        .map(|(id, addr)| format!("{}={}", id, addr))
+        .collect();
+    format!(
+        "id: {}\nrole: {:?}\nterm: {}\nleader: {}\ncommit: {}\napplied: {}\nsnapshot: {}\npeers: {}",
+        st.id,
+        st.role,
+        st.term,
+        st.leader_addr().unwrap_or_default(),
+        st.commit_index,
+        st.last_applied,
+        st.snapshot_index,
+        peers.join(","),
+    )
+}
+
+fn put_u64(buffer: &mut Vec<u8>, n: u64) {
+    buffer.extend(&n.to_le_bytes());
+}
+
+fn put_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    put_u64(buffer, bytes.len() as u64);
+    buffer.extend(bytes);
+}
+
+fn put_peers(buffer: &mut Vec<u8>, peers: &Peers) {
+    put_u64(buffer, peers.len() as u64);
+    for (id, addr) in peers {
+        put_u64(buffer, *id);
+        put_bytes(buffer, addr.as_bytes());
+    }
+}
+
+/// Size of the entry as put by `put_entry()`
+fn entry_size(entry: &Entry) -> usize {
+    let size = match &entry.command {
+        Command::Noop => 0,
+        Command::Put(key, value) => 16 + key.len() + value.len(),
+        Command::Delete(key) => 8 + key.len(),
+        Command::Config(peers) => 8 + peers.values().map(|x| 16 + x.len()).sum::<usize>(),
+    };
+    9 + size
+}
+
+fn put_entry(buffer: &mut Vec<u8>, entry: &Entry) {
+    put_u64(buffer, entry.term);
+    match &entry.command {
+        Command::Noop => buffer.push(0),
+        Command::Put(key, value) => {
+            buffer.push(1);
+            put_bytes(buffer, key.as_bytes());
+            put_bytes(buffer, value.as_bytes());
+        }
+        Command::Delete(key) => {
+            buffer.push(2);
+            put_bytes(buffer, key.as_bytes());
+        }
+        Command::Config(peers) => {
+            buffer.push(3);
+            put_peers(buffer, peers);
+        }
+    }
+}
+
+struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(data: &'a [u8]) -> Decoder<'a> {
+        Decoder { data, pos: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pos == self.data.len()
+    }
+
+    fn take(&mut self, size: usize) -> Option<&'a [u8]> {
+        if self.data.len() - self.pos < size {
+            return None;
+        }
+        self.pos += size;
+        Some(&self.data[self.pos - size..self.pos])
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        Some(tools::bytes_to_u64(self.take(8)?))
+    }
+
+    fn bytes(&mut self) -> Option<Vec<u8>> {
+        let size = self.u64()?;
+        if size > (self.data.len() - self.pos) as u64 {
+            return None;
+        }
+        Some(self.take(size as usize)?.to_vec())
+    }
+
+    fn string(&mut self) -> Option<String> {
+        String::from_utf8(self.bytes()?).ok()
+    }
+
+    fn peers(&mut self) -> Option<Peers> {
+        let mut peers = Peers::new();
+        for _ in 0..self.u64()? {
+            let id = self.u64()?;
+            peers.insert(id, self.string()?);
+        }
+        Some(peers)
+    }
+
+    fn entry(&mut self) -> Option<Entry> {
+        let term = self.u64()?;
+        let command = match self.u8()? {
+            0 => Command::Noop,
+            1 => Command::Put(self.string()?, self.string()?),
+            2 => Command::Delete(self.string()?),
+            3 => Command::Config(self.peers()?),
+            _ => return None,
+        };
+        Some(Entry { term, command })
+    }
+}
+
+fn encode_message(message: &Message) -> Vec<u8> {
+    let mut buffer = vec![];
+    match message {
+        Message::RequestVote {
+            term,
+            candidate,
+            last_index,
+            last_term,
+        } => {
+            buffer.push(1);
+            for x in &[*term, *candidate, *last_index, *last_term] {
+                put_u64(&mut buffer, *x);
+            }
+        }
+        Message::VoteReply { term, granted } => {
+            buffer.push(2);
+            put_u64(&mut buffer, *term);
+            buffer.push(*granted as u8);
+        }
+        Message::AppendEntries {
+            term,
+            leader,
+            prev_index,
+            prev_term,
+            commit,
+            entries,
+        } => {
+            buffer.push(3);
+            for x in &[*term, *leader, *prev_index, *prev_term, *commit] {
+                put_u64(&mut buffer, *x);
+            }
+            put_u64(&mut buffer, entries.len() as u64);
+            for entry in entries {
+                put_entry(&mut buffer, entry);
+            }
+        }
+        Message::AppendReply {
+            term,
+            success,
+            last_index,
+        } => {
+            buffer.push(4);
+            put_u64(&mut buffer, *term);
+            buffer.push(*success as u8);
+            put_u64(&mut buffer, *last_index);
+        }
+        Message::InstallSnapshot {
+            term,
+            leader,
+            last_index,
+            last_term,
+            peers,
+            offset,
+            data,
+            done,
+        } => {
+            buffer.push(5);
+            for x in &[*term, *leader, *last_index, *last_term] {
+                put_u64(&mut buffer, *x);
+            }
+            put_peers(&mut buffer, peers);
+            put_u64(&mut buffer, *offset);
+            put_bytes(&mut buffer, data);
+            buffer.push(*done as u8);
+        }
+        Message::SnapshotReply { term, next } => {
+            buffer.push(6);
+            put_u64(&mut buffer, *term);
+            put_u64(&mut buffer, *next);
+        }
+    }
+    buffer
+}
+
+fn decode_message(data: &[u8]) -> Option<Message> {
+    let mut d = Decoder::new(data);
+    let message = match d.u8()? {
+        1 => Message::RequestVote {
+            term: d.u64()?,
+            candidate: d.u64()?,
+            last_index: d.u64()?,
+            last_term: d.u64()?,
+        },
+        2 => Message::VoteReply {
+            term: d.u64()?,
+            granted: d.u8()? != 0,
+        },
+        3 => {
+            let (term, leader, prev_index, prev_term, commit) =
+                (d.u64()?, d.u64()?, d.u64()?, d.u64()?, d.u64()?);
+            let mut entries = vec![];
+            for _ in 0..d.u64()? {
+                entries.push(d.entry()?);
+            }
+            Message::AppendEntries {
+                term,
+                leader,
+                prev_index,
+                prev_term,
+                commit,
+                entries,
+            }
+        }
+        4 => Message::AppendReply {
+            term: d.u64()?,
+            success: d.u8()? != 0,
+            last_index: d.u64()?,
+        },
+        5 => Message::InstallSnapshot {
+            term: d.u64()?,
+            leader: d.u64()?,
+            last_index: d.u64()?,
+            last_term: d.u64()?,
+            peers: d.peers()?,
+            offset: d.u64()?,
+            data: d.bytes()?,
+            done: d.u8()? != 0,
+        },
+        6 => Message::SnapshotReply {
+            term: d.u64()?,
+            next: d.u64()?,
+        },
+        _ => return None,
+    };
+    Some(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_message, encode_message, on_append_entries, on_request_vote};
+    use super::{on_install_snapshot, on_reply, SNAPSHOT_CHUNK};
+    use super::{Command, Entry, Message, Peers, Role, State};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    fn peers(ids: &[u64]) -> Peers {
+        ids.iter()
+            .map(|x| (*x, format!("127.0.0.1:{}", x)))
+            .collect()
+    }
+
+    fn put(term: u64, key: &str) -> Entry {
+        Entry {
+            term,
+            command: Command::Put(key.to_string(), "v".to_string()),
+        }
+    }
+
+    fn append(st: &mut State, prev_index: u64, prev_term: u64, entries: Vec<Entry>) -> Message {
+        on_append_entries(st, 2, 1, prev_index, prev_term, 0, entries).unwrap()
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let messages = vec![
+            Message::RequestVote {
+                term: 3,
+                candidate: 1,
+                last_index: 10,
+                last_term: 2,
+            },
+            Message::AppendEntries {
+                term: 3,
+                leader: 1,
+                prev_index: 10,
+                prev_term: 2,
+                commit: 9,
+                entries: vec![
+                    put(3, "foo"),
+                    Entry {
+                        term: 3,
+                        command: Command::Config(peers(&[1, 2])),
+                    },
+                ],
+            },
+            Message::InstallSnapshot {
+                term: 3,
+                leader: 1,
+                last_index: 10,
+                last_term: 2,
+                peers: peers(&[1, 2, 3]),
+                offset: 0,
+                data: b"\x0c\x01\x01k\x01\x01v".to_vec(),
+                done: true,
+            },
+            Message::SnapshotReply { term: 3, next: 7 },
+            Message::AppendReply {
+                term: 3,
+                success: true,
+                last_index: 12,
+            },
+        ];
+        for message in messages {
+            let data = encode_message(&message);
+            assert_eq!(decode_message(&data), Some(message));
+            assert_eq!(decode_message(&data[..data.len() - 1]), None);
+        }
+    }
+
+    #[test]
+    fn test_append_entries() {
+        let mut st = State::new(2, peers(&[1, 2, 3]), 100);
+        let reply = append(&mut st, 0, 0, vec![put(1, "a"), put(1, "b"), put(1, "c")]);
+        assert_eq!(
+            reply,
+            Message::AppendReply {
+                term: 2,
+                success: true,
+                last_index: 3
+            }
+        );
+        assert_eq!(st.leader, Some(1));
+
+        // missing entries before
+        let reply = append(&mut st, 5, 2, vec![put(2, "x")]);
+        assert_eq!(
+            reply,
+            Message::AppendReply {
+                term: 2,
+                success: false,
+                last_index: 3
+            }
+        );
+
+        // conflicting entries are replaced
+        let reply = append(&mut st, 1, 1, vec![put(2, "x")]);
+        assert_eq!(
+            reply,
+            Message::AppendReply {
+                term: 2,
+                success: true,
+                last_index: 2
+            }
+        );
+        assert_eq!(st.last_index(), 2);
+        assert_eq!(st.term_at(2), Some(2));
+
+        // a stale leader is rejected
+        let reply = on_append_entries(&mut st, 1, 3, 2, 2, 0, vec![]).unwrap();
+        assert_eq!(
+            reply,
+            Message::AppendReply {
+                term: 2,
+                success: false,
+                last_index: 0
+            }
+        );
+
+        // commit index follows the leader, up to the entries we have
+        on_append_entries(&mut st, 2, 1, 2, 2, 10, vec![]).unwrap();
+        assert_eq!(st.commit_index, 2);
+    }
+
+    #[test]
+    fn test_request_vote() {
+        let mut st = State::new(2, peers(&[1, 2, 3]), 100);
+        append(&mut st, 0, 0, vec![put(1, "a"), put(2, "b")]);
+        st.last_contact = None;
+
+        // candidate's log is behind ours
+        let reply = on_request_vote(&mut st, 3, 1, 1, 1).unwrap();
+        assert_eq!(
+            reply,
+            Message::VoteReply {
+                term: 3,
+                granted: false
+            }
+        );
+
+        let reply = on_request_vote(&mut st, 3, 3, 2, 2).unwrap();
+        assert_eq!(
+            reply,
+            Message::VoteReply {
+                term: 3,
+                granted: true
+            }
+        );
+        // only one vote per term
+        let reply = on_request_vote(&mut st, 3, 1, 5, 3).unwrap();
+        assert_eq!(
+            reply,
+            Message::VoteReply {
+                term: 3,
+                granted: false
+            }
+        );
+    }
+
+    /// Handle the InstallSnapshot on the follower
+    fn install(st: &mut State, request: &Message) -> Message {
+        match request {
+            Message::InstallSnapshot {
+                term,
+                leader,
+                last_index,
+                last_term,
+                peers,
+                offset,
+                data,
+                done,
+            } => on_install_snapshot(
+                st,
+                *term,
+                *leader,
+                *last_index,
+                *last_term,
+                peers.clone(),
+                *offset,
+                data.clone(),
+                *done,
+            )
+            .unwrap(),
+            x => panic!("not a snapshot: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_install_snapshot() {
+        let mut leader = State::new(1, peers(&[1, 2]), 100);
+        leader.term = 2;
+        leader.role = Role::Leader;
+        leader.entries = vec![put(1, "a"), put(2, "b")];
+        let data = vec![7; SNAPSHOT_CHUNK * 2 + 1];
+        leader.compact(2, data.clone());
+        leader.next_index.insert(2, 1);
+
+        // sent in chunks
+        let mut follower = State::new(2, peers(&[1, 2]), 100);
+        let mut count = 0;
+        loop {
+            let request = leader.request_for(2);
+            if let Message::AppendEntries { .. } = request {
+                break;
+            }
+            let reply = install(&mut follower, &request);
+            on_reply(&mut leader, 2, 2, &request, reply);
+            count += 1;
+        }
+        assert_eq!(count, 3);
+        assert_eq!(follower.snapshot_index, 2);
+        assert_eq!(follower.snapshot, data);
+        assert_eq!(follower.pending_snapshot, Some(data));
+        assert_eq!(leader.match_index.get(&2), Some(&2));
+
+        // a chunk not following the ones got is asked again
+        let mut follower = State::new(2, peers(&[1, 2]), 100);
+        leader.next_index.insert(2, 1);
+        leader.snapshot_sent.insert(2, (2, SNAPSHOT_CHUNK as u64));
+        let request = leader.request_for(2);
+        let reply = install(&mut follower, &request);
+        assert_eq!(reply, Message::SnapshotReply { term: 2, next: 0 });
+        on_reply(&mut leader, 2, 2, &request, reply);
+        match leader.request_for(2) {
+            Message::InstallSnapshot { offset, .. } => assert_eq!(offset, 0),
+            x => panic!("not a snapshot: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_batch() {
+        let mut st = State::new(1, peers(&[1, 2]), 100);
+        st.term = 1;
+        let value = "v".repeat(600 * 1024);
+        for key in &["a", "b", "c"] {
+            st.entries.push(Entry {
+                term: 1,
+                command: Command::Put(key.to_string(), value.clone()),
+            });
+        }
+        st.entries.push(put(1, "d"));
+        st.entries.push(put(1, "e"));
+        // by bytes, with at least one entry
+        let count = |st: &mut State, next: u64| {
+            st.next_index.insert(2, next);
+            match st.request_for(2) {
+                Message::AppendEntries { entries, .. } => entries.len(),
+                x => panic!("not entries: {:?}", x),
+            }
+        };
+        assert_eq!(count(&mut st, 1), 1);
+        assert_eq!(count(&mut st, 3), 3);
+        assert_eq!(count(&mut st, 4), 2);
+    }
+
+    #[test]
+    fn test_persist_failed() {
+        let mut path = env::temp_dir();
+        path.push(format!("h2okv-missing-{}", std::process::id()));
+        path.push("h2okv.raft");
+        let mut st = State::new(2, peers(&[1, 2, 3]), 100);
+        st.state_file = Some(path.to_str().unwrap().to_string());
+        // nothing is replied without the state saved
+        assert!(on_request_vote(&mut st, 3, 1, 0, 0).is_err());
+        assert!(on_append_entries(&mut st, 3, 1, 0, 0, 0, vec![put(3, "a")]).is_err());
+    }
+
+    #[test]
+    fn test_commit_and_compact() {
+        let mut st = State::new(1, peers(&[1, 2, 3]), 100);
+        st.term = 2;
+        st.role = Role::Leader;
+        st.entries = vec![put(1, "a"), put(2, "b"), put(2, "c")];
+
+        assert!(!st.advance_commit());
+        st.match_index.insert(2, 2);
+        assert!(st.advance_commit());
+        assert_eq!(st.commit_index, 2);
+        st.match_index.insert(3, 3);
+        assert!(st.advance_commit());
+        assert_eq!(st.commit_index, 3);
+
+        st.entries.push(Entry {
+            term: 2,
+            command: Command::Config(peers(&[1, 2, 3, 4])),
+        });
+        assert!(st.config_pending());
+        assert_eq!(st.peers().len(), 4);
+        assert_eq!(st.peers_at(3).len(), 3);
+
+        st.compact(2, b"data".to_vec());
+        assert_eq!(st.snapshot_index, 2);
+        assert_eq!(st.snapshot_term, 2);
+        assert_eq!(st.entries.len(), 2);
+        assert_eq!(st.term_at(2), Some(2));
+        assert_eq!(st.term_at(1), None);
+        assert_eq!(st.peers().len(), 4);
+
+        // removed leader steps down once the change is committed
+        st.entries.push(Entry {
+            term: 2,
+            command: Command::Config(peers(&[2, 3, 4])),
+        });
+        st.match_index.insert(2, 5);
+        st.match_index.insert(4, 5);
+        assert!(st.advance_commit());
+        assert_eq!(st.commit_index, 5);
+        assert_eq!(st.role, Role::Follower);
+    }
+
+    #[test]
+    fn test_persist() {
+        let mut path = env::temp_dir();
+        path.push(format!("h2okv-raft-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let _ = fs::remove_file(&path);
+        let load = || {
+            let mut st = State::new(1, peers(&[1, 2, 3]), 100);
+            st.load(&path).map(|_| st)
+        };
+        assert!(!State::new(1, peers(&[1]), 100).load(&path).unwrap());
+
+        let mut st = State::new(1, peers(&[1, 2, 3]), 100);
+        st.state_file = Some(path.clone());
+        st.term = 2;
+        st.entries = vec![put(1, "a"), put(2, "b")];
+        st.persist().unwrap();
+        let size = fs::metadata(&path).unwrap().len();
+
+        // changes are appended
+        st.voted_for = Some(3);
+        st.truncate(2);
+        st.entries.push(put(2, "c"));
+        st.entries.push(put(2, "d"));
+        st.persist().unwrap();
+        assert!(fs::metadata(&path).unwrap().len() > size);
+        let loaded = load().unwrap();
+        assert_eq!((loaded.term, loaded.voted_for), (2, Some(3)));
+        assert_eq!(loaded.entries, st.entries);
+
+        // a record cut short by a crash is ignored
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[9, 0, 0, 0, 0, 0, 0, 0, 0x01]).unwrap();
+        assert_eq!(load().unwrap().entries, st.entries);
+
+        // written anew with the snapshot
+        st.compact(1, b"data".to_vec());
+        assert_eq!(st.saved.as_ref().unwrap().records, 0);
+        let loaded = load().unwrap();
+        assert_eq!(loaded.snapshot_index, 1);
+        assert_eq!(loaded.entries, st.entries);
+
+        // but a broken file is an error
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 0, 0, 0, 0, 0, 0, 0, 0x09]).unwrap();
+        assert!(load().is_err());
+        fs::write(&path, b"broken").unwrap();
+        assert!(load().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,294 @@
+// Leader-follower replication.
+//
+// A server started with `--replicaof HOST:PORT` (or told so with the
+// REPLICAOF query) connects to the primary and sends SYNC with the
+// sequence number of its next change (see `src/changes.rs`). If the
+// primary still has all changes since then, it just streams them to the
+// replica (partial sync), else it first sends a snapshot of the whole DB,
+// in the format of the disk file (see `src/persistence.rs`), then the
+// changes following it (full sync).
+//
+// The replica applies changes with `store::put()` and `store::delete()`,
+// and `store::push()` and `store::pop()` for lists, so its sequence
+// numbers are the same as the primary's, and its own watchers and CHANGES
+// followers work as on the primary. While replicating, writes from
+// clients, e.g. PUT, DEL or pops of lists, are rejected, and a lost
+// connection to the
+// primary is retried every second. REPLICAOF with an empty address
+// promotes the replica to primary.
+//
+// SYNC also has the ID of the history of changes of the replica, which it
+// got with the snapshot of its primary. A promoted replica starts a new
+// history, so that an old primary made a replica of it, which may have
+// changes of its own with the same sequence numbers, gets a full sync
+// instead of going on from there. Replicas which were following the same
+// primary until the promotion can still go on from where they were.
+
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::auth;
+use crate::changes;
+use crate::large;
+use crate::logging;
+use crate::persistence;
+use crate::store;
+use crate::tools;
+use crate::watch;
+
+pub struct Replication {
+    /// address of the primary, `None` when we are the primary
+    primary: Option<String>,
+    /// bumped each time the primary changes, so that the thread
+    /// replicating from the old one knows it should quit
+    generation: u64,
+    /// connection to the primary, to shut it down when it changes
+    link: Option<TcpStream>,
+}
+
+impl Replication {
+    pub fn new() -> Replication {
+        Replication {
+            primary: None,
+            generation: 0,
+            link: None,
+        }
+    }
+}
+
+pub fn is_replica(arc_repl: &Arc<Mutex<Replication>>) -> bool {
+    arc_repl.lock().unwrap().primary.is_some()
+}
+
+/// Start replicating from the primary at `addr`, or become primary if
+/// `addr` is empty.
+pub fn replicate_from(
+    addr: &str,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_repl: Arc<Mutex<Replication>>,
+) {
+    let generation = {
+        let mut repl = arc_repl.lock().unwrap();
+        repl.generation += 1;
+        if let Some(link) = repl.link.take() {
+            let _ = link.shutdown(Shutdown::Both);
+        }
+        if addr.is_empty() {
+            if repl.primary.take().is_some() {
+                changes::new_history(&mut arc_db.lock().unwrap().changes);
+                logging::info!("promoted to primary");
+            }
+            return;
+        }
+        repl.primary = Some(addr.to_string());
+        repl.generation
+    };
+
+    let addr = addr.to_string();
+    thread::spawn(move || loop {
+        if !is_current(generation, &arc_repl) {
+            break;
+        }
+        match TcpStream::connect(&addr) {
+            Ok(mut stream) => {
+                if !auth::login(&mut stream) {
+                    logging::warning!("authentication to primary {} failed", &addr);
+                } else {
+                    logging::info!("connected to primary {}", &addr);
+                    sync(stream, generation, &arc_db, &arc_repl);
+                    logging::warning!("lost connection to primary {}", &addr);
+                }
+            }
+            Err(e) => {
+                logging::warning!("cannot connect to primary {}: {:?}", &addr, e);
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    });
+}
+
+fn is_current(generation: u64, arc_repl: &Arc<Mutex<Replication>>) -> bool {
+    arc_repl.lock().unwrap().generation == generation
+}
+
+/// Read `size` bytes, which are only allocated as they come in
+fn read_exact(stream: &mut TcpStream, size: usize) -> Option<Vec<u8>> {
+    let mut buffer = Vec::new();
+    match Read::by_ref(stream)
+        .take(size as u64)
+        .read_to_end(&mut buffer)
+    {
+        Ok(n) if n == size => Some(buffer),
+        _ => None,
+    }
+}
+
+/// Read a u64 of `count` bytes at most 8
+fn read_u64(stream: &mut TcpStream, count: u8) -> Option<u64> {
+    if count > 8 {
+        logging::warning!("number of {} bytes from primary", count);
+        return None;
+    }
+    let bytes = read_exact(stream, count as usize)?;
+    Some(tools::bytes_to_u64(&bytes))
+}
+
+/// Read `<len-byte><len-bytes>`, the variable-length u64 of the protocol
+fn read_len(stream: &mut TcpStream) -> Option<u64> {
+    let buf_llen = read_exact(stream, 1)?;
+    read_u64(stream, buf_llen[0])
+}
+
+fn save(db: &store::DB) {
+    if let Some(db_file) = tools::get_db_file() {
+        if let Err(e) = persistence::save_to_file(&db_file, db) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+}
+
+/// Sync with the primary on the connection, until it's closed or the
+/// primary changed.
+fn sync(
+    mut stream: TcpStream,
+    generation: u64,
+    arc_db: &Arc<Mutex<store::DB>>,
+    arc_repl: &Arc<Mutex<Replication>>,
+) {
+    {
+        let mut repl = arc_repl.lock().unwrap();
+        if repl.generation != generation {
+            return;
+        }
+        repl.link = stream.try_clone().ok();
+    }
+
+    // a replica which never got any change asks for a full sync
+    let (id, next_seq) = {
+        let db = arc_db.lock().unwrap();
+        (changes::id(&db.changes), changes::next_seq(&db.changes))
+    };
+    let from = if next_seq > 1 { next_seq } else { 0 };
+    let (count, bytes) = tools::u64_to_bytes(from);
+    let mut query = vec![0x0c, 0x0d, 0x00];
+    query.extend(&tools::u16_to_bytes(8 + count as u16));
+    query.extend(&id.to_le_bytes());
+    query.extend(&bytes);
+    if let Err(e) = stream.write_all(&query) {
+        logging::warning!("cannot send SYNC: {:?}", e);
+        return;
+    }
+
+    let reply = match read_exact(&mut stream, 3) {
+        Some(x) => x,
+        None => return,
+    };
+    if reply[0] != 0x0c || reply[1] != 0x00 {
+        logging::warning!("SYNC rejected by primary: {:?}", reply);
+        return;
+    }
+    if reply[2] == 0x01 {
+        let size = match read_len(&mut stream) {
+            Some(x) => x,
+            None => return,
+        };
+        let snapshot = match read_exact(&mut stream, size as usize) {
+            Some(x) => x,
+            None => return,
+        };
+        if !is_current(generation, arc_repl) {
+            return;
+        }
+        let mut db = arc_db.lock().unwrap();
+        db.items.clear();
+        db.lists.clear();
+        changes::set_next_seq(1, &mut db.changes);
+        if let Err(e) = persistence::load(&mut &snapshot[..], &mut db) {
+            logging::error!("cannot load snapshot from primary: {}", e);
+            return;
+        }
+        save(&db);
+        logging::info!("full sync done, {} keys", db.items.len());
+    }
+
+    loop {
+        let header = match read_exact(&mut stream, 3) {
+            Some(x) => x,
+            None => return,
+        };
+        if header[0] != 0x0c || header[1] != 0x12 {
+            logging::warning!("bad change frame from primary");
+            return;
+        }
+        let seq = match read_u64(&mut stream, header[2]) {
+            Some(x) => x,
+            None => return,
+        };
+        let event = match read_exact(&mut stream, 1) {
+            Some(x) => x[0],
+            None => return,
+        };
+        let buf_klen = match read_exact(&mut stream, 2) {
+            Some(x) => x,
+            None => return,
+        };
+        let klen = match large::read_len(&buf_klen, &mut stream, true) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+        let key = match read_exact(&mut stream, klen) {
+            Some(x) => x,
+            None => return,
+        };
+        let vlen = match read_len(&mut stream) {
+            Some(x) => x,
+            None => return,
+        };
+        let value = match read_exact(&mut stream, vlen as usize) {
+            Some(x) => x,
+            None => return,
+        };
+        let key = match str::from_utf8(&key) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        if !is_current(generation, arc_repl) {
+            return;
+        }
+        let mut db = arc_db.lock().unwrap();
+        if changes::next_seq(&db.changes) != seq {
+            // out of sync with the primary, start over with a full sync
+            logging::warning!("unexpected change {} from primary", seq);
+            changes::set_next_seq(1, &mut db.changes);
+            return;
+        }
+        let applied = match event {
+            watch::EVENT_PUT => store::put(key, &value, &mut db).is_ok(),
+            watch::EVENT_DELETE => {
+                store::delete(key, &mut db);
+                true
+            }
+            watch::EVENT_LPUSH | watch::EVENT_RPUSH => match str::from_utf8(&value) {
+                Ok(x) => {
+                    let left = event == watch::EVENT_LPUSH;
+                    store::push(key, store::Value::from(x), left, &mut db).is_ok()
+                }
+                Err(_) => false,
+            },
+            watch::EVENT_LPOP | watch::EVENT_RPOP => {
+                store::pop(key, event == watch::EVENT_LPOP, &mut db).is_ok()
+            }
+            _ => false,
+        };
+        if !applied {
+            logging::warning!("cannot apply change {} from primary", seq);
+            return;
+        }
+        save(&db);
+    }
+}
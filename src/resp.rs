@@ -0,0 +1,696 @@
+// Listener speaking the Redis protocol (RESP), so that `redis-cli` and
+// Redis client libraries can be used with h2okv.
+//
+// Commands are arrays of bulk strings, or inline commands split by
+// spaces, e.g. `PING` typed into telnet. Replies are in RESP2, until the
+// client switches to RESP3 with `HELLO 3`, where null values and maps
+// have their own types.
+//
+// Supported: PING, ECHO, HELLO, AUTH, GET, SET (with NX/XX), MGET, MSET,
+// DEL, UNLINK, EXISTS, KEYS, SCAN, DBSIZE, TYPE, SELECT 0, INFO, COMMAND,
+// CLIENT SETNAME, ACL (LIST, SETUSER and DELUSER), ASKING and QUIT.
+// When users are configured (see `src/auth.rs`), other commands are
+// replied with `-NOAUTH` until AUTH, or HELLO with AUTH, succeeds, and
+// commands or keys the user may not access with `-NOPERM`, KEYS and
+// SCAN only return the keys the user may access. Writes go through the
+// same checks as
+// the h2okv protocol: replicas are read-only, in cluster mode they are
+// committed through the Raft log, and keys of other servers are
+// redirected with `-MOVED` or `-ASK`.
+//
+// Addresses in redirects are the ones of the h2okv protocol, shifted by
+// the offset between `--resp-port` and `--port`, i.e. all servers are
+// expected to use the same offset.
+//
+// Bulk strings are bounded by `--max-key-size` or `--max-value-size`,
+// whichever is larger, and whole commands by `--max-request-size`, see
+// `src/limits.rs`. Lines, i.e. inline commands and lengths, are bounded
+// by `MAX_LINE`.
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use crate::access;
+use crate::auth;
+use crate::limits;
+use crate::logging;
+use crate::raft;
+use crate::replication;
+use crate::slots;
+use crate::store;
+use crate::tools;
+
+/// Longest line accepted, as inline commands in Redis
+const MAX_LINE: usize = 64 * 1024;
+const MAX_ARGS: usize = 1024 * 1024;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<Reply>),
+    Map(Vec<(Reply, Reply)>),
+}
+
+/// Per-connection state
+struct Session {
+    /// 2 or 3, as switched by HELLO
+    version: u8,
+    /// set by ASKING, for the next command only
+    asking: bool,
+    /// `--resp-port` minus `--port`, for addresses in redirects
+    offset: i32,
+    /// the user authenticated with AUTH
+    user: Option<String>,
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<String>, String> {
+    let mut line = vec![];
+    match reader.take(MAX_LINE as u64).read_until(b'\n', &mut line) {
+        Ok(0) => return Ok(None),
+        Ok(_) => {}
+        Err(e) => return Err(format!("{}", e)),
+    }
+    if line.len() == MAX_LINE && !line.ends_with(b"\n") {
+        return Err("too big inline request".to_string());
+    }
+    match String::from_utf8(line) {
+        Ok(x) => Ok(Some(x.trim_end_matches(&['\r', '\n'][..]).to_string())),
+        Err(_) => Err("invalid UTF-8".to_string()),
+    }
+}
+
+fn parse_length(text: &str, max: usize) -> Result<usize, String> {
+    match text.parse::<usize>() {
+        Ok(x) if x <= max => Ok(x),
+        _ => Err(format!("invalid length: {}", text)),
+    }
+}
+
+/// Read a command, returns `None` when the client disconnected
+fn read_command<R: BufRead>(
+    reader: &mut R,
+    limits: &limits::Limits,
+) -> Result<Option<Vec<Vec<u8>>>, String> {
+    let line = match read_line(reader)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    if !line.starts_with('*') {
+        let args = line
+            .split_whitespace()
+            .map(|x| x.as_bytes().to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let max_bulk = limits.max_key_size.max(limits.max_value_size);
+    let count = parse_length(&line[1..], MAX_ARGS)?;
+    // grown as the arguments come in, not as claimed by the count
+    let mut args = vec![];
+    let mut total = 0;
+    for _ in 0..count {
+        let line = match read_line(reader)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        if !line.starts_with('$') {
+            return Err(format!("expected bulk string, got {:?}", line));
+        }
+        let size = parse_length(&line[1..], max_bulk)?;
+        total += size;
+        if total > limits.max_request_size {
+            return Err(format!("request of over {} bytes", total));
+        }
+        // allocated as the bytes come in, not as claimed by the length
+        let mut buffer = vec![];
+        match reader
+            .by_ref()
+            .take(size as u64 + 2)
+            .read_to_end(&mut buffer)
+        {
+            Ok(n) if n == size + 2 => {}
+            Ok(_) => return Err("unexpected end of stream".to_string()),
+            Err(e) => return Err(format!("{}", e)),
+        }
+        buffer.truncate(size);
+        args.push(buffer);
+    }
+    Ok(Some(args))
+}
+
+fn encode(reply: &Reply, version: u8, buffer: &mut Vec<u8>) {
+    match reply {
+        Reply::Simple(x) => buffer.extend(format!("+{}\r\n", x).as_bytes()),
+        Reply::Error(x) => buffer.extend(format!("-{}\r\n", x).as_bytes()),
+        Reply::Integer(x) => buffer.extend(format!(":{}\r\n", x).as_bytes()),
+        Reply::Bulk(x) => {
+            buffer.extend(format!("${}\r\n", x.len()).as_bytes());
+            buffer.extend(x);
+            buffer.extend(b"\r\n");
+        }
+        Reply::Null if version == 3 => buffer.extend(b"_\r\n"),
+        Reply::Null => buffer.extend(b"$-1\r\n"),
+        Reply::Array(items) => {
+            buffer.extend(format!("*{}\r\n", items.len()).as_bytes());
+            for x in items {
+                encode(x, version, buffer);
+            }
+        }
+        Reply::Map(items) => {
+            if version == 3 {
+                buffer.extend(format!("%{}\r\n", items.len()).as_bytes());
+            } else {
+                buffer.extend(format!("*{}\r\n", items.len() * 2).as_bytes());
+            }
+            for (k, v) in items {
+                encode(k, version, buffer);
+                encode(v, version, buffer);
+            }
+        }
+    }
+}
+
+fn ok() -> Reply {
+    Reply::Simple("OK".to_string())
+}
+
+fn bulk(text: &str) -> Reply {
+    Reply::Bulk(text.as_bytes().to_vec())
+}
+
+fn wrong_args(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name
+    ))
+}
+
+fn to_string(arg: &[u8]) -> Result<String, Reply> {
+    match String::from_utf8(arg.to_vec()) {
+        Ok(x) => Ok(x),
+        Err(_) => Err(Reply::Error(
+            "ERR only UTF-8 keys and values are supported".to_string(),
+        )),
+    }
+}
+
+/// Reply of an access error
+fn error_reply(e: access::Error, session: &Session) -> Reply {
+    let text = match e {
+        access::Error::ReadOnly => {
+            "READONLY You can't write against a read only replica.".to_string()
+        }
+        access::Error::NotLeader(Some(leader)) => {
+            format!(
+                "NOTLEADER leader is {}",
+                access::shift_port(&leader, session.offset)
+            )
+        }
+        access::Error::NotLeader(None) => "NOTLEADER leader unknown".to_string(),
+        access::Error::Moved(slot, addr) => {
+            format!(
+                "MOVED {} {}",
+                slot,
+                access::shift_port(&addr, session.offset)
+            )
+        }
+        access::Error::Ask(slot, addr) => {
+            format!("ASK {} {}", slot, access::shift_port(&addr, session.offset))
+        }
+        access::Error::Failed => "ERR write failed".to_string(),
+    };
+    Reply::Error(text)
+}
+
+/// Put the pairs, or only when none of the keys `exist` as wished for
+/// SET with NX or XX. Returns if they were put.
+fn put_all(
+    pairs: Vec<(String, String)>,
+    exist: Option<bool>,
+    session: &Session,
+    ctx: &access::Context,
+) -> Result<bool, access::Error> {
+    access::check_writable(ctx)?;
+    let keys: Vec<String> = pairs.iter().map(|x| x.0.clone()).collect();
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&keys, session.asking, &db, ctx)?;
+    if let Some(exist) = exist {
+        if keys.iter().any(|x| db.items.contains_key(x) != exist) {
+            return Ok(false);
+        }
+    }
+    let commands = pairs
+        .into_iter()
+        .map(|(k, v)| raft::Command::Put(k, v))
+        .collect();
+    access::write(commands, db, ctx)?;
+    Ok(true)
+}
+
+/// Delete the keys, returns how many were there
+fn delete_all(
+    keys: Vec<String>,
+    session: &Session,
+    ctx: &access::Context,
+) -> Result<i64, access::Error> {
+    access::check_writable(ctx)?;
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(&keys, session.asking, &db, ctx)?;
+    let commands = keys.into_iter().map(raft::Command::Delete).collect();
+    let result = access::write(commands, db, ctx)?;
+    Ok(result.iter().filter(|x| **x).count() as i64)
+}
+
+/// Get the keys, `Reply::Null` for the missing ones
+fn get_all(
+    keys: &[String],
+    session: &Session,
+    ctx: &access::Context,
+) -> Result<Vec<Reply>, access::Error> {
+    let db = ctx.arc_db.lock().unwrap();
+    access::check_keys(keys, session.asking, &db, ctx)?;
+    let values = keys
+        .iter()
+        .map(|x| match store::get(x, &db) {
+            Some(value) => Reply::Bulk(value.into_bytes()),
+            None => Reply::Null,
+        })
+        .collect();
+    Ok(values)
+}
+
+fn keys_matching(pattern: &str, acl: &auth::Acl, ctx: &access::Context) -> Reply {
+    let db = ctx.arc_db.lock().unwrap();
+    let mut keys: Vec<&String> = db
+        .items
+        .keys()
+        .filter(|x| tools::glob_match(pattern, x) && auth::allows_key(x, acl))
+        .collect();
+    keys.sort();
+    Reply::Array(keys.into_iter().map(|x| bulk(x)).collect())
+}
+
+fn noauth() -> Reply {
+    Reply::Error("NOAUTH Authentication required.".to_string())
+}
+
+fn login(user: &str, password: &str, session: &mut Session, ctx: &access::Context) -> Reply {
+    match auth::check(user, password, &ctx.arc_auth.lock().unwrap()) {
+        Some(x) => {
+            session.user = Some(x);
+            ok()
+        }
+        None => Reply::Error(
+            "WRONGPASS invalid username-password pair or user is disabled.".to_string(),
+        ),
+    }
+}
+
+/// Category of the command, for ACL
+fn category(name: &str) -> Option<auth::Category> {
+    match name {
+        "get" | "mget" | "exists" | "type" | "keys" | "scan" | "dbsize" => {
+            Some(auth::Category::Read)
+        }
+        "set" | "mset" | "del" | "unlink" => Some(auth::Category::Write),
+        "acl" => Some(auth::Category::Admin),
+        _ => None,
+    }
+}
+
+/// Keys in the arguments of the command
+fn keys_of<'a>(name: &str, args: &'a [String]) -> Vec<&'a String> {
+    match name {
+        "get" | "mget" | "exists" | "type" | "del" | "unlink" => args.iter().collect(),
+        "set" => args.iter().take(1).collect(),
+        "mset" => args.iter().step_by(2).collect(),
+        _ => vec![],
+    }
+}
+
+/// ACL LIST, ACL SETUSER name [rule ...] and ACL DELUSER name, with the
+/// rules in `src/auth.rs`
+fn acl_command(args: &[String], ctx: &access::Context) -> Reply {
+    let mut users = ctx.arc_auth.lock().unwrap();
+    let rules: Vec<&str> = args.iter().skip(2).map(|x| x.as_str()).collect();
+    let result = match (args[0].to_lowercase().as_str(), args.get(1)) {
+        ("list", None) => {
+            let lines = auth::list(&users);
+            return Reply::Array(lines.lines().map(bulk).collect());
+        }
+        ("setuser", Some(name)) => auth::set_user(name, &rules, &mut users).map(|_| true),
+        ("deluser", Some(name)) if rules.is_empty() => auth::delete_user(name, &mut users),
+        _ => {
+            return Reply::Error(
+                "ERR unknown subcommand or wrong number of arguments for 'acl'".to_string(),
+            )
+        }
+    };
+    match result {
+        Ok(changed) => {
+            if changed {
+                auth::save(&users);
+            }
+            match args[0].to_lowercase().as_str() {
+                "setuser" => ok(),
+                _ => Reply::Integer(changed as i64),
+            }
+        }
+        Err(e) => Reply::Error(format!("ERR {}", e)),
+    }
+}
+
+/// HELLO [protover [AUTH username password] [SETNAME clientname]]
+fn hello(args: &[String], session: &mut Session, ctx: &access::Context) -> Reply {
+    let mut i = 1;
+    while i < args.len() {
+        match (
+            args[i].to_lowercase().as_str(),
+            args.get(i + 1),
+            args.get(i + 2),
+        ) {
+            ("auth", Some(user), Some(password)) => {
+                let reply = login(user, password, session, ctx);
+                if reply != ok() {
+                    return reply;
+                }
+                i += 3;
+            }
+            ("setname", Some(_), _) => i += 2,
+            _ => return Reply::Error("ERR syntax error".to_string()),
+        }
+    }
+    if session.user.is_none() && auth::is_required(&ctx.arc_auth.lock().unwrap()) {
+        return noauth();
+    }
+    if let Some(version) = args.first() {
+        match version.as_str() {
+            "2" => session.version = 2,
+            "3" => session.version = 3,
+            _ => return Reply::Error("NOPROTO unsupported protocol version".to_string()),
+        }
+    }
+    let mode = if slots::is_sharded(&ctx.arc_slots.lock().unwrap()) {
+        "cluster"
+    } else {
+        "standalone"
+    };
+    let role = if replication::is_replica(&ctx.arc_repl) {
+        "replica"
+    } else {
+        "master"
+    };
+    Reply::Map(vec![
+        (bulk("server"), bulk("h2okv")),
+        (bulk("version"), bulk(env!("CARGO_PKG_VERSION"))),
+        (bulk("proto"), Reply::Integer(session.version as i64)),
+        (bulk("mode"), bulk(mode)),
+        (bulk("role"), bulk(role)),
+        (bulk("modules"), Reply::Array(vec![])),
+    ])
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count], all keys are returned at
+/// once, with cursor 0.
+fn scan(args: &[String], acl: &auth::Acl, ctx: &access::Context) -> Reply {
+    let mut pattern = "*";
+    let mut i = 1;
+    while i < args.len() {
+        match (args[i].to_lowercase().as_str(), args.get(i + 1)) {
+            ("match", Some(x)) => pattern = x,
+            ("count", Some(x)) if x.parse::<usize>().is_ok() => {}
+            ("type", Some(_)) => {}
+            _ => return Reply::Error("ERR syntax error".to_string()),
+        }
+        i += 2;
+    }
+    Reply::Array(vec![bulk("0"), keys_matching(pattern, acl, ctx)])
+}
+
+fn set(
+    args: Vec<String>,
+    session: &Session,
+    ctx: &access::Context,
+) -> Result<Reply, access::Error> {
+    let mut exist = None;
+    for option in args[2..].iter() {
+        match option.to_lowercase().as_str() {
+            "nx" if exist.is_none() => exist = Some(false),
+            "xx" if exist.is_none() => exist = Some(true),
+            _ => return Ok(Reply::Error("ERR syntax error".to_string())),
+        }
+    }
+    let mut args = args.into_iter();
+    let key = args.next().unwrap_or_default();
+    let value = args.next().unwrap_or_default();
+    if put_all(vec![(key, value)], exist, session, ctx)? {
+        Ok(ok())
+    } else {
+        Ok(Reply::Null)
+    }
+}
+
+fn execute(
+    name: &str,
+    args: Vec<String>,
+    session: &mut Session,
+    ctx: &access::Context,
+) -> Result<Reply, access::Error> {
+    if session.user.is_none()
+        && name != "auth"
+        && name != "hello"
+        && auth::is_required(&ctx.arc_auth.lock().unwrap())
+    {
+        return Ok(noauth());
+    }
+    let acl = auth::acl_of(&session.user, &ctx.arc_auth.lock().unwrap());
+    if let Some(x) = category(name) {
+        if !auth::allows(x, &acl) {
+            let text = format!(
+                "NOPERM this user has no permissions to run the '{}' command",
+                name
+            );
+            return Ok(Reply::Error(text));
+        }
+    }
+    if !keys_of(name, &args)
+        .iter()
+        .all(|x| auth::allows_key(x, &acl))
+    {
+        let text = "NOPERM No permissions to access a key".to_string();
+        return Ok(Reply::Error(text));
+    }
+    let reply = match (name, args.len()) {
+        ("auth", 1) => login("", &args[0], session, ctx),
+        ("auth", 2) => login(&args[0], &args[1], session, ctx),
+        ("ping", 0) => Reply::Simple("PONG".to_string()),
+        ("ping", 1) | ("echo", 1) => bulk(&args[0]),
+        ("hello", _) => hello(&args, session, ctx),
+        ("get", 1) => get_all(&args, session, ctx)?.remove(0),
+        ("set", n) if n >= 2 => set(args, session, ctx)?,
+        ("mget", n) if n >= 1 => Reply::Array(get_all(&args, session, ctx)?),
+        ("mset", n) if n >= 2 && n % 2 == 0 => {
+            let mut pairs = vec![];
+            let mut args = args.into_iter();
+            while let (Some(key), Some(value)) = (args.next(), args.next()) {
+                pairs.push((key, value));
+            }
+            put_all(pairs, None, session, ctx)?;
+            ok()
+        }
+        ("del", n) | ("unlink", n) if n >= 1 => Reply::Integer(delete_all(args, session, ctx)?),
+        ("exists", n) if n >= 1 => {
+            let values = get_all(&args, session, ctx)?;
+            Reply::Integer(values.iter().filter(|x| **x != Reply::Null).count() as i64)
+        }
+        ("type", 1) => match get_all(&args, session, ctx)?.remove(0) {
+            Reply::Null => Reply::Simple("none".to_string()),
+            _ => Reply::Simple("string".to_string()),
+        },
+        ("keys", 1) => keys_matching(&args[0], &acl, ctx),
+        ("scan", n) if n >= 1 && n % 2 == 1 => scan(&args, &acl, ctx),
+        ("dbsize", 0) => Reply::Integer(ctx.arc_db.lock().unwrap().items.len() as i64),
+        ("select", 1) if args[0] == "0" => ok(),
+        ("select", 1) => Reply::Error("ERR DB index is out of range".to_string()),
+        ("info", _) => bulk(&format!(
+            "# Server\r\nh2okv_version:{}\r\n",
+            env!("CARGO_PKG_VERSION")
+        )),
+        ("command", _) => Reply::Array(vec![]),
+        ("client", n) if n >= 1 && args[0].to_lowercase() == "setname" => ok(),
+        ("acl", n) if n >= 1 => acl_command(&args, ctx),
+        ("asking", 0) => {
+            session.asking = true;
+            return Ok(ok());
+        }
+        ("auth", _)
+        | ("ping", _)
+        | ("echo", _)
+        | ("get", _)
+        | ("set", _)
+        | ("mget", _)
+        | ("mset", _)
+        | ("del", _)
+        | ("unlink", _)
+        | ("exists", _)
+        | ("type", _)
+        | ("keys", _)
+        | ("scan", _)
+        | ("dbsize", _)
+        | ("select", _)
+        | ("asking", _)
+        | ("acl", _) => wrong_args(name),
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    };
+    session.asking = false;
+    Ok(reply)
+}
+
+fn handle_client(stream: TcpStream, offset: i32, ctx: access::Context) {
+    let peer = match stream.peer_addr() {
+        Ok(x) => x.to_string(),
+        Err(_) => "unknown".to_string(),
+    };
+    logging::new_connection(&peer);
+    logging::info!("RESP client accepted");
+    let mut writer = match stream.try_clone() {
+        Ok(x) => x,
+        Err(e) => {
+            logging::warning!("cannot clone client stream: {:?}", e);
+            return;
+        }
+    };
+    let mut reader = BufReader::new(stream);
+    let mut session = Session {
+        version: 2,
+        asking: false,
+        offset,
+        user: None,
+    };
+
+    loop {
+        let args = match read_command(&mut reader, limits::get()) {
+            Ok(Some(x)) => x,
+            Ok(None) => break,
+            Err(e) => {
+                logging::warning!("bad RESP command: {}", e);
+                let mut buffer = vec![];
+                encode(
+                    &Reply::Error(format!("ERR Protocol error: {}", e)),
+                    2,
+                    &mut buffer,
+                );
+                let _ = writer.write_all(&buffer);
+                break;
+            }
+        };
+        if args.is_empty() {
+            continue;
+        }
+
+        let name = String::from_utf8_lossy(&args[0]).to_lowercase();
+        if name == "quit" {
+            let mut buffer = vec![];
+            encode(&ok(), session.version, &mut buffer);
+            let _ = writer.write_all(&buffer);
+            break;
+        }
+        let reply = match args[1..].iter().map(|x| to_string(x)).collect() {
+            Ok(args) => match execute(&name, args, &mut session, &ctx) {
+                Ok(x) => x,
+                Err(e) => error_reply(e, &session),
+            },
+            Err(x) => x,
+        };
+        let mut buffer = vec![];
+        encode(&reply, session.version, &mut buffer);
+        if writer.write_all(&buffer).is_err() {
+            break;
+        }
+    }
+    logging::info!("RESP client disconnected");
+}
+
+/// Listen on `addr` for RESP clients, in a thread, `offset` is
+/// `--resp-port` minus `--port`.
+pub fn start(addr: &str, offset: i32, ctx: access::Context) {
+    let listener = match TcpListener::bind(addr) {
+        Ok(x) => x,
+        Err(e) => {
+            logging::error!("cannot listen on {} for RESP: {:?}", addr, e);
+            return;
+        }
+    };
+    logging::info!("RESP listener started at {}", addr);
+    thread::spawn(move || {
+        for connection in listener.incoming() {
+            match connection {
+                Ok(stream) => {
+                    let _ = stream.set_nodelay(true);
+                    let ctx = ctx.clone();
+                    thread::spawn(move || handle_client(stream, offset, ctx));
+                }
+                Err(e) => {
+                    logging::warning!("RESP accept failed: {:?}", e);
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{encode, read_command, Reply};
+    use crate::limits::Limits;
+
+    #[test]
+    fn test_read_command() {
+        let limits = Limits::new();
+        let mut input: &[u8] = b"*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$5\r\nb\r\nar\r\nPING\r\n";
+        let args = read_command(&mut input, &limits).unwrap().unwrap();
+        assert_eq!(
+            args,
+            vec![b"SET".to_vec(), b"foo".to_vec(), b"b\r\nar".to_vec()]
+        );
+        let args = read_command(&mut input, &limits).unwrap().unwrap();
+        assert_eq!(args, vec![b"PING".to_vec()]);
+        assert_eq!(read_command(&mut input, &limits), Ok(None));
+
+        let mut input: &[u8] = b"*1\r\n:3\r\n";
+        assert!(read_command(&mut input, &limits).is_err());
+        let mut input: &[u8] = b"*-1\r\n";
+        assert!(read_command(&mut input, &limits).is_err());
+        let mut input: &[u8] = b"*1\r\n$3\r\nfo";
+        assert!(read_command(&mut input, &limits).is_err());
+        let mut input: &[u8] = b"*1\r\n$600000000\r\nfoo\r\n";
+        assert!(read_command(&mut input, &limits).is_err());
+        let line = vec![b'a'; 70000];
+        assert!(read_command(&mut &line[..], &limits).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        let reply = Reply::Array(vec![
+            Reply::Bulk(b"foo".to_vec()),
+            Reply::Null,
+            Reply::Integer(3),
+            Reply::Error("ERR no".to_string()),
+        ]);
+        let mut buffer = vec![];
+        encode(&reply, 2, &mut buffer);
+        assert_eq!(buffer, b"*4\r\n$3\r\nfoo\r\n$-1\r\n:3\r\n-ERR no\r\n");
+
+        let reply = Reply::Map(vec![(Reply::Simple("a".to_string()), Reply::Null)]);
+        let mut buffer = vec![];
+        encode(&reply, 2, &mut buffer);
+        assert_eq!(buffer, b"*2\r\n+a\r\n$-1\r\n");
+        let mut buffer = vec![];
+        encode(&reply, 3, &mut buffer);
+        assert_eq!(buffer, b"%1\r\n+a\r\n_\r\n");
+    }
+}
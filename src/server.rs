@@ -1,268 +1,2054 @@
-use std::io::Read;
-use std::io::Write;
-use std::net::{TcpListener, TcpStream};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
 use std::str;
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
+use crate::access;
+use crate::auth;
+use crate::blocking;
+use crate::changes;
+use crate::cipher;
+use crate::compress;
+use crate::config;
+use crate::encryption;
+use crate::error::Error;
+use crate::hello;
+use crate::http;
+use crate::large;
+use crate::limits;
+use crate::logging;
+use crate::memcache;
+use crate::migration;
 use crate::persistence;
+use crate::pubsub;
+use crate::push;
+use crate::raft;
+use crate::replication;
+use crate::resp;
+use crate::slots;
 use crate::store;
+use crate::stream::{self, Stream};
+use crate::tls;
 use crate::tools;
+use crate::watch;
 
-pub fn run(arc_db: Arc<Mutex<store::DB>>) {
-    let host = "127.0.0.1";
-    let port = 30160;
-    let addr = format!("{}:{}", host, port);
-    let listener = TcpListener::bind(&addr).expect("socket bind failed");
-    println!("H2o KV statted at {}", &addr);
+/// A connection in push mode, i.e. subscribed to some channels or
+/// watching some keys.
+///
+/// All writes to the client go through `tx`, and the `writer` thread
+/// sends them out, so that the pushed messages and the replies of the
+/// connection itself never interleave. See `src/push.rs` for clients not
+/// reading fast enough.
+struct Pusher {
+    tx: push::Queue,
+    writer: JoinHandle<()>,
+}
+
+/// Per-connection state
+struct Session {
+    id: usize,
+    /// set when the connection is in push mode
+    pusher: Option<Pusher>,
+    /// set by ASKING, for the next query only
+    asking: bool,
+    /// the user authenticated with AUTH
+    user: Option<String>,
+    /// what the user may do, as of the current query
+    acl: auth::Acl,
+    /// features of the client, from HELLO
+    features: Vec<String>,
+}
 
+pub fn run(arc_db: Arc<Mutex<store::DB>>, config: &config::Config) {
+    let addr = format!("{}:{}", config.host, config.port);
+    // with `--port 0`, only the unix socket or TLS is listened on
+    let listener = match config.port {
+        0 => None,
+        _ => match TcpListener::bind(&addr) {
+            Ok(x) => Some(x),
+            Err(e) => {
+                logging::error!("cannot listen on {}: {:?}", addr, e);
+                return;
+            }
+        },
+    };
+    if listener.is_some() {
+        logging::info!("H2o KV statted at {}", &addr);
+    }
+    // before connecting to other servers, which is done with `--password`
+    let mut users = auth::from_config(&config.password, &config.users);
+    // the users saved after ACL changes are more recent
+    if auth::load(&mut users) {
+        logging::info!("users loaded");
+    }
+    if config.memcache_port.is_some() {
+        // the memcached text protocol has no authentication
+        if auth::is_required(&users) {
+            logging::error!("cannot use --memcache-port with --password, --users or users saved");
+            return;
+        }
+        auth::freeze(&mut users);
+    }
+    let arc_auth = Arc::new(Mutex::new(users));
+    if let Some(path) = &config.cipher_key_file {
+        if let Err(e) = cipher::load(path) {
+            logging::error!("{}", e);
+            return;
+        }
+    }
+    let arc_ps = Arc::new(Mutex::new(pubsub::PubSub::new()));
+    let arc_repl = Arc::new(Mutex::new(replication::Replication::new()));
+    if let Some(primary) = &config.replica_of {
+        replication::replicate_from(primary, arc_db.clone(), arc_repl.clone());
+    }
+    let mut slot_map = slots::SlotMap::new(&addr);
+    if let Some(spec) = &config.slots {
+        // the map saved after migrating slots is more recent
+        if slots::load(&mut slot_map) {
+            logging::info!("slot map loaded");
+        } else if let Err(e) = slots::parse(spec, &mut slot_map) {
+            logging::error!("{}", e);
+            return;
+        }
+    }
+    let arc_slots = Arc::new(Mutex::new(slot_map));
+    let cluster = match config.raft_id {
+        Some(id) => {
+            let peers = config.raft_peers.clone();
+            match raft::start(id, peers, config.raft_snapshot, arc_db.clone()) {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    logging::error!("raft: {}", e);
+                    return;
+                }
+            }
+        }
+        None => None,
+    };
+    let ctx = access::Context {
+        arc_db: arc_db.clone(),
+        arc_repl: arc_repl.clone(),
+        cluster: cluster.clone(),
+        arc_slots: arc_slots.clone(),
+        arc_auth,
+    };
+    if let Some(port) = config.resp_port {
+        let offset = port as i32 - config.port as i32;
+        resp::start(&format!("{}:{}", config.host, port), offset, ctx.clone());
+    }
+    if let Some(port) = config.memcache_port {
+        memcache::start(&format!("{}:{}", config.host, port), ctx.clone());
+    }
+    if let Some(port) = config.http_port {
+        let offset = port as i32 - config.port as i32;
+        http::start(&format!("{}:{}", config.host, port), offset, ctx.clone());
+    }
+
+    let mut listeners = vec![];
+    if let Some(path) = &config.unix_socket {
+        let unix_listener = match stream::bind_unix(path, config.unix_socket_perm) {
+            Ok(x) => x,
+            Err(e) => {
+                logging::error!("cannot listen on {}: {:?}", path, e);
+                return;
+            }
+        };
+        logging::info!("H2o KV listening on {}", path);
+        let (ps, ctx) = (arc_ps.clone(), ctx.clone());
+        listeners.push(thread::spawn(move || {
+            for connection in unix_listener.incoming() {
+                match connection {
+                    Ok(x) => spawn_client(Stream::Unix(x), &ps, &ctx),
+                    Err(e) => logging::warning!("unix socket accept failed: {:?}", e),
+                }
+            }
+        }));
+    }
+    if let Some(port) = config.tls_port {
+        let (cert, key) = (
+            config.tls_cert.as_ref().unwrap(),
+            config.tls_key.as_ref().unwrap(),
+        );
+        let tls_config = match tls::server_config(cert, key, config.tls_ca_cert.as_deref()) {
+            Ok(x) => x,
+            Err(e) => {
+                logging::error!("{}", e);
+                return;
+            }
+        };
+        let tls_addr = format!("{}:{}", config.host, port);
+        let tls_listener = match TcpListener::bind(&tls_addr) {
+            Ok(x) => x,
+            Err(e) => {
+                logging::error!("cannot listen on {} for TLS: {:?}", tls_addr, e);
+                return;
+            }
+        };
+        logging::info!("H2o KV listening on {} for TLS", tls_addr);
+        let (ps, ctx) = (arc_ps.clone(), ctx.clone());
+        listeners.push(thread::spawn(move || {
+            for connection in tls_listener.incoming() {
+                let stream = connection.and_then(|x| {
+                    let _ = x.set_nodelay(true);
+                    tls::accept(x, &tls_config)
+                });
+                match stream {
+                    Ok(x) => spawn_client(Stream::Tls(x), &ps, &ctx),
+                    Err(e) => logging::warning!("TLS accept failed: {:?}", e),
+                }
+            }
+        }));
+    }
+
+    let listener = match listener {
+        Some(x) => x,
+        None => {
+            for x in listeners {
+                let _ = x.join();
+            }
+            return;
+        }
+    };
     for connection in listener.incoming() {
         match connection {
-            Ok(mut stream) => {
-                let clone_arc = arc_db.clone();
-                thread::spawn(move || {
-                    handle_client(&mut stream, clone_arc);
-                });
+            Ok(stream) => {
+                // replies are written in pieces, don't wait to send them
+                let _ = stream.set_nodelay(true);
+                spawn_client(Stream::Tcp(stream), &arc_ps, &ctx);
             }
-            Err(e) => panic!(e),
+            Err(e) => logging::warning!("accept failed: {:?}", e),
         }
     }
 }
 
-fn handle_del(data: &[u8], stream: &mut TcpStream, arc_db: Arc<Mutex<store::DB>>) -> bool {
-    let klen = tools::bytes_to_u16(&data[3..]);
-    let mut buf_key = Vec::with_capacity(klen as usize);
-    for _ in 0..klen {
-        buf_key.push(0_u8);
+/// Serve the client in a new thread
+fn spawn_client(mut stream: Stream, arc_ps: &Arc<Mutex<pubsub::PubSub>>, ctx: &access::Context) {
+    let clone_arc = ctx.arc_db.clone();
+    let clone_ps = arc_ps.clone();
+    let clone_repl = ctx.arc_repl.clone();
+    let clone_cluster = ctx.cluster.clone();
+    let clone_slots = ctx.arc_slots.clone();
+    let clone_auth = ctx.arc_auth.clone();
+    thread::spawn(move || {
+        let (repl, cluster, slots) = (clone_repl, clone_cluster, clone_slots);
+        handle_client(
+            &mut stream,
+            clone_arc,
+            clone_ps,
+            repl,
+            cluster,
+            slots,
+            clone_auth,
+        );
+    });
+}
+
+/// Reply MOVED or ASK if the key is not served here, returns if so
+fn reply_redirect(
+    key: &str,
+    exists: bool,
+    asking: bool,
+    stream: &mut Stream,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+) -> bool {
+    let route = slots::route(key, exists, asking, &arc_slots.lock().unwrap());
+    if route == slots::Route::Local {
+        return false;
     }
-    match stream.read_exact(&mut buf_key) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full key bytes: {:?}", e);
-            return false;
-        }
+    let _ = stream.write_all(&slots::redirect_reply(&route));
+    true
+}
+
+/// Reply "no permission" if the user may not access the key, returns if
+/// so
+fn reply_denied(key: &str, acl: &auth::Acl, stream: &mut Stream) -> bool {
+    if auth::allows_key(key, acl) {
+        return false;
     }
+    let _ = stream.write_all(b"\x0c\x09");
+    true
+}
+
+fn handle_del(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let buf_key = match read_key(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
 
     match str::from_utf8(&buf_key) {
         Ok(key) => {
+            if reply_denied(key, acl, stream) {
+                return true;
+            }
+            // hold the DB, so that the key is not migrated meanwhile
             let mut db = arc_db.lock().unwrap();
-            if let Some(_) = store::delete(key, &mut db) {
-                stream.write(b"\x0c\x00").unwrap();
+            let exists = store::exists(key, &db);
+            if reply_redirect(key, exists, asking, stream, arc_slots) {
+                return true;
+            }
+            let reply = match store::delete(key, &mut db) {
+                Some(_) => b"\x0c\x00",
+                None => b"\x0c\x02",
+            };
+            // saved before replying, not to lose writes acknowledged
+            if let Err(e) = save_db(&db) {
+                logging::error!("cannot save the DB: {}", e);
+            }
+            stream.write_all(reply).is_ok()
+        }
+        Err(_) => fail(Error::InvalidUtf8, stream),
+    }
+}
+
+/// Handle GET, with the value compressed if the client accepts `gzip`,
+/// with Flag `\x01` or from HELLO, or encrypted with Flag `\x02`. Large
+/// values are replied from their chunks in the store, without Flag, and
+/// in chunks to clients with `chunked`, see `src/large.rs`.
+fn handle_get(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let gzip = data[2] == 0x01 || has_feature("gzip", session);
+    let acl = &session.acl;
+    let buffer = match read_key(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+
+    match str::from_utf8(&buffer) {
+        Ok(key) => {
+            if reply_denied(key, acl, stream) {
+                return true;
+            }
+            let db = arc_db.lock().unwrap();
+            let exists = store::exists(key, &db);
+            if reply_redirect(key, exists, asking, stream, arc_slots) {
+                return true;
+            }
+            if db.lists.contains_key(key) {
+                return stream.write_all(b"\x0c\x0c").is_ok();
+            }
+            if let Some(x) = store::get_value(key, &db) {
+                drop(db);
+                let chunked = has_feature("chunked", session);
+                if x.len() > large::CHUNK_SIZE && data[2] != 0x02 {
+                    return write_value(&[0x0c, 0x00, 0x00], &x, chunked, stream).is_ok();
+                }
+                let x = x.to_string();
+                let (flag, value) = match data[2] {
+                    0x02 => (0x02, cipher::encrypt(x.as_bytes(), key.as_bytes())),
+                    _ => compress::reply_value(x.as_bytes(), gzip),
+                };
+                if value.len() > large::CHUNK_SIZE && chunked {
+                    return stream
+                        .write_all(&[0x0c, 0x00, flag, large::CHUNKED])
+                        .and_then(|_| large::write_chunks(value.chunks(large::CHUNK_SIZE), stream))
+                        .is_ok();
+                }
+                let mut reply = vec![0x0c, 0x00, flag];
+                let (count, len_buffer) = tools::u64_to_bytes(value.len() as u64);
+                reply.push(count);
+                reply.extend(&len_buffer);
+                reply.extend(&value);
+                stream.write_all(&reply).is_ok()
             } else {
-                stream.write(b"\x0c\x02").unwrap();
+                stream.write_all(b"\x0c\x02").is_ok()
             }
         }
+        Err(_) => fail(Error::InvalidUtf8, stream),
+    }
+}
+
+/// Reply the value of GET from its chunks, without Flag, in chunks or not,
+/// after the `head` of the reply, e.g. `\x0C\x00\x00`
+fn write_value(
+    head: &[u8],
+    value: &store::Value,
+    chunked: bool,
+    stream: &mut Stream,
+) -> io::Result<()> {
+    if chunked {
+        stream.write_all(&[head, &[large::CHUNKED]].concat())?;
+        return large::write_chunks(value.chunks(), stream);
+    }
+    let mut reply = head.to_vec();
+    let (count, len_buffer) = tools::u64_to_bytes(value.len() as u64);
+    reply.push(count);
+    reply.extend(&len_buffer);
+    stream.write_all(&reply)?;
+    for chunk in value.chunks() {
+        stream.write_all(chunk)?;
+    }
+    Ok(())
+}
+
+/// Read the rest of a PUT-like query: `<key-len:2><key><vllen><vlen><value>`,
+/// or with the value in chunks (see `src/large.rs`), with the value
+/// decompressed if the query has Flag `\x01`, or decrypted if it has Flag
+/// `\x02`. The value is in pieces of up to `large::CHUNK_SIZE`, or whole
+/// if decompressed or decrypted.
+///
+/// Returns an error if the stream is broken, the query is over a limit,
+/// the key is not valid UTF-8, or the value cannot be decompressed or
+/// authenticated.
+fn read_key_value(
+    data: &[u8],
+    stream: &mut Stream,
+    bigkeys: bool,
+) -> Result<(String, Vec<Vec<u8>>), Error> {
+    let (key, pieces) = read_raw_key_value(data, stream, bigkeys)?;
+    let value = match data[2] {
+        0x01 => compress::gunzip(&pieces.concat(), limits::get().max_value_size as u64),
+        0x02 => cipher::decrypt(&pieces.concat(), key.as_bytes()),
+        _ => return Ok((key, pieces)),
+    };
+    match value {
+        Ok(x) => Ok((key, vec![x])),
+        Err(e) if e == compress::TOO_LARGE => {
+            Err(Error::TooLarge("decompressed value".to_string()))
+        }
+        Err(e) => Err(Error::InvalidValue(e)),
+    }
+}
+
+/// Read the rest of a PUT-like query, with the value as sent, in pieces
+fn read_raw_key_value(
+    data: &[u8],
+    stream: &mut Stream,
+    bigkeys: bool,
+) -> Result<(String, Vec<Vec<u8>>), Error> {
+    let buf_key = read_key(data, stream, bigkeys)?;
+    let key = String::from_utf8(buf_key).map_err(|_| Error::InvalidUtf8)?;
+
+    let mut buf_vllen = [0; 1];
+    stream.read_exact(&mut buf_vllen)?;
+    let limits = limits::get();
+    if buf_vllen[0] == large::CHUNKED {
+        let max = limits.max_request_size.saturating_sub(key.len());
+        let max = limits.max_value_size.min(max) as u64;
+        return match large::read_chunks(stream, max) {
+            Ok(x) => Ok((key, x)),
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                Err(Error::TooLarge("chunked value".to_string()))
+            }
+            Err(e) => Err(Error::Io(e)),
+        };
+    }
+    if buf_vllen[0] > 8 {
+        let e = format!("value length of {} bytes", buf_vllen[0]);
+        return Err(Error::InvalidQuery(e));
+    }
+
+    let buf_vlen = read_exact(stream, buf_vllen[0] as u64)?;
+    let vlen = tools::bytes_to_u64(&buf_vlen);
+    if limits::exceeds(key.len(), vlen, limits) {
+        return Err(Error::TooLarge(format!("value of {} bytes", vlen)));
+    }
+    let pieces = large::read_pieces(stream, vlen)?;
+    Ok((key, pieces))
+}
+
+fn handle_put(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let (key, pieces) = match read_key_value(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    if reply_denied(&key, acl, stream) {
+        return true;
+    }
+
+    let mut db = arc_db.lock().unwrap();
+    let exists = store::exists(&key, &db);
+    if reply_redirect(&key, exists, asking, stream, arc_slots) {
+        return true;
+    }
+    let reply = match store::Value::from_pieces(pieces) {
+        Ok(value) => {
+            store::put_value(&key, value, &mut db);
+            vec![0x0c, 0x00]
+        }
         Err(e) => {
-            println!("from_utf8 failed: {:?}", e);
-            stream.write(b"\x0c\x01").unwrap();
-            return false;
+            logging::warning!("put failed: {}", e);
+            vec![0x0c, e.status().unwrap_or(0x01)]
         }
+    };
+    // saved before replying, not to lose writes acknowledged
+    if let Err(e) = save_db(&db) {
+        logging::error!("cannot save the DB: {}", e);
     }
-    true
+    stream.write_all(&reply).is_ok()
 }
 
-fn handle_get(data: &[u8], stream: &mut TcpStream, arc_db: Arc<Mutex<store::DB>>) -> bool {
-    let size = tools::bytes_to_u16(&data[3..]);
-    let mut buffer = Vec::with_capacity(size as usize);
-    for _ in 0..size {
-        buffer.push(0_u8);
+/// Handle LPUSH and RPUSH, replied with the length of the list then,
+/// `<len:4>`, or Wrong type if the key holds a string
+fn handle_list_push(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let (key, pieces) = match read_key_value(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    if reply_denied(&key, acl, stream) {
+        return true;
     }
-    match stream.read_exact(&mut buffer) {
-        Ok(_) => {}
+
+    let mut db = arc_db.lock().unwrap();
+    let exists = store::exists(&key, &db);
+    if reply_redirect(&key, exists, asking, stream, arc_slots) {
+        return true;
+    }
+    let pushed = store::Value::from_pieces(pieces)
+        .and_then(|x| store::push(&key, x, data[1] == 0x1B, &mut db));
+    let reply = match pushed {
+        Ok(len) => {
+            let mut reply = vec![0x0c, 0x00];
+            reply.extend(&tools::u32_to_bytes(len as u32));
+            reply
+        }
         Err(e) => {
-            println!("cannot read full key bytes: {:?}", e);
-            return false;
+            logging::warning!("push failed: {}", e);
+            vec![0x0c, e.status().unwrap_or(0x01)]
         }
+    };
+    // saved before replying, not to lose writes acknowledged
+    if let Err(e) = save_db(&db) {
+        logging::error!("cannot save the DB: {}", e);
     }
+    stream.write_all(&reply).is_ok()
+}
+
+/// Handle LPOP and RPOP, replied as GET, or Wrong type if the key holds
+/// a string
+fn handle_list_pop(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let buffer = match read_key(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let key = match str::from_utf8(&buffer) {
+        Ok(x) => x,
+        Err(_) => return fail(Error::InvalidUtf8, stream),
+    };
+    if reply_denied(key, acl, stream) {
+        return true;
+    }
+
+    let mut db = arc_db.lock().unwrap();
+    let exists = store::exists(key, &db);
+    if reply_redirect(key, exists, asking, stream, arc_slots) {
+        return true;
+    }
+    let popped = store::pop(key, data[1] == 0x1D, &mut db);
+    if let Ok(Some(_)) = popped {
+        if let Err(e) = save_db(&db) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+    drop(db);
+    let chunked = has_feature("chunked", session);
+    match popped {
+        Ok(Some(x)) => write_value(&[0x0c, 0x00, 0x00], &x, chunked, stream).is_ok(),
+        Ok(None) => stream.write_all(b"\x0c\x02").is_ok(),
+        Err(e) => stream
+            .write_all(&[0x0c, e.status().unwrap_or(0x01)])
+            .is_ok(),
+    }
+}
+
+/// Handle BLPOP and BRPOP, with content `<timeout-ms:4><klen:2><key>...`,
+/// popping from the first of the keys with a list, or else waiting for a
+/// push to any of them until the timeout, forever if 0, see
+/// `src/blocking.rs`. Replied with `\x0C\x00\x00<klen:2><key>` and the
+/// value as GET, or Not found when the timeout expires.
+fn handle_blocking_pop(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let left = data[1] == 0x1F;
+    let content = match read_bytes(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let keys = match content.get(4..).and_then(parse_keys) {
+        Some(x) if !x.is_empty() => x,
+        _ => {
+            logging::warning!("invalid blocking pop query");
+            return stream.write_all(b"\x0c\x01").is_ok();
+        }
+    };
+    let timeout = tools::bytes_to_u64(&content[..4]);
+
+    for key in keys.iter() {
+        if reply_denied(key, acl, stream) {
+            return true;
+        }
+    }
+    let mut db = arc_db.lock().unwrap();
+    for key in keys.iter() {
+        let exists = store::exists(key, &db);
+        if reply_redirect(key, exists, asking, stream, arc_slots) {
+            return true;
+        }
+    }
+    let mut popped = None;
+    for key in keys.iter() {
+        match store::pop(key, left, &mut db) {
+            Ok(Some(x)) => {
+                popped = Some((key.to_string(), x));
+                break;
+            }
+            Ok(None) => (),
+            Err(e) => {
+                return stream
+                    .write_all(&[0x0c, e.status().unwrap_or(0x01)])
+                    .is_ok()
+            }
+        }
+    }
+    if popped.is_some() {
+        if let Err(e) = save_db(&db) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+    drop(db);
+
+    let (key, value) = match popped {
+        Some(x) => x,
+        None => match wait_popped(&keys, left, timeout, &arc_db, session) {
+            Some(x) => x,
+            None => return stream.write_all(b"\x0c\x02").is_ok(),
+        },
+    };
+    let mut head = vec![0x0c, 0x00, 0x00];
+    head.extend(large::len_bytes(key.len()));
+    head.extend(key.as_bytes());
+    let chunked = has_feature("chunked", session);
+    if write_value(&head, &value, chunked, stream).is_ok() {
+        return true;
+    }
+    // not to lose the element, back where it was popped from
+    let mut db = arc_db.lock().unwrap();
+    if store::push(&key, value, left, &mut db).is_ok() {
+        if let Err(e) = save_db(&db) {
+            logging::error!("cannot save the DB: {}", e);
+        }
+    }
+    false
+}
+
+/// Park until an element is pushed to any of the keys, or the timeout of
+/// `timeout` ms expires, forever if 0
+fn wait_popped(
+    keys: &[String],
+    left: bool,
+    timeout: u64,
+    arc_db: &Arc<Mutex<store::DB>>,
+    session: &Session,
+) -> Option<blocking::Popped> {
+    let rx = blocking::block(session.id, keys, left, &mut arc_db.lock().unwrap().blocked);
+    let received = match timeout {
+        0 => rx.recv().ok(),
+        x => rx.recv_timeout(Duration::from_millis(x)).ok(),
+    };
+    if received.is_some() {
+        return received;
+    }
+    // an element may be handed until we leave the queues, with the DB held
+    let mut db = arc_db.lock().unwrap();
+    blocking::unblock(session.id, &mut db.blocked);
+    rx.try_recv().ok()
+}
+
+/// Handle SCAN, only with the keys the user may access, and keys of
+/// 64 KiB or more only for clients with `bigkeys`
+fn handle_scan(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    session: &Session,
+) -> bool {
+    let bigkeys = has_feature("bigkeys", session);
+    let buffer = match read_bytes(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
 
     match str::from_utf8(&buffer) {
         Ok(key) => {
             let db = arc_db.lock().unwrap();
-            if let Some(x) = store::get(key, &db) {
-                stream.write(b"\x0c\x00\x00").unwrap();
+            let mut items = store::scan(key, &db);
+            items.retain(|x| auth::allows_key(x, &session.acl));
+            items.retain(|x| bigkeys || x.len() < large::LONG_LEN as usize);
+            let len = items.len();
+            if len == 0 {
+                return stream.write_all(b"\x0c\x02").is_ok();
+            }
+
+            let mut reply = b"\x0c\x00\x00".to_vec();
+            reply.extend(&tools::u32_to_bytes(len as u32));
+            for x in items {
+                reply.extend(large::len_bytes(x.len()));
+                reply.extend(x.as_bytes());
+            }
+            stream.write_all(&reply).is_ok()
+        }
+        Err(_) => fail(Error::InvalidUtf8, stream),
+    }
+}
+
+/// Split MGET content `<klen:2><key><klen:2><key>...` into keys
+fn parse_keys(content: &[u8]) -> Option<Vec<String>> {
+    let mut keys = Vec::new();
+    let mut i = 0;
+    while i < content.len() {
+        if i + 2 > content.len() {
+            return None;
+        }
+        let klen = tools::bytes_to_u16(&content[i..i + 2]) as usize;
+        i += 2;
+        if i + klen > content.len() {
+            return None;
+        }
+        match str::from_utf8(&content[i..i + klen]) {
+            Ok(x) => keys.push(x.to_string()),
+            Err(_) => return None,
+        }
+        i += klen;
+    }
+    Some(keys)
+}
+
+/// Handle MGET, replying `<count:4>` and then `\x00` for a missing key,
+/// or `\x01<vllen><vlen><value>`, for each key in order.
+///
+/// When any of the keys is not served here, only the redirect of the
+/// first such key is replied.
+fn handle_mget(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    asking: bool,
+    session: &Session,
+) -> bool {
+    let acl = &session.acl;
+    let content = match read_bytes(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let keys = match parse_keys(&content) {
+        Some(x) => x,
+        None => {
+            logging::warning!("invalid MGET keys");
+            return stream.write_all(b"\x0c\x01").is_ok();
+        }
+    };
+
+    for key in keys.iter() {
+        if reply_denied(key, acl, stream) {
+            return true;
+        }
+    }
+    let db = arc_db.lock().unwrap();
+    for key in keys.iter() {
+        let exists = store::exists(key, &db);
+        if reply_redirect(key, exists, asking, stream, arc_slots) {
+            return true;
+        }
+    }
+    let mut reply = b"\x0c\x00".to_vec();
+    reply.extend(&tools::u32_to_bytes(keys.len() as u32));
+    for key in keys.iter() {
+        match store::get(key, &db) {
+            Some(x) => {
                 let (count, len_buffer) = tools::u64_to_bytes(x.len() as u64);
-                stream.write(&[count]).unwrap();
-                stream.write(&len_buffer).unwrap();
-                stream.write(x.as_bytes()).unwrap();
-            } else {
-                stream.write(b"\x0c\x02").unwrap();
+                reply.push(0x01);
+                reply.push(count);
+                reply.extend(&len_buffer);
+                reply.extend(x.as_bytes());
             }
+            None => reply.push(0x00),
         }
+    }
+    stream.write_all(&reply).is_ok()
+}
+
+/// Read the content part of a query, whose length is in the header, or
+/// follows it for long keys, see `src/large.rs`
+fn read_bytes(data: &[u8], stream: &mut Stream, bigkeys: bool) -> Result<Vec<u8>, Error> {
+    read_sized(data, stream, limits::get().max_request_size, bigkeys)
+}
+
+/// Read the key of a query, in the content part or before the value
+fn read_key(data: &[u8], stream: &mut Stream, bigkeys: bool) -> Result<Vec<u8>, Error> {
+    read_sized(data, stream, limits::get().max_key_size, bigkeys)
+}
+
+/// Read the content part of a query, which is too large if it's longer
+/// than `max`, see `src/limits.rs`. The length is only long for clients
+/// with `bigkeys`.
+fn read_sized(
+    data: &[u8],
+    stream: &mut Stream,
+    max: usize,
+    bigkeys: bool,
+) -> Result<Vec<u8>, Error> {
+    let size = large::read_len(&data[3..], stream, bigkeys)?;
+    if size > max {
+        return Err(Error::TooLarge(format!("query of {} bytes", size)));
+    }
+    read_exact(stream, size as u64)
+}
+
+/// Read `size` bytes, which are only allocated as they come in, rather
+/// than all the length a client claims upfront
+fn read_exact(stream: &mut Stream, size: u64) -> Result<Vec<u8>, Error> {
+    let mut buffer = vec![];
+    Read::by_ref(stream).take(size).read_to_end(&mut buffer)?;
+    if buffer.len() as u64 != size {
+        return Err(Error::Io(io::ErrorKind::UnexpectedEof.into()));
+    }
+    Ok(buffer)
+}
+
+/// Read the content part of a query as UTF-8 string
+fn read_content(data: &[u8], stream: &mut Stream, bigkeys: bool) -> Result<String, Error> {
+    let buffer = read_bytes(data, stream, bigkeys)?;
+    String::from_utf8(buffer).map_err(|_| Error::InvalidUtf8)
+}
+
+/// Read and drop a query we are not going to serve, keeping the stream
+/// in sync for the next query.
+fn discard_query(data: &[u8], stream: &mut Stream, bigkeys: bool) -> Result<(), Error> {
+    match data[1] {
+        0x02 | 0x08 | 0x16 | 0x1B | 0x1C => read_raw_key_value(data, stream, bigkeys).map(|_| ()),
+        0x0F => read_message(stream).map(|_| ()),
+        _ => read_bytes(data, stream, bigkeys).map(|_| ()),
+    }
+}
+
+/// Drop a query we are not going to serve, and reply the frame instead.
+/// Returns false to disconnect.
+fn reject(frame: &[u8], data: &[u8], stream: &mut Stream, session: &Session) -> bool {
+    match discard_query(data, stream, has_feature("bigkeys", session)) {
+        Ok(_) => reply(frame, stream, session),
+        Err(e) => fail(e, stream),
+    }
+}
+
+/// Log the error of a query, and reply its Stat if the client can still
+/// be told, see `src/error.rs`. Returns false, to disconnect.
+fn fail(e: Error, stream: &mut Stream) -> bool {
+    logging::warning!("{}", e);
+    if let Some(x) = e.status() {
+        let _ = stream.write_all(&[0x0c, x]);
+    }
+    false
+}
+
+fn start_pusher(stream: &Stream) -> Option<Pusher> {
+    let mut stream = match stream.try_clone() {
+        Ok(x) => x,
         Err(e) => {
-            println!("from_utf8 failed: {:?}", e);
-            return false;
+            logging::warning!("cannot clone client stream: {:?}", e);
+            return None;
+        }
+    };
+    if let Err(e) = stream.set_write_timeout(Some(push::WRITE_TIMEOUT)) {
+        logging::warning!("cannot set write timeout: {:?}", e);
+        return None;
+    }
+    let (tx, mut frames) = push::queue();
+    let connection = logging::connection();
+    let writer = thread::spawn(move || {
+        logging::set_connection(connection);
+        for frame in frames.by_ref() {
+            if let Err(e) = stream.write_all(&frame) {
+                logging::warning!("cannot push to subscriber: {:?}", e);
+                break;
+            }
+        }
+        if push::is_lagging(&frames) {
+            logging::warning!("subscriber too slow, disconnect it");
         }
+        // so that the connection is closed, if it's not already
+        let _ = stream.shutdown();
+    });
+    Some(Pusher { tx, writer })
+}
+
+/// Leave push mode, after all queued frames are sent out.
+///
+/// The connection must have no subscriptions left, or the writer thread
+/// will never quit.
+fn stop_pusher(pusher: Pusher) {
+    drop(pusher.tx);
+    if pusher.writer.join().is_err() {
+        logging::error!("subscriber writer thread panicked");
     }
+}
 
-    true
+fn subscribe_reply(count: usize) -> Vec<u8> {
+    let mut reply = vec![0x0c, 0x00];
+    reply.extend(&tools::u32_to_bytes(count as u32));
+    reply
 }
 
-fn handle_put(data: &[u8], stream: &mut TcpStream, arc_db: Arc<Mutex<store::DB>>) -> bool {
-    let klen = tools::bytes_to_u16(&data[3..]);
-    let mut buf_key = Vec::with_capacity(klen as usize);
-    for _ in 0..klen {
-        buf_key.push(0_u8);
+/// Number of channels, patterns, keys, prefixes and change feeds the
+/// connection is subscribed to, i.e. whether it should stay in push mode.
+fn subscriptions(
+    id: usize,
+    arc_db: &Arc<Mutex<store::DB>>,
+    arc_ps: &Arc<Mutex<pubsub::PubSub>>,
+) -> usize {
+    let subscribed = pubsub::count(id, &arc_ps.lock().unwrap());
+    let db = arc_db.lock().unwrap();
+    subscribed + watch::count(id, &db.watchers) + changes::count(id, &db.changes)
+}
+
+/// Handle SUBSCRIBE, PSUBSCRIBE, WATCH & PWATCH, switching the connection
+/// into push mode
+fn handle_subscribe(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    session: &mut Session,
+) -> bool {
+    let name = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let allowed = match data[1] {
+        0x09 => auth::allows_key(&name, &session.acl),
+        0x0A => auth::allows_prefix(&name, &session.acl),
+        _ => true,
+    };
+    if !allowed {
+        return reply(b"\x0c\x09", stream, session);
     }
-    match stream.read_exact(&mut buf_key) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full key bytes: {:?}", e);
-            return false;
+    if session.pusher.is_none() {
+        session.pusher = start_pusher(stream);
+    }
+    let tx = match &session.pusher {
+        Some(x) => &x.tx,
+        None => return false,
+    };
+
+    let id = session.id;
+    match data[1] {
+        0x05 => {
+            pubsub::subscribe(&name, id, tx, &mut arc_ps.lock().unwrap());
+        }
+        0x07 => {
+            pubsub::psubscribe(&name, id, tx, &mut arc_ps.lock().unwrap());
+        }
+        0x09 => {
+            watch::watch(&name, id, tx, &mut arc_db.lock().unwrap().watchers);
+        }
+        _ => {
+            watch::watch_prefix(&name, id, tx, &mut arc_db.lock().unwrap().watchers);
         }
     }
+    let count = subscriptions(id, &arc_db, &arc_ps);
+    push::send(subscribe_reply(count), tx)
+}
 
-    let key;
-    match str::from_utf8(&buf_key) {
-        Ok(x) => key = x,
-        Err(e) => {
-            println!("from_utf8 failed: {:?}", e);
-            return false;
+/// Handle UNSUBSCRIBE & UNWATCH, leaving push mode when nothing is
+/// subscribed
+fn handle_unsubscribe(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    session: &mut Session,
+) -> bool {
+    let name = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    if data[1] == 0x06 {
+        pubsub::unsubscribe(&name, session.id, &mut arc_ps.lock().unwrap());
+    } else {
+        watch::unwatch(&name, session.id, &mut arc_db.lock().unwrap().watchers);
+    }
+    let count = subscriptions(session.id, &arc_db, &arc_ps);
+
+    match session.pusher.take() {
+        Some(x) => {
+            let sent = push::send(subscribe_reply(count), &x.tx);
+            if count > 0 {
+                session.pusher = Some(x);
+            } else {
+                stop_pusher(x);
+            }
+            sent
         }
+        None => stream.write_all(&subscribe_reply(count)).is_ok(),
     }
+}
 
-    let mut buf_vllen = [0; 1];
-    match stream.read_exact(&mut buf_vllen) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full bytes: {:?}", e);
-            return false;
+/// Reply of CHANGES, and the changes to push before the new ones
+fn changes_reply(from: u64, db: &store::DB) -> (Vec<u8>, Option<Vec<Vec<u8>>>) {
+    let backlog = changes::since(from, &db.changes);
+    let mut reply = vec![0x0c];
+    reply.push(if backlog.is_some() { 0x00 } else { 0x03 });
+    let (count, bytes) = tools::u64_to_bytes(changes::next_seq(&db.changes));
+    reply.push(count);
+    reply.extend(&bytes);
+    (reply, backlog)
+}
+
+/// Reply of SYNC, with the DB snapshot if the changes since `from` are
+/// not available, and the changes to push before the new ones
+fn sync_reply(id: u64, from: u64, db: &store::DB) -> (Vec<u8>, Option<Vec<Vec<u8>>>) {
+    if from > 0 && changes::same_history(id, from, &db.changes) {
+        if let Some(backlog) = changes::since(from, &db.changes) {
+            return (b"\x0c\x00\x00".to_vec(), Some(backlog));
         }
     }
+    let snapshot = persistence::dump(db);
+    let mut reply = b"\x0c\x00\x01".to_vec();
+    let (count, bytes) = tools::u64_to_bytes(snapshot.len() as u64);
+    reply.push(count);
+    reply.extend(&bytes);
+    reply.extend(&snapshot);
+    (reply, Some(vec![]))
+}
 
-    let mut buf_vlen = Vec::with_capacity(buf_vllen[0] as usize);
-    for _ in 0..buf_vllen[0] {
-        buf_vlen.push(0_u8);
+/// Handle CHANGES & SYNC: push all changes since the sequence number in
+/// the query, then the new ones as they happen. SYNC has the ID of the
+/// history of the replica before it, see `replication.rs`.
+fn handle_changes(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    session: &mut Session,
+) -> bool {
+    let content = match read_bytes(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let (id, seq) = if data[1] == 0x0D {
+        if content.len() < 8 {
+            let e = format!("replication ID of {} bytes", content.len());
+            return fail(Error::InvalidQuery(e), stream);
+        }
+        let (id, seq) = content.split_at(8);
+        (tools::bytes_to_u64(id), seq)
+    } else {
+        (0, &content[..])
+    };
+    if seq.len() > 8 {
+        let e = format!("sequence number of {} bytes", seq.len());
+        return fail(Error::InvalidQuery(e), stream);
     }
-    match stream.read_exact(&mut buf_vlen) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full len bytes: {:?}", e);
-            return false;
+    let from = tools::bytes_to_u64(seq);
+    // changes of all keys are pushed
+    if !auth::allows_prefix("", &session.acl) {
+        return reply(b"\x0c\x09", stream, session);
+    }
+    if session.pusher.is_none() {
+        session.pusher = start_pusher(stream);
+    }
+    let pusher = match session.pusher.take() {
+        Some(x) => x,
+        None => return false,
+    };
+
+    let sent = {
+        // hold the lock until following, so no change is missed
+        let mut db = arc_db.lock().unwrap();
+        let (reply, backlog) = if data[1] == 0x0C {
+            changes_reply(from, &db)
+        } else {
+            sync_reply(id, from, &db)
+        };
+
+        let mut sent = push::send(reply, &pusher.tx);
+        if let Some(frames) = backlog {
+            sent = sent && push::send_backlog(frames, &pusher.tx);
+            changes::follow(session.id, &pusher.tx, &mut db.changes);
         }
+        sent
+    };
+
+    if subscriptions(session.id, &arc_db, &arc_ps) > 0 {
+        session.pusher = Some(pusher);
+    } else {
+        stop_pusher(pusher);
     }
+    sent
+}
 
-    let vlen = tools::bytes_to_u64(&buf_vlen);
-    let mut buf_value = Vec::with_capacity(vlen as usize);
-    for _ in 0..vlen {
-        buf_value.push(0_u8);
+/// Handle REPLICAOF: replicate from the primary in the query, or become
+/// primary if it's empty
+fn handle_replicaof(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_repl: Arc<Mutex<replication::Replication>>,
+    session: &Session,
+) -> bool {
+    let addr = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    replication::replicate_from(&addr, arc_db, arc_repl);
+    stream.write_all(b"\x0c\x00").is_ok()
+}
+
+fn handle_publish(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    session: &Session,
+) -> bool {
+    let (channel, message) = match read_key_value(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let count = {
+        let mut ps = arc_ps.lock().unwrap();
+        pubsub::publish(&channel, &message.concat(), &mut ps)
+    };
+    stream.write_all(&subscribe_reply(count)).is_ok()
+}
+
+/// Read `<len-byte><len-bytes><message>` of the RAFT query, which has its
+/// own limit, see `src/raft.rs`
+fn read_message(stream: &mut Stream) -> Result<Vec<u8>, Error> {
+    let mut buf_llen = [0_u8; 1];
+    stream.read_exact(&mut buf_llen)?;
+    if buf_llen[0] > 8 {
+        let e = format!("message length of {} bytes", buf_llen[0]);
+        return Err(Error::InvalidQuery(e));
     }
-    match stream.read_exact(&mut buf_value) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full content bytes: {:?}", e);
-            return false;
-        }
+    let buf_len = read_exact(stream, buf_llen[0] as u64)?;
+    let size = tools::bytes_to_u64(&buf_len);
+    if size > raft::max_message_size() {
+        return Err(Error::TooLarge(format!("message of {} bytes", size)));
     }
+    read_exact(stream, size)
+}
 
-    let mut db = arc_db.lock().unwrap();
-    match store::put(key, &buf_value, &mut db) {
-        Ok(_) => {
-            stream.write(b"\x0c\x00").unwrap();
+/// Handle RAFT, a message from another node of the cluster
+fn handle_raft(stream: &mut Stream, node: &raft::Node) -> bool {
+    let message = match read_message(stream) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let reply = match raft::handle_rpc(node, &message) {
+        Some(x) => x,
+        None => return false,
+    };
+    let (count, bytes) = tools::u64_to_bytes(reply.len() as u64);
+    let mut buffer = vec![count];
+    buffer.extend(&bytes);
+    buffer.extend(&reply);
+    stream.write_all(&buffer).is_ok()
+}
+
+/// Reply of a write rejected by a follower: `"\x0C\x05<addr-len:2><addr>"`
+/// with the address of the leader, which is empty if unknown.
+fn not_leader_reply(leader: Option<String>) -> Vec<u8> {
+    let leader = leader.unwrap_or_default();
+    let mut reply = vec![0x0c, 0x05];
+    reply.extend(&tools::u16_to_bytes(leader.len() as u16));
+    reply.extend(leader.as_bytes());
+    reply
+}
+
+/// Handle PUT and DEL in cluster mode, committing them through the
+/// Raft log before replying.
+fn handle_cluster_write(
+    data: &[u8],
+    stream: &mut Stream,
+    node: &Arc<raft::Node>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    session: &Session,
+) -> bool {
+    let command = if data[1] == 0x02 {
+        let (key, value) = match read_key_value(data, stream, has_feature("bigkeys", session)) {
+            Ok(x) => x,
+            Err(e) => return fail(e, stream),
+        };
+        match String::from_utf8(value.concat()) {
+            Ok(value) => raft::Command::Put(key, value),
+            Err(_) => return stream.write_all(b"\x0c\x01").is_ok(),
         }
-        Err(_) => {
-            stream.write(b"\x0c\x01").unwrap();
+    } else {
+        match read_content(data, stream, has_feature("bigkeys", session)) {
+            Ok(key) => raft::Command::Delete(key),
+            Err(e) => return fail(e, stream),
         }
+    };
+    let key = match &command {
+        raft::Command::Put(key, _) | raft::Command::Delete(key) => key,
+        _ => "",
+    };
+    if reply_denied(key, &session.acl, stream) {
+        return true;
     }
-    true
+    // slots are not migrated in cluster mode
+    if reply_redirect(key, true, false, stream, arc_slots) {
+        return true;
+    }
+
+    let reply = match raft::propose(command, node) {
+        Ok(true) => b"\x0c\x00".to_vec(),
+        Ok(false) if data[1] == 0x03 => b"\x0c\x02".to_vec(),
+        Ok(false) | Err(raft::Error::Failed) => b"\x0c\x01".to_vec(),
+        Err(raft::Error::NotLeader(leader)) => not_leader_reply(leader),
+    };
+    stream.write_all(&reply).is_ok()
+}
+
+/// Handle CLUSTER, whose content is one of:
+///
+/// - empty: status of the node
+/// - `add ID HOST:PORT`: add a node into the cluster
+/// - `remove ID`: remove a node from the cluster
+///
+/// replied with `"\x0C<status><text-len:2><text>"`, or as a rejected write
+/// when not sent to the leader.
+fn handle_cluster(
+    data: &[u8],
+    stream: &mut Stream,
+    cluster: &Option<Arc<raft::Node>>,
+    session: &Session,
+) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let node = match cluster {
+        Some(x) => x,
+        None => {
+            return stream
+                .write_all(&text_reply(0x01, "not in cluster mode"))
+                .is_ok()
+        }
+    };
+
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let result = match tokens.as_slice() {
+        [] => {
+            return stream
+                .write_all(&text_reply(0x00, &raft::info(node)))
+                .is_ok()
+        }
+        ["add", id, addr] => match id.parse::<u64>() {
+            Ok(id) if id > 0 => raft::add_peer(id, addr, node),
+            _ => {
+                return stream
+                    .write_all(&text_reply(0x01, "invalid node id"))
+                    .is_ok()
+            }
+        },
+        ["remove", id] => match id.parse::<u64>() {
+            Ok(id) => raft::remove_peer(id, node),
+            _ => {
+                return stream
+                    .write_all(&text_reply(0x01, "invalid node id"))
+                    .is_ok()
+            }
+        },
+        _ => {
+            return stream
+                .write_all(&text_reply(0x01, "unknown cluster command"))
+                .is_ok()
+        }
+    };
+    let reply = match result {
+        Ok(true) => text_reply(0x00, ""),
+        Ok(false) => text_reply(0x02, "no such node"),
+        Err(raft::Error::Failed) => text_reply(0x01, "membership change failed"),
+        Err(raft::Error::NotLeader(leader)) => not_leader_reply(leader),
+    };
+    stream.write_all(&reply).is_ok()
+}
+
+/// Handle MIGRATE, with content `START[-END] HOST:PORT`: move the slots
+/// and their keys to the server at the address.
+fn handle_migrate(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    session: &Session,
+) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let result = match tokens.as_slice() {
+        [range, target] => match slots::parse_range(range) {
+            Ok((start, end)) => migration::migrate(start, end, target, arc_db, arc_slots),
+            Err(e) => Err(e),
+        },
+        _ => Err("usage: MIGRATE START[-END] HOST:PORT".to_string()),
+    };
+    let reply = match result {
+        Ok(count) => text_reply(0x00, &format!("{} keys moved", count)),
+        Err(e) => text_reply(0x01, &e),
+    };
+    stream.write_all(&reply).is_ok()
 }
 
-fn handle_scan(data: &[u8], stream: &mut TcpStream, arc_db: Arc<Mutex<store::DB>>) -> bool {
-    let size = tools::bytes_to_u16(&data[3..]);
-    let mut buffer = Vec::with_capacity(size as usize);
-    for _ in 0..size {
-        buffer.push(0_u8);
+/// Handle SETSLOT, sent between servers while migrating slots, with
+/// content one of:
+///
+/// - `importing START[-END] HOST:PORT`: the slots are being moved to us
+///   from the server at the address
+/// - `node START[-END] HOST:PORT`: the slots are owned by the server at
+///   the address from now on
+/// - `stable START[-END]`: cancel importing or migrating of the slots
+fn handle_setslot(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    session: &Session,
+) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let range = match tokens.get(1).map(|x| slots::parse_range(x)) {
+        Some(Ok(x)) => x,
+        _ => {
+            return stream
+                .write_all(&text_reply(0x01, "invalid slot range"))
+                .is_ok()
+        }
+    };
+
+    let mut map = arc_slots.lock().unwrap();
+    if !slots::is_sharded(&map) {
+        return stream.write_all(&text_reply(0x01, "not sharded")).is_ok();
     }
-    match stream.read_exact(&mut buffer) {
-        Ok(_) => {}
-        Err(e) => {
-            println!("cannot read full key bytes: {:?}", e);
-            return false;
+    let (start, end) = range;
+    match tokens.as_slice() {
+        ["importing", _, source] => slots::set_importing(start, end, source, &mut map),
+        ["node", _, owner] => {
+            slots::set_owner(start, end, owner, &mut map);
+            slots::save(&map);
+        }
+        ["stable", _] => slots::set_stable(start, end, &mut map),
+        _ => {
+            return stream
+                .write_all(&text_reply(0x01, "unknown setslot command"))
+                .is_ok()
         }
     }
+    stream.write_all(&text_reply(0x00, "")).is_ok()
+}
 
-    match str::from_utf8(&buffer) {
-        Ok(key) => {
-            let db = arc_db.lock().unwrap();
-            let items = store::scan(key, &db);
-            let len = items.len();
-            if len == 0 {
-                stream.write(b"\x0c\x02").unwrap();
-                return true;
+fn text_reply(status: u8, text: &str) -> Vec<u8> {
+    let mut reply = vec![0x0c, status];
+    reply.extend(&tools::u16_to_bytes(text.len() as u16));
+    reply.extend(text.as_bytes());
+    reply
+}
+
+/// Serve a query when the connection is in push mode, where only
+/// (un)subscribing and (un)watching are allowed.
+fn handle_push_mode(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    session: &mut Session,
+) -> bool {
+    match data[1] {
+        0x05 | 0x07 | 0x09 | 0x0A => handle_subscribe(data, stream, arc_db, arc_ps, session),
+        0x06 | 0x0B => handle_unsubscribe(data, stream, arc_db, arc_ps, session),
+        0x0C | 0x0D => handle_changes(data, stream, arc_db, arc_ps, session),
+        _ => reject(b"\x0c\x01", data, stream, session),
+    }
+}
+
+/// AUTH, the content is as PUT's, with the user as key (empty for the
+/// default user) and the password as value.
+fn handle_auth(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_auth: &Arc<Mutex<auth::Auth>>,
+    session: &mut Session,
+) -> bool {
+    let (user, password) = match read_key_value(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let password = String::from_utf8(password.concat()).unwrap_or_default();
+    let frame = match auth::check(&user, &password, &arc_auth.lock().unwrap()) {
+        Some(x) => {
+            session.user = Some(x);
+            b"\x0c\x00"
+        }
+        None => {
+            logging::warning!("authentication failed for user {:?}", user);
+            b"\x0c\x08"
+        }
+    };
+    reply(frame, stream, session)
+}
+
+/// Handle ACL, whose content is one of:
+///
+/// - `list`: the users and their rules, without passwords
+/// - `setuser NAME RULE...`: create or change the user, see
+///   `src/auth.rs` for the rules
+/// - `deluser NAME`: delete the user
+///
+/// replied with `"\x0C<status><text-len:2><text>"`. The users are saved
+/// after changes.
+fn handle_acl(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_auth: &Arc<Mutex<auth::Auth>>,
+    session: &Session,
+) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let mut users = arc_auth.lock().unwrap();
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let result = match tokens.as_slice() {
+        ["list"] => {
+            return stream
+                .write_all(&text_reply(0x00, &auth::list(&users)))
+                .is_ok()
+        }
+        ["setuser", name, rules @ ..] => auth::set_user(name, rules, &mut users).map(|_| true),
+        ["deluser", name] => auth::delete_user(name, &mut users),
+        _ => Err("unknown acl command".to_string()),
+    };
+    let reply = match result {
+        Ok(true) => {
+            auth::save(&users);
+            text_reply(0x00, "")
+        }
+        Ok(false) => text_reply(0x02, "no such user"),
+        Err(e) => text_reply(0x01, &e),
+    };
+    stream.write_all(&reply).is_ok()
+}
+
+/// Handle LOG, whose content is one of, see `src/logging.rs`:
+///
+/// - empty: the current settings, e.g. `level info format text`
+/// - `level error|warn|info|debug`: change the level
+/// - `format text|json`: change the format
+///
+/// Replied with `"\x0C<status><text-len:2><text>"`.
+fn handle_log(data: &[u8], stream: &mut Stream, session: &Session) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let tokens: Vec<&str> = content.split_whitespace().collect();
+    let result = match tokens.as_slice() {
+        [] => Ok(()),
+        ["level", name] => logging::parse_level(name).map(logging::set_level),
+        ["format", name] => logging::parse_format(name).map(logging::set_format),
+        _ => Err("unknown log command".to_string()),
+    };
+    let reply = match result {
+        Ok(_) => {
+            if !tokens.is_empty() {
+                logging::info!("log settings changed: {}", logging::settings());
             }
+            text_reply(0x00, &logging::settings())
+        }
+        Err(e) => text_reply(0x01, &e),
+    };
+    stream.write_all(&reply).is_ok()
+}
 
-            let buf_len = tools::u32_to_bytes(len as u32);
-            stream.write(b"\x0c\x00\x00").unwrap();
-            assert_eq!(buf_len.len(), 4);
-            stream.write(&buf_len).unwrap();
-            for x in items {
-                let klen = x.len();
-                assert!(klen <= 0xFFFF);
-                let buf_klen = tools::u16_to_bytes(klen as u16);
-                assert_eq!(buf_klen.len(), 2);
-                stream.write(&buf_klen).unwrap();
-                stream.write(x.as_bytes()).unwrap();
+/// Handle ROTATEKEY: read the data key file again, and save the data file
+/// and the Raft state encrypted with the last key, see
+/// `src/encryption.rs`. Replied with `"\x0C<status><text-len:2><text>"`,
+/// the ID of the key, or the error.
+fn handle_rotate_key(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    cluster: &Option<Arc<raft::Node>>,
+    session: &Session,
+) -> bool {
+    if let Err(e) = discard_query(data, stream, has_feature("bigkeys", session)) {
+        return fail(e, stream);
+    }
+    let reply = match encryption::rotate() {
+        Ok(id) => {
+            let saved = match cluster {
+                Some(node) => raft::persist(node).map_err(Error::from),
+                None => save_db(&arc_db.lock().unwrap()),
+            };
+            match saved {
+                Ok(_) => text_reply(0x00, &id),
+                Err(e) => {
+                    logging::error!("cannot save the DB: {}", e);
+                    text_reply(0x01, &e.to_string())
+                }
             }
         }
+        Err(e) => text_reply(0x01, &e),
+    };
+    stream.write_all(&reply).is_ok()
+}
+
+/// Save the DB into the data file under current working directory
+fn save_db(db: &store::DB) -> Result<(), Error> {
+    match tools::get_db_file() {
+        Some(db_file) => persistence::save_to_file(&db_file, db),
+        None => Ok(()),
+    }
+}
+
+/// Handle HELLO, see `src/hello.rs`, replied with
+/// `"\x0C<status><text-len:2><text>"`: the version and the features of
+/// the server, or the error. Returns false to disconnect clients of
+/// versions not spoken any more.
+fn handle_hello(
+    data: &[u8],
+    stream: &mut Stream,
+    arc_auth: &Arc<Mutex<auth::Auth>>,
+    cluster: &Option<Arc<raft::Node>>,
+    arc_slots: &Arc<Mutex<slots::SlotMap>>,
+    session: &mut Session,
+) -> bool {
+    let content = match read_content(data, stream, has_feature("bigkeys", session)) {
+        Ok(x) => x,
+        Err(e) => return fail(e, stream),
+    };
+    let client = match hello::parse(&content) {
+        Ok(x) => x,
+        Err(e) => return reply(&text_reply(0x01, &e), stream, session),
+    };
+    let version = match hello::negotiate(client.version) {
+        Ok(x) => x,
         Err(e) => {
-            println!("failed when from_utf8: {:?}", e);
+            logging::warning!("{}", e);
+            let _ = reply(&text_reply(0x0A, &e), stream, session);
             return false;
         }
+    };
+    let features = hello::features(
+        cipher::enabled(),
+        auth::is_required(&arc_auth.lock().unwrap()),
+        cluster.is_some(),
+        slots::is_sharded(&arc_slots.lock().unwrap()),
+    );
+    session.features = client.features;
+    let text = format!("{} {}", version, features.join(" "));
+    reply(&text_reply(0x00, &text), stream, session)
+}
+
+/// Category of the command, for ACL
+fn category(cmd: u8) -> Option<auth::Category> {
+    match cmd {
+        0x01 | 0x04..=0x07 | 0x09..=0x0C | 0x11 | 0x14 | 0x15 => Some(auth::Category::Read),
+        0x02 | 0x03 | 0x08 | 0x1B..=0x20 => Some(auth::Category::Write),
+        0x0D..=0x10 | 0x12 | 0x13 | 0x17 | 0x18 | 0x1A => Some(auth::Category::Admin),
+        _ => None,
     }
+}
 
-    true
+/// If the client has the feature, from HELLO
+fn has_feature(name: &str, session: &Session) -> bool {
+    session.features.iter().any(|x| x == name)
+}
+
+/// Reply, through the pusher when in push mode
+fn reply(frame: &[u8], stream: &mut Stream, session: &Session) -> bool {
+    match &session.pusher {
+        Some(x) => push::send(frame.to_vec(), &x.tx),
+        None => stream.write_all(frame).is_ok(),
+    }
 }
 
-fn handle_client(stream: &mut TcpStream, arc_db: Arc<Mutex<store::DB>>) {
-    println!("client accepted");
+fn handle_client(
+    stream: &mut Stream,
+    arc_db: Arc<Mutex<store::DB>>,
+    arc_ps: Arc<Mutex<pubsub::PubSub>>,
+    arc_repl: Arc<Mutex<replication::Replication>>,
+    cluster: Option<Arc<raft::Node>>,
+    arc_slots: Arc<Mutex<slots::SlotMap>>,
+    arc_auth: Arc<Mutex<auth::Auth>>,
+) {
+    let mut session = Session {
+        id: logging::new_connection(&stream.peer()),
+        pusher: None,
+        asking: false,
+        user: None,
+        acl: auth::Acl::none(),
+        features: vec![],
+    };
+    logging::info!("client accepted");
 
     loop {
         let mut data = [0; 5];
         match stream.read_exact(&mut data) {
             Ok(_) => {
                 if data[0] != 0x0c {
-                    println!("invalid query header");
+                    logging::warning!("invalid query header");
                     break;
                 }
-                if data[2] != 0x00 {
-                    println!("currently only plain text is supported");
+                // GZIP with Flag `\x01`, see `src/compress.rs`, and ciphered
+                // values with `\x02` if there is a key, see `src/cipher.rs`
+                if data[2] > 0x02 || (data[2] == 0x02 && !cipher::enabled()) {
+                    logging::warning!("unsupported flag: {}", data[2]);
+                    if !reject(b"\x0c\x01", &data, stream, &session) {
+                        break;
+                    }
                     continue;
                 }
             }
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
             Err(_) => {
-                println!("bad data received from client, disconnect it");
+                logging::warning!("bad data received from client, disconnect it");
+                break;
+            }
+        }
+        logging::debug!("query {:#04x}, flag {}", data[1], data[2]);
+
+        if data[1] == 0x19 {
+            let (auth, slots) = (&arc_auth, &arc_slots);
+            if !handle_hello(&data, stream, auth, &cluster, slots, &mut session) {
+                break;
+            }
+            continue;
+        }
+        if data[1] == 0x16 {
+            if !handle_auth(&data, stream, &arc_auth, &mut session) {
+                break;
+            }
+            continue;
+        }
+        if session.user.is_none() && auth::is_required(&arc_auth.lock().unwrap()) {
+            if !reject(b"\x0c\x08", &data, stream, &session) {
                 break;
             }
+            continue;
+        }
+        session.acl = auth::acl_of(&session.user, &arc_auth.lock().unwrap());
+        if let Some(x) = category(data[1]) {
+            if !auth::allows(x, &session.acl) {
+                if !reject(b"\x0c\x09", &data, stream, &session) {
+                    break;
+                }
+                continue;
+            }
+        }
+
+        if session.pusher.is_some() {
+            let (db, ps) = (arc_db.clone(), arc_ps.clone());
+            if !handle_push_mode(&data, stream, db, ps, &mut session) {
+                break;
+            }
+            continue;
         }
 
+        let asking = session.asking;
+        session.asking = false;
         match data[1] {
             0x01 => {
-                handle_get(&data, stream, arc_db.clone());
+                let db = arc_db.clone();
+                if !handle_get(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
+                }
+            }
+            0x02 | 0x03 if cluster.is_some() => {
+                let node = cluster.as_ref().unwrap();
+                if !handle_cluster_write(&data, stream, node, &arc_slots, &session) {
+                    break;
+                }
+            }
+            0x02 | 0x03 if replication::is_replica(&arc_repl) => {
+                if !reject(b"\x0c\x04", &data, stream, &session) {
+                    break;
+                }
             }
             0x02 => {
-                handle_put(&data, stream, arc_db.clone());
-                if let Some(db_file) = tools::get_db_file() {
-                    let db = arc_db.lock().unwrap();
-                    persistence::save_to_file(&db_file, &db);
+                let db = arc_db.clone();
+                if !handle_put(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
                 }
             }
             0x03 => {
-                handle_del(&data, stream, arc_db.clone());
-                if let Some(db_file) = tools::get_db_file() {
-                    let db = arc_db.lock().unwrap();
-                    persistence::save_to_file(&db_file, &db);
+                let db = arc_db.clone();
+                if !handle_del(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
                 }
             }
             0x04 => {
-                handle_scan(&data, stream, arc_db.clone());
+                if !handle_scan(&data, stream, arc_db.clone(), &session) {
+                    break;
+                }
+            }
+            0x05 | 0x07 | 0x09 | 0x0A => {
+                let (db, ps) = (arc_db.clone(), arc_ps.clone());
+                if !handle_subscribe(&data, stream, db, ps, &mut session) {
+                    break;
+                }
+            }
+            0x06 | 0x0B => {
+                let (db, ps) = (arc_db.clone(), arc_ps.clone());
+                if !handle_unsubscribe(&data, stream, db, ps, &mut session) {
+                    break;
+                }
+            }
+            0x08 => {
+                if !handle_publish(&data, stream, arc_ps.clone(), &session) {
+                    break;
+                }
+            }
+            0x0C | 0x0D => {
+                let (db, ps) = (arc_db.clone(), arc_ps.clone());
+                if !handle_changes(&data, stream, db, ps, &mut session) {
+                    break;
+                }
+            }
+            0x0E => {
+                let repl = arc_repl.clone();
+                if !handle_replicaof(&data, stream, arc_db.clone(), repl, &session) {
+                    break;
+                }
+            }
+            0x0F => {
+                let ok = match &cluster {
+                    Some(node) => handle_raft(stream, node),
+                    None => false,
+                };
+                if !ok {
+                    break;
+                }
+            }
+            0x10 => {
+                if !handle_cluster(&data, stream, &cluster, &session) {
+                    break;
+                }
+            }
+            0x11 => {
+                let reply = slots::slots_reply(&arc_slots.lock().unwrap());
+                if !reject(&reply, &data, stream, &session) {
+                    break;
+                }
+            }
+            0x12 => {
+                let ok = match &cluster {
+                    Some(_) => {
+                        let reply = text_reply(0x01, "not supported in cluster mode");
+                        reject(&reply, &data, stream, &session)
+                    }
+                    None => handle_migrate(&data, stream, arc_db.clone(), &arc_slots, &session),
+                };
+                if !ok {
+                    break;
+                }
+            }
+            0x13 => {
+                if !handle_setslot(&data, stream, &arc_slots, &session) {
+                    break;
+                }
+            }
+            0x14 => {
+                session.asking = true;
+                if !reject(b"\x0c\x00", &data, stream, &session) {
+                    break;
+                }
+            }
+            0x15 => {
+                let db = arc_db.clone();
+                if !handle_mget(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
+                }
+            }
+            0x17 => {
+                if !handle_acl(&data, stream, &arc_auth, &session) {
+                    break;
+                }
+            }
+            0x18 => {
+                if !handle_rotate_key(&data, stream, arc_db.clone(), &cluster, &session) {
+                    break;
+                }
+            }
+            0x1A => {
+                if !handle_log(&data, stream, &session) {
+                    break;
+                }
+            }
+            0x1B..=0x20 if cluster.is_some() => {
+                // lists are not replicated through the Raft log
+                if !reject(b"\x0c\x01", &data, stream, &session) {
+                    break;
+                }
+            }
+            0x1B..=0x20 if replication::is_replica(&arc_repl) => {
+                if !reject(b"\x0c\x04", &data, stream, &session) {
+                    break;
+                }
+            }
+            0x1B | 0x1C => {
+                let db = arc_db.clone();
+                if !handle_list_push(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
+                }
+            }
+            0x1D | 0x1E => {
+                let db = arc_db.clone();
+                if !handle_list_pop(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
+                }
+            }
+            0x1F | 0x20 => {
+                let db = arc_db.clone();
+                if !handle_blocking_pop(&data, stream, db, &arc_slots, asking, &session) {
+                    break;
+                }
             }
             _ => {
                 // unknown command
-                if let Err(_) = stream.write(b"\x0c\xff") {
+                if stream.write_all(b"\x0c\xff").is_err() {
                     break;
                 }
             }
         }
     }
 
-    println!("client disconnected")
+    if let Some(x) = session.pusher {
+        pubsub::unsubscribe("", session.id, &mut arc_ps.lock().unwrap());
+        let mut db = arc_db.lock().unwrap();
+        watch::unwatch("", session.id, &mut db.watchers);
+        changes::unfollow(session.id, &mut db.changes);
+        drop(db);
+        stop_pusher(x);
+    }
+    logging::info!("client disconnected")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{discard_query, read_key, read_key_value};
+    use crate::compress;
+    use crate::error::Error;
+    use crate::limits::{self, Limits};
+    use crate::stream::Stream;
+    use std::io::{self, Read, Write};
+    use std::net::Shutdown;
+    use std::os::unix::net::UnixStream;
+
+    const FAILED: Option<u8> = Some(0x01);
+    const TOO_LARGE: Option<u8> = Some(0x0B);
+
+    fn set_limits() {
+        limits::set(Limits {
+            max_key_size: 1024,
+            max_value_size: 4096,
+            max_request_size: 5000,
+        });
+    }
+
+    type Parser<T> = fn(&[u8], &mut Stream, bool) -> Result<T, Error>;
+
+    /// Parse the query with `f`, as the server does after reading its
+    /// header for a client with `bigkeys`, returns the result, with the
+    /// Stat of the error if any
+    fn parse<T>(query: &[u8], f: Parser<T>) -> Result<T, Option<u8>> {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        client.write_all(&query[5..]).unwrap();
+        client.shutdown(Shutdown::Write).unwrap();
+        let mut stream = Stream::Unix(server);
+        let result = f(&query[..5], &mut stream, true);
+        // drop the rest, as the connection is closed, without a reset
+        io::copy(&mut stream, &mut io::sink()).unwrap();
+        drop(stream);
+        // nothing is replied while parsing
+        let mut reply = vec![];
+        client.read_to_end(&mut reply).unwrap();
+        assert_eq!(reply, b"");
+        result.map_err(|e| e.status())
+    }
+
+    fn put_query(flag: u8, klen: &[u8], key: &[u8], vlen: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut query = vec![0x0c, 0x02, flag];
+        query.extend(klen);
+        query.extend(key);
+        query.extend(vlen);
+        query.extend(value);
+        query
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        set_limits();
+        let query = put_query(0, b"\x03\x00", b"foo", b"\x01\x03", b"bar");
+        let result = parse(&query, read_key_value);
+        assert_eq!(result, Ok(("foo".to_string(), vec![b"bar".to_vec()])));
+        assert_eq!(parse(&query[..query.len() - 1], read_key_value), Err(None));
+        let query = put_query(0, b"\x01\x00", b"\xff", b"\x01\x01", b"v");
+        assert_eq!(parse(&query, read_key_value), Err(FAILED));
+
+        // lengths over the limits, or invalid
+        let query = put_query(0, b"\x03\x00", b"foo", b"\x08", &[0xFF; 8]);
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+        let query = put_query(0, b"\x03\x00", b"foo", b"\x09", &[0x01; 9]);
+        assert_eq!(parse(&query, read_key_value), Err(FAILED));
+        let query = put_query(0, b"\x01\x04", &[b'k'; 1025], b"\x01\x01", b"v");
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+        let query = put_query(0, b"\xff\xff\xff\xff\xff\xff", b"", b"", b"");
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+        assert_eq!(parse(&query, read_key), Err(TOO_LARGE));
+        // the long form only with `bigkeys`
+        let query = b"\x0c\x01\x00\xff\xff\x00\x00\x00\x00";
+        assert_eq!(parse(query, read_key), Ok(vec![]));
+        assert_eq!(
+            parse(query, |d, s, _| read_key(d, s, false)),
+            Err(TOO_LARGE)
+        );
+        let query = put_query(0, b"\x00\x04", &[b'k'; 1024], b"\x02\x00\x10", b"");
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+
+        // chunked values
+        let mut chunks = vec![];
+        for _ in 0..4 {
+            chunks.extend(b"\x00\x04\x00\x00");
+            chunks.extend(&[b'v'; 1024]);
+        }
+        let query = put_query(0, b"\x01\x00", b"k", b"\xff", &chunks);
+        let query = [&query[..], b"\x00\x00\x00\x00"].concat();
+        assert_eq!(
+            parse(&query, read_key_value).unwrap().1.concat().len(),
+            4096
+        );
+        let query = [&query[..query.len() - 4], b"\x01\x00\x00\x00v"].concat();
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+
+        // GZIP decompressed over the value limit
+        let value = compress::gzip(&[0; 4097]);
+        let vlen = [&[2], &(value.len() as u16).to_le_bytes()[..]].concat();
+        let query = put_query(0x01, b"\x01\x00", b"k", &vlen, &value);
+        assert_eq!(parse(&query, read_key_value), Err(TOO_LARGE));
+        let value = compress::gzip(&[0; 4096]);
+        let vlen = [&[2], &(value.len() as u16).to_le_bytes()[..]].concat();
+        let query = put_query(0x01, b"\x01\x00", b"k", &vlen, &value);
+        assert_eq!(
+            parse(&query, read_key_value).unwrap().1.concat().len(),
+            4096
+        );
+
+        // RAFT messages and other contents
+        let query = b"\x0c\x0f\x00\x00\x00\x08\xff\xff\xff\xff\xff\xff\xff\xff";
+        assert_eq!(parse(query, discard_query), Err(TOO_LARGE));
+        let query = b"\x0c\x0f\x00\x00\x00\x09\xff\xff\xff\xff\xff\xff\xff\xff\xff";
+        assert_eq!(parse(query, discard_query), Err(FAILED));
+        let query = b"\x0c\x04\x00\x89\x13";
+        assert_eq!(parse(query, discard_query), Err(TOO_LARGE));
+    }
+
+    /// xorshift, so that failures can be reproduced
+    struct Random(u64);
+
+    impl Random {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            (self.next() % n as u64) as usize
+        }
+    }
+
+    /// A random query, made of parts likely to be lengths
+    fn random_query(random: &mut Random) -> Vec<u8> {
+        let cmds = [0x01, 0x02, 0x03, 0x04, 0x08, 0x0F, 0x15, 0x16, 0x19];
+        let parts: [&[u8]; 10] = [
+            b"\x00\x00",
+            b"\x03\x00foo",
+            b"\xff\xff",
+            b"\xff\xff\xff\xff",
+            b"\x00\x04\x00\x00",
+            b"\x08",
+            b"\x09",
+            b"\xff",
+            b"\x02\x00\x01",
+            b"\x00\x00\x00\x00",
+        ];
+        let mut query = vec![0x0c, cmds[random.below(cmds.len())], random.below(4) as u8];
+        for _ in 0..random.below(12) {
+            if random.below(3) == 0 {
+                let len = random.below(64);
+                query.extend((0..len).map(|_| random.next() as u8));
+            } else {
+                query.extend(parts[random.below(parts.len())]);
+            }
+        }
+        query.resize(query.len().max(5), 0);
+        query
+    }
+
+    #[test]
+    fn test_parse_fuzz() {
+        set_limits();
+        let mut random = Random(0x2545F4914F6CDD1D);
+        for _ in 0..5000 {
+            let query = random_query(&mut random);
+            let _ = parse(&query, discard_query);
+            if query[1] == 0x02 {
+                if let Ok((key, value)) = parse(&query, read_key_value) {
+                    assert!(key.len() <= 1024 && value.concat().len() <= 4096);
+                }
+            }
+        }
+    }
 }
@@ -0,0 +1,384 @@
+// Sharding with hash slots.
+//
+// The key space is split into `SLOTS` hash slots, a key belongs to slot
+// `CRC16(key) % SLOTS` (the CRC16/XMODEM as in Redis Cluster), and each
+// slot is owned by one server. When only part of a key is between `{`
+// and `}`, e.g. `user:{42}:name`, only that part is hashed, so related
+// keys can be kept on the same server.
+//
+// Every server is started with the same slot map, `--slots` with ranges
+// of slots and their owners: `0-8191=HOST:PORT,8192-16383=HOST:PORT`.
+// GET, PUT and DEL of a key owned by another server are replied with a
+// MOVED status, with the slot and the address of its owner. Clients get
+// the whole map with the SLOTS query, and send queries of a key to its
+// owner directly.
+//
+// Slots are moved between servers online with MIGRATE (see
+// `src/migration.rs`). While a slot is migrating, its keys are on either
+// server: the source serves the keys it still has, and replies ASK with
+// the target for the others. The target serves the slot only for queries
+// right after ASKING, which clients send when following an ASK, and
+// replies MOVED back to the source otherwise. Once all keys are moved,
+// the target, the source and then the other servers are told the new
+// owner, and the map is saved into the file `h2okv.slots` under current
+// working directory, which overrides `--slots` when restarting.
+
+use std::collections::HashMap;
+use std::fs;
+
+use crate::logging;
+use crate::tools;
+
+pub const SLOTS: usize = 16384;
+
+pub struct SlotMap {
+    /// address of this server, as in the map
+    myself: String,
+    /// addresses of all servers in the map
+    servers: Vec<String>,
+    /// index into `servers` of the owner of each slot, empty when the
+    /// server is not sharded and owns all keys
+    owners: Vec<usize>,
+    /// slots of ours being moved to another server, and its address
+    migrating: HashMap<u16, String>,
+    /// slots being moved to us from another server, and its address
+    importing: HashMap<u16, String>,
+}
+
+impl SlotMap {
+    pub fn new(myself: &str) -> SlotMap {
+        SlotMap {
+            myself: myself.to_string(),
+            servers: vec![],
+            owners: vec![],
+            migrating: HashMap::new(),
+            importing: HashMap::new(),
+        }
+    }
+}
+
+/// Where a query of a key should be served
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Local,
+    Moved(u16, String),
+    Ask(u16, String),
+}
+
+/// CRC16/XMODEM
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for x in bytes {
+        crc ^= (*x as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// The slot the key belongs to
+pub fn key_slot(key: &str) -> u16 {
+    let mut part = key;
+    if let Some(start) = key.find('{') {
+        if let Some(len) = key[start + 1..].find('}') {
+            if len > 0 {
+                part = &key[start + 1..start + 1 + len];
+            }
+        }
+    }
+    (crc16(part.as_bytes()) as usize % SLOTS) as u16
+}
+
+/// Parse the slot map `START-END=HOST:PORT,...`, which must cover all
+/// slots, the END is included.
+pub fn parse(spec: &str, map: &mut SlotMap) -> Result<(), String> {
+    let mut servers: Vec<String> = vec![];
+    let mut owners = vec![usize::MAX; SLOTS];
+    for item in spec.split(',').filter(|x| !x.is_empty()) {
+        let invalid = || format!("invalid slot range: {}", item);
+        let mut parts = item.splitn(2, '=');
+        let range = parts.next().unwrap_or_default();
+        let addr = match parts.next() {
+            Some(x) if !x.is_empty() => x,
+            _ => return Err(invalid()),
+        };
+        let (start, end) = match parse_range(range) {
+            Ok((start, end)) => (start as usize, end as usize),
+            Err(_) => return Err(invalid()),
+        };
+
+        let index = match servers.iter().position(|x| x == addr) {
+            Some(x) => x,
+            None => {
+                servers.push(addr.to_string());
+                servers.len() - 1
+            }
+        };
+        for owner in &mut owners[start..=end] {
+            *owner = index;
+        }
+    }
+    if let Some(slot) = owners.iter().position(|x| *x == usize::MAX) {
+        return Err(format!("slot {} is not assigned", slot));
+    }
+    map.servers = servers;
+    map.owners = owners;
+    map.migrating.clear();
+    map.importing.clear();
+    Ok(())
+}
+
+/// Parse a slot or a range of slots: `START[-END]`
+pub fn parse_range(text: &str) -> Result<(u16, u16), String> {
+    let mut bounds = text.splitn(2, '-').map(|x| x.parse::<usize>());
+    let (start, end) = match (bounds.next(), bounds.next()) {
+        (Some(Ok(start)), Some(Ok(end))) => (start, end),
+        (Some(Ok(start)), None) => (start, start),
+        _ => return Err(format!("invalid slot range: {}", text)),
+    };
+    if start > end || end >= SLOTS {
+        return Err(format!("invalid slot range: {}", text));
+    }
+    Ok((start as u16, end as u16))
+}
+
+pub fn is_sharded(map: &SlotMap) -> bool {
+    !map.owners.is_empty()
+}
+
+pub fn myself(map: &SlotMap) -> &str {
+    &map.myself
+}
+
+/// Addresses of all servers in the map
+pub fn servers(map: &SlotMap) -> Vec<String> {
+    map.servers.clone()
+}
+
+pub fn owner(slot: u16, map: &SlotMap) -> Option<&str> {
+    let index = map.owners.get(slot as usize)?;
+    Some(&map.servers[*index])
+}
+
+/// Where to serve a query of the key, which `exists` in our DB or not,
+/// `asking` if the query follows ASKING.
+pub fn route(key: &str, exists: bool, asking: bool, map: &SlotMap) -> Route {
+    if map.owners.is_empty() {
+        return Route::Local;
+    }
+    let slot = key_slot(key);
+    let owner = &map.servers[map.owners[slot as usize]];
+    if *owner == map.myself {
+        match map.migrating.get(&slot) {
+            Some(target) if !exists => Route::Ask(slot, target.clone()),
+            _ => Route::Local,
+        }
+    } else if asking && map.importing.contains_key(&slot) {
+        Route::Local
+    } else {
+        Route::Moved(slot, owner.clone())
+    }
+}
+
+/// Set the owner of the slots, which are not migrating or importing
+/// anymore.
+pub fn set_owner(start: u16, end: u16, addr: &str, map: &mut SlotMap) {
+    let index = match map.servers.iter().position(|x| x == addr) {
+        Some(x) => x,
+        None => {
+            map.servers.push(addr.to_string());
+            map.servers.len() - 1
+        }
+    };
+    for slot in start..=end {
+        map.owners[slot as usize] = index;
+        map.migrating.remove(&slot);
+        map.importing.remove(&slot);
+    }
+}
+
+pub fn set_migrating(start: u16, end: u16, target: &str, map: &mut SlotMap) {
+    for slot in start..=end {
+        map.migrating.insert(slot, target.to_string());
+    }
+}
+
+pub fn set_importing(start: u16, end: u16, source: &str, map: &mut SlotMap) {
+    for slot in start..=end {
+        map.importing.insert(slot, source.to_string());
+    }
+}
+
+/// Cancel migrating or importing of the slots
+pub fn set_stable(start: u16, end: u16, map: &mut SlotMap) {
+    for slot in start..=end {
+        map.migrating.remove(&slot);
+        map.importing.remove(&slot);
+    }
+}
+
+/// The map in the format of `--slots`
+pub fn spec(map: &SlotMap) -> String {
+    let ranges: Vec<String> = ranges(map)
+        .iter()
+        .map(|(start, end, addr)| format!("{}-{}={}", start, end, addr))
+        .collect();
+    ranges.join(",")
+}
+
+/// Save the map, so that it's used when restarting
+pub fn save(map: &SlotMap) {
+    if let Some(path) = tools::get_slots_file() {
+        if let Err(e) = fs::write(&path, spec(map)) {
+            logging::error!("cannot save slot map: {:?}", e);
+        }
+    }
+}
+
+/// Load the map saved, returns if any
+pub fn load(map: &mut SlotMap) -> bool {
+    let spec = match tools::get_slots_file().map(fs::read_to_string) {
+        Some(Ok(x)) => x,
+        _ => return false,
+    };
+    match parse(spec.trim(), map) {
+        Ok(_) => true,
+        Err(e) => {
+            logging::warning!("invalid slot map saved: {}", e);
+            false
+        }
+    }
+}
+
+/// The map as ranges of slots: `(start, end, address)`
+pub fn ranges(map: &SlotMap) -> Vec<(u16, u16, String)> {
+    let mut ranges: Vec<(u16, u16, String)> = vec![];
+    for (slot, index) in map.owners.iter().enumerate() {
+        let addr = &map.servers[*index];
+        match ranges.last_mut() {
+            Some(x) if x.2 == *addr => x.1 = slot as u16,
+            _ => ranges.push((slot as u16, slot as u16, addr.clone())),
+        }
+    }
+    ranges
+}
+
+/// Reply of MOVED: `"\x0C\x06<slot:2><addr-len:2><addr>"`, or of ASK
+/// with status `\x07`
+pub fn redirect_reply(route: &Route) -> Vec<u8> {
+    let (status, slot, addr) = match route {
+        Route::Moved(slot, addr) => (0x06, *slot, addr),
+        Route::Ask(slot, addr) => (0x07, *slot, addr),
+        Route::Local => return vec![],
+    };
+    let mut reply = vec![0x0c, status];
+    reply.extend(&tools::u16_to_bytes(slot));
+    reply.extend(&tools::u16_to_bytes(addr.len() as u16));
+    reply.extend(addr.as_bytes());
+    reply
+}
+
+/// Reply of SLOTS: `"\x0C\x00<count:2>"` followed by `count` ranges of
+/// `<start:2><end:2><addr-len:2><addr>`, no ranges when not sharded.
+pub fn slots_reply(map: &SlotMap) -> Vec<u8> {
+    let ranges = ranges(map);
+    let mut reply = vec![0x0c, 0x00];
+    reply.extend(&tools::u16_to_bytes(ranges.len() as u16));
+    for (start, end, addr) in ranges {
+        reply.extend(&tools::u16_to_bytes(start));
+        reply.extend(&tools::u16_to_bytes(end));
+        reply.extend(&tools::u16_to_bytes(addr.len() as u16));
+        reply.extend(addr.as_bytes());
+    }
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{key_slot, parse, parse_range, ranges, route, slots_reply, spec};
+    use super::{set_importing, set_migrating, set_owner, Route, SlotMap};
+
+    #[test]
+    fn test_key_slot() {
+        // same as CLUSTER KEYSLOT of Redis
+        assert_eq!(key_slot("foo"), 12182);
+        assert_eq!(key_slot("bar"), 5061);
+        assert_eq!(key_slot(""), 0);
+        assert_eq!(key_slot("user:{42}:name"), key_slot("42"));
+        assert_eq!(key_slot("user:{42}:age"), key_slot("user:{42}:name"));
+        assert_eq!(key_slot("a{}b"), key_slot("a{}b"));
+        assert_ne!(key_slot("{}a"), key_slot("{}b"));
+    }
+
+    #[test]
+    fn test_slot_map() {
+        let mut map = SlotMap::new("127.0.0.1:30160");
+        assert_eq!(route("foo", false, false, &map), Route::Local);
+        assert_eq!(slots_reply(&map), b"\x0c\x00\x00\x00".to_vec());
+
+        let spec = "0-8191=127.0.0.1:30160,8192-16000=127.0.0.1:30161,16001-16383=127.0.0.1:30160";
+        parse(spec, &mut map).unwrap();
+        assert_eq!(route("bar", false, false, &map), Route::Local);
+        let moved = Route::Moved(12182, "127.0.0.1:30161".to_string());
+        assert_eq!(route("foo", true, false, &map), moved);
+        assert_eq!(ranges(&map).len(), 3);
+        assert_eq!(
+            ranges(&map)[1],
+            (8192, 16000, "127.0.0.1:30161".to_string())
+        );
+
+        assert!(parse("0-8191=a:1", &mut map).is_err());
+        assert!(parse("0-16384=a:1", &mut map).is_err());
+        assert!(parse("100-0=a:1", &mut map).is_err());
+        assert!(parse("0-16383", &mut map).is_err());
+        assert!(parse("0-16383=a:1", &mut map).is_ok());
+        assert_eq!(
+            route("foo", false, false, &map),
+            Route::Moved(12182, "a:1".to_string())
+        );
+    }
+
+    #[test]
+    fn test_migrating() {
+        let mut map = SlotMap::new("a:1");
+        parse("0-8191=a:1,8192-16383=b:2", &mut map).unwrap();
+        assert_eq!(parse_range("5061"), Ok((5061, 5061)));
+        assert_eq!(parse_range("0-99"), Ok((0, 99)));
+        assert!(parse_range("99-0").is_err());
+
+        // on the source, missing keys are asked to the target
+        set_migrating(5000, 5100, "c:3", &mut map);
+        assert_eq!(route("bar", true, false, &map), Route::Local);
+        assert_eq!(
+            route("bar", false, false, &map),
+            Route::Ask(5061, "c:3".to_string())
+        );
+
+        // on the target, only after ASKING
+        let mut target = SlotMap::new("c:3");
+        parse("0-8191=a:1,8192-16383=b:2", &mut target).unwrap();
+        set_importing(5000, 5100, "a:1", &mut target);
+        assert_eq!(route("bar", false, true, &target), Route::Local);
+        assert_eq!(
+            route("bar", false, false, &target),
+            Route::Moved(5061, "a:1".to_string())
+        );
+
+        set_owner(5000, 5100, "c:3", &mut map);
+        set_owner(5000, 5100, "c:3", &mut target);
+        assert_eq!(
+            route("bar", true, false, &map),
+            Route::Moved(5061, "c:3".to_string())
+        );
+        assert_eq!(route("bar", false, false, &target), Route::Local);
+        assert_eq!(
+            spec(&map),
+            "0-4999=a:1,5000-5100=c:3,5101-8191=a:1,8192-16383=b:2"
+        );
+    }
+}
@@ -3,41 +3,255 @@
 /// with Rust's builtin: `std::collections::HashMap`.
 ///
 /// [0] https://en.wikipedia.org/wiki/2-3_tree
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
 use std::str;
+use std::sync::Arc;
 
-// FIXME: we should use `HashMap<&[u8], &[u8]>` here,
-// using String for it to work for now.
-pub type DB = HashMap<String, String>;
+use crate::blocking;
+use crate::changes;
+use crate::error::Error;
+use crate::large;
+use crate::watch;
 
-/// Get value of the key in KV Store
+/// A value, in chunks of up to `large::CHUNK_SIZE` bytes, so that large
+/// values are never one contiguous buffer, see `src/large.rs`. Clones
+/// share the chunks, e.g. to reply the value without holding the DB.
+#[derive(Clone, Debug)]
+pub struct Value(Arc<Vec<String>>);
+
+impl Value {
+    /// The value of the pieces read, which may split UTF-8 characters
+    pub fn from_pieces(pieces: Vec<Vec<u8>>) -> Result<Value, Error> {
+        let mut chunks = vec![];
+        let mut rest = vec![];
+        for piece in pieces {
+            let buffer = if rest.is_empty() {
+                piece
+            } else {
+                [&rest[..], &piece[..]].concat()
+            };
+            match String::from_utf8(buffer) {
+                Ok(x) => {
+                    rest.clear();
+                    chunks.push(x);
+                }
+                // a character going on in the next piece
+                Err(e) if e.utf8_error().error_len().is_none() => {
+                    let valid = e.utf8_error().valid_up_to();
+                    let mut buffer = e.into_bytes();
+                    rest = buffer.split_off(valid);
+                    chunks.push(String::from_utf8(buffer).map_err(|_| Error::InvalidUtf8)?);
+                }
+                Err(_) => return Err(Error::InvalidUtf8),
+            }
+        }
+        if !rest.is_empty() {
+            return Err(Error::InvalidUtf8);
+        }
+        chunks.retain(|x| !x.is_empty());
+        Ok(Value(Arc::new(chunks)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.iter().map(|x| x.len()).sum()
+    }
+
+    pub fn chunks(&self) -> impl Iterator<Item = &[u8]> {
+        self.0.iter().map(|x| x.as_bytes())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(text: &str) -> Value {
+        let mut chunks = vec![];
+        let mut text = text;
+        while !text.is_empty() {
+            let mut end = text.len().min(large::CHUNK_SIZE);
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            chunks.push(text[..end].to_string());
+            text = &text[end..];
+        }
+        Value(Arc::new(chunks))
+    }
+}
+
+/// The whole value, e.g. `to_string()`
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.0.iter() {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+/// Same bytes, however they are chunked
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        let bytes = |x: &Value| x.0.iter().flat_map(|x| x.bytes()).collect::<Vec<u8>>();
+        self.len() == other.len() && bytes(self) == bytes(other)
+    }
+}
+
+/// Keys hold either a string, in `items`, or a list, in `lists`. Lists
+/// are never empty: they are removed with their last element.
+pub struct DB {
+    pub items: HashMap<String, Value>,
+    pub lists: HashMap<String, VecDeque<Value>>,
+    /// connections to notify when keys changed
+    pub watchers: watch::Watchers,
+    /// latest changes, with their sequence numbers
+    pub changes: changes::ChangeLog,
+    /// connections waiting for elements pushed to lists
+    pub blocked: blocking::Blocked,
+}
+
+impl DB {
+    pub fn new() -> DB {
+        DB {
+            items: HashMap::new(),
+            lists: HashMap::new(),
+            watchers: watch::Watchers::new(),
+            changes: changes::ChangeLog::new(),
+            blocked: blocking::Blocked::new(),
+        }
+    }
+}
+
+/// Take the items, the lists and the changes of `loaded`, e.g. a
+/// snapshot, keeping the watchers, the followers of the changes and the
+/// blocked connections
+pub fn replace(loaded: DB, db: &mut DB) {
+    db.items = loaded.items;
+    db.lists = loaded.lists;
+    changes::replace(loaded.changes, &mut db.changes);
+}
+
+/// If the key holds a string or a list
+pub fn exists(key: &str, db: &DB) -> bool {
+    db.items.contains_key(key) || db.lists.contains_key(key)
+}
+
+/// Get value of the key in KV Store, as a whole. Lists are not values.
 pub fn get(key: &str, db: &DB) -> Option<String> {
-    match db.get(key) {
-        Some(x) => Some(x.clone()),
+    match db.items.get(key) {
+        Some(x) => Some(x.to_string()),
         None => None,
     }
 }
 
+/// Get value of the key in KV Store, in its chunks
+pub fn get_value(key: &str, db: &DB) -> Option<Value> {
+    db.items.get(key).cloned()
+}
+
 /// Set value of the key in KV Store to value
-/// Old value will be replaced.
-pub fn put(key: &str, value: &[u8], db: &mut DB) -> Result<(), &'static str> {
-    let data = str::from_utf8(value).unwrap();
-    db.insert(key.to_string(), data.to_string());
+/// Old value will be replaced, as well as a list, the change is recorded
+/// and watchers of the key are notified. Values which are not UTF-8 are
+/// refused.
+pub fn put(key: &str, value: &[u8], db: &mut DB) -> Result<(), Error> {
+    let data = str::from_utf8(value).map_err(|_| Error::InvalidUtf8)?;
+    put_value(key, Value::from(data), db);
     Ok(())
 }
 
-/// Delete a Key/Value pair from KV Store
+/// Set value of the key in KV Store to value, as `put()`
+pub fn put_value(key: &str, value: Value, db: &mut DB) {
+    changes::record(watch::EVENT_PUT, key, Some(&value), &mut db.changes);
+    watch::notify(watch::EVENT_PUT, key, Some(&value), &mut db.watchers);
+    db.lists.remove(key);
+    db.items.insert(key.to_string(), value);
+}
+
+/// Delete a Key/Value pair from KV Store, or a list, returns the value,
+/// empty for a list
 pub fn delete(key: &str, db: &mut DB) -> Option<String> {
-    match db.remove(key) {
-        Some(x) => Some(x),
-        None => None,
+    let value = match db.items.remove(key) {
+        Some(x) => x.to_string(),
+        None => {
+            db.lists.remove(key)?;
+            String::new()
+        }
+    };
+    changes::record(watch::EVENT_DELETE, key, None, &mut db.changes);
+    watch::notify(watch::EVENT_DELETE, key, None, &mut db.watchers);
+    Some(value)
+}
+
+/// Push the element to the left or the right of the list of the key,
+/// created if missing, returns the length of the list then. The element
+/// is handed right away to the connections blocked on the key, if any,
+/// see `src/blocking.rs`.
+pub fn push(key: &str, value: Value, left: bool, db: &mut DB) -> Result<usize, Error> {
+    if db.items.contains_key(key) {
+        return Err(Error::WrongType);
+    }
+    let event = if left {
+        watch::EVENT_LPUSH
+    } else {
+        watch::EVENT_RPUSH
+    };
+    changes::record(event, key, Some(&value), &mut db.changes);
+    watch::notify(event, key, Some(&value), &mut db.watchers);
+    let list = db.lists.entry(key.to_string()).or_default();
+    if left {
+        list.push_front(value);
+    } else {
+        list.push_back(value);
     }
+    let len = list.len();
+
+    while db.lists.contains_key(key) {
+        let (left, tx) = match blocking::next(key, &mut db.blocked) {
+            Some(x) => x,
+            None => break,
+        };
+        if let Some(x) = pop(key, left, db)? {
+            // still waiting: a connection only gives up with the DB held,
+            // after leaving the queues
+            let _ = tx.send((key.to_string(), x));
+        }
+    }
+    Ok(len)
+}
+
+/// Pop an element from the left or the right of the list of the key,
+/// `None` when there is no list
+pub fn pop(key: &str, left: bool, db: &mut DB) -> Result<Option<Value>, Error> {
+    if db.items.contains_key(key) {
+        return Err(Error::WrongType);
+    }
+    let list = match db.lists.get_mut(key) {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let value = if left {
+        list.pop_front()
+    } else {
+        list.pop_back()
+    };
+    if list.is_empty() {
+        db.lists.remove(key);
+    }
+    let event = if left {
+        watch::EVENT_LPOP
+    } else {
+        watch::EVENT_RPOP
+    };
+    if let Some(x) = &value {
+        changes::record(event, key, Some(x), &mut db.changes);
+        watch::notify(event, key, Some(x), &mut db.watchers);
+    }
+    Ok(value)
 }
 
-/// List all keys contains the key
+/// List all keys contains the key, of strings and lists
 pub fn scan(key: &str, db: &DB) -> Vec<String> {
     let mut result = Vec::new();
-    for k in db.keys() {
+    for k in db.items.keys().chain(db.lists.keys()) {
         if k.contains(key) {
             result.push(k.to_string());
         }
@@ -47,7 +261,14 @@ pub fn scan(key: &str, db: &DB) -> Vec<String> {
 
 #[cfg(test)]
 mod tests {
-    use super::{delete, get, put, scan, DB};
+    use super::{delete, exists, get, get_value, pop, push, put, replace, scan, Value, DB};
+    use crate::blocking;
+    use crate::changes;
+    use crate::error::Error;
+    use crate::large::CHUNK_SIZE;
+    use crate::push;
+    use crate::watch;
+    use std::iter;
 
     #[test]
     fn test_store() {
@@ -73,5 +294,132 @@ mod tests {
         assert_eq!(scan("f", &db), vec!["find".to_string()]);
 
         assert_eq!(delete("foo", &mut db), None);
+        assert_eq!(changes::next_seq(&db.changes), 4);
+
+        assert!(put("bin", b"\xff\xfe", &mut db).is_err());
+        assert_eq!(get("bin", &db), None);
+        assert_eq!(changes::next_seq(&db.changes), 4);
+    }
+
+    #[test]
+    fn test_store_notify() {
+        let mut db = DB::new();
+        let (tx, rx) = push::queue();
+        watch::watch_prefix("f", 1, &tx, &mut db.watchers);
+
+        put("foo", "bar".as_bytes(), &mut db).unwrap();
+        put("lang", "rust".as_bytes(), &mut db).unwrap();
+        delete("foo", &mut db);
+        delete("foo", &mut db); // no such key, no event
+
+        let events: Vec<Vec<u8>> = iter::from_fn(|| rx.try_next()).collect();
+        assert_eq!(
+            events,
+            vec![
+                watch::event_frame(watch::EVENT_PUT, "foo", Some(&Value::from("bar"))),
+                watch::event_frame(watch::EVENT_DELETE, "foo", None),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut db = DB::new();
+        let (tx, rx) = push::queue();
+        watch::watch("foo", 1, &tx, &mut db.watchers);
+        put("old", b"1", &mut db).unwrap();
+
+        let mut loaded = DB::new();
+        put("foo", b"bar", &mut loaded).unwrap();
+        replace(loaded, &mut db);
+        assert_eq!(get("old", &db), None);
+        assert_eq!(get("foo", &db), Some("bar".to_string()));
+        assert_eq!(changes::next_seq(&db.changes), 2);
+
+        // still watched
+        put("foo", b"baz", &mut db).unwrap();
+        let event = watch::event_frame(watch::EVENT_PUT, "foo", Some(&Value::from("baz")));
+        assert_eq!(rx.try_next(), Some(event));
+    }
+
+    #[test]
+    fn test_value() {
+        let text = "宏".repeat(CHUNK_SIZE / 3 + 1);
+        let value = Value::from(text.as_str());
+        assert_eq!(value.chunks().count(), 2);
+        assert!(value.chunks().all(|x| x.len() <= CHUNK_SIZE));
+        assert_eq!(value.len(), text.len());
+        assert_eq!(value.to_string(), text);
+
+        // pieces read may split characters
+        let pieces: Vec<Vec<u8>> = text.as_bytes().chunks(1000).map(|x| x.to_vec()).collect();
+        let value2 = Value::from_pieces(pieces).unwrap();
+        assert_eq!(value2, value);
+        assert_eq!(value2.to_string(), text);
+        assert!(Value::from_pieces(vec![b"\xe5\xae".to_vec()]).is_err());
+        assert!(Value::from_pieces(vec![b"a\xff".to_vec(), b"b".to_vec()]).is_err());
+        assert_eq!(Value::from_pieces(vec![]).unwrap(), Value::from(""));
+
+        let mut db = DB::new();
+        put("foo", text.as_bytes(), &mut db).unwrap();
+        assert_eq!(get_value("foo", &db), Some(value));
+        assert_eq!(get("foo", &db), Some(text));
+    }
+
+    #[test]
+    fn test_list() {
+        let mut db = DB::new();
+        assert_eq!(push("jobs", Value::from("b"), true, &mut db).unwrap(), 1);
+        assert_eq!(push("jobs", Value::from("a"), true, &mut db).unwrap(), 2);
+        assert_eq!(push("jobs", Value::from("c"), false, &mut db).unwrap(), 3);
+        assert!(exists("jobs", &db));
+        assert_eq!(get("jobs", &db), None);
+        assert_eq!(scan("j", &db), vec!["jobs".to_string()]);
+
+        assert_eq!(pop("jobs", true, &mut db).unwrap(), Some(Value::from("a")));
+        assert_eq!(pop("jobs", false, &mut db).unwrap(), Some(Value::from("c")));
+        assert_eq!(pop("jobs", false, &mut db).unwrap(), Some(Value::from("b")));
+        // removed with its last element
+        assert!(!exists("jobs", &db));
+        assert_eq!(pop("jobs", true, &mut db).unwrap(), None);
+        assert_eq!(changes::next_seq(&db.changes), 7);
+
+        // strings and lists do not mix, but PUT and DEL take any key
+        put("foo", b"bar", &mut db).unwrap();
+        assert!(matches!(
+            push("foo", Value::from("x"), true, &mut db),
+            Err(Error::WrongType)
+        ));
+        assert!(matches!(pop("foo", true, &mut db), Err(Error::WrongType)));
+        push("jobs", Value::from("a"), true, &mut db).unwrap();
+        put("jobs", b"done", &mut db).unwrap();
+        assert_eq!(get("jobs", &db), Some("done".to_string()));
+        push("mail", Value::from("a"), true, &mut db).unwrap();
+        assert_eq!(delete("mail", &mut db), Some("".to_string()));
+        assert!(!exists("mail", &db));
+    }
+
+    #[test]
+    fn test_push_blocked() {
+        let mut db = DB::new();
+        let jobs = vec!["jobs".to_string()];
+        let rx1 = blocking::block(1, &jobs, true, &mut db.blocked);
+        let rx2 = blocking::block(2, &jobs, false, &mut db.blocked);
+
+        // handed to the longest waiting first, one element each
+        assert_eq!(push("jobs", Value::from("a"), false, &mut db).unwrap(), 1);
+        assert_eq!(
+            rx1.try_recv().unwrap(),
+            ("jobs".to_string(), Value::from("a"))
+        );
+        assert!(rx2.try_recv().is_err());
+        assert!(!exists("jobs", &db));
+        push("jobs", Value::from("b"), false, &mut db).unwrap();
+        assert_eq!(rx2.try_recv().unwrap().1, Value::from("b"));
+
+        // nobody is waiting anymore
+        push("jobs", Value::from("c"), false, &mut db).unwrap();
+        assert!(rx1.try_recv().is_err());
+        assert_eq!(pop("jobs", true, &mut db).unwrap(), Some(Value::from("c")));
     }
 }
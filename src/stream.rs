@@ -0,0 +1,138 @@
+// Client connections, over TCP, a unix domain socket (`--unix-socket`)
+// or TLS (`--tls-port`), served the same way by `src/server.rs`.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::tls::TlsStream;
+
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Tls(TlsStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> io::Result<Stream> {
+        match self {
+            Stream::Tcp(x) => x.try_clone().map(Stream::Tcp),
+            Stream::Unix(x) => x.try_clone().map(Stream::Unix),
+            Stream::Tls(x) => x.try_clone().map(Stream::Tls),
+        }
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.set_write_timeout(timeout),
+            Stream::Unix(x) => x.set_write_timeout(timeout),
+            Stream::Tls(x) => x.set_write_timeout(timeout),
+        }
+    }
+
+    /// Close the connection, for both directions
+    pub fn shutdown(&self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.shutdown(Shutdown::Both),
+            Stream::Unix(x) => x.shutdown(Shutdown::Both),
+            Stream::Tls(x) => x.shutdown(),
+        }
+    }
+
+    /// Address of the client, for logging
+    pub fn peer(&self) -> String {
+        let addr = match self {
+            Stream::Tcp(x) => x.peer_addr(),
+            Stream::Tls(x) => x.peer_addr(),
+            Stream::Unix(_) => return "unix".to_string(),
+        };
+        match addr {
+            Ok(x) => x.to_string(),
+            Err(_) => "unknown".to_string(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.read(buf),
+            Stream::Unix(x) => x.read(buf),
+            Stream::Tls(x) => x.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Tcp(x) => x.write(buf),
+            Stream::Unix(x) => x.write(buf),
+            Stream::Tls(x) => x.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Tcp(x) => x.flush(),
+            Stream::Unix(x) => x.flush(),
+            Stream::Tls(x) => x.flush(),
+        }
+    }
+}
+
+/// Listen on the socket file at `path` with the permissions `mode`,
+/// replacing the socket left by a previous run. Any other file there is
+/// an error, rather than removed.
+pub fn bind_unix(path: &str, mode: u32) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(x) if x.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            let e = format!("{} exists and is not a socket", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, e));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{bind_unix, Stream};
+    use std::env;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::net::UnixStream;
+
+    #[test]
+    fn test_bind_unix() {
+        let mut path = env::temp_dir();
+        path.push(format!("h2okv-test-{}.sock", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        fs::write(&path, b"not a socket").unwrap();
+        assert!(bind_unix(&path, 0o600).is_err());
+        assert_eq!(fs::read(&path).unwrap(), b"not a socket");
+        fs::remove_file(&path).unwrap();
+
+        // the socket left by a previous run is replaced
+        drop(bind_unix(&path, 0o600).unwrap());
+        let listener = bind_unix(&path, 0o600).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        let stream = Stream::Unix(listener.accept().unwrap().0);
+        client.write_all(b"ping").unwrap();
+        let mut buffer = [0; 4];
+        stream.try_clone().unwrap().read_exact(&mut buffer).unwrap();
+        assert_eq!(&buffer, b"ping");
+        let _ = fs::remove_file(&path);
+    }
+}
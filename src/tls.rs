@@ -0,0 +1,146 @@
+// TLS for client connections on `--tls-port`, with the certificate and
+// key from `--tls-cert` and `--tls-key` in PEM. With `--tls-ca-cert`,
+// clients must present a certificate signed by one of the CAs in it,
+// i.e. mutual TLS.
+//
+// A connection in push mode is written by another thread while its
+// queries are still read (see `Pusher` in `src/server.rs`), so the TLS
+// state is shared between the clones of a stream, and only locked while
+// records are decrypted or encrypted, never while waiting on the socket.
+
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig, ServerConnection};
+
+pub struct TlsStream {
+    conn: Arc<Mutex<ServerConnection>>,
+    tcp: TcpStream,
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    let certs: Result<Vec<_>, _> = rustls_pemfile::certs(&mut BufReader::new(file)).collect();
+    match certs {
+        Ok(x) if !x.is_empty() => Ok(x),
+        Ok(_) => Err(format!("no certificates in {}", path)),
+        Err(e) => Err(format!("invalid certificates in {}: {}", path, e)),
+    }
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot open {}: {}", path, e))?;
+    match rustls_pemfile::private_key(&mut BufReader::new(file)) {
+        Ok(Some(x)) => Ok(x),
+        Ok(None) => Err(format!("no private key in {}", path)),
+        Err(e) => Err(format!("invalid private key in {}: {}", path, e)),
+    }
+}
+
+/// Server config from the PEM files, `ca` to verify client certificates
+pub fn server_config(cert: &str, key: &str, ca: Option<&str>) -> Result<Arc<ServerConfig>, String> {
+    let certs = load_certs(cert)?;
+    let key = load_key(key)?;
+    let builder = match ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for x in load_certs(path)? {
+                roots
+                    .add(x)
+                    .map_err(|e| format!("invalid CA in {}: {}", path, e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder(Arc::new(roots))
+                .build()
+                .map_err(|e| format!("invalid CA in {}: {}", path, e))?;
+            ServerConfig::builder().with_client_cert_verifier(verifier)
+        }
+        None => ServerConfig::builder().with_no_client_auth(),
+    };
+    match builder.with_single_cert(certs, key) {
+        Ok(x) => Ok(Arc::new(x)),
+        Err(e) => Err(format!("invalid certificate or key: {}", e)),
+    }
+}
+
+/// Start TLS on the accepted connection, the handshake is done with the
+/// first read.
+pub fn accept(tcp: TcpStream, config: &Arc<ServerConfig>) -> io::Result<TlsStream> {
+    let conn = ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+    Ok(TlsStream {
+        conn: Arc::new(Mutex::new(conn)),
+        tcp,
+    })
+}
+
+impl TlsStream {
+    pub fn try_clone(&self) -> io::Result<TlsStream> {
+        Ok(TlsStream {
+            conn: self.conn.clone(),
+            tcp: self.tcp.try_clone()?,
+        })
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tcp.peer_addr()
+    }
+
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.tcp.set_write_timeout(timeout)
+    }
+
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.tcp.shutdown(Shutdown::Both)
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut raw = [0; 16 * 1024];
+        loop {
+            match self.conn.lock().unwrap().reader().read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                result => return result,
+            }
+            let size = self.tcp.read(&mut raw)?;
+            if size == 0 {
+                return Ok(0);
+            }
+            let mut conn = self.conn.lock().unwrap();
+            let mut data = &raw[..size];
+            while !data.is_empty() {
+                if conn.read_tls(&mut data)? == 0 {
+                    return Err(io::Error::other("TLS buffer full"));
+                }
+                if let Err(e) = conn.process_new_packets() {
+                    // send the alert before giving up
+                    let _ = conn.write_tls(&mut self.tcp);
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                }
+            }
+            // handshake messages, or TLS 1.3 tickets
+            while conn.wants_write() {
+                conn.write_tls(&mut self.tcp)?;
+            }
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut conn = self.conn.lock().unwrap();
+        conn.writer().write_all(buf)?;
+        while conn.wants_write() {
+            conn.write_tls(&mut self.tcp)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,6 +1,8 @@
 use std::env;
 use std::io::Cursor;
 
+use crate::logging;
+
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 
 /// convert bytes to u64, LittleEndian
@@ -62,12 +64,41 @@ pub fn u16_to_bytes(n: u16) -> [u8; 2] {
     array
 }
 
+/// Glob-style match of the text against pattern
+///
+/// `*` matches any sequence of characters (including none) and `?`
+/// matches exactly one character. All other characters match themselves.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.chars().collect();
+    let t: Vec<char> = text.chars().collect();
+    let (mut i, mut j) = (0, 0);
+    // position of the last `*` in pattern, and where it started in text
+    let mut star: Option<(usize, usize)> = None;
+    while j < t.len() {
+        if i < p.len() && (p[i] == '?' || p[i] == t[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == '*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            // let the last `*` eat one more character
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+    p[i..].iter().all(|x| *x == '*')
+}
+
 fn current_dir() -> Option<String> {
     let _current_dir;
     match env::current_dir() {
         Ok(x) => _current_dir = x,
         Err(e) => {
-            println!("env current_dir error: {:?}", e);
+            logging::error!("env current_dir error: {:?}", e);
             return None;
         }
     }
@@ -75,7 +106,7 @@ fn current_dir() -> Option<String> {
     match _current_dir.to_str() {
         Some(x) => current_dir = x,
         None => {
-            println!("current_dir to_str error");
+            logging::error!("current_dir to_str error");
             return None;
         }
     }
@@ -89,10 +120,27 @@ pub fn get_db_file() -> Option<String> {
     }
 }
 
+pub fn get_raft_file() -> Option<String> {
+    current_dir().map(|dir| format!("{}/{}", dir, "h2okv.raft"))
+}
+
+pub fn get_slots_file() -> Option<String> {
+    current_dir().map(|dir| format!("{}/{}", dir, "h2okv.slots"))
+}
+
+pub fn get_memcache_file() -> Option<String> {
+    current_dir().map(|dir| format!("{}/{}", dir, "h2okv.memcache"))
+}
+
+pub fn get_acl_file() -> Option<String> {
+    current_dir().map(|dir| format!("{}/{}", dir, "h2okv.acl"))
+}
+
 #[cfg(test)]
 mod tests {
     use super::bytes_to_u16;
     use super::bytes_to_u64;
+    use super::glob_match;
     use super::u16_to_bytes;
     use super::u32_to_bytes;
     use super::u64_to_bytes;
@@ -126,6 +174,24 @@ mod tests {
         assert_eq!(&u32_to_bytes(0xFFFF0001), &[1, 0, 0xFF, 0xFF]);
     }
 
+    #[test]
+    fn test_glob_match() {
+        assert!(glob_match("", ""));
+        assert!(!glob_match("", "a"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*", "news"));
+        assert!(glob_match("news", "news"));
+        assert!(!glob_match("news", "new"));
+        assert!(glob_match("n*", "news"));
+        assert!(glob_match("*s", "news"));
+        assert!(glob_match("n?ws", "news"));
+        assert!(!glob_match("n?ws", "nws"));
+        assert!(glob_match("n*w*s", "nabwcds"));
+        assert!(!glob_match("n*w*s", "nabwcd"));
+        assert!(glob_match("a*b*c", "aXbYbZc"));
+        assert!(glob_match("名*", "名字"));
+    }
+
     fn _u64_assert(number: u64, bytes_count: u8, buf: &[u8]) {
         assert_eq!(bytes_to_u64(buf), number);
         let (_count, _buf) = u64_to_bytes(number);
@@ -0,0 +1,175 @@
+// Keyspace change notifications.
+//
+// A connection can WATCH a key, or PWATCH a key prefix, and then every
+// change made by `store::put()`, `store::delete()`, `store::push()` or
+// `store::pop()` on a matching key is pushed to it as an event, together
+// with the new value for PUT, or the element pushed or popped. With
+// this, clients can keep a local mirror of (part of) the DB in sync,
+// without polling SCAN.
+//
+// Events are delivered the same way as Pub/Sub messages: through the
+// `push::Queue` of the connection in push mode, see `server.rs`.
+
+use std::collections::HashMap;
+
+use crate::large;
+use crate::push;
+use crate::store::Value;
+use crate::tools;
+
+pub const EVENT_PUT: u8 = 0x01;
+pub const EVENT_DELETE: u8 = 0x02;
+pub const EVENT_LPUSH: u8 = 0x03;
+pub const EVENT_RPUSH: u8 = 0x04;
+pub const EVENT_LPOP: u8 = 0x05;
+pub const EVENT_RPOP: u8 = 0x06;
+
+pub struct Watchers {
+    keys: HashMap<String, HashMap<usize, push::Queue>>,
+    prefixes: HashMap<String, HashMap<usize, push::Queue>>,
+}
+
+impl Watchers {
+    pub fn new() -> Watchers {
+        Watchers {
+            keys: HashMap::new(),
+            prefixes: HashMap::new(),
+        }
+    }
+}
+
+/// Watch changes of the key, returns how many keys and prefixes the
+/// connection `id` is watching now.
+pub fn watch(key: &str, id: usize, tx: &push::Queue, ws: &mut Watchers) -> usize {
+    ws.keys
+        .entry(key.to_string())
+        .or_default()
+        .insert(id, tx.clone());
+    count(id, ws)
+}
+
+/// Watch changes of all keys starting with the prefix
+pub fn watch_prefix(prefix: &str, id: usize, tx: &push::Queue, ws: &mut Watchers) -> usize {
+    ws.prefixes
+        .entry(prefix.to_string())
+        .or_default()
+        .insert(id, tx.clone());
+    count(id, ws)
+}
+
+/// Stop watching the key or prefix `name`, an empty name for all of them.
+pub fn unwatch(name: &str, id: usize, ws: &mut Watchers) -> usize {
+    for map in [&mut ws.keys, &mut ws.prefixes].iter_mut() {
+        for (key, watchers) in map.iter_mut() {
+            if name.is_empty() || key == name {
+                watchers.remove(&id);
+            }
+        }
+        map.retain(|_, watchers| !watchers.is_empty());
+    }
+    count(id, ws)
+}
+
+/// How many keys and prefixes the connection is watching
+pub fn count(id: usize, ws: &Watchers) -> usize {
+    let keys = ws.keys.values().filter(|x| x.contains_key(&id));
+    let prefixes = ws.prefixes.values().filter(|x| x.contains_key(&id));
+    keys.count() + prefixes.count()
+}
+
+/// Push the change event of the key to all its watchers.
+///
+/// A connection watching the key more than once (e.g. with the key
+/// itself and a prefix of it) gets the event only once.
+pub fn notify(event: u8, key: &str, value: Option<&Value>, ws: &mut Watchers) {
+    if ws.keys.is_empty() && ws.prefixes.is_empty() {
+        return;
+    }
+
+    let mut targets: HashMap<usize, push::Queue> = HashMap::new();
+    if let Some(watchers) = ws.keys.get(key) {
+        targets.extend(watchers.iter().map(|(id, tx)| (*id, tx.clone())));
+    }
+    for (prefix, watchers) in ws.prefixes.iter() {
+        if key.starts_with(prefix.as_str()) {
+            targets.extend(watchers.iter().map(|(id, tx)| (*id, tx.clone())));
+        }
+    }
+
+    if targets.is_empty() {
+        return;
+    }
+    let frame = event_frame(event, key, value);
+    for (id, tx) in targets {
+        if !push::send(frame.clone(), &tx) {
+            // connection has gone away
+            unwatch("", id, ws);
+        }
+    }
+}
+
+/// Build the frame pushed to watchers:
+/// `"\x0C\x11<event><key-len:2><key><value-len-byte><value-len-bytes><value>"`
+/// where the value part is empty (`"\x00"`) for DEL, and the key length
+/// may be long, see `src/large.rs`.
+pub fn event_frame(event: u8, key: &str, value: Option<&Value>) -> Vec<u8> {
+    let mut frame = vec![0x0c, 0x11, event];
+    frame.extend(&large::len_bytes(key.len()));
+    frame.extend(key.as_bytes());
+    let len = value.map_or(0, |x| x.len());
+    let (count, bytes) = tools::u64_to_bytes(len as u64);
+    frame.push(count);
+    frame.extend(&bytes);
+    for chunk in value.iter().flat_map(|x| x.chunks()) {
+        frame.extend(chunk);
+    }
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, event_frame, notify, unwatch, watch, watch_prefix, Watchers};
+    use super::{EVENT_DELETE, EVENT_PUT};
+    use crate::push;
+    use crate::store::Value;
+
+    #[test]
+    fn test_watch() {
+        let mut ws = Watchers::new();
+        let (tx1, rx1) = push::queue();
+        let (tx2, rx2) = push::queue();
+        assert_eq!(watch("foo", 1, &tx1, &mut ws), 1);
+        assert_eq!(watch_prefix("f", 1, &tx1, &mut ws), 2);
+        assert_eq!(watch_prefix("", 2, &tx2, &mut ws), 1);
+
+        notify(EVENT_PUT, "foo", Some(&Value::from("bar")), &mut ws);
+        let frame = event_frame(EVENT_PUT, "foo", Some(&Value::from("bar")));
+        assert_eq!(rx1.try_next().unwrap(), frame);
+        assert!(rx1.try_next().is_none()); // only once
+        assert_eq!(rx2.try_next().unwrap(), frame);
+
+        notify(EVENT_DELETE, "lang", None, &mut ws);
+        assert!(rx1.try_next().is_none());
+        let frame = event_frame(EVENT_DELETE, "lang", None);
+        assert_eq!(rx2.try_next().unwrap(), frame);
+
+        assert_eq!(unwatch("foo", 1, &mut ws), 1);
+        assert_eq!(unwatch("", 1, &mut ws), 0);
+
+        drop(rx2);
+        notify(EVENT_DELETE, "foo", None, &mut ws);
+        assert_eq!(count(2, &ws), 0);
+    }
+
+    #[test]
+    fn test_event_frame() {
+        assert_eq!(
+            event_frame(EVENT_PUT, "k", Some(&Value::from("v"))),
+            b"\x0c\x11\x01\x01\x00k\x01\x01v".to_vec()
+        );
+        assert_eq!(
+            event_frame(EVENT_DELETE, "k", None),
+            b"\x0c\x11\x02\x01\x00k\x00".to_vec()
+        );
+    }
+}
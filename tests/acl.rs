@@ -0,0 +1,241 @@
+// Run a server with users restricted by ACL on localhost ports, and talk
+// to it with the h2okv protocol, RESP, HTTP and the CLI.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use common::{cli, connect, start, work_dir};
+
+/// Send a PUT-like query, returns the Stat of the reply
+fn send(stream: &mut TcpStream, cmd: u8, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(1);
+    buffer.push(value.len() as u8);
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    send(stream, 0x02, key, value)
+}
+
+fn auth(stream: &mut TcpStream, user: &str, password: &str) -> u8 {
+    send(stream, 0x16, user, password)
+}
+
+/// Send a query with the content, returns the Stat of the reply
+fn query(stream: &mut TcpStream, cmd: u8, content: &str) -> u8 {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET, returns the Stat of the reply and the value
+fn get(stream: &mut TcpStream, key: &str) -> (u8, Option<String>) {
+    let stat = query(stream, 0x01, key);
+    if stat != 0x00 {
+        return (stat, None);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, Some(String::from_utf8(value).unwrap()))
+}
+
+/// SCAN, returns the keys sorted
+fn scan(stream: &mut TcpStream, prefix: &str) -> Vec<String> {
+    if query(stream, 0x04, prefix) != 0x00 {
+        return vec![];
+    }
+    let mut buf_flag_count = [0; 5];
+    stream.read_exact(&mut buf_flag_count).unwrap();
+    let mut buf_count = [0; 4];
+    buf_count.copy_from_slice(&buf_flag_count[1..]);
+    let mut keys = vec![];
+    for _ in 0..u32::from_le_bytes(buf_count) {
+        let mut buf_len = [0; 2];
+        stream.read_exact(&mut buf_len).unwrap();
+        let mut key = vec![0; u16::from_le_bytes(buf_len) as usize];
+        stream.read_exact(&mut key).unwrap();
+        keys.push(String::from_utf8(key).unwrap());
+    }
+    keys.sort();
+    keys
+}
+
+/// ACL, returns the Stat and the text of the reply
+fn acl(stream: &mut TcpStream, args: &str) -> (u8, String) {
+    let stat = query(stream, 0x17, args);
+    if stat > 0x02 {
+        return (stat, String::new());
+    }
+    let mut buf_len = [0; 2];
+    stream.read_exact(&mut buf_len).unwrap();
+    let mut text = vec![0; u16::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut text).unwrap();
+    (stat, String::from_utf8(text).unwrap())
+}
+
+/// Send a RESP command, returns the first line of the reply, or the
+/// value of a bulk string
+fn command(reader: &mut BufReader<TcpStream>, args: &[&str]) -> String {
+    let mut buffer = format!("*{}\r\n", args.len());
+    for x in args {
+        buffer.push_str(&format!("${}\r\n{}\r\n", x.len(), x));
+    }
+    reader.get_mut().write_all(buffer.as_bytes()).unwrap();
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    if line.starts_with('$') && line != "$-1\r\n" {
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+    }
+    line.trim_end().to_string()
+}
+
+/// Send an HTTP GET, returns the status and the body
+fn http_get(port: u16, path: &str, credentials: &str) -> (u16, String) {
+    let mut stream = connect(port);
+    let buffer = format!(
+        "GET {} HTTP/1.1\r\nConnection: close\r\nAuthorization: Basic {}\r\n\r\n",
+        path, credentials
+    );
+    stream.write_all(buffer.as_bytes()).unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response.split(' ').nth(1).unwrap().parse().unwrap();
+    let body = response.split("\r\n\r\n").nth(1).unwrap_or("").to_string();
+    (status, body)
+}
+
+#[test]
+fn test_acl() {
+    let dir = work_dir("acl");
+    let args = [
+        "--port",
+        "30380",
+        "--resp-port",
+        "30381",
+        "--http-port",
+        "30382",
+        "--password",
+        "pw",
+    ];
+    let server = start(&dir, &args);
+    let mut admin = connect(30380);
+    assert_eq!(auth(&mut admin, "", "pw"), 0x00);
+    let rules = "setuser team1 >s1 +read +write ~team1:*";
+    assert_eq!(acl(&mut admin, rules), (0x00, String::new()));
+    assert_eq!(acl(&mut admin, "setuser team2 >s2 +read ~team2:*").0, 0x00);
+    assert_eq!(acl(&mut admin, "setuser team2 +nothing").0, 0x01);
+    assert_eq!(
+        acl(&mut admin, "list"),
+        (
+            0x00,
+            "default +read +write +admin ~*\nteam1 +read +write ~team1:*\nteam2 +read ~team2:*"
+                .to_string()
+        )
+    );
+    assert_eq!(put(&mut admin, "team1:a", "1"), 0x00);
+    assert_eq!(put(&mut admin, "team2:a", "2"), 0x00);
+
+    let mut t1 = connect(30380);
+    assert_eq!(auth(&mut t1, "team1", "s1"), 0x00);
+    assert_eq!(put(&mut t1, "team1:b", "x"), 0x00);
+    assert_eq!(put(&mut t1, "team2:b", "x"), 0x09);
+    assert_eq!(get(&mut t1, "team1:a"), (0x00, Some("1".to_string())));
+    assert_eq!(get(&mut t1, "team2:a"), (0x09, None));
+    assert_eq!(scan(&mut t1, ""), vec!["team1:a", "team1:b"]);
+    assert_eq!(acl(&mut t1, "list").0, 0x09);
+    // CHANGES pushes the changes of all keys
+    assert_eq!(query(&mut t1, 0x0C, "\x01"), 0x09);
+    assert_eq!(query(&mut t1, 0x0A, "team"), 0x09);
+
+    let mut t2 = connect(30380);
+    assert_eq!(auth(&mut t2, "team2", "s2"), 0x00);
+    assert_eq!(get(&mut t2, "team2:a"), (0x00, Some("2".to_string())));
+    assert_eq!(put(&mut t2, "team2:a", "x"), 0x09);
+
+    // RESP
+    let mut r = BufReader::new(connect(30381));
+    assert_eq!(command(&mut r, &["AUTH", "team1", "s1"]), "+OK");
+    assert!(command(&mut r, &["GET", "team2:a"]).starts_with("-NOPERM"));
+    assert!(command(&mut r, &["MSET", "team1:c", "x", "team2:c", "x"]).starts_with("-NOPERM"));
+    assert_eq!(command(&mut r, &["SET", "team1:c", "x"]), "+OK");
+    assert!(command(&mut r, &["ACL", "LIST"]).starts_with("-NOPERM"));
+    assert_eq!(command(&mut r, &["KEYS", "*"]), "*3");
+
+    // HTTP, "team1:s1"
+    let (status, _) = http_get(30382, "/keys/team2:a", "dGVhbTE6czE=");
+    assert_eq!(status, 403);
+    let (status, body) = http_get(30382, "/keys/team1:a", "dGVhbTE6czE=");
+    assert_eq!((status, body.as_str()), (200, "1"));
+
+    // CLI
+    let url = "h2okv://team1:s1@127.0.0.1:30380";
+    let output = cli(&[url], b"get team2:a\nacl list\n", 3);
+    assert_eq!(output[1], "h2okv> (error) permission denied");
+    assert_eq!(output[2], "h2okv> (error) permission denied");
+
+    // deleted users can do nothing, even if authenticated
+    assert_eq!(acl(&mut admin, "deluser team1"), (0x00, String::new()));
+    assert_eq!(acl(&mut admin, "deluser team1").0, 0x02);
+    assert_eq!(get(&mut t1, "team1:a"), (0x09, None));
+
+    // the users saved override --password when restarting
+    drop(server);
+    let _server = start(&dir, &["--port", "30380", "--password", "other"]);
+    let mut s = connect(30380);
+    assert_eq!(auth(&mut s, "", "other"), 0x08);
+    assert_eq!(auth(&mut s, "team1", "s1"), 0x08);
+    assert_eq!(auth(&mut s, "team2", "s2"), 0x00);
+    assert_eq!(get(&mut s, "team2:a"), (0x00, Some("2".to_string())));
+    assert_eq!(auth(&mut s, "", "pw"), 0x00);
+    assert_eq!(
+        acl(&mut s, "list").1,
+        "default +read +write +admin ~*\nteam2 +read ~team2:*"
+    );
+}
+
+#[test]
+fn test_acl_memcache() {
+    let dir = work_dir("acl-memcache");
+    let args = ["--port", "30383", "--memcache-port", "30384"];
+    let server = start(&dir, &args);
+    let mut s = connect(30383);
+
+    // users would lock memcached clients out, they cannot authenticate
+    let rules = "setuser admin >pw +all";
+    assert_eq!(acl(&mut s, rules).0, 0x01);
+    assert_eq!(acl(&mut s, "list"), (0x00, String::new()));
+    drop(server);
+
+    // without memcached clients, the first user must be able to manage
+    // the others
+    let server = start(&dir, &["--port", "30383"]);
+    let mut s = connect(30383);
+    assert_eq!(acl(&mut s, "setuser team1 >s1 +read").0, 0x01);
+    assert_eq!(acl(&mut s, rules).0, 0x00);
+    drop(server);
+
+    // nor with the users saved
+    let mut server = start(&dir, &args);
+    assert!(server.child.wait().is_ok());
+    assert!(TcpStream::connect(("127.0.0.1", 30383)).is_err());
+}
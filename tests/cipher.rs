@@ -0,0 +1,165 @@
+// Run servers on localhost ports, and send them values encrypted with
+// Flag `\x02` and a pre-shared key, with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+
+use common::{cli, connect, start, work_dir};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+fn cipher_key(hex: &str) -> LessSafeKey {
+    let bytes: Vec<u8> = (0..64)
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect();
+    LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, &bytes).unwrap())
+}
+
+fn encrypt(hex: &str, nonce: u8, data: &[u8], aad: &str) -> Vec<u8> {
+    let nonce = [nonce; 12];
+    let mut result = data.to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    cipher_key(hex)
+        .seal_in_place_append_tag(n, Aad::from(aad.as_bytes()), &mut result)
+        .unwrap();
+    let mut sealed = nonce.to_vec();
+    sealed.extend(result);
+    sealed
+}
+
+fn decrypt(hex: &str, data: &[u8], aad: &str) -> Vec<u8> {
+    let mut nonce = [0; 12];
+    nonce.copy_from_slice(&data[..12]);
+    let mut result = data[12..].to_vec();
+    let n = Nonce::assume_unique_for_key(nonce);
+    let len = cipher_key(hex)
+        .open_in_place(n, Aad::from(aad.as_bytes()), &mut result)
+        .unwrap()
+        .len();
+    result.truncate(len);
+    result
+}
+
+/// PUT with the flag, returns the Stat of the reply
+fn put(stream: &mut TcpStream, flag: u8, key: &str, value: &[u8]) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, flag];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value);
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET with the flag, returns the Stat and Flag of the reply, and the
+/// value as is
+fn get(stream: &mut TcpStream, flag: u8, key: &str) -> (u8, u8, Vec<u8>) {
+    let mut buffer = vec![0x0c, 0x01, flag];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], 0x00, vec![]);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, buf_flag_llen[0], value)
+}
+
+#[test]
+fn test_cipher() {
+    let dir = work_dir("cipher");
+    fs::write(dir.join("h2okv.key"), format!("{}\n", KEY)).unwrap();
+    let _server = start(&dir, &["--port", "30400", "--cipher-key-file", "h2okv.key"]);
+    let mut s = connect(30400);
+
+    assert_eq!(
+        put(&mut s, 0x02, "foo", &encrypt(KEY, 1, b"bar", "foo")),
+        0x00
+    );
+    // values are stored decrypted, and encrypted in replies when asked for
+    assert_eq!(get(&mut s, 0x00, "foo"), (0x00, 0x00, b"bar".to_vec()));
+    let (stat, flag, value) = get(&mut s, 0x02, "foo");
+    assert_eq!((stat, flag), (0x00, 0x02));
+    assert_eq!(value.len(), 12 + 3 + 16);
+    assert_eq!(decrypt(KEY, &value, "foo"), b"bar");
+    assert_ne!(get(&mut s, 0x02, "foo").2, value);
+    assert_eq!(get(&mut s, 0x02, "none"), (0x02, 0x00, vec![]));
+
+    // CLI with the key file, and without it
+    let key_file = dir.join("h2okv.key");
+    let args = [
+        "--cipher-key-file",
+        key_file.to_str().unwrap(),
+        "127.0.0.1:30400",
+    ];
+    let output = cli(&args, b"put cli baz\nget cli\nget foo\n", 4);
+    assert_eq!(output[1], "h2okv> OK");
+    assert_eq!(output[2], "h2okv> \"baz\"");
+    assert_eq!(output[3], "h2okv> \"bar\"");
+    let output = cli(&["127.0.0.1:30400"], b"get cli\n", 2);
+    assert_eq!(output[1], "h2okv> \"baz\"");
+
+    // tampered values, values of another key, and values encrypted with
+    // another key are replied Failed and drop the connection, and nothing
+    // is written
+    let mut tampered = encrypt(KEY, 2, b"qux", "foo");
+    tampered[13] ^= 0x01;
+    let other = KEY.replace("1f", "20");
+    for value in &[
+        tampered,
+        encrypt(KEY, 3, b"qux", "moved"),
+        encrypt(&other, 4, b"qux", "foo"),
+        vec![0; 8],
+    ] {
+        let mut s = connect(30400);
+        let mut buffer = vec![0x0c, 0x02, 0x02, 0x03, 0x00];
+        buffer.extend(b"foo\x01");
+        buffer.push(value.len() as u8);
+        buffer.extend(value);
+        s.write_all(&buffer).unwrap();
+        let mut reply = [0; 2];
+        s.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, [0x0c, 0x01]);
+        assert_eq!(s.read(&mut [0; 2]).unwrap(), 0);
+    }
+    assert_eq!(get(&mut s, 0x00, "foo"), (0x00, 0x00, b"bar".to_vec()));
+}
+
+#[test]
+fn test_cipher_no_key() {
+    let dir = work_dir("cipher-no-key");
+    let _server = start(&dir, &["--port", "30401"]);
+    let mut s = connect(30401);
+
+    // without a key, ciphered queries are consumed, and fail
+    assert_eq!(
+        put(&mut s, 0x02, "foo", &encrypt(KEY, 1, b"bar", "foo")),
+        0x01
+    );
+    assert_eq!(get(&mut s, 0x02, "foo"), (0x01, 0x00, vec![]));
+    assert_eq!(put(&mut s, 0x00, "foo", b"bar"), 0x00);
+    assert_eq!(get(&mut s, 0x00, "foo"), (0x00, 0x00, b"bar".to_vec()));
+
+    // a server exits with an invalid key file
+    fs::write(dir.join("bad.key"), "not hex").unwrap();
+    let mut server = start(&dir, &["--port", "30402", "--cipher-key-file", "bad.key"]);
+    server.child.wait().unwrap();
+}
@@ -0,0 +1,219 @@
+// Run a cluster of h2okv nodes on localhost ports, each in its own working
+// directory, and check that writes are committed through the leader and
+// survive killing nodes, including the leader.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, start, work_dir, Server};
+
+const PEERS: &str = "1=127.0.0.1:30271,2=127.0.0.1:30272,3=127.0.0.1:30273";
+
+fn start_node(dir: &PathBuf, id: u64, peers: &str) -> Server {
+    let port = format!("{}", 30270 + id);
+    let id = format!("{}", id);
+    let mut args = vec!["--port", &port, "--raft-id", &id, "--raft-snapshot", "5"];
+    if !peers.is_empty() {
+        args.extend(&["--raft-peers", peers]);
+    }
+    start(dir, &args)
+}
+
+fn query(stream: &mut TcpStream, cmd: u8, content: &[u8]) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content);
+    stream.write_all(&buffer).unwrap();
+}
+
+fn read_string(stream: &mut TcpStream) -> String {
+    let mut buf_len = [0; 2];
+    stream.read_exact(&mut buf_len).unwrap();
+    let mut buffer = vec![0; u16::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Read reply of a write, with the leader address if not the leader
+fn write_reply(stream: &mut TcpStream) -> (u8, String) {
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] == 0x05 {
+        return (reply[1], read_string(stream));
+    }
+    (reply[1], String::new())
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> (u8, String) {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    write_reply(stream)
+}
+
+fn del(stream: &mut TcpStream, key: &str) -> (u8, String) {
+    query(stream, 0x03, key.as_bytes());
+    write_reply(stream)
+}
+
+fn get(stream: &mut TcpStream, key: &str) -> Option<String> {
+    query(stream, 0x01, key.as_bytes());
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+fn cluster(stream: &mut TcpStream, args: &str) -> (u8, String) {
+    query(stream, 0x10, args.as_bytes());
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    (reply[1], read_string(stream))
+}
+
+/// Wait until one of the nodes on the ports is the leader, returns its port
+fn find_leader(ports: &[u16]) -> u16 {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        for port in ports {
+            let (status, info) = cluster(&mut connect(*port), "");
+            assert_eq!(status, 0x00);
+            if info.contains("role: Leader") {
+                return *port;
+            }
+        }
+        if Instant::now() > deadline {
+            panic!("no leader elected in time");
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Wait until the key has the value on the node
+fn wait_for(stream: &mut TcpStream, key: &str, value: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while get(stream, key).as_deref() != value {
+        if Instant::now() > deadline {
+            panic!("key {:?} is not {:?} in time", key, value);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_cluster() {
+    let dirs: Vec<PathBuf> = (1..=4)
+        .map(|x| work_dir(&format!("cluster-{}", x)))
+        .collect();
+    let mut nodes: Vec<Option<Server>> = (1..=3)
+        .map(|x| Some(start_node(&dirs[x - 1], x as u64, PEERS)))
+        .collect();
+    let ports = [30271, 30272, 30273];
+
+    let leader = find_leader(&ports);
+    let mut l = connect(leader);
+    for i in 0..20 {
+        assert_eq!(
+            put(&mut l, &format!("key{}", i), "v1"),
+            (0x00, String::new())
+        );
+    }
+    assert_eq!(del(&mut l, "key0").0, 0x00);
+    assert_eq!(del(&mut l, "key0").0, 0x02);
+
+    // followers have the data, and redirect writes to the leader
+    let follower = *ports.iter().find(|x| **x != leader).unwrap();
+    let mut f = connect(follower);
+    wait_for(&mut f, "key19", Some("v1"));
+    let leader_addr = format!("127.0.0.1:{}", leader);
+    assert_eq!(put(&mut f, "foo", "bar"), (0x05, leader_addr.clone()));
+    assert_eq!(del(&mut f, "key1"), (0x05, leader_addr));
+
+    // kill the leader, the others elect a new one and keep all data
+    let old = (leader - 30271) as usize;
+    nodes[old] = None;
+    let others: Vec<u16> = ports.iter().cloned().filter(|x| *x != leader).collect();
+    let new_leader = find_leader(&others);
+    let mut l = connect(new_leader);
+    assert_eq!(get(&mut l, "key19"), Some("v1".to_string()));
+    assert_eq!(get(&mut l, "key0"), None);
+    for i in 0..20 {
+        assert_eq!(put(&mut l, &format!("key{}", i), "v2").0, 0x00);
+    }
+
+    // the killed node catches up after restart, from a snapshot
+    nodes[old] = Some(start_node(&dirs[old], old as u64 + 1, PEERS));
+    let mut o = connect(leader);
+    wait_for(&mut o, "key19", Some("v2"));
+    wait_for(&mut o, "key0", Some("v2"));
+
+    // add a new node
+    let _new_node = start_node(&dirs[3], 4, "");
+    let (status, _) = cluster(&mut l, "add 4 127.0.0.1:30274");
+    assert_eq!(status, 0x00);
+    let mut n = connect(30274);
+    wait_for(&mut n, "key19", Some("v2"));
+    assert_eq!(put(&mut l, "foo", "bar").0, 0x00);
+    wait_for(&mut n, "foo", Some("bar"));
+
+    // remove a follower, writes still go on with the rest
+    let removed = others.iter().find(|x| **x != new_leader).unwrap() - 30270;
+    let (status, _) = cluster(&mut l, &format!("remove {}", removed));
+    assert_eq!(status, 0x00);
+    nodes[removed as usize - 1] = None;
+    let (_, info) = cluster(&mut l, "");
+    assert!(!info.contains(&format!("{}=127.0.0.1", removed)));
+    assert_eq!(put(&mut l, "lang", "Rust").0, 0x00);
+    wait_for(&mut n, "lang", Some("Rust"));
+    assert_eq!(cluster(&mut l, "remove 9").0, 0x02);
+}
+
+#[test]
+fn test_cluster_restart() {
+    let dirs: Vec<PathBuf> = (1..=3)
+        .map(|x| work_dir(&format!("restart-{}", x)))
+        .collect();
+    let peers = "1=127.0.0.1:30281,2=127.0.0.1:30282,3=127.0.0.1:30283";
+    let start_all = || -> Vec<Server> {
+        (0..3)
+            .map(|i| {
+                let port = format!("{}", 30281 + i);
+                let id = format!("{}", i + 1);
+                let args = ["--port", &port, "--raft-id", &id, "--raft-peers", peers];
+                start(&dirs[i], &args)
+            })
+            .collect()
+    };
+    let ports = [30281, 30282, 30283];
+
+    // the whole cluster restarted keeps committed writes
+    let nodes = start_all();
+    let mut l = connect(find_leader(&ports));
+    assert_eq!(put(&mut l, "foo", "bar").0, 0x00);
+    assert_eq!(put(&mut l, "lang", "Rust").0, 0x00);
+    drop(nodes);
+
+    let _nodes = start_all();
+    let mut l = connect(find_leader(&ports));
+    wait_for(&mut l, "foo", Some("bar"));
+    wait_for(&mut l, "lang", Some("Rust"));
+}
@@ -0,0 +1,84 @@
+// Helpers shared by the integration tests: run the server in its own
+// working directory, connect to it, and run the CLI.
+#![allow(dead_code)]
+
+use std::fs;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A running server, killed when dropped
+pub struct Server {
+    pub child: Child,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// An empty directory for the test
+pub fn work_dir(name: &str) -> PathBuf {
+    let mut dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR"));
+    dir.push(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+pub fn start(dir: &PathBuf, args: &[&str]) -> Server {
+    start_with_envs(dir, args, &[])
+}
+
+pub fn start_with_envs(dir: &PathBuf, args: &[&str], envs: &[(&str, &str)]) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_h2okv"))
+        .args(args)
+        .envs(envs.iter().cloned())
+        .current_dir(dir)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server { child }
+}
+
+/// Connect to the port, waiting for the server to be up
+pub fn connect(port: u16) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match TcpStream::connect(("127.0.0.1", port)) {
+            Ok(x) => return x,
+            Err(e) => {
+                if Instant::now() > deadline {
+                    panic!("cannot connect to port {}: {:?}", port, e);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+/// Run the CLI with the input, and return the first `count` lines it
+/// prints
+pub fn cli(args: &[&str], input: &[u8], count: usize) -> Vec<String> {
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_h2okv-cli"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = cli.stdin.take().unwrap();
+    stdin.write_all(input).unwrap();
+    let mut lines = BufReader::new(cli.stdout.take().unwrap()).lines();
+    let mut output = vec![];
+    for _ in 0..count {
+        output.push(lines.next().unwrap().unwrap());
+    }
+    let _ = cli.kill();
+    let _ = cli.wait();
+    output
+}
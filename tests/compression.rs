@@ -0,0 +1,106 @@
+// Run a server with compressed snapshots on a localhost port, and check
+// that the data file is compressed, and loaded again after restarting
+// with or without compression, and with a data key.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, start, start_with_envs, work_dir};
+
+const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+/// PUT, returns the Stat of the reply
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET, returns the Stat of the reply and the value
+fn get(stream: &mut TcpStream, key: &str) -> (u8, Option<String>) {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], None);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, Some(String::from_utf8(value).unwrap()))
+}
+
+/// Wait until the file is saved with the header
+fn wait_file(path: &PathBuf, header: &[u8]) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let data = fs::read(path).unwrap_or_default();
+        if data.starts_with(header) {
+            return data;
+        }
+        if Instant::now() > deadline {
+            panic!("{:?} is not saved with header {:?}", path, header);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_compression() {
+    let dir = work_dir("compression");
+    let data_file = dir.join("h2okv.data");
+    let large = "h2okv ".repeat(1000);
+    let args = ["--port", "30420", "--snapshot-compression", "lz4"];
+
+    let server = start(&dir, &args);
+    let mut s = connect(30420);
+    assert_eq!(put(&mut s, "large", &large), 0x00);
+    let data = wait_file(&data_file, b"\x0fH2OKV\x01");
+    assert!(data.len() < large.len() / 10);
+    drop(server);
+
+    // compressed files are loaded without compression, and saved as is
+    let server = start(&dir, &["--port", "30420"]);
+    let mut s = connect(30420);
+    assert_eq!(get(&mut s, "large"), (0x00, Some(large.clone())));
+    assert_eq!(put(&mut s, "foo", "bar"), 0x00);
+    let data = wait_file(&data_file, b"\x0d");
+    assert!(data.len() > large.len());
+    drop(server);
+
+    // compressed, then encrypted
+    let envs = [("H2OKV_DATA_KEY", KEY)];
+    let server = start_with_envs(&dir, &args, &envs);
+    let mut s = connect(30420);
+    assert_eq!(get(&mut s, "large"), (0x00, Some(large.clone())));
+    assert_eq!(put(&mut s, "foo", "baz"), 0x00);
+    let data = wait_file(&data_file, b"\x0eH2OKV\x01");
+    assert!(data.len() < large.len() / 10);
+    drop(server);
+
+    let _server = start_with_envs(&dir, &["--port", "30420"], &envs);
+    let mut s = connect(30420);
+    assert_eq!(get(&mut s, "large"), (0x00, Some(large)));
+    assert_eq!(get(&mut s, "foo"), (0x00, Some("baz".to_string())));
+}
@@ -0,0 +1,221 @@
+// Run servers with data keys on localhost ports, and check that the data
+// file and the Raft state are encrypted, loaded again after restarting,
+// and encrypted with the new key after ROTATEKEY.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{cli, connect, start, start_with_envs, work_dir};
+
+const KEY1: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+const KEY2: &str = "1f1e1d1c1b1a191817161514131211100f0e0d0c0b0a09080706050403020100";
+
+/// Send a PUT-like query, returns the Stat of the reply
+fn send(stream: &mut TcpStream, cmd: u8, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(1);
+    buffer.push(value.len() as u8);
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    send(stream, 0x02, key, value)
+}
+
+/// ROTATEKEY, returns the Stat and the text of the reply
+fn rotate_key(stream: &mut TcpStream) -> (u8, String) {
+    stream.write_all(b"\x0c\x18\x00\x00\x00").unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).unwrap();
+    let mut text = vec![0; u16::from_le_bytes([reply[2], reply[3]]) as usize];
+    stream.read_exact(&mut text).unwrap();
+    (reply[1], String::from_utf8(text).unwrap())
+}
+
+/// GET, returns the Stat of the reply and the value
+fn get(stream: &mut TcpStream, key: &str) -> (u8, Option<String>) {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], None);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, Some(String::from_utf8(value).unwrap()))
+}
+
+/// Key ID in the header of an encrypted file
+fn key_id_of(data: &[u8]) -> Option<String> {
+    if !data.starts_with(b"\x0eH2OKV\x01") || data.len() < 15 {
+        return None;
+    }
+    Some(data[7..15].iter().map(|x| format!("{:02x}", x)).collect())
+}
+
+/// Wait until the file is saved with a header of the key ID, or none
+fn wait_file(path: &PathBuf, key_id: Option<&str>) -> Vec<u8> {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        let data = fs::read(path).unwrap_or_default();
+        if !data.is_empty() && key_id_of(&data).as_deref() == key_id {
+            return data;
+        }
+        if Instant::now() > deadline {
+            panic!("{:?} is not saved with key {:?}", path, key_id);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn contains(data: &[u8], text: &str) -> bool {
+    data.windows(text.len()).any(|x| x == text.as_bytes())
+}
+
+#[test]
+fn test_encryption() {
+    let dir = work_dir("encryption");
+    let data_file = dir.join("h2okv.data");
+    fs::write(dir.join("data.key"), format!("{}\n", KEY1)).unwrap();
+    let args = ["--port", "30410", "--data-key-file", "data.key"];
+
+    // a file saved without key is loaded, and encrypted when saved again
+    let server = start(&dir, &["--port", "30410"]);
+    assert_eq!(put(&mut connect(30410), "foo", "plain-value"), 0x00);
+    assert!(contains(&wait_file(&data_file, None), "plain-value"));
+    drop(server);
+    let server = start(&dir, &args);
+    let mut s = connect(30410);
+    assert_eq!(get(&mut s, "foo"), (0x00, Some("plain-value".to_string())));
+    assert_eq!(put(&mut s, "bar", "secret-value"), 0x00);
+    thread::sleep(Duration::from_millis(200));
+    let data = fs::read(&data_file).unwrap();
+    assert!(data.starts_with(b"\x0eH2OKV\x01"));
+    assert!(!contains(&data, "plain-value") && !contains(&data, "secret-value"));
+    let key_id = key_id_of(&data).unwrap();
+    drop(server);
+
+    // without the key, or with another one, the server does not start
+    let mut server = start(&dir, &["--port", "30410"]);
+    assert!(!server.child.wait().unwrap().success());
+    let mut server = start_with_envs(&dir, &["--port", "30410"], &[("H2OKV_DATA_KEY", KEY2)]);
+    assert!(!server.child.wait().unwrap().success());
+
+    // the key from the environment, which cannot be rotated
+    let server = start_with_envs(&dir, &["--port", "30410"], &[("H2OKV_DATA_KEY", KEY1)]);
+    let mut s = connect(30410);
+    assert_eq!(get(&mut s, "bar"), (0x00, Some("secret-value".to_string())));
+    let (stat, text) = rotate_key(&mut s);
+    assert_eq!(stat, 0x01);
+    assert!(text.contains("H2OKV_DATA_KEY"));
+    drop(server);
+
+    // rotate to a new key appended to the key file
+    fs::write(dir.join("data.key"), format!("{}\n{}\n", KEY1, KEY2)).unwrap();
+    let server = start(&dir, &args);
+    let mut s = connect(30410);
+    assert_eq!(get(&mut s, "bar"), (0x00, Some("secret-value".to_string())));
+    assert_eq!(wait_file(&data_file, Some(&key_id)).len(), data.len());
+    let output = cli(&["127.0.0.1:30410"], b"rotatekey\n", 2);
+    let new_id = output[1]
+        .trim_start_matches("h2okv> data key: ")
+        .to_string();
+    assert_eq!(new_id.len(), 16);
+    assert_ne!(new_id, key_id);
+    wait_file(&data_file, Some(&new_id));
+    assert_eq!(rotate_key(&mut s), (0x00, new_id));
+    drop(server);
+
+    // the old key can be removed after rotating
+    fs::write(dir.join("data.key"), KEY2).unwrap();
+    let _server = start(&dir, &args);
+    let mut s = connect(30410);
+    assert_eq!(get(&mut s, "foo"), (0x00, Some("plain-value".to_string())));
+    assert_eq!(get(&mut s, "bar"), (0x00, Some("secret-value".to_string())));
+}
+
+#[test]
+fn test_encryption_raft() {
+    let dir = work_dir("encryption-raft");
+    let raft_file = dir.join("h2okv.raft");
+    fs::write(dir.join("data.key"), KEY1).unwrap();
+    let args = [
+        "--port",
+        "30411",
+        "--raft-id",
+        "1",
+        "--raft-peers",
+        "1=127.0.0.1:30411",
+        "--data-key-file",
+        "data.key",
+    ];
+    let server = start(&dir, &args);
+    let mut s = connect(30411);
+    // until elected as the leader of itself
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match put(&mut s, "foo", "raft-value") {
+            0x00 => break,
+            // not the leader yet, followed by the address of the leader
+            0x05 => {
+                let mut buf_len = [0; 2];
+                s.read_exact(&mut buf_len).unwrap();
+                s.read_exact(&mut vec![0; u16::from_le_bytes(buf_len) as usize])
+                    .unwrap();
+            }
+            x => panic!("put failed: {}", x),
+        }
+        assert!(Instant::now() < deadline, "no leader elected in time");
+        thread::sleep(Duration::from_millis(50));
+    }
+    let data = fs::read(&raft_file).unwrap();
+    // the state and the records appended after it, each encrypted
+    assert!(data[8..].starts_with(b"\x0eH2OKV\x01"));
+    assert!(!contains(&data, "raft-value"));
+    drop(server);
+
+    let wait_value = |s: &mut TcpStream| {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while get(s, "foo").1.is_none() {
+            assert!(Instant::now() < deadline, "raft log is not applied");
+            thread::sleep(Duration::from_millis(50));
+        }
+        assert_eq!(get(s, "foo"), (0x00, Some("raft-value".to_string())));
+    };
+    let server = start(&dir, &args);
+    let mut s = connect(30411);
+    wait_value(&mut s);
+
+    // the whole state is written anew with the new key, even when
+    // nothing changed since the records appended with the old one
+    fs::write(dir.join("data.key"), format!("{}\n{}\n", KEY1, KEY2)).unwrap();
+    let (stat, new_id) = rotate_key(&mut s);
+    assert_eq!(stat, 0x00);
+    let data = fs::read(&raft_file).unwrap();
+    assert_eq!(key_id_of(&data[8..]), Some(new_id));
+    drop(server);
+    fs::write(dir.join("data.key"), KEY2).unwrap();
+    let _server = start(&dir, &args);
+    wait_value(&mut connect(30411));
+}
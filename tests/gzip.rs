@@ -0,0 +1,106 @@
+// Run a server on a localhost port, and send it GZIP-compressed values
+// with Flag `\x01`, with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+
+use common::{cli, connect, start, work_dir};
+
+fn gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(vec![], Compression::default());
+    encoder.write_all(data).unwrap();
+    encoder.finish().unwrap()
+}
+
+fn gunzip(data: &[u8]) -> Vec<u8> {
+    let mut result = vec![];
+    GzDecoder::new(data).read_to_end(&mut result).unwrap();
+    result
+}
+
+/// PUT with the flag, returns the Stat of the reply
+fn put(stream: &mut TcpStream, flag: u8, key: &str, value: &[u8]) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, flag];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value);
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET with the flag, returns the Stat and Flag of the reply, and the
+/// value as is
+fn get(stream: &mut TcpStream, flag: u8, key: &str) -> (u8, u8, Vec<u8>) {
+    let mut buffer = vec![0x0c, 0x01, flag];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], 0x00, vec![]);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, buf_flag_llen[0], value)
+}
+
+#[test]
+fn test_gzip() {
+    let dir = work_dir("gzip");
+    let _server = start(&dir, &["--port", "30390"]);
+    let mut s = connect(30390);
+
+    let large = "h2okv ".repeat(1000);
+    assert_eq!(put(&mut s, 0x01, "large", &gzip(large.as_bytes())), 0x00);
+    assert_eq!(put(&mut s, 0x01, "small", &gzip(b"bar")), 0x00);
+
+    // replies are compressed only when asked for, and worth it
+    assert_eq!(
+        get(&mut s, 0x00, "large"),
+        (0x00, 0x00, large.clone().into_bytes())
+    );
+    let (stat, flag, value) = get(&mut s, 0x01, "large");
+    assert_eq!((stat, flag), (0x00, 0x01));
+    assert!(value.len() < large.len());
+    assert_eq!(gunzip(&value), large.as_bytes());
+    assert_eq!(get(&mut s, 0x01, "small"), (0x00, 0x00, b"bar".to_vec()));
+    assert_eq!(get(&mut s, 0x01, "none"), (0x02, 0x00, vec![]));
+
+    // queries with unknown flags are consumed, and fail
+    assert_eq!(put(&mut s, 0x07, "foo", b"bar"), 0x01);
+    assert_eq!(get(&mut s, 0x00, "foo"), (0x02, 0x00, vec![]));
+
+    // CLI
+    let value = "x".repeat(2000);
+    let input = format!("put cli {}\nget cli\n", value);
+    let output = cli(&["127.0.0.1:30390"], input.as_bytes(), 3);
+    assert_eq!(output[1], "h2okv> OK");
+    assert_eq!(output[2], format!("h2okv> {:?}", value));
+    assert_eq!(get(&mut s, 0x00, "cli").2, value.into_bytes());
+
+    // invalid GZIP data is replied Failed, and drops the connection
+    let mut buffer = vec![0x0c, 0x02, 0x01, 0x03, 0x00];
+    buffer.extend(b"bad\x01\x08not gzip");
+    s.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    s.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [0x0c, 0x01]);
+    assert_eq!(s.read(&mut [0; 2]).unwrap(), 0);
+}
@@ -0,0 +1,146 @@
+// Run servers on localhost ports, and negotiate the protocol version and
+// features with HELLO, with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::thread;
+
+use common::{cli, connect, start, work_dir};
+
+/// HELLO, returns the Stat and the text of the reply
+fn hello(stream: &mut TcpStream, content: &str) -> (u8, String) {
+    let mut buffer = vec![0x0c, 0x19, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).unwrap();
+    let mut text = vec![0; u16::from_le_bytes([reply[2], reply[3]]) as usize];
+    stream.read_exact(&mut text).unwrap();
+    (reply[1], String::from_utf8(text).unwrap())
+}
+
+/// PUT, returns the Stat of the reply
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET without flag, returns the Stat and Flag of the reply, and the
+/// length of the value
+fn get(stream: &mut TcpStream, key: &str) -> (u8, u8, usize) {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], 0x00, 0);
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    (0x00, buf_flag_llen[0], value.len())
+}
+
+#[test]
+fn test_hello() {
+    let dir = work_dir("hello");
+    let _server = start(&dir, &["--port", "30430"]);
+    let mut s = connect(30430);
+
+    let (stat, text) = hello(&mut s, "1 gzip");
+    assert_eq!(stat, 0x00);
+    assert_eq!(
+        text,
+        "1 gzip acl pubsub watch changes mget bigkeys chunked type:string type:list"
+    );
+    // newer clients get the version of the server
+    assert!(hello(&mut s, "200 gzip zstd").1.starts_with("1 gzip "));
+    assert_eq!(hello(&mut s, "v1").0, 0x01);
+
+    // GET replies are compressed for clients with gzip
+    let large = "h2okv ".repeat(1000);
+    assert_eq!(put(&mut s, "large", &large), 0x00);
+    let (stat, flag, len) = get(&mut s, "large");
+    assert_eq!((stat, flag), (0x00, 0x01));
+    assert!(len < large.len());
+    assert_eq!(hello(&mut s, "1").0, 0x00);
+    assert_eq!(get(&mut s, "large"), (0x00, 0x00, large.len()));
+    let mut s = connect(30430);
+    assert_eq!(get(&mut s, "large"), (0x00, 0x00, large.len()));
+
+    // clients of versions not spoken are disconnected
+    let (stat, text) = hello(&mut s, "0");
+    assert_eq!(stat, 0x0A);
+    assert_eq!(text, "unsupported protocol version 0, supported 1-1");
+    assert_eq!(s.read(&mut [0; 2]).unwrap(), 0);
+
+    // CLI, compressing large values
+    let value = "x".repeat(2000);
+    let input = format!("put cli {}\nget cli\n", value);
+    let output = cli(&["127.0.0.1:30430"], input.as_bytes(), 3);
+    assert_eq!(output[1], "h2okv> OK");
+    assert_eq!(output[2], format!("h2okv> {:?}", value));
+    fs::write(dir.join("h2okv.key"), "00".repeat(32)).unwrap();
+    let key_file = dir.join("h2okv.key");
+    let args = [
+        "--cipher-key-file",
+        key_file.to_str().unwrap(),
+        "127.0.0.1:30430",
+    ];
+    let output = cli(&args, b"", 1);
+    assert_eq!(output[0], "Failed to connect: server has no cipher key");
+}
+
+#[test]
+fn test_hello_auth() {
+    let dir = work_dir("hello-auth");
+    let _server = start(&dir, &["--port", "30431", "--password", "pw"]);
+    let mut s = connect(30431);
+
+    // before authenticating
+    let (stat, text) = hello(&mut s, "1");
+    assert_eq!(stat, 0x00);
+    assert!(text.starts_with("1 gzip auth acl "));
+}
+
+#[test]
+fn test_hello_incompatible() {
+    // a server of a later version, which no longer speaks version 1
+    let listener = TcpListener::bind("127.0.0.1:30432").unwrap();
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut header = [0; 5];
+        stream.read_exact(&mut header).unwrap();
+        let mut content = vec![0; u16::from_le_bytes([header[3], header[4]]) as usize];
+        stream.read_exact(&mut content).unwrap();
+        let text = "unsupported protocol version 1, supported 2-3";
+        let mut reply = vec![0x0c, 0x0a];
+        reply.extend(&(text.len() as u16).to_le_bytes());
+        reply.extend(text.as_bytes());
+        stream.write_all(&reply).unwrap();
+    });
+    let output = cli(&["127.0.0.1:30432"], b"", 1);
+    assert_eq!(
+        output[0],
+        "Failed to connect: unsupported protocol version 1, supported 2-3"
+    );
+}
@@ -0,0 +1,194 @@
+// Run a server with the HTTP listener on localhost ports, and talk to it
+// as an HTTP client would.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use common::{connect, start, work_dir};
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+fn client(port: u16) -> Client {
+    let stream = connect(port);
+    Client {
+        writer: stream.try_clone().unwrap(),
+        reader: BufReader::new(stream),
+    }
+}
+
+struct Response {
+    status: u16,
+    /// with lowercase names
+    headers: Vec<(String, String)>,
+    body: String,
+}
+
+fn header<'a>(response: &'a Response, name: &str) -> Option<&'a str> {
+    response
+        .headers
+        .iter()
+        .find(|x| x.0 == name)
+        .map(|x| x.1.as_str())
+}
+
+/// Send a request on the kept-alive connection
+fn request(c: &mut Client, method: &str, path: &str, headers: &[&str], body: &str) -> Response {
+    let mut buffer = format!("{} {} HTTP/1.1\r\nHost: localhost\r\n", method, path);
+    for x in headers {
+        buffer.push_str(&format!("{}\r\n", x));
+    }
+    if !body.is_empty() || method == "PUT" {
+        buffer.push_str(&format!("Content-Length: {}\r\n", body.len()));
+    }
+    buffer.push_str("\r\n");
+    buffer.push_str(body);
+    c.writer.write_all(buffer.as_bytes()).unwrap();
+
+    let mut line = String::new();
+    c.reader.read_line(&mut line).unwrap();
+    let status = line.split(' ').nth(1).unwrap().parse().unwrap();
+    let mut headers = vec![];
+    loop {
+        let mut line = String::new();
+        c.reader.read_line(&mut line).unwrap();
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let i = line.find(':').unwrap();
+        let name = line[..i].to_lowercase();
+        headers.push((name, line[i + 1..].trim().to_string()));
+    }
+    let mut response = Response {
+        status,
+        headers,
+        body: String::new(),
+    };
+    let size: usize = header(&response, "content-length")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let mut body = vec![0; size];
+    c.reader.read_exact(&mut body).unwrap();
+    response.body = String::from_utf8(body).unwrap();
+    response
+}
+
+/// GET with the h2okv protocol
+fn get(stream: &mut TcpStream, key: &str) -> Option<String> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn test_http() {
+    let dir = work_dir("http");
+    let _server = start(&dir, &["--port", "30340", "--http-port", "30341"]);
+    let mut h = connect(30340);
+    let mut c = client(30341);
+
+    let r = request(&mut c, "GET", "/keys/foo", &[], "");
+    assert_eq!(r.status, 404);
+    assert_eq!(r.body, "{\"error\": \"no such key\", \"status\": 2}");
+
+    let r = request(&mut c, "PUT", "/keys/foo", &[], "bar \"baz\"");
+    assert_eq!(r.status, 204);
+    assert_eq!(get(&mut h, "foo"), Some("bar \"baz\"".to_string()));
+
+    let r = request(&mut c, "GET", "/keys/foo", &[], "");
+    assert_eq!(r.status, 200);
+    assert_eq!(header(&r, "content-type"), Some("application/octet-stream"));
+    assert_eq!(r.body, "bar \"baz\"");
+
+    let accept = ["Accept: application/json"];
+    let r = request(&mut c, "GET", "/keys/foo", &accept, "");
+    assert_eq!(header(&r, "content-type"), Some("application/json"));
+    assert_eq!(r.body, "{\"key\": \"foo\", \"value\": \"bar \\\"baz\\\"\"}");
+
+    // keys are percent-decoded
+    let r = request(&mut c, "PUT", "/keys/user%3A1%20a", &[], "Hugo");
+    assert_eq!(r.status, 204);
+    assert_eq!(get(&mut h, "user:1 a"), Some("Hugo".to_string()));
+    request(&mut c, "PUT", "/keys/user:2", &[], "");
+
+    let r = request(&mut c, "GET", "/keys?prefix=user%3A", &[], "");
+    assert_eq!(r.status, 200);
+    assert_eq!(r.body, "{\"keys\": [\"user:1 a\", \"user:2\"]}");
+    let r = request(&mut c, "GET", "/keys", &[], "");
+    assert_eq!(r.body, "{\"keys\": [\"foo\", \"user:1 a\", \"user:2\"]}");
+
+    let r = request(&mut c, "DELETE", "/keys/foo", &[], "");
+    assert_eq!(r.status, 204);
+    assert_eq!(get(&mut h, "foo"), None);
+    let r = request(&mut c, "DELETE", "/keys/foo", &[], "");
+    assert_eq!(r.status, 404);
+
+    let r = request(&mut c, "POST", "/keys/foo", &[], "x");
+    assert_eq!(r.status, 405);
+    assert_eq!(header(&r, "allow"), Some("GET, PUT, DELETE"));
+    let r = request(&mut c, "GET", "/foo", &[], "");
+    assert_eq!(r.status, 404);
+    assert_eq!(r.body, "{\"error\": \"not found\", \"status\": 255}");
+
+    // request bodies need a Content-Length
+    let r = request(
+        &mut c,
+        "PUT",
+        "/keys/a",
+        &["Transfer-Encoding: chunked"],
+        "",
+    );
+    assert_eq!(r.status, 411);
+}
+
+#[test]
+fn test_http_moved() {
+    let (dir1, dir2) = (work_dir("http-moved-1"), work_dir("http-moved-2"));
+    let spec = "0-8191=127.0.0.1:30342,8192-16383=127.0.0.1:30343";
+    let _s1 = start(
+        &dir1,
+        &["--port", "30342", "--http-port", "31342", "--slots", spec],
+    );
+    let _s2 = start(
+        &dir2,
+        &["--port", "30343", "--http-port", "31343", "--slots", spec],
+    );
+    let mut c1 = client(31342);
+    let mut c2 = client(31343);
+
+    // "foo" is in slot 12182, owned by the second server
+    let r = request(&mut c1, "PUT", "/keys/foo", &[], "bar");
+    assert_eq!(r.status, 307);
+    assert_eq!(
+        header(&r, "location"),
+        Some("http://127.0.0.1:31343/keys/foo")
+    );
+    assert!(r.body.contains("\"status\": 6"));
+    let r = request(&mut c2, "PUT", "/keys/foo", &[], "bar");
+    assert_eq!(r.status, 204);
+    let r = request(&mut c1, "GET", "/keys/foo", &[], "");
+    assert_eq!(r.status, 307);
+    let r = request(&mut c2, "GET", "/keys/foo", &[], "");
+    assert_eq!(r.body, "bar");
+}
@@ -0,0 +1,195 @@
+// Run a server on localhost ports, and send keys of 64 KiB or more, and
+// values in chunks, with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{cli, connect, start, work_dir};
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Letters hardly compressed, so that values stay large with GZIP
+fn random_text(len: usize) -> String {
+    let mut seed: u32 = 42;
+    let mut text = String::with_capacity(len);
+    for _ in 0..len {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        text.push((b'a' + (seed >> 16) as u8 % 26) as char);
+    }
+    text
+}
+
+/// `<len:2>`, or `\xFF\xFF<len:4>` for long keys
+fn key_len(key: &str) -> Vec<u8> {
+    if key.len() < 0xFFFF {
+        return (key.len() as u16).to_le_bytes().to_vec();
+    }
+    let mut bytes = vec![0xFF, 0xFF];
+    bytes.extend(&(key.len() as u32).to_le_bytes());
+    bytes
+}
+
+fn chunks(value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![];
+    for chunk in value.chunks(CHUNK_SIZE) {
+        buffer.extend(&(chunk.len() as u32).to_le_bytes());
+        buffer.extend(chunk);
+    }
+    buffer.extend(&[0; 4]);
+    buffer
+}
+
+fn read_u32(stream: &mut TcpStream) -> usize {
+    let mut buffer = [0; 4];
+    stream.read_exact(&mut buffer).unwrap();
+    u32::from_le_bytes(buffer) as usize
+}
+
+/// HELLO, returns the Stat of the reply
+fn hello(stream: &mut TcpStream, content: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x19, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).unwrap();
+    let mut text = vec![0; u16::from_le_bytes([reply[2], reply[3]]) as usize];
+    stream.read_exact(&mut text).unwrap();
+    reply[1]
+}
+
+/// PUT with the value in chunks, returns the Stat of the reply
+fn put_chunked(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(key_len(key));
+    buffer.extend(key.as_bytes());
+    buffer.push(0xFF);
+    buffer.extend(chunks(value.as_bytes()));
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// GET without flag, returns the Stat of the reply, if the value is in
+/// chunks, and the value
+fn get(stream: &mut TcpStream, key: &str) -> (u8, bool, String) {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(key_len(key));
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], false, String::new());
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    assert_eq!(buf_flag_llen[0], 0x00);
+    let mut value = vec![];
+    if buf_flag_llen[1] == 0xFF {
+        loop {
+            let len = read_u32(stream);
+            if len == 0 {
+                break;
+            }
+            assert!(len <= CHUNK_SIZE);
+            let mut chunk = vec![0; len];
+            stream.read_exact(&mut chunk).unwrap();
+            value.extend(chunk);
+        }
+    } else {
+        let mut buf_len = [0; 8];
+        stream
+            .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+            .unwrap();
+        value = vec![0; u64::from_le_bytes(buf_len) as usize];
+        stream.read_exact(&mut value).unwrap();
+    }
+    let chunked = buf_flag_llen[1] == 0xFF;
+    (0x00, chunked, String::from_utf8(value).unwrap())
+}
+
+/// SCAN, returns the lengths of the keys, read in the long form as for
+/// clients with `bigkeys`
+fn scan(stream: &mut TcpStream, pattern: &str) -> Vec<usize> {
+    let mut buffer = vec![0x0c, 0x04, 0x00];
+    buffer.extend(&(pattern.len() as u16).to_le_bytes());
+    buffer.extend(pattern.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] == 0x02 {
+        return vec![];
+    }
+    let mut buf_count = [0; 5];
+    stream.read_exact(&mut buf_count).unwrap();
+    let count = u32::from_le_bytes([buf_count[1], buf_count[2], buf_count[3], buf_count[4]]);
+    let mut lens = vec![];
+    for _ in 0..count {
+        let mut buf_len = [0; 2];
+        stream.read_exact(&mut buf_len).unwrap();
+        let mut len = u16::from_le_bytes(buf_len) as usize;
+        if len == 0xFFFF {
+            len = read_u32(stream);
+        }
+        let mut key = vec![0; len];
+        stream.read_exact(&mut key).unwrap();
+        lens.push(len);
+    }
+    lens.sort();
+    lens
+}
+
+#[test]
+fn test_large() {
+    let dir = work_dir("large");
+    let _server = start(&dir, &["--port", "30440"]);
+    let mut big = connect(30440);
+    assert_eq!(hello(&mut big, "1 bigkeys chunked"), 0x00);
+
+    let key = "k".repeat(70000);
+    let value = random_text(CHUNK_SIZE * 2 + 100);
+    assert_eq!(put_chunked(&mut big, &key, &value), 0x00);
+    assert_eq!(put_chunked(&mut big, "small", "v"), 0x00);
+    assert_eq!(get(&mut big, &key), (0x00, true, value.clone()));
+    assert_eq!(get(&mut big, "small"), (0x00, false, "v".to_string()));
+    assert_eq!(scan(&mut big, ""), vec![5, 70000]);
+
+    // clients without the features get values whole, and no long keys
+    let mut whole = connect(30440);
+    assert_eq!(hello(&mut whole, "1 bigkeys"), 0x00);
+    assert_eq!(get(&mut whole, &key), (0x00, false, value.clone()));
+    let mut s = connect(30440);
+    assert_eq!(scan(&mut s, ""), vec![5]);
+
+    // truncated chunks drop the connection
+    s.write_all(b"\x0c\x02\x00\x03\x00foo\xff\x05\x00\x00\x00ab")
+        .unwrap();
+    drop(s);
+    let mut s = connect(30440);
+    assert_eq!(get(&mut s, "foo").0, 0x02);
+
+    // a key of exactly 65535 bytes takes the long form only with `bigkeys`
+    let key = "e".repeat(0xFFFF);
+    let mut query = b"\x0c\x02\x00\xff\xff".to_vec();
+    query.extend(key.as_bytes());
+    query.extend(b"\x01\x01v");
+    s.write_all(&query).unwrap();
+    let mut reply = [0; 2];
+    s.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [0x0c, 0x00]);
+    assert_eq!(scan(&mut s, "e"), vec![]);
+    assert_eq!(get(&mut big, &key), (0x00, false, "v".to_string()));
+    assert_eq!(scan(&mut big, "e"), vec![0xFFFF]);
+
+    // CLI, with the value compressed, and in chunks both ways
+    let key = "c".repeat(0x10000);
+    let input = format!("put {} {}\nget {}\n", key, value, key);
+    let output = cli(&["127.0.0.1:30440"], input.as_bytes(), 3);
+    assert_eq!(output[1], "h2okv> OK");
+    assert_eq!(output[2], format!("h2okv> {:?}", value));
+    assert_eq!(get(&mut whole, &key), (0x00, false, value));
+}
@@ -0,0 +1,77 @@
+// Run a server on localhost ports with size limits, and send queries
+// over them, with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{cli, connect, start, work_dir};
+
+/// Send the query, returns the Stat of the reply
+fn query(stream: &mut TcpStream, query: &[u8]) -> u8 {
+    stream.write_all(query).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// PUT, with the VLen claimed, and the value sent
+fn put_query(key: &str, vlen: u64, value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&vlen.to_le_bytes());
+    buffer.extend(value);
+    buffer
+}
+
+fn get_query(key: &str) -> Vec<u8> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer
+}
+
+/// If the server closed the connection
+fn closed(stream: &mut TcpStream) -> bool {
+    stream.read(&mut [0; 1]).unwrap_or(0) == 0
+}
+
+#[test]
+fn test_limits() {
+    let dir = work_dir("limits");
+    let args = [
+        "--port",
+        "30450",
+        "--max-key-size",
+        "1K",
+        "--max-value-size",
+        "64K",
+        "--max-request-size",
+        "65000",
+    ];
+    let _server = start(&dir, &args);
+    let mut s = connect(30450);
+    let value = vec![b'v'; 60000];
+    assert_eq!(query(&mut s, &put_query("foo", 60000, &value)), 0x00);
+    assert_eq!(query(&mut s, &get_query(&"k".repeat(1024))), 0x02);
+
+    // a claimed length is rejected before anything is allocated for it,
+    // and the connection closed
+    assert_eq!(query(&mut s, &put_query("foo", 1 << 40, b"")), 0x0B);
+    assert!(closed(&mut s));
+    let mut s = connect(30450);
+    assert_eq!(query(&mut s, &get_query(&"k".repeat(1025))), 0x0B);
+    assert!(closed(&mut s));
+    let mut s = connect(30450);
+    let key = "k".repeat(1024);
+    assert_eq!(query(&mut s, &put_query(&key, 65536, b"")), 0x0B);
+    assert!(closed(&mut s));
+
+    // CLI, with the value compressed under the limits but not after
+    let input = format!("put bar {}\n", "v".repeat(65537));
+    let output = cli(&["127.0.0.1:30450"], input.as_bytes(), 2);
+    assert_eq!(output[1], "h2okv> (error) request too large");
+}
@@ -0,0 +1,171 @@
+// Run servers on localhost ports, and push to and pop from lists, with
+// clients blocked popping until other clients push.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, start, work_dir};
+
+/// LPUSH or RPUSH, returns the Stat of the reply, and the length of the
+/// list
+fn push(stream: &mut TcpStream, cmd: u8, key: &str, value: &str) -> (u8, u32) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], 0);
+    }
+    let mut buf_len = [0; 4];
+    stream.read_exact(&mut buf_len).unwrap();
+    (0x00, u32::from_le_bytes(buf_len))
+}
+
+/// Read `<vllen><vlen><value>` of a reply
+fn read_value(stream: &mut TcpStream) -> String {
+    let mut buf_llen = [0; 1];
+    stream.read_exact(&mut buf_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_llen[0] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    String::from_utf8(value).unwrap()
+}
+
+/// A query of the key only, e.g. LPOP or GET, returns the Stat of the
+/// reply, and the value
+fn query_key(stream: &mut TcpStream, cmd: u8, key: &str) -> (u8, Option<String>) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], None);
+    }
+    let mut buf_flag = [0; 1];
+    stream.read_exact(&mut buf_flag).unwrap();
+    (0x00, Some(read_value(stream)))
+}
+
+/// Send BLPOP or BRPOP, with the timeout in ms
+fn send_blocking_pop(stream: &mut TcpStream, cmd: u8, keys: &[&str], timeout: u32) {
+    let mut content = timeout.to_le_bytes().to_vec();
+    for key in keys {
+        content.extend(&(key.len() as u16).to_le_bytes());
+        content.extend(key.as_bytes());
+    }
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content);
+    stream.write_all(&buffer).unwrap();
+}
+
+/// Read the reply of BLPOP or BRPOP, returns the Stat, and the key and
+/// the value
+fn read_blocking_pop(stream: &mut TcpStream) -> (u8, Option<(String, String)>) {
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return (reply[1], None);
+    }
+    let mut buf_flag_klen = [0; 3];
+    stream.read_exact(&mut buf_flag_klen).unwrap();
+    let mut key = vec![0; u16::from_le_bytes([buf_flag_klen[1], buf_flag_klen[2]]) as usize];
+    stream.read_exact(&mut key).unwrap();
+    let value = read_value(stream);
+    (0x00, Some((String::from_utf8(key).unwrap(), value)))
+}
+
+#[test]
+fn test_lists() {
+    let dir = work_dir("lists");
+    let server = start(&dir, &["--port", "30490"]);
+    let mut s = connect(30490);
+
+    assert_eq!(push(&mut s, 0x1C, "jobs", "b"), (0x00, 1));
+    assert_eq!(push(&mut s, 0x1C, "jobs", "c"), (0x00, 2));
+    assert_eq!(push(&mut s, 0x1B, "jobs", "a"), (0x00, 3));
+    assert_eq!(
+        query_key(&mut s, 0x1D, "jobs"),
+        (0x00, Some("a".to_string()))
+    );
+    assert_eq!(
+        query_key(&mut s, 0x1E, "jobs"),
+        (0x00, Some("c".to_string()))
+    );
+
+    // a key holds either a string or a list
+    assert_eq!(query_key(&mut s, 0x01, "jobs"), (0x0C, None));
+    assert_eq!(query_key(&mut s, 0x1E, "none"), (0x02, None));
+    let mut buffer = vec![0x0c, 0x02, 0x00, 0x04, 0x00];
+    buffer.extend(b"name\x01\x04Hugo");
+    s.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    s.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [0x0c, 0x00]);
+    assert_eq!(push(&mut s, 0x1B, "name", "x"), (0x0C, 0));
+    assert_eq!(query_key(&mut s, 0x1D, "name"), (0x0C, None));
+
+    // kept in the data file
+    drop(server);
+    let _server = start(&dir, &["--port", "30490"]);
+    let mut s = connect(30490);
+    assert_eq!(
+        query_key(&mut s, 0x1D, "jobs"),
+        (0x00, Some("b".to_string()))
+    );
+    assert_eq!(query_key(&mut s, 0x1D, "jobs"), (0x02, None));
+    assert_eq!(
+        query_key(&mut s, 0x01, "name"),
+        (0x00, Some("Hugo".to_string()))
+    );
+}
+
+#[test]
+fn test_blocking_pop() {
+    let dir = work_dir("lists-blocking");
+    let _server = start(&dir, &["--port", "30491"]);
+    let mut s = connect(30491);
+
+    // popped right away from the first key with a list
+    assert_eq!(push(&mut s, 0x1C, "mail", "m1"), (0x00, 1));
+    send_blocking_pop(&mut s, 0x1F, &["jobs", "mail"], 0);
+    let popped = Some(("mail".to_string(), "m1".to_string()));
+    assert_eq!(read_blocking_pop(&mut s), (0x00, popped));
+
+    // not found when the timeout expires
+    let started = Instant::now();
+    send_blocking_pop(&mut s, 0x20, &["jobs"], 200);
+    assert_eq!(read_blocking_pop(&mut s), (0x02, None));
+    assert!(started.elapsed() >= Duration::from_millis(200));
+
+    // woken up by pushes, the longest waiting first
+    let mut w1 = connect(30491);
+    send_blocking_pop(&mut w1, 0x1F, &["jobs", "mail"], 0);
+    thread::sleep(Duration::from_millis(100));
+    let mut w2 = connect(30491);
+    send_blocking_pop(&mut w2, 0x20, &["jobs"], 5000);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(push(&mut s, 0x1C, "jobs", "j1"), (0x00, 1));
+    assert_eq!(push(&mut s, 0x1C, "jobs", "j2"), (0x00, 1));
+    let popped = Some(("jobs".to_string(), "j1".to_string()));
+    assert_eq!(read_blocking_pop(&mut w1), (0x00, popped));
+    let popped = Some(("jobs".to_string(), "j2".to_string()));
+    assert_eq!(read_blocking_pop(&mut w2), (0x00, popped));
+
+    // elements handed to waiters are not left in the list
+    assert_eq!(query_key(&mut s, 0x1D, "jobs"), (0x02, None));
+}
@@ -0,0 +1,93 @@
+// Run a server on a localhost port logging into a file, change its log
+// settings with LOG, and check the lines logged.
+
+mod common;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+
+use common::{cli, connect, start, work_dir};
+
+/// Send LOG with the content, returns the Stat and the text of the reply
+fn log(stream: &mut TcpStream, content: &str) -> (u8, String) {
+    let mut buffer = vec![0x0c, 0x1a, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 4];
+    stream.read_exact(&mut reply).unwrap();
+    let mut text = vec![0; u16::from_le_bytes([reply[2], reply[3]]) as usize];
+    stream.read_exact(&mut text).unwrap();
+    (reply[1], String::from_utf8(text).unwrap())
+}
+
+fn get_query(key: &str) -> Vec<u8> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer
+}
+
+/// Lines logged so far, waiting a bit for the ones of other threads
+fn log_lines(path: &PathBuf) -> Vec<String> {
+    thread::sleep(Duration::from_millis(200));
+    let text = fs::read_to_string(path).unwrap();
+    text.lines().map(|x| x.to_string()).collect()
+}
+
+#[test]
+fn test_logging() {
+    let dir = work_dir("logging");
+    let args = [
+        "--port",
+        "30460",
+        "--log-file",
+        "h2okv.log",
+        "--log-format",
+        "json",
+    ];
+    let _server = start(&dir, &args);
+    let mut s = connect(30460);
+    let path = dir.join("h2okv.log");
+
+    let (stat, text) = log(&mut s, "");
+    assert_eq!(stat, 0x00);
+    assert_eq!(text, "level info format json file h2okv.log");
+    let lines = log_lines(&path);
+    assert!(lines[0].starts_with("{\"time\":\""));
+    let peer = s.local_addr().unwrap().to_string();
+    let accepted = format!(",\"peer\":\"{}\",\"msg\":\"client accepted\"}}", peer);
+    assert!(lines
+        .iter()
+        .any(|x| x.contains("\"level\":\"info\"") && x.ends_with(&accepted)));
+
+    // queries are logged at the level debug
+    assert_eq!(log(&mut s, "level debug").0, 0x00);
+    s.write_all(&get_query("foo")).unwrap();
+    s.read_exact(&mut [0; 2]).unwrap();
+    let lines = log_lines(&path);
+    let query = lines.last().unwrap();
+    assert!(query.contains("\"level\":\"debug\""));
+    assert!(query.ends_with("\"msg\":\"query 0x01, flag 0\"}"));
+
+    assert_eq!(log(&mut s, "format text").0, 0x00);
+    assert_eq!(log(&mut s, "level warn").0, 0x00);
+    let lines = log_lines(&path);
+    let line = format!(
+        "INFO [#1 {}] log settings changed: level debug format text",
+        peer
+    );
+    assert!(lines.iter().any(|x| x.contains(&line)));
+    assert!(lines.last().unwrap().contains("DEBUG [#1 "));
+    let (stat, text) = log(&mut s, "level trace");
+    assert_eq!((stat, text.as_str()), (0x01, "unknown log level: trace"));
+    assert_eq!(log(&mut s, "").1, "level warn format text file h2okv.log");
+
+    // CLI
+    let output = cli(&["127.0.0.1:30460"], b"log level info\n", 2);
+    assert_eq!(output[1], "h2okv> level info format text file h2okv.log");
+}
@@ -0,0 +1,148 @@
+// Run a server with the memcached protocol listener on localhost ports,
+// and talk to it as a memcached client would.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::Duration;
+
+use common::{connect, start, work_dir};
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+fn client(port: u16) -> Client {
+    let stream = connect(port);
+    Client {
+        writer: stream.try_clone().unwrap(),
+        reader: BufReader::new(stream),
+    }
+}
+
+/// Send the text, returns the reply up to the line `until`, or the
+/// first line if `until` is empty
+fn send(c: &mut Client, text: &str, until: &str) -> String {
+    c.writer.write_all(text.as_bytes()).unwrap();
+    let mut reply = String::new();
+    loop {
+        let mut line = String::new();
+        c.reader.read_line(&mut line).unwrap();
+        reply.push_str(&line);
+        if until.is_empty() || line == format!("{}\r\n", until) {
+            return reply;
+        }
+    }
+}
+
+fn get(c: &mut Client, keys: &str) -> String {
+    send(c, &format!("get {}\r\n", keys), "END")
+}
+
+/// PUT with the h2okv protocol
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+#[test]
+fn test_memcache() {
+    let dir = work_dir("memcache");
+    let args = [
+        "--port",
+        "30330",
+        "--memcache-port",
+        "30331",
+        "--max-value-size",
+        "1M",
+    ];
+    let server = start(&dir, &args);
+    let mut h = connect(30330);
+    let mut c = client(30331);
+
+    assert_eq!(get(&mut c, "foo"), "END\r\n");
+    assert_eq!(send(&mut c, "set foo 42 0 3\r\nbar\r\n", ""), "STORED\r\n");
+    assert_eq!(get(&mut c, "foo nope"), "VALUE foo 42 3\r\nbar\r\nEND\r\n");
+    assert_eq!(send(&mut c, "add foo 0 0 1\r\nx\r\n", ""), "NOT_STORED\r\n");
+    assert_eq!(
+        send(&mut c, "replace nope 0 0 1\r\nx\r\n", ""),
+        "NOT_STORED\r\n"
+    );
+    assert_eq!(send(&mut c, "append foo 0 0 2\r\n!!\r\n", ""), "STORED\r\n");
+    assert_eq!(send(&mut c, "prepend foo 0 0 1\r\n<\r\n", ""), "STORED\r\n");
+    assert_eq!(get(&mut c, "foo"), "VALUE foo 42 6\r\n<bar!!\r\nEND\r\n");
+
+    // cas
+    let reply = send(&mut c, "gets foo\r\n", "END");
+    let cas = reply.lines().next().unwrap().rsplit(' ').next().unwrap();
+    let query = format!("cas foo 1 0 3 {}\r\nbaz\r\n", cas);
+    assert_eq!(send(&mut c, &query, ""), "STORED\r\n");
+    assert_eq!(send(&mut c, &query, ""), "EXISTS\r\n");
+    assert_eq!(
+        send(&mut c, "cas nope 0 0 1 1\r\nx\r\n", ""),
+        "NOT_FOUND\r\n"
+    );
+    assert_eq!(get(&mut c, "foo"), "VALUE foo 1 3\r\nbaz\r\nEND\r\n");
+
+    // incr & decr
+    assert_eq!(send(&mut c, "set n 0 0 2\r\n10\r\n", ""), "STORED\r\n");
+    assert_eq!(send(&mut c, "incr n 5\r\n", ""), "15\r\n");
+    assert_eq!(send(&mut c, "decr n 20\r\n", ""), "0\r\n");
+    assert_eq!(send(&mut c, "incr nope 1\r\n", ""), "NOT_FOUND\r\n");
+    assert!(send(&mut c, "incr foo 1\r\n", "").starts_with("CLIENT_ERROR"));
+
+    // noreply, then delete
+    c.writer
+        .write_all(b"set quiet 0 0 1 noreply\r\nq\r\n")
+        .unwrap();
+    assert_eq!(send(&mut c, "delete quiet\r\n", ""), "DELETED\r\n");
+    assert_eq!(send(&mut c, "delete quiet\r\n", ""), "NOT_FOUND\r\n");
+    assert_eq!(send(&mut c, "bogus\r\n", ""), "ERROR\r\n");
+
+    // values over the limit are dropped, as are lines too long
+    let size = 2 * 1024 * 1024;
+    let text = format!("set big 0 0 {}\r\n{}\r\n", size, "x".repeat(size));
+    let reply = "SERVER_ERROR object too large for cache\r\n";
+    assert_eq!(send(&mut c, &text, ""), reply);
+    assert_eq!(get(&mut c, "big"), "END\r\n");
+    let mut c2 = client(30331);
+    // all of it is read before closing, as it is 64 KiB
+    let text = format!("get {}", "k ".repeat(32766));
+    assert_eq!(send(&mut c2, &text, ""), "CLIENT_ERROR line too long\r\n");
+    let mut c2 = client(30331);
+    let text = format!("set big 0 0 {}\r\n", usize::MAX);
+    c2.writer.write_all(text.as_bytes()).unwrap();
+    assert_eq!(c2.reader.read_line(&mut String::new()).unwrap(), 0);
+
+    // expired keys are gone
+    assert_eq!(send(&mut c, "set tmp 0 1 1\r\nt\r\n", ""), "STORED\r\n");
+    assert_eq!(send(&mut c, "touch n 100\r\n", ""), "TOUCHED\r\n");
+    thread::sleep(Duration::from_millis(3000));
+    assert_eq!(get(&mut c, "tmp"), "END\r\n");
+    assert_eq!(send(&mut c, "add tmp 0 0 1\r\nu\r\n", ""), "STORED\r\n");
+
+    // values changed by other protocols lose their flags
+    assert_eq!(put(&mut h, "foo", "qux"), 0x00);
+    assert_eq!(get(&mut c, "foo"), "VALUE foo 0 3\r\nqux\r\nEND\r\n");
+
+    // flags are kept after restarting
+    assert_eq!(send(&mut c, "set foo 7 0 1\r\nf\r\n", ""), "STORED\r\n");
+    drop(server);
+    let _server = start(&dir, &args);
+    let mut c = client(30331);
+    assert_eq!(
+        get(&mut c, "foo n"),
+        "VALUE foo 7 1\r\nf\r\nVALUE n 0 1\r\n0\r\nEND\r\n"
+    );
+}
@@ -0,0 +1,302 @@
+// Run backend servers and a proxy in front of them on localhost ports,
+// and check that queries through the proxy reach the right backends.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, start, work_dir, Server};
+
+fn start_proxy(args: &[&str]) -> Server {
+    let child = Command::new(env!("CARGO_BIN_EXE_h2okv-proxy"))
+        .args(args)
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    Server { child }
+}
+
+fn query(stream: &mut TcpStream, cmd: u8, content: &[u8]) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&len_bytes(content.len()));
+    buffer.extend(content);
+    stream.write_all(&buffer).unwrap();
+}
+
+fn read_exact(stream: &mut TcpStream, size: usize) -> Vec<u8> {
+    let mut buffer = vec![0; size];
+    stream.read_exact(&mut buffer).unwrap();
+    buffer
+}
+
+fn read_value(stream: &mut TcpStream) -> String {
+    let llen = read_exact(stream, 1)[0] as usize;
+    let mut buf_len = [0; 8];
+    stream.read_exact(&mut buf_len[..llen]).unwrap();
+    let value = read_exact(stream, u64::from_le_bytes(buf_len) as usize);
+    String::from_utf8(value).unwrap()
+}
+
+/// Length of 2 bytes, or `\xFF\xFF` and 4 bytes for 64 KiB or more
+fn len_bytes(len: usize) -> Vec<u8> {
+    if len < 0xFFFF {
+        return (len as u16).to_le_bytes().to_vec();
+    }
+    let mut bytes = vec![0xff, 0xff];
+    bytes.extend(&(len as u32).to_le_bytes());
+    bytes
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    put_cmd(stream, 0x02, key, value)
+}
+
+/// AUTH of the user, empty for the default user
+fn auth(stream: &mut TcpStream, user: &str, password: &str) -> u8 {
+    put_cmd(stream, 0x16, user, password)
+}
+
+fn put_cmd(stream: &mut TcpStream, cmd: u8, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&len_bytes(key.len()));
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    read_exact(stream, 2)[1]
+}
+
+fn del(stream: &mut TcpStream, key: &str) -> u8 {
+    query(stream, 0x03, key.as_bytes());
+    read_exact(stream, 2)[1]
+}
+
+/// Returns the status, and the value if found
+fn get(stream: &mut TcpStream, key: &str) -> (u8, Option<String>) {
+    query(stream, 0x01, key.as_bytes());
+    let status = read_exact(stream, 2)[1];
+    match status {
+        0x00 => {
+            read_exact(stream, 1);
+            (status, Some(read_value(stream)))
+        }
+        0x06 | 0x07 => {
+            read_exact(stream, 2);
+            let len = read_exact(stream, 2);
+            read_exact(stream, u16::from_le_bytes([len[0], len[1]]) as usize);
+            (status, None)
+        }
+        _ => (status, None),
+    }
+}
+
+/// Returns the status, and the keys found
+fn scan(stream: &mut TcpStream, key: &str) -> (u8, Vec<String>) {
+    query(stream, 0x04, key.as_bytes());
+    let status = read_exact(stream, 2)[1];
+    if status != 0x00 {
+        return (status, vec![]);
+    }
+    let buf_count = read_exact(stream, 5);
+    let count = u32::from_le_bytes([buf_count[1], buf_count[2], buf_count[3], buf_count[4]]);
+    let mut keys = vec![];
+    for _ in 0..count {
+        let len = read_exact(stream, 2);
+        let key = read_exact(stream, u16::from_le_bytes([len[0], len[1]]) as usize);
+        keys.push(String::from_utf8(key).unwrap());
+    }
+    (status, keys)
+}
+
+fn mget(stream: &mut TcpStream, keys: &[&str]) -> Vec<Option<String>> {
+    let mut content = vec![];
+    for key in keys {
+        content.extend(&(key.len() as u16).to_le_bytes());
+        content.extend(key.as_bytes());
+    }
+    query(stream, 0x15, &content);
+    assert_eq!(read_exact(stream, 2), b"\x0c\x00");
+    let buf_count = read_exact(stream, 4);
+    let count = u32::from_le_bytes([buf_count[0], buf_count[1], buf_count[2], buf_count[3]]);
+    let mut values = vec![];
+    for _ in 0..count {
+        if read_exact(stream, 1)[0] == 0x00 {
+            values.push(None);
+        } else {
+            values.push(Some(read_value(stream)));
+        }
+    }
+    values
+}
+
+#[test]
+fn test_proxy_plain() {
+    let (dir1, dir2) = (work_dir("proxy-plain-1"), work_dir("proxy-plain-2"));
+    let _s1 = start(&dir1, &["--port", "30311"]);
+    let _s2 = start(&dir2, &["--port", "30312"]);
+    let mut s1 = connect(30311);
+    let mut s2 = connect(30312);
+    let _proxy = start_proxy(&[
+        "--port",
+        "30310",
+        "--backends",
+        "127.0.0.1:30311,127.0.0.1:30312",
+    ]);
+    let mut p = connect(30310);
+
+    let keys: Vec<String> = (0..20).map(|i| format!("key{:02}", i)).collect();
+    for key in keys.iter() {
+        assert_eq!(put(&mut p, key, &format!("value of {}", key)), 0x00);
+    }
+    for key in keys.iter() {
+        assert_eq!(get(&mut p, key), (0x00, Some(format!("value of {}", key))));
+    }
+    assert_eq!(get(&mut p, "foo"), (0x02, None));
+
+    // keys are split between the backends
+    let (_, keys1) = scan(&mut s1, "key");
+    let (_, keys2) = scan(&mut s2, "key");
+    assert!(!keys1.is_empty() && !keys2.is_empty());
+    assert_eq!(keys1.len() + keys2.len(), 20);
+
+    // merged and sorted
+    assert_eq!(scan(&mut p, "key"), (0x00, keys.clone()));
+    assert_eq!(scan(&mut p, "key1"), (0x00, keys[10..].to_vec()));
+    assert_eq!(scan(&mut p, "foo"), (0x02, vec![]));
+
+    let values = mget(&mut p, &[&keys1[0], "foo", &keys2[0]]);
+    assert_eq!(values[0], Some(format!("value of {}", keys1[0])));
+    assert_eq!(values[1], None);
+    assert_eq!(values[2], Some(format!("value of {}", keys2[0])));
+
+    assert_eq!(del(&mut p, &keys2[0]), 0x00);
+    assert_eq!(get(&mut s2, &keys2[0]), (0x02, None));
+
+    // clients see no slot map, and send everything to the proxy
+    query(&mut p, 0x11, b"");
+    assert_eq!(read_exact(&mut p, 4), b"\x0c\x00\x00\x00");
+
+    // HELLO is replied by the proxy, without `cipher` as the backends
+    // have no key
+    query(&mut p, 0x19, b"1 gzip");
+    let reply = b"\x0c\x00\x1f\x001 gzip mget bigkeys type:string";
+    assert_eq!(read_exact(&mut p, reply.len()), reply);
+    query(&mut p, 0x19, b"0");
+    assert_eq!(read_exact(&mut p, 2), b"\x0c\x0a");
+}
+
+#[test]
+fn test_proxy_sharded() {
+    let (dir1, dir2) = (work_dir("proxy-sharded-1"), work_dir("proxy-sharded-2"));
+    let spec = "0-8191=127.0.0.1:30314,8192-16383=127.0.0.1:30315";
+    let _s1 = start(&dir1, &["--port", "30314", "--slots", spec]);
+    let _s2 = start(&dir2, &["--port", "30315", "--slots", spec]);
+    let mut s1 = connect(30314);
+    let mut s2 = connect(30315);
+    // the slot map is learned from the backend
+    let _proxy = start_proxy(&["--port", "30313", "--backends", "127.0.0.1:30314"]);
+    let mut p = connect(30313);
+
+    // "foo" is in slot 12182, "bar" in slot 5061
+    assert_eq!(put(&mut p, "foo", "1"), 0x00);
+    assert_eq!(put(&mut p, "bar", "2"), 0x00);
+    assert_eq!(get(&mut s2, "foo"), (0x00, Some("1".to_string())));
+    assert_eq!(get(&mut s1, "bar"), (0x00, Some("2".to_string())));
+    assert_eq!(get(&mut s1, "foo").0, 0x06);
+
+    let values = mget(&mut p, &["foo", "bar", "baz"]);
+    assert_eq!(
+        values,
+        vec![Some("1".to_string()), Some("2".to_string()), None]
+    );
+    assert_eq!(scan(&mut p, "").1, vec!["bar", "foo"]);
+    assert_eq!(del(&mut p, "foo"), 0x00);
+    assert_eq!(get(&mut p, "foo"), (0x02, None));
+}
+
+#[test]
+fn test_proxy_health() {
+    let (dir1, dir2) = (work_dir("proxy-health-1"), work_dir("proxy-health-2"));
+    let _s1 = start(&dir1, &["--port", "30317"]);
+    let s2 = start(&dir2, &["--port", "30318"]);
+    connect(30317);
+    connect(30318);
+    let _proxy = start_proxy(&[
+        "--port",
+        "30316",
+        "--backends",
+        "127.0.0.1:30317,127.0.0.1:30318",
+    ]);
+    let mut p = connect(30316);
+
+    // with two backends, "bar" (slot 5061) is on the first, "foo" on the
+    // second
+    assert_eq!(put(&mut p, "foo", "1"), 0x00);
+    assert_eq!(put(&mut p, "bar", "2"), 0x00);
+
+    drop(s2);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(&mut p, "foo").0 != 0x01 {
+        assert!(Instant::now() < deadline, "backend is not found down");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(get(&mut p, "bar"), (0x00, Some("2".to_string())));
+    assert_eq!(put(&mut p, "foo", "3"), 0x01);
+    assert_eq!(scan(&mut p, "").0, 0x01);
+
+    let _s2 = start(&dir2, &["--port", "30318"]);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(&mut p, "foo").0 != 0x00 {
+        assert!(Instant::now() < deadline, "backend is not found up");
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(get(&mut p, "foo"), (0x00, Some("1".to_string())));
+}
+
+#[test]
+fn test_proxy_auth() {
+    let (dir1, dir2) = (work_dir("proxy-auth-1"), work_dir("proxy-auth-2"));
+    let _s1 = start(&dir1, &["--port", "30481", "--password", "pw"]);
+    let _s2 = start(&dir2, &["--port", "30482", "--password", "pw"]);
+    connect(30481);
+    connect(30482);
+    let _proxy = start_proxy(&[
+        "--port",
+        "30480",
+        "--backends",
+        "127.0.0.1:30481,127.0.0.1:30482",
+        "--password",
+        "pw",
+        "--max-value-size",
+        "1K",
+    ]);
+    let mut p = connect(30480);
+
+    assert_eq!(put(&mut p, "foo", "1"), 0x08);
+    assert_eq!(auth(&mut p, "", "wrong"), 0x08);
+    assert_eq!(put(&mut p, "foo", "1"), 0x08);
+    assert_eq!(auth(&mut p, "", "pw"), 0x00);
+    assert_eq!(put(&mut p, "foo", "1"), 0x00);
+    assert_eq!(put(&mut p, "bar", "2"), 0x00);
+    assert_eq!(get(&mut p, "foo"), (0x00, Some("1".to_string())));
+    assert_eq!(scan(&mut p, "").1, vec!["bar", "foo"]);
+
+    // keys of 64 KiB or more have their length in the long form, with
+    // `bigkeys`
+    query(&mut p, 0x19, b"1 bigkeys");
+    let len = read_exact(&mut p, 4)[2] as usize;
+    read_exact(&mut p, len);
+    let key = "k".repeat(70000);
+    assert_eq!(put(&mut p, &key, "long"), 0x00);
+    assert_eq!(get(&mut p, &key), (0x00, Some("long".to_string())));
+
+    // values over the limit of the proxy are refused before they are read
+    assert_eq!(put(&mut p, "foo", &"x".repeat(2000)), 0x0b);
+    let mut p = connect(30480);
+    assert_eq!(get(&mut p, "foo").0, 0x08);
+}
@@ -0,0 +1,178 @@
+// Run a server on a localhost port, and follow it in push mode with
+// SUBSCRIBE, WATCH and CHANGES, including a subscriber too slow to read.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+
+use common::{connect, start, work_dir};
+
+/// Query with a content, e.g. SUBSCRIBE
+fn content_query(cmd: u8, content: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content);
+    buffer
+}
+
+/// PUT-like query, e.g. PUBLISH
+fn key_value_query(cmd: u8, key: &str, value: &[u8]) -> Vec<u8> {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value);
+    buffer
+}
+
+fn read(stream: &mut TcpStream, size: usize) -> Vec<u8> {
+    let mut buffer = vec![0; size];
+    stream.read_exact(&mut buffer).unwrap();
+    buffer
+}
+
+/// Reply of (UN)SUBSCRIBE, PUBLISH, WATCH: the count
+fn count(stream: &mut TcpStream) -> u32 {
+    let reply = read(stream, 6);
+    assert_eq!(&reply[..2], b"\x0c\x00");
+    u32::from_le_bytes([reply[2], reply[3], reply[4], reply[5]])
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) {
+    stream
+        .write_all(&key_value_query(0x02, key, value.as_bytes()))
+        .unwrap();
+    assert_eq!(read(stream, 2), b"\x0c\x00");
+}
+
+#[test]
+fn test_subscribe() {
+    let dir = work_dir("push-subscribe");
+    let _server = start(&dir, &["--port", "30470"]);
+    let mut sub = connect(30470);
+    let mut s = connect(30470);
+
+    sub.write_all(&content_query(0x05, b"news")).unwrap();
+    assert_eq!(count(&mut sub), 1);
+    sub.write_all(&content_query(0x07, b"n*")).unwrap();
+    assert_eq!(count(&mut sub), 2);
+
+    s.write_all(&key_value_query(0x08, "news", b"hi")).unwrap();
+    assert_eq!(count(&mut s), 2);
+    assert_eq!(read(&mut sub, 12), b"\x0c\x10\x00\x00\x04\x00news\x01\x02");
+    assert_eq!(read(&mut sub, 2), b"hi");
+    assert_eq!(
+        read(&mut sub, 14),
+        b"\x0c\x10\x02\x00n*\x04\x00news\x01\x02"
+    );
+    assert_eq!(read(&mut sub, 2), b"hi");
+
+    sub.write_all(&content_query(0x06, b"")).unwrap();
+    assert_eq!(count(&mut sub), 0);
+    s.write_all(&key_value_query(0x08, "news", b"hi")).unwrap();
+    assert_eq!(count(&mut s), 0);
+}
+
+#[test]
+fn test_watch() {
+    let dir = work_dir("push-watch");
+    let _server = start(&dir, &["--port", "30471"]);
+    let mut watcher = connect(30471);
+    let mut s = connect(30471);
+
+    watcher.write_all(&content_query(0x09, b"foo")).unwrap();
+    assert_eq!(count(&mut watcher), 1);
+    put(&mut s, "foo", "bar");
+    put(&mut s, "other", "x");
+    s.write_all(&content_query(0x03, b"foo")).unwrap();
+    assert_eq!(read(&mut s, 2), b"\x0c\x00");
+
+    assert_eq!(read(&mut watcher, 10), b"\x0c\x11\x01\x03\x00foo\x01\x03");
+    assert_eq!(read(&mut watcher, 3), b"bar");
+    assert_eq!(read(&mut watcher, 9), b"\x0c\x11\x02\x03\x00foo\x00");
+}
+
+#[test]
+fn test_changes() {
+    let dir = work_dir("push-changes");
+    let server = start(&dir, &["--port", "30472"]);
+    let mut s = connect(30472);
+    put(&mut s, "a", "1");
+    put(&mut s, "b", "2");
+
+    // from the second change, then the new ones
+    let mut follower = connect(30472);
+    follower.write_all(&content_query(0x0C, &[2])).unwrap();
+    assert_eq!(read(&mut follower, 4), b"\x0c\x00\x01\x03");
+    assert_eq!(
+        read(&mut follower, 10),
+        b"\x0c\x12\x01\x02\x01\x01\x00b\x01\x01"
+    );
+    assert_eq!(read(&mut follower, 1), b"2");
+    put(&mut s, "c", "3");
+    assert_eq!(
+        read(&mut follower, 10),
+        b"\x0c\x12\x01\x03\x01\x01\x00c\x01\x01"
+    );
+    assert_eq!(read(&mut follower, 1), b"3");
+
+    // no longer, or not yet, in the log
+    let mut s2 = connect(30472);
+    s2.write_all(&content_query(0x0C, &[9])).unwrap();
+    assert_eq!(read(&mut s2, 4), b"\x0c\x03\x01\x04");
+
+    // a sequence number of more than 8 bytes
+    let mut s3 = connect(30472);
+    s3.write_all(&content_query(0x0C, &[1; 9])).unwrap();
+    assert_eq!(read(&mut s3, 2), b"\x0c\x01");
+
+    // the changes are still there after a restart
+    drop(server);
+    let _server = start(&dir, &["--port", "30472"]);
+    let mut follower = connect(30472);
+    follower.write_all(&content_query(0x0C, &[1])).unwrap();
+    assert_eq!(read(&mut follower, 4), b"\x0c\x00\x01\x04");
+    assert_eq!(
+        read(&mut follower, 10),
+        b"\x0c\x12\x01\x01\x01\x01\x00a\x01\x01"
+    );
+    assert_eq!(read(&mut follower, 1), b"1");
+}
+
+#[test]
+fn test_slow_subscriber() {
+    let dir = work_dir("push-slow");
+    let _server = start(&dir, &["--port", "30473"]);
+    let mut sub = connect(30473);
+    let mut s = connect(30473);
+    sub.write_all(&content_query(0x05, b"news")).unwrap();
+    assert_eq!(count(&mut sub), 1);
+
+    // the subscriber doesn't read, until it's dropped
+    let message = vec![b'm'; 1024 * 1024];
+    let mut dropped = false;
+    for _ in 0..1000 {
+        s.write_all(&key_value_query(0x08, "news", &message))
+            .unwrap();
+        if count(&mut s) == 0 {
+            dropped = true;
+            break;
+        }
+    }
+    assert!(dropped);
+
+    // and disconnected, after what was queued
+    let mut received = 0;
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match sub.read(&mut buffer) {
+            Ok(0) | Err(_) => break,
+            Ok(x) => received += x,
+        }
+    }
+    assert!(received < 200 * 1024 * 1024);
+    s.write_all(&key_value_query(0x08, "news", b"hi")).unwrap();
+    assert_eq!(count(&mut s), 0);
+}
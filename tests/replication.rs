@@ -0,0 +1,139 @@
+// Run a primary and a replica server on localhost ports, each in its own
+// working directory, and check that writes on the primary show up on
+// the replica.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{connect, start, work_dir};
+
+fn query(stream: &mut TcpStream, cmd: u8, content: &[u8]) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content);
+    stream.write_all(&buffer).unwrap();
+}
+
+fn put(stream: &mut TcpStream, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(8);
+    buffer.extend(&(value.len() as u64).to_le_bytes());
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn del(stream: &mut TcpStream, key: &str) -> u8 {
+    query(stream, 0x03, key.as_bytes());
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn get(stream: &mut TcpStream, key: &str) -> Option<String> {
+    query(stream, 0x01, key.as_bytes());
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+fn replicaof(stream: &mut TcpStream, addr: &str) -> u8 {
+    query(stream, 0x0E, addr.as_bytes());
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+/// Wait until the key has the value on the server
+fn wait_for(stream: &mut TcpStream, key: &str, value: Option<&str>) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(stream, key).as_deref() != value {
+        if Instant::now() > deadline {
+            panic!("key {:?} is not {:?} in time", key, value);
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_replication() {
+    let primary_dir = work_dir("replication-primary");
+    let replica_dir = work_dir("replication-replica");
+    let _primary = start(&primary_dir, &["--port", "30261"]);
+    let mut p = connect(30261);
+    assert_eq!(put(&mut p, "foo", "bar"), 0x00);
+
+    // full sync
+    let _replica = start(
+        &replica_dir,
+        &["--port", "30262", "--replicaof", "127.0.0.1:30261"],
+    );
+    let mut r = connect(30262);
+    wait_for(&mut r, "foo", Some("bar"));
+
+    // live changes
+    assert_eq!(put(&mut p, "lang", "Rust"), 0x00);
+    assert_eq!(del(&mut p, "foo"), 0x00);
+    wait_for(&mut r, "lang", Some("Rust"));
+    wait_for(&mut r, "foo", None);
+
+    // replica is read only
+    assert_eq!(put(&mut r, "foo", "baz"), 0x04);
+    assert_eq!(del(&mut r, "lang"), 0x04);
+
+    // promoted to primary
+    assert_eq!(replicaof(&mut r, ""), 0x00);
+    assert_eq!(put(&mut r, "foo", "baz"), 0x00);
+    assert_eq!(get(&mut r, "foo"), Some("baz".to_string()));
+    assert_eq!(put(&mut p, "name", "Hugo"), 0x00);
+    assert_eq!(get(&mut p, "foo"), None);
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(get(&mut r, "name"), None);
+
+    // the old primary made a replica of the new one has the same sequence
+    // numbers, but not the same changes, and gets a full sync
+    assert_eq!(replicaof(&mut p, "127.0.0.1:30262"), 0x00);
+    wait_for(&mut p, "foo", Some("baz"));
+    wait_for(&mut p, "name", None);
+}
+
+#[test]
+fn test_replica_restart() {
+    let primary_dir = work_dir("restart-primary");
+    let replica_dir = work_dir("restart-replica");
+    let _primary = start(&primary_dir, &["--port", "30263"]);
+    let mut p = connect(30263);
+    let args = ["--port", "30264", "--replicaof", "127.0.0.1:30263"];
+    let replica = start(&replica_dir, &args);
+    let mut r = connect(30264);
+    assert_eq!(put(&mut p, "foo", "bar"), 0x00);
+    wait_for(&mut r, "foo", Some("bar"));
+
+    // changes while the replica is down are caught up after restart
+    drop(replica);
+    assert_eq!(put(&mut p, "lang", "Rust"), 0x00);
+    assert_eq!(del(&mut p, "foo"), 0x00);
+    let _replica = start(&replica_dir, &args);
+    let mut r = connect(30264);
+    wait_for(&mut r, "lang", Some("Rust"));
+    wait_for(&mut r, "foo", None);
+}
@@ -0,0 +1,166 @@
+// Run a server with the Redis protocol listener on localhost ports, and
+// talk to it as a Redis client would.
+
+mod common;
+
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+use common::{connect, start, work_dir};
+
+struct Client {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+}
+
+fn client(port: u16) -> Client {
+    let stream = connect(port);
+    Client {
+        writer: stream.try_clone().unwrap(),
+        reader: BufReader::new(stream),
+    }
+}
+
+/// Read a whole reply, returned in RESP as is
+fn read_reply(c: &mut Client) -> String {
+    let mut line = String::new();
+    c.reader.read_line(&mut line).unwrap();
+    let count = line[1..].trim_end().parse::<i64>().unwrap_or(0);
+    match line.as_bytes()[0] {
+        b'$' if count >= 0 => {
+            let mut value = vec![0; count as usize + 2];
+            c.reader.read_exact(&mut value).unwrap();
+            line.push_str(&String::from_utf8(value).unwrap());
+        }
+        b'*' => {
+            for _ in 0..count {
+                line.push_str(&read_reply(c));
+            }
+        }
+        b'%' => {
+            for _ in 0..count * 2 {
+                line.push_str(&read_reply(c));
+            }
+        }
+        _ => {}
+    }
+    line
+}
+
+fn command(c: &mut Client, args: &[&str]) -> String {
+    let mut buffer = format!("*{}\r\n", args.len());
+    for x in args {
+        buffer.push_str(&format!("${}\r\n{}\r\n", x.len(), x));
+    }
+    c.writer.write_all(buffer.as_bytes()).unwrap();
+    read_reply(c)
+}
+
+/// GET with the h2okv protocol
+fn get(stream: &mut TcpStream, key: &str) -> Option<String> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn test_resp() {
+    let dir = work_dir("resp");
+    let _server = start(&dir, &["--port", "30320", "--resp-port", "30321"]);
+    let mut h = connect(30320);
+    let mut c = client(30321);
+
+    assert_eq!(command(&mut c, &["PING"]), "+PONG\r\n");
+    assert_eq!(command(&mut c, &["ECHO", "hi"]), "$2\r\nhi\r\n");
+    assert_eq!(command(&mut c, &["GET", "foo"]), "$-1\r\n");
+    assert_eq!(command(&mut c, &["SET", "foo", "bar baz"]), "+OK\r\n");
+    assert_eq!(command(&mut c, &["GET", "foo"]), "$7\r\nbar baz\r\n");
+    assert_eq!(get(&mut h, "foo"), Some("bar baz".to_string()));
+
+    assert_eq!(command(&mut c, &["SET", "foo", "x", "NX"]), "$-1\r\n");
+    assert_eq!(command(&mut c, &["SET", "lang", "Rust", "XX"]), "$-1\r\n");
+    assert_eq!(command(&mut c, &["SET", "foo", "x", "XX"]), "+OK\r\n");
+    assert_eq!(
+        command(&mut c, &["MSET", "lang", "Rust", "name", "Hugo"]),
+        "+OK\r\n"
+    );
+    assert_eq!(
+        command(&mut c, &["MGET", "foo", "nope", "lang"]),
+        "*3\r\n$1\r\nx\r\n$-1\r\n$4\r\nRust\r\n"
+    );
+    assert_eq!(
+        command(&mut c, &["EXISTS", "foo", "nope", "lang"]),
+        ":2\r\n"
+    );
+    assert_eq!(command(&mut c, &["DBSIZE"]), ":3\r\n");
+    assert_eq!(
+        command(&mut c, &["KEYS", "*a*"]),
+        "*2\r\n$4\r\nlang\r\n$4\r\nname\r\n"
+    );
+    assert_eq!(
+        command(&mut c, &["SCAN", "0", "MATCH", "f*", "COUNT", "10"]),
+        "*2\r\n$1\r\n0\r\n*1\r\n$3\r\nfoo\r\n"
+    );
+    assert_eq!(command(&mut c, &["TYPE", "foo"]), "+string\r\n");
+    assert_eq!(command(&mut c, &["DEL", "foo", "nope", "name"]), ":2\r\n");
+    assert_eq!(get(&mut h, "foo"), None);
+
+    assert!(command(&mut c, &["GET"]).starts_with("-ERR wrong number"));
+    assert!(command(&mut c, &["FOO"]).starts_with("-ERR unknown command"));
+
+    // inline commands
+    c.writer.write_all(b"PING\r\n").unwrap();
+    assert_eq!(read_reply(&mut c), "+PONG\r\n");
+
+    // RESP3
+    let reply = command(&mut c, &["HELLO", "3"]);
+    assert!(reply.starts_with("%6\r\n$6\r\nserver\r\n$5\r\nh2okv\r\n"));
+    assert!(reply.contains("$5\r\nproto\r\n:3\r\n"));
+    assert_eq!(command(&mut c, &["GET", "foo"]), "_\r\n");
+    assert!(command(&mut c, &["HELLO", "4"]).starts_with("-NOPROTO"));
+
+    assert_eq!(command(&mut c, &["QUIT"]), "+OK\r\n");
+}
+
+#[test]
+fn test_resp_moved() {
+    let (dir1, dir2) = (work_dir("resp-moved-1"), work_dir("resp-moved-2"));
+    let spec = "0-8191=127.0.0.1:30322,8192-16383=127.0.0.1:30323";
+    let _s1 = start(
+        &dir1,
+        &["--port", "30322", "--resp-port", "31322", "--slots", spec],
+    );
+    let _s2 = start(
+        &dir2,
+        &["--port", "30323", "--resp-port", "31323", "--slots", spec],
+    );
+    let mut c1 = client(31322);
+    let mut c2 = client(31323);
+
+    // "foo" is in slot 12182, owned by the second server
+    assert_eq!(
+        command(&mut c1, &["SET", "foo", "bar"]),
+        "-MOVED 12182 127.0.0.1:31323\r\n"
+    );
+    assert_eq!(command(&mut c2, &["SET", "foo", "bar"]), "+OK\r\n");
+    assert_eq!(
+        command(&mut c1, &["GET", "foo"]),
+        "-MOVED 12182 127.0.0.1:31323\r\n"
+    );
+    assert_eq!(command(&mut c2, &["GET", "foo"]), "$3\r\nbar\r\n");
+}
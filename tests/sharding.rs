@@ -0,0 +1,274 @@
+// Run servers sharing the hash slots on localhost ports, and check that
+// keys are redirected to their owner, that the CLI follows the redirects,
+// and that slots are migrated without losing writes.
+
+mod common;
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use common::{connect, start, work_dir};
+
+const SLOTS: &str = "0-8191=127.0.0.1:30291,8192-16383=127.0.0.1:30292";
+
+fn query(stream: &mut TcpStream, cmd: u8, content: &[u8]) {
+    let mut buffer = vec![0x0c, cmd, 0x00];
+    buffer.extend(&(content.len() as u16).to_le_bytes());
+    buffer.extend(content);
+    stream.write_all(&buffer).unwrap();
+}
+
+fn read_exact(stream: &mut TcpStream, size: usize) -> Vec<u8> {
+    let mut buffer = vec![0; size];
+    stream.read_exact(&mut buffer).unwrap();
+    buffer
+}
+
+fn read_string(stream: &mut TcpStream) -> String {
+    let buf_len = read_exact(stream, 2);
+    let size = u16::from_le_bytes([buf_len[0], buf_len[1]]) as usize;
+    String::from_utf8(read_exact(stream, size)).unwrap()
+}
+
+#[test]
+fn test_moved() {
+    let _a = start(
+        &work_dir("sharding-a"),
+        &["--port", "30291", "--slots", SLOTS],
+    );
+    let _b = start(
+        &work_dir("sharding-b"),
+        &["--port", "30292", "--slots", SLOTS],
+    );
+    let mut a = connect(30291);
+    let mut b = connect(30292);
+
+    // slot of "foo" is 12182, owned by b
+    query(&mut a, 0x01, b"foo");
+    assert_eq!(read_exact(&mut a, 4), b"\x0c\x06\x96\x2f");
+    assert_eq!(read_string(&mut a), "127.0.0.1:30292");
+    query(&mut a, 0x03, b"foo");
+    assert_eq!(read_exact(&mut a, 4), b"\x0c\x06\x96\x2f");
+    assert_eq!(read_string(&mut a), "127.0.0.1:30292");
+    query(&mut b, 0x03, b"foo");
+    assert_eq!(read_exact(&mut b, 2), b"\x0c\x02");
+
+    // slot of "bar" is 5061, owned by a
+    query(&mut b, 0x01, b"bar");
+    assert_eq!(read_exact(&mut b, 4), b"\x0c\x06\xc5\x13");
+    assert_eq!(read_string(&mut b), "127.0.0.1:30291");
+
+    query(&mut b, 0x11, b"");
+    assert_eq!(read_exact(&mut b, 4), b"\x0c\x00\x02\x00");
+    assert_eq!(read_exact(&mut b, 4), b"\x00\x00\xff\x1f");
+    assert_eq!(read_string(&mut b), "127.0.0.1:30291");
+    assert_eq!(read_exact(&mut b, 4), b"\x00\x20\xff\x3f");
+    assert_eq!(read_string(&mut b), "127.0.0.1:30292");
+}
+
+#[test]
+fn test_cli_routing() {
+    let slots = "0-8191=127.0.0.1:30293,8192-16383=127.0.0.1:30294";
+    let _a = start(
+        &work_dir("routing-a"),
+        &["--port", "30293", "--slots", slots],
+    );
+    let _b = start(
+        &work_dir("routing-b"),
+        &["--port", "30294", "--slots", slots],
+    );
+    connect(30293);
+    connect(30294);
+
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_h2okv-cli"))
+        .arg("127.0.0.1:30293")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = cli.stdin.take().unwrap();
+    stdin
+        .write_all(b"set foo 1\nset bar 2\nget foo\nget bar\nscan\n")
+        .unwrap();
+    let mut lines = BufReader::new(cli.stdout.take().unwrap()).lines();
+    let mut output = vec![];
+    for _ in 0..6 {
+        output.push(lines.next().unwrap().unwrap());
+    }
+    let _ = cli.kill();
+    let _ = cli.wait();
+
+    assert_eq!(output[1], "h2okv> OK");
+    assert_eq!(output[2], "h2okv> OK");
+    assert_eq!(output[3], "h2okv> \"1\"");
+    assert_eq!(output[4], "h2okv> \"2\"");
+    // keys are on their own servers, SCAN is sent to the first one
+    assert_eq!(output[5], "h2okv> 1) \"bar\"");
+}
+
+/// A client following MOVED and ASK, as the CLI does
+struct Client {
+    streams: HashMap<String, TcpStream>,
+    /// the server to send all keys first
+    default: String,
+}
+
+impl Client {
+    fn new(default: &str) -> Client {
+        Client {
+            streams: HashMap::new(),
+            default: default.to_string(),
+        }
+    }
+
+    /// Send the query, returns the status and the value for GET
+    fn send(&mut self, query: &[u8]) -> (u8, Option<String>) {
+        let mut addr = self.default.clone();
+        let mut asking = false;
+        for _ in 0..5 {
+            let stream = self
+                .streams
+                .entry(addr.clone())
+                .or_insert_with(|| TcpStream::connect(&addr).unwrap());
+            if asking {
+                stream.write_all(b"\x0c\x14\x00\x00\x00").unwrap();
+                assert_eq!(read_exact(stream, 2), b"\x0c\x00");
+            }
+            stream.write_all(query).unwrap();
+            let reply = read_exact(stream, 2);
+            match reply[1] {
+                0x06 | 0x07 => {
+                    read_exact(stream, 2);
+                    addr = read_string(stream);
+                    asking = reply[1] == 0x07;
+                }
+                0x00 if query[1] == 0x01 => {
+                    let buf_flag_llen = read_exact(stream, 2);
+                    let mut buf_len = [0; 8];
+                    stream
+                        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+                        .unwrap();
+                    let value = read_exact(stream, u64::from_le_bytes(buf_len) as usize);
+                    return (0x00, Some(String::from_utf8(value).unwrap()));
+                }
+                x => return (x, None),
+            }
+        }
+        panic!("too many redirects");
+    }
+
+    fn put(&mut self, key: &str, value: &str) -> u8 {
+        let mut query = vec![0x0c, 0x02, 0x00];
+        query.extend(&(key.len() as u16).to_le_bytes());
+        query.extend(key.as_bytes());
+        query.push(8);
+        query.extend(&(value.len() as u64).to_le_bytes());
+        query.extend(value.as_bytes());
+        self.send(&query).0
+    }
+
+    fn del(&mut self, key: &str) -> u8 {
+        let mut query = vec![0x0c, 0x03, 0x00];
+        query.extend(&(key.len() as u16).to_le_bytes());
+        query.extend(key.as_bytes());
+        self.send(&query).0
+    }
+
+    fn get(&mut self, key: &str) -> Option<String> {
+        let mut query = vec![0x0c, 0x01, 0x00];
+        query.extend(&(key.len() as u16).to_le_bytes());
+        query.extend(key.as_bytes());
+        self.send(&query).1
+    }
+}
+
+#[test]
+fn test_migrate() {
+    let slots = "0-8191=127.0.0.1:30295,8192-16383=127.0.0.1:30296";
+    let _a = start(
+        &work_dir("migrate-a"),
+        &["--port", "30295", "--slots", slots],
+    );
+    let _b = start(
+        &work_dir("migrate-b"),
+        &["--port", "30296", "--slots", slots],
+    );
+    // the new server owns nothing yet
+    let _c = start(
+        &work_dir("migrate-c"),
+        &["--port", "30297", "--slots", slots],
+    );
+    connect(30295);
+    connect(30296);
+    connect(30297);
+
+    let mut client = Client::new("127.0.0.1:30295");
+    for i in 0..300 {
+        assert_eq!(client.put(&format!("key{}", i), "0"), 0x00);
+    }
+
+    // keep writing and deleting while migrating, remembering the
+    // acknowledged writes
+    let stop = Arc::new(AtomicBool::new(false));
+    let clone_stop = stop.clone();
+    let writer = thread::spawn(move || {
+        let mut client = Client::new("127.0.0.1:30295");
+        let mut acked = HashMap::new();
+        let mut round = 1;
+        while !clone_stop.load(Ordering::SeqCst) {
+            for i in (0..600).step_by(7) {
+                let key = format!("key{}", i);
+                let value = format!("{}", round);
+                assert_eq!(client.put(&key, &value), 0x00);
+                acked.insert(key, Some(value));
+            }
+            for i in (0..300).step_by(11) {
+                let key = format!("key{}", i);
+                assert!([0x00, 0x02].contains(&client.del(&key)));
+                acked.insert(key, None);
+            }
+            round += 1;
+        }
+        acked
+    });
+
+    thread::sleep(Duration::from_millis(100));
+    let mut a = connect(30295);
+    query(&mut a, 0x12, b"0-8191 127.0.0.1:30297");
+    assert_eq!(read_exact(&mut a, 2), b"\x0c\x00");
+    assert!(read_string(&mut a).ends_with("keys moved"));
+    thread::sleep(Duration::from_millis(100));
+    stop.store(true, Ordering::SeqCst);
+    let acked = writer.join().unwrap();
+
+    // no writes are lost
+    let mut client = Client::new("127.0.0.1:30296");
+    for i in 0..600 {
+        let key = format!("key{}", i);
+        let expected = match acked.get(&key) {
+            Some(x) => x.clone(),
+            None if i < 300 => Some("0".to_string()),
+            None => None,
+        };
+        assert_eq!(client.get(&key), expected, "{}", key);
+    }
+
+    // all servers know the new owner, the old one has no keys left
+    query(&mut a, 0x04, b"");
+    assert_eq!(read_exact(&mut a, 2), b"\x0c\x02");
+    let mut b = connect(30296);
+    query(&mut b, 0x01, b"bar");
+    assert_eq!(read_exact(&mut b, 4), b"\x0c\x06\xc5\x13");
+    assert_eq!(read_string(&mut b), "127.0.0.1:30297");
+
+    // migrating slots not owned is rejected
+    query(&mut a, 0x12, b"0-10 127.0.0.1:30296");
+    assert_eq!(read_exact(&mut a, 2), b"\x0c\x01");
+    assert_eq!(read_string(&mut a), "slot 0 is not owned by this server");
+}
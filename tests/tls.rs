@@ -0,0 +1,214 @@
+// Run servers with TLS on localhost ports, with certificates generated
+// here, and talk to them with the h2okv protocol and the CLI.
+
+mod common;
+
+use std::convert::TryFrom;
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use rcgen::{
+    BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa,
+    KeyPair,
+};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+
+use common::{connect, start, work_dir};
+
+/// Sign a certificate for `names` with the CA, written into
+/// `{dir}/{name}.pem` and `{dir}/{name}.key`
+fn issue(
+    dir: &Path,
+    name: &str,
+    names: &[&str],
+    purpose: ExtendedKeyUsagePurpose,
+    ca: &Certificate,
+    ca_key: &KeyPair,
+) {
+    let key = KeyPair::generate().unwrap();
+    let names: Vec<String> = names.iter().map(|x| x.to_string()).collect();
+    let mut params = CertificateParams::new(names).unwrap();
+    params.distinguished_name.push(DnType::CommonName, name);
+    params.extended_key_usages = vec![purpose];
+    let cert = params.signed_by(&key, ca, ca_key).unwrap();
+    fs::write(dir.join(format!("{}.pem", name)), cert.pem()).unwrap();
+    fs::write(dir.join(format!("{}.key", name)), key.serialize_pem()).unwrap();
+}
+
+/// Write a CA, and a server and a client certificates signed by it
+fn generate_certs(dir: &Path) {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut params = CertificateParams::new(vec![]).unwrap();
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    params
+        .distinguished_name
+        .push(DnType::CommonName, "h2okv test CA");
+    let ca = params.self_signed(&ca_key).unwrap();
+    fs::write(dir.join("ca.pem"), ca.pem()).unwrap();
+
+    let names = ["localhost", "127.0.0.1"];
+    let purpose = ExtendedKeyUsagePurpose::ServerAuth;
+    issue(dir, "server", &names, purpose, &ca, &ca_key);
+    let purpose = ExtendedKeyUsagePurpose::ClientAuth;
+    issue(dir, "client", &["client"], purpose, &ca, &ca_key);
+}
+
+fn path(dir: &Path, name: &str) -> String {
+    dir.join(name).to_str().unwrap().to_string()
+}
+
+fn client_config(dir: &Path, with_cert: bool) -> Arc<ClientConfig> {
+    let ca = fs::read(dir.join("ca.pem")).unwrap();
+    let mut roots = RootCertStore::empty();
+    for x in rustls_pemfile::certs(&mut &ca[..]) {
+        roots.add(x.unwrap()).unwrap();
+    }
+    let builder = ClientConfig::builder().with_root_certificates(roots);
+    if !with_cert {
+        return Arc::new(builder.with_no_client_auth());
+    }
+    let cert = fs::read(dir.join("client.pem")).unwrap();
+    let certs: Vec<CertificateDer> = rustls_pemfile::certs(&mut &cert[..])
+        .map(|x| x.unwrap())
+        .collect();
+    let key = fs::read(dir.join("client.key")).unwrap();
+    let key: PrivateKeyDer = rustls_pemfile::private_key(&mut &key[..]).unwrap().unwrap();
+    Arc::new(builder.with_client_auth_cert(certs, key).unwrap())
+}
+
+fn connect_tls(port: u16, config: &Arc<ClientConfig>) -> StreamOwned<ClientConnection, TcpStream> {
+    let name = ServerName::try_from("127.0.0.1").unwrap();
+    let conn = ClientConnection::new(config.clone(), name).unwrap();
+    StreamOwned::new(conn, connect(port))
+}
+
+fn put<S: Read + Write>(stream: &mut S, key: &str, value: &str) -> Option<u8> {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(1);
+    buffer.push(value.len() as u8);
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).ok()?;
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).ok()?;
+    Some(reply[1])
+}
+
+fn get<S: Read + Write>(stream: &mut S, key: &str) -> Option<String> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn test_tls() {
+    let dir = work_dir("tls");
+    generate_certs(&dir);
+    let (cert, key) = (path(&dir, "server.pem"), path(&dir, "server.key"));
+    let args = [
+        "--port",
+        "30360",
+        "--tls-port",
+        "30361",
+        "--tls-cert",
+        &cert,
+        "--tls-key",
+        &key,
+    ];
+    let _server = start(&dir, &args);
+    let mut plain = connect(30360);
+    let mut tls = connect_tls(30361, &client_config(&dir, false));
+
+    assert_eq!(put(&mut tls, "foo", "bar"), Some(0x00));
+    assert_eq!(get(&mut tls, "foo"), Some("bar".to_string()));
+    assert_eq!(get(&mut plain, "foo"), Some("bar".to_string()));
+
+    // messages are pushed while the subscriber may still send queries
+    let mut subscriber = connect_tls(30361, &client_config(&dir, false));
+    subscriber.write_all(b"\x0c\x05\x00\x04\x00news").unwrap();
+    let mut reply = [0; 6];
+    subscriber.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"\x0c\x00\x01\x00\x00\x00");
+    plain
+        .write_all(b"\x0c\x08\x00\x04\x00news\x01\x05hello")
+        .unwrap();
+    plain.read_exact(&mut reply).unwrap();
+    assert_eq!(&reply, b"\x0c\x00\x01\x00\x00\x00");
+    let mut frame = [0; 17];
+    subscriber.read_exact(&mut frame).unwrap();
+    assert_eq!(&frame, b"\x0c\x10\x00\x00\x04\x00news\x01\x05hello");
+
+    // plain text is not accepted on the TLS port
+    let mut stream = connect(30361);
+    assert_ne!(put(&mut stream, "foo", "baz"), Some(0x00));
+    assert_eq!(get(&mut plain, "foo"), Some("bar".to_string()));
+
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_h2okv-cli"))
+        .args(["--tls-ca-cert", &path(&dir, "ca.pem"), "127.0.0.1:30361"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = cli.stdin.take().unwrap();
+    stdin.write_all(b"get foo\nset lang Rust\n").unwrap();
+    let mut lines = BufReader::new(cli.stdout.take().unwrap()).lines();
+    let mut output = vec![];
+    for _ in 0..3 {
+        output.push(lines.next().unwrap().unwrap());
+    }
+    let _ = cli.kill();
+    let _ = cli.wait();
+
+    assert_eq!(output[1], "h2okv> \"bar\"");
+    assert_eq!(output[2], "h2okv> OK");
+    assert_eq!(get(&mut plain, "lang"), Some("Rust".to_string()));
+}
+
+#[test]
+fn test_mutual_tls() {
+    let dir = work_dir("tls-mutual");
+    generate_certs(&dir);
+    let (cert, key) = (path(&dir, "server.pem"), path(&dir, "server.key"));
+    let ca = path(&dir, "ca.pem");
+    let args = [
+        "--port",
+        "0",
+        "--tls-port",
+        "30363",
+        "--tls-cert",
+        &cert,
+        "--tls-key",
+        &key,
+        "--tls-ca-cert",
+        &ca,
+    ];
+    let _server = start(&dir, &args);
+
+    let mut stream = connect_tls(30363, &client_config(&dir, false));
+    assert_eq!(put(&mut stream, "foo", "bar"), None);
+
+    let mut stream = connect_tls(30363, &client_config(&dir, true));
+    assert_eq!(put(&mut stream, "foo", "bar"), Some(0x00));
+    assert_eq!(get(&mut stream, "foo"), Some("bar".to_string()));
+}
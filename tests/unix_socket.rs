@@ -0,0 +1,122 @@
+// Run servers listening on unix domain sockets, and talk to them with
+// the h2okv protocol and the CLI.
+
+mod common;
+
+use std::fs;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use common::{start, work_dir};
+
+fn connect_unix(path: &Path) -> UnixStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match UnixStream::connect(path) {
+            Ok(x) => return x,
+            Err(e) => {
+                if Instant::now() > deadline {
+                    panic!("cannot connect to {:?}: {:?}", path, e);
+                }
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+}
+
+fn put<S: Read + Write>(stream: &mut S, key: &str, value: &str) -> u8 {
+    let mut buffer = vec![0x0c, 0x02, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    buffer.push(1);
+    buffer.push(value.len() as u8);
+    buffer.extend(value.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    reply[1]
+}
+
+fn get<S: Read + Write>(stream: &mut S, key: &str) -> Option<String> {
+    let mut buffer = vec![0x0c, 0x01, 0x00];
+    buffer.extend(&(key.len() as u16).to_le_bytes());
+    buffer.extend(key.as_bytes());
+    stream.write_all(&buffer).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    if reply[1] != 0x00 {
+        return None;
+    }
+    let mut buf_flag_llen = [0; 2];
+    stream.read_exact(&mut buf_flag_llen).unwrap();
+    let mut buf_len = [0; 8];
+    stream
+        .read_exact(&mut buf_len[..buf_flag_llen[1] as usize])
+        .unwrap();
+    let mut value = vec![0; u64::from_le_bytes(buf_len) as usize];
+    stream.read_exact(&mut value).unwrap();
+    Some(String::from_utf8(value).unwrap())
+}
+
+#[test]
+fn test_unix_socket_only() {
+    let dir = work_dir("unix-socket-only");
+    let path = dir.join("h2okv.sock");
+    let args = [
+        "--port",
+        "0",
+        "--unix-socket",
+        path.to_str().unwrap(),
+        "--unix-socket-perm",
+        "600",
+    ];
+    let _server = start(&dir, &args);
+    let mut stream = connect_unix(&path);
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    assert_eq!(put(&mut stream, "foo", "bar"), 0x00);
+    assert_eq!(get(&mut stream, "foo"), Some("bar".to_string()));
+
+    let mut cli = Command::new(env!("CARGO_BIN_EXE_h2okv-cli"))
+        .arg(format!("unix:{}", path.to_str().unwrap()))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = cli.stdin.take().unwrap();
+    stdin.write_all(b"get foo\nset lang Rust\n").unwrap();
+    let mut lines = BufReader::new(cli.stdout.take().unwrap()).lines();
+    let mut output = vec![];
+    for _ in 0..3 {
+        output.push(lines.next().unwrap().unwrap());
+    }
+    let _ = cli.kill();
+    let _ = cli.wait();
+
+    assert!(output[0].starts_with("Connected to h2okv server unix:"));
+    assert_eq!(output[1], "h2okv> \"bar\"");
+    assert_eq!(output[2], "h2okv> OK");
+    assert_eq!(get(&mut stream, "lang"), Some("Rust".to_string()));
+}
+
+#[test]
+fn test_unix_socket_and_tcp() {
+    let dir = work_dir("unix-socket-tcp");
+    let path = dir.join("h2okv.sock");
+    let args = ["--port", "30350", "--unix-socket", path.to_str().unwrap()];
+    let _server = start(&dir, &args);
+    let mut unix = connect_unix(&path);
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o700);
+    let mut tcp = TcpStream::connect("127.0.0.1:30350").unwrap();
+
+    assert_eq!(put(&mut unix, "foo", "bar"), 0x00);
+    assert_eq!(get(&mut tcp, "foo"), Some("bar".to_string()));
+}